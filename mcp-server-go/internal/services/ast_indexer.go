@@ -2,7 +2,9 @@ package services
 
 import (
 	"context"
+	"crypto/sha256"
 	"database/sql"
+	"encoding/hex"
 	"encoding/json"
 	"fmt"
 	"os"
@@ -33,19 +35,39 @@ type Node struct {
 	LineStart     int      `json:"line_start"`
 	LineEnd       int      `json:"line_end"`
 	Signature     string   `json:"signature,omitempty"`
+	Anchor        string   `json:"anchor,omitempty"`
+	Doc           string   `json:"doc,omitempty"`
+	Source        string   `json:"source,omitempty"` // overlay | disk，仅在存在未保存缓冲区覆盖层时出现
 	Calls         []string `json:"calls,omitempty"`
 }
 
 // Stats 统计信息
 type Stats struct {
-	TotalFiles   int `json:"total_files"`
-	TotalSymbols int `json:"total_symbols"`
+	TotalFiles   int           `json:"total_files"`
+	TotalSymbols int           `json:"total_symbols"`
+	Licenses     LicenseReport `json:"licenses"`
+}
+
+// LicenseReport 许可证构成（文件头 SPDX / 正文识别）及与项目许可证不一致的文件
+type LicenseReport struct {
+	Composition map[string]int    `json:"composition"` // 许可证 -> 文件数，空串为未声明
+	Project     map[string]string `json:"project"`     // root 名 -> 项目许可证
+	Mismatched  []LicenseMismatch `json:"mismatched"`
+}
+
+// LicenseMismatch 来源与项目许可证不同的文件
+type LicenseMismatch struct {
+	FilePath       string `json:"file_path"`
+	License        string `json:"license"`
+	Copyright      string `json:"copyright,omitempty"`
+	ProjectLicense string `json:"project_license"`
 }
 
 // MapResult 项目地图结果 (--mode map)
 type MapResult struct {
 	Statistics    Stats              `json:"statistics"`
 	Structure     map[string][]Node  `json:"structure"`
+	Readmes       map[string]string  `json:"readmes,omitempty"` // 目录 -> README 首段（根目录为 ""）
 	Elapsed       string             `json:"elapsed"`
 	ComplexityMap map[string]float64 `json:"complexity_map,omitempty"` // 符号名 -> 复杂度分数
 }
@@ -54,6 +76,7 @@ type MapResult struct {
 type StructureDirInfo struct {
 	FileCount int      `json:"file_count"`
 	Files     []string `json:"files"`
+	Readme    string   `json:"readme,omitempty"` // 目录 README 的首段
 }
 
 // StructureResult 目录结构结果（--mode structure）
@@ -72,8 +95,20 @@ type CandidateMatch struct {
 
 // CallerInfo 调用者信息
 type CallerInfo struct {
-	Node     Node   `json:"node"`
-	CallType string `json:"call_type"`
+	Node         Node      `json:"node"`
+	CallType     string    `json:"call_type"`               // direct / indirect / http / reference / dynamic / override
+	ReceiverType string    `json:"receiver_type,omitempty"` // obj.method() 推断出的 obj 类型
+	Confidence   float64   `json:"confidence,omitempty"`    // 推测边（--expand-dynamic 动态分发）的置信度
+	CallSite     *CallSite `json:"call_site,omitempty"`     // query 的调用点所在行
+}
+
+// CallSite 调用点源码片段；Stale 表示文件在索引后改动过，不附文本
+type CallSite struct {
+	Line   int      `json:"line"`
+	Text   string   `json:"text,omitempty"`
+	Before []string `json:"before,omitempty"` // --context-lines 指定的前后若干行
+	After  []string `json:"after,omitempty"`
+	Stale  bool     `json:"stale"`
 }
 
 // QueryResult 查询结果 (--mode query)
@@ -83,6 +118,8 @@ type QueryResult struct {
 	FoundSymbol  *Node            `json:"found_symbol"`
 	MatchType    string           `json:"match_type,omitempty"`
 	Candidates   []CandidateMatch `json:"candidates"`
+	Confidence   float32          `json:"confidence"`
+	ScoreVersion int              `json:"score_version"` // 打分契约版本，不同版本的 score 不可直接比较
 	RelatedNodes []CallerInfo     `json:"related_nodes"`
 }
 
@@ -90,6 +127,7 @@ type QueryResult struct {
 type ImpactResult struct {
 	Status                string       `json:"status"`
 	NodeID                string       `json:"node_id"`
+	Source                string       `json:"source,omitempty"`
 	ComplexityScore       float64      `json:"complexity_score"`
 	ComplexityLevel       string       `json:"complexity_level"`
 	RiskLevel             string       `json:"risk_level"`
@@ -97,18 +135,160 @@ type ImpactResult struct {
 	DirectCallers         []CallerInfo `json:"direct_callers"`
 	IndirectCallers       []CallerInfo `json:"indirect_callers"`
 	ModificationChecklist []string     `json:"modification_checklist"`
+	Recursive             bool         `json:"recursive,omitempty"`        // 目标自递归或处在相互递归环上
+	MutualRecursion       []string     `json:"mutual_recursion,omitempty"` // 同一调用环上的其他符号
+	Implementations       []Node       `json:"implementations,omitempty"`  // 目标为接口 / 基类方法时各子类型里的实现
+	Overrides             []Node       `json:"overrides,omitempty"`        // 目标为重写方法时被它重写的基方法
+	GeneratedStubs        int          `json:"generated_stubs,omitempty"`  // 折叠进 proto 定义的生成桩符号数
 	Message               string       `json:"message,omitempty"`
 }
 
+// SourceResult 符号源码片段 (--mode source)
+type SourceResult struct {
+	Status    string  `json:"status"`
+	ID        string  `json:"id"`
+	FilePath  string  `json:"file_path"`
+	LineStart int     `json:"line_start"`
+	LineEnd   int     `json:"line_end"`
+	Fresh     bool    `json:"fresh"`
+	Relocated bool    `json:"relocated"`
+	Text      *string `json:"text"`
+}
+
+// ReindexFileResult 未保存缓冲区的覆盖层写入结果 (--mode reindex-file)
+// Status 为 overlay（读模式按缓冲区作答）或 cleared（内容与索引一致，覆盖层已移除）
+type ReindexFileResult struct {
+	Status     string `json:"status"`
+	Generation int64  `json:"generation"`
+	FilePath   string `json:"file_path"`
+	Symbols    int    `json:"symbols"`
+}
+
+// SandboxDiffResult 命名沙箱相对基础索引的符号差异 (--mode sandbox-diff)
+// 覆盖层不存调用边，Details 只包含符号本身的变化
+type SandboxDiffResult struct {
+	Status     string                `json:"status"`
+	Generation int64                 `json:"generation"`
+	Sandbox    string                `json:"sandbox"`
+	Files      []string              `json:"files"`
+	Added      []string              `json:"added"`
+	Removed    []string              `json:"removed"`
+	Modified   []string              `json:"modified"`
+	Details    map[string]DiffDetail `json:"details"`
+}
+
+// DiffDetail 单个符号的变化说明
+type DiffDetail struct {
+	ChangeType string `json:"change_type"`
+	DiffMsg    string `json:"diff_msg"`
+}
+
+// RenamePlanResult 重命名影响面 (--mode rename-plan)
+// Definitions / References 来自已链接的边；Dynamic 是推测位置（未链接调用、重写、同名导入），需要人工确认
+type RenamePlanResult struct {
+	Status      string       `json:"status"`
+	Generation  int64        `json:"generation"`
+	ID          string       `json:"id"`
+	Name        string       `json:"name"`
+	To          string       `json:"to"`
+	Definitions []RenameSite `json:"definitions"`
+	References  []RenameSite `json:"references"`
+	Dynamic     []RenameSite `json:"dynamic"`
+	Conflicts   []string     `json:"conflicts"`
+}
+
+// CallsiteCheckResult 调用点兼容性检查 (--mode check-callsites)
+type CallsiteCheckResult struct {
+	Status     string           `json:"status"`
+	Generation int64            `json:"generation"`
+	ID         string           `json:"id"`
+	Name       string           `json:"name"`
+	Signatures []string         `json:"signatures"`
+	Checked    int              `json:"checked"`
+	Unchecked  int              `json:"unchecked"` // 展开实参、JS 等无法核对的调用
+	Broken     []BrokenCallsite `json:"broken"`
+}
+
+// BrokenCallsite 按新签名会出错的调用点
+type BrokenCallsite struct {
+	FilePath string   `json:"file_path"`
+	Line     int      `json:"line"`
+	CallerID string   `json:"caller_id"`
+	Context  string   `json:"context"`
+	Problems []string `json:"problems"`
+}
+
+// ValidateResult 抽样重解析自检结果 (--mode validate)
+type ValidateResult struct {
+	Status       string                        `json:"status"` // ok | drift
+	Generation   int64                         `json:"generation"`
+	IndexedFiles int                           `json:"indexed_files"`
+	Sampled      int                           `json:"sampled"`
+	Checked      int                           `json:"checked"`
+	Seed         uint64                        `json:"seed"`
+	Stale        []string                      `json:"stale"`    // 索引后已修改
+	Missing      []string                      `json:"missing"`  // 磁盘上已不存在
+	Outdated     []string                      `json:"outdated"` // 旧版抽取器写入
+	Languages    map[string]LanguageValidation `json:"languages"`
+	Drift        []FileDrift                   `json:"drift"`
+}
+
+// LanguageValidation 单个语言（扩展名）的抽样统计
+type LanguageValidation struct {
+	Sampled int `json:"sampled"`
+	Checked int `json:"checked"`
+	Drifted int `json:"drifted"`
+}
+
+// FileDrift 库中符号与重解析结果不一致的文件
+type FileDrift struct {
+	FilePath       string   `json:"file_path"`
+	Language       string   `json:"language"`
+	MissingSymbols []string `json:"missing_symbols"`
+	ExtraSymbols   []string `json:"extra_symbols"`
+	Changed        []string `json:"changed"`
+}
+
+// RenameSite 一处需要改名的位置
+type RenameSite struct {
+	FilePath string `json:"file_path"`
+	Line     int    `json:"line"`
+	Col      int    `json:"col,omitempty"` // 1 起，按字符计；0 表示该行找不到整词
+	Context  string `json:"context"`
+	Kind     string `json:"kind"`
+	Reason   string `json:"reason,omitempty"`
+}
+
 // IndexResult 索引结果 (--mode index)
 type IndexResult struct {
-	Status       string `json:"status"`
-	TotalFiles   int    `json:"total_files"`
-	ParsedFiles  int    `json:"parsed_files,omitempty"`
-	MetaFiles    int    `json:"meta_files,omitempty"`
-	SkippedFiles int    `json:"skipped_files,omitempty"`
-	Strategy     string `json:"strategy,omitempty"`
-	ElapsedMs    int64  `json:"elapsed_ms"`
+	Status         string         `json:"status"`
+	TotalFiles     int            `json:"total_files"`
+	ParsedFiles    int            `json:"parsed_files,omitempty"`
+	MetaFiles      int            `json:"meta_files,omitempty"`
+	SkippedFiles   int            `json:"skipped_files,omitempty"`
+	Strategy       string         `json:"strategy,omitempty"`
+	ElapsedMs      int64          `json:"elapsed_ms"`
+	PeakRSSBytes   uint64         `json:"peak_rss_bytes,omitempty"` // 索引进程峰值内存
+	SlowestFiles   []SlowFile     `json:"slowest_files,omitempty"`  // 解析 + 写库最慢的文件
+	Checkpoints    int            `json:"checkpoints,omitempty"`    // 提交次数（每次提交一个检查点）
+	SkipEfficiency SkipEfficiency `json:"skip_efficiency"`          // 增量跳过命中情况
+}
+
+// SkipEfficiency 未变更文件的跳过方式；MtimeChurn 高说明 mtime 不可信，每次都要重读文件
+type SkipEfficiency struct {
+	MetadataSkips     int     `json:"metadata_skips"`
+	HashSkips         int     `json:"hash_skips"`
+	UnchangedReparses int     `json:"unchanged_reparses"`
+	MtimeChurn        float64 `json:"mtime_churn"`
+}
+
+// SlowFile 单文件索引耗时（用于定位该加 ignore 的大文件）
+type SlowFile struct {
+	FilePath  string  `json:"file_path"`
+	Language  string  `json:"language"`
+	SizeBytes int64   `json:"size_bytes"`
+	ParseMs   float64 `json:"parse_ms"`
+	WriteMs   float64 `json:"write_ms"`
 }
 
 // NamingAnalysis 命名风格分析结果
@@ -377,6 +557,81 @@ func detectTechStackAndConfig(projectRoot string) (extensions string, ignoreDirs
 		ignores = append(ignores, ".gradle")
 	}
 
+	// 7. 检测 Swift (iOS/macOS)
+	if hasExt(".swift") || fileExists(filepath.Join(projectRoot, "Package.swift")) {
+		stackDetected = append(stackDetected, "swift")
+		exts = append(exts, ".swift")
+		ignores = append(ignores, ".build", "Pods", "DerivedData")
+	}
+
+	// 8. 检测 Ruby (Rails)
+	if hasExt(".rb") || fileExists(filepath.Join(projectRoot, "Gemfile")) {
+		stackDetected = append(stackDetected, "ruby")
+		exts = append(exts, ".rb")
+		ignores = append(ignores, ".bundle", "tmp", "log")
+	}
+
+	// 9. 检测 PHP (Laravel/WordPress)
+	if hasExt(".php") || fileExists(filepath.Join(projectRoot, "composer.json")) {
+		stackDetected = append(stackDetected, "php")
+		exts = append(exts, ".php")
+		ignores = append(ignores, "vendor", "storage")
+	}
+
+	// 10. 检测 Lua (Neovim 插件 / 游戏脚本)
+	if hasExt(".lua") {
+		stackDetected = append(stackDetected, "lua")
+		exts = append(exts, ".lua")
+		ignores = append(ignores, "lua_modules", ".luarocks")
+	}
+
+	// 11. 检测 Elixir (Phoenix)
+	if hasExt(".ex") || hasExt(".exs") || fileExists(filepath.Join(projectRoot, "mix.exs")) {
+		stackDetected = append(stackDetected, "elixir")
+		exts = append(exts, ".ex", ".exs")
+		ignores = append(ignores, "_build", "deps")
+	}
+
+	// 12. 检测 Haskell (Cabal / Stack)
+	if hasExt(".hs") || fileExists(filepath.Join(projectRoot, "stack.yaml")) {
+		stackDetected = append(stackDetected, "haskell")
+		exts = append(exts, ".hs")
+		ignores = append(ignores, "dist-newstyle", ".stack-work")
+	}
+
+	// 13. 检测 SQL (数据库迁移)
+	if hasExt(".sql") {
+		stackDetected = append(stackDetected, "sql")
+		exts = append(exts, ".sql")
+	}
+
+	// 14. 检测 Protobuf (gRPC 接口定义)
+	if hasExt(".proto") {
+		stackDetected = append(stackDetected, "protobuf")
+		exts = append(exts, ".proto")
+	}
+
+	// 15. 检测 Terraform (HCL)
+	if hasExt(".tf") {
+		stackDetected = append(stackDetected, "terraform")
+		exts = append(exts, ".tf")
+		ignores = append(ignores, ".terraform")
+	}
+
+	// 16. 检测 Objective-C（与 Swift 混编的 iOS 仓库）
+	if hasExt(".m") || hasExt(".mm") {
+		stackDetected = append(stackDetected, "objc")
+		exts = append(exts, ".m", ".mm", ".h")
+		ignores = append(ignores, "Pods", "DerivedData")
+	}
+
+	// 17. 检测 C# (.NET)
+	if hasExt(".cs") || hasExt(".csproj") || hasExt(".sln") {
+		stackDetected = append(stackDetected, "csharp")
+		exts = append(exts, ".cs")
+		ignores = append(ignores, "bin", "obj", "packages")
+	}
+
 	// 如果没有检测到特定栈，不限制扩展名
 	if len(stackDetected) == 0 {
 		return "", uniqueJoin(ignores)
@@ -719,6 +974,119 @@ func (ai *ASTIndexer) SearchSymbolWithScope(projectRoot string, query string, sc
 	return &result, nil
 }
 
+// SearchSymbolOfType 只在指定类型里搜索符号 (--mode query --symbol-type)
+// symbolType 接受同义词：fn / func / procedure 命中函数与方法，method 只命中方法
+func (ai *ASTIndexer) SearchSymbolOfType(projectRoot string, query string, symbolType string) (*QueryResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "query")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "query",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--query", query,
+		"--symbol-type", symbolType,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if err := cmd.Run(); err != nil {
+		return nil, fmt.Errorf("符号搜索失败: %v", err)
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取搜索结果失败: %v", err)
+	}
+
+	var result QueryResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析搜索结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// SearchSymbolWithAttributes 只在带有全部指定属性的符号里搜索 (--mode query --attribute)
+// 属性写作 key 或 key=value，例如 []string{"async", "visibility=public"}、[]string{"derive=Serialize"}
+func (ai *ASTIndexer) SearchSymbolWithAttributes(projectRoot string, query string, attributes []string) (*QueryResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "query")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "query",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--query", query,
+		"--attribute", strings.Join(attributes, ","),
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if err := cmd.Run(); err != nil {
+		return nil, fmt.Errorf("符号搜索失败: %v", err)
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取搜索结果失败: %v", err)
+	}
+
+	var result QueryResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析搜索结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// ListAnnotated 列出带任一注解 / 装饰器的符号 (--mode query --annotation)，结果在 Candidates 里
+// 例如 []string{"GetMapping", "PostMapping"} 列出全部 Spring HTTP 端点
+func (ai *ASTIndexer) ListAnnotated(projectRoot string, annotations []string) (*QueryResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "query")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "query",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--annotation", strings.Join(annotations, ","),
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if err := cmd.Run(); err != nil {
+		return nil, fmt.Errorf("注解查询失败: %v", err)
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取注解查询结果失败: %v", err)
+	}
+
+	var result QueryResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析注解查询结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
 // GetSymbolAtLine 获取指定文件行号处的符号信息 (--mode query --file --line)
 func (ai *ASTIndexer) GetSymbolAtLine(projectRoot string, filePath string, line int) (*Node, error) {
 	dbPath := getDBPath(projectRoot)
@@ -767,6 +1135,331 @@ func (ai *ASTIndexer) GetSymbolAtLine(projectRoot string, filePath string, line
 	return result.FoundSymbol, nil
 }
 
+// GetSymbolSource 按符号 ID 回读当前源码 (--mode source)
+// 行范围与文件哈希校验都在 Rust 侧完成，文件改动后会重新定位符号
+func (ai *ASTIndexer) GetSymbolSource(projectRoot string, id string) (*SourceResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "source")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "source",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--id", id,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("读取符号源码失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取源码结果失败: %v", err)
+	}
+
+	var result SourceResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析源码结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// ReindexBuffer 把编辑器中未保存的文件内容经 stdin 交给 --mode reindex-file
+// 之后的查询反映缓冲区状态；文件保存并重新索引后覆盖层自动失效
+// sandbox 非空时写入该命名沙箱，只有带同名 --sandbox 的查询才能看到
+func (ai *ASTIndexer) ReindexBuffer(projectRoot string, filePath string, content string, sandbox string) (*ReindexFileResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "reindex_file")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "reindex-file",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--file", filePath,
+		"--content", "-",
+	}
+	if sandbox != "" {
+		args = append(args, "--sandbox", sandbox)
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+	cmd.Stdin = strings.NewReader(content)
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("更新缓冲区索引失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取缓冲区索引结果失败: %v", err)
+	}
+
+	var result ReindexFileResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析缓冲区索引结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// DiffSandbox 列出命名沙箱相对基础索引的符号差异 (--mode sandbox-diff)
+func (ai *ASTIndexer) DiffSandbox(projectRoot string, sandbox string) (*SandboxDiffResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "sandbox_diff")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "sandbox-diff",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--sandbox", sandbox,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("沙箱差异计算失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取沙箱差异失败: %v", err)
+	}
+
+	var result SandboxDiffResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析沙箱差异失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// DiscardSandbox 丢弃命名沙箱里的全部文件副本 (--mode sandbox-discard)
+func (ai *ASTIndexer) DiscardSandbox(projectRoot string, sandbox string) error {
+	args := []string{
+		"--mode", "sandbox-discard",
+		"--project", projectRoot,
+		"--db", getDBPath(projectRoot),
+		"--sandbox", sandbox,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return fmt.Errorf("丢弃沙箱失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+	return nil
+}
+
+// PlanRename 列出把符号改名为 to 需要改动的所有位置 (--mode rename-plan)，不修改任何文件
+func (ai *ASTIndexer) PlanRename(projectRoot string, id string, to string) (*RenamePlanResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "rename_plan")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "rename-plan",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--id", id,
+		"--to", to,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("生成重命名计划失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取重命名计划失败: %v", err)
+	}
+
+	var result RenamePlanResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析重命名计划失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// CheckCallsites 核对符号的所有调用点是否仍符合当前签名 (--mode check-callsites)
+func (ai *ASTIndexer) CheckCallsites(projectRoot string, id string) (*CallsiteCheckResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "check_callsites")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "check-callsites",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--id", id,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("调用点检查失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取调用点检查结果失败: %v", err)
+	}
+
+	var result CallsiteCheckResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析调用点检查结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// GenerateSBOM 从项目的包管理清单生成软件物料清单 (--mode sbom)
+// format 为 "cyclonedx"（默认）或 "spdx"，返回对应规范的 JSON 文档
+func (ai *ASTIndexer) GenerateSBOM(projectRoot string, format string) (json.RawMessage, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "sbom")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	if format == "" {
+		format = "cyclonedx"
+	}
+
+	args := []string{
+		"--mode", "sbom",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+		"--format", format,
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("生成 SBOM 失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取 SBOM 失败: %v", err)
+	}
+
+	if !json.Valid(data) {
+		return nil, fmt.Errorf("解析 SBOM 失败: 输出不是合法 JSON")
+	}
+
+	return json.RawMessage(data), nil
+}
+
+// Validate 抽样重解析已索引文件并与库中符号比对，用于排查索引损坏 (--mode validate)
+func (ai *ASTIndexer) Validate(projectRoot string, sample int) (*ValidateResult, error) {
+	dbPath := getDBPath(projectRoot)
+	outputPath := getOutputPath(projectRoot, "validate")
+
+	// 清理旧文件
+	_ = os.Remove(outputPath)
+
+	args := []string{
+		"--mode", "validate",
+		"--project", projectRoot,
+		"--db", dbPath,
+		"--output", outputPath,
+	}
+	if sample > 0 {
+		args = append(args, "--sample", strconv.Itoa(sample))
+	}
+
+	cmd := exec.Command(ai.BinaryPath, args...)
+	cmd.Dir = projectRoot
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return nil, fmt.Errorf("索引自检失败: %v: %s", err, strings.TrimSpace(string(out)))
+	}
+
+	data, err := os.ReadFile(outputPath)
+	if err != nil {
+		return nil, fmt.Errorf("读取索引自检结果失败: %v", err)
+	}
+
+	var result ValidateResult
+	if err := json.Unmarshal(data, &result); err != nil {
+		return nil, fmt.Errorf("解析索引自检结果失败: %v", err)
+	}
+
+	return &result, nil
+}
+
+// EditAnchor 与 Rust 侧 edit_anchor 同一规则计算 "pre:post" 编辑锚点：
+// pre = 起始行及其前一行，post = 结束行及其后一行，以 "\n" 连接（去掉行尾 \r）后取 SHA-256 前 12 位
+func EditAnchor(content string, lineStart, lineEnd int) string {
+	content = strings.TrimPrefix(content, "\ufeff")
+	lines := strings.Split(content, "\n")
+	// 与 Rust str::lines 一致：末尾换行不产生空行
+	if n := len(lines); n > 0 && lines[n-1] == "" {
+		lines = lines[:n-1]
+	}
+	for i, l := range lines {
+		lines[i] = strings.TrimSuffix(l, "\r")
+	}
+	digest := func(from, to int) string {
+		if to > len(lines) {
+			to = len(lines)
+		}
+		if from < 0 {
+			from = 0
+		}
+		if from > to {
+			from = to
+		}
+		sum := sha256.Sum256([]byte(strings.Join(lines[from:to], "\n")))
+		return hex.EncodeToString(sum[:])[:12]
+	}
+	return digest(lineStart-2, lineStart) + ":" + digest(lineEnd-1, lineEnd+1)
+}
+
+// VerifyAnchor 套用补丁前校验：磁盘文件在符号起止处仍与索引时一致才返回 true
+func VerifyAnchor(projectRoot string, node *Node) (bool, error) {
+	if node.Anchor == "" {
+		return false, fmt.Errorf("符号 %s 没有编辑锚点，请先重新索引", node.ID)
+	}
+	data, err := os.ReadFile(filepath.Join(projectRoot, node.FilePath))
+	if err != nil {
+		return false, fmt.Errorf("读取 %s 失败: %v", node.FilePath, err)
+	}
+	return EditAnchor(string(data), node.LineStart, node.LineEnd) == node.Anchor, nil
+}
+
 // Analyze 执行影响分析 (--mode analyze)
 func (ai *ASTIndexer) Analyze(projectRoot string, symbol string, direction string) (*ImpactResult, error) {
 	// 先确保索引是最新的
@@ -4,16 +4,17 @@ use rayon::prelude::*;
 use rusqlite::{params, Connection, OptionalExtension, Result};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    mpsc, Arc,
+    mpsc, Arc, Mutex, OnceLock,
 };
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use streaming_iterator::StreamingIterator;
 use tree_sitter::{Language, Parser as TsParser, Query, QueryCursor};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
 
 // ============================================================================
 // CLI Arguments
@@ -21,19 +22,27 @@ use tree_sitter::{Language, Parser as TsParser, Query, QueryCursor};
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Project root path
+    /// Project root path (repeat for multi-root workspaces)
     #[arg(short, long)]
-    project: String,
+    project: Vec<String>,
+
+    /// Workspace manifest listing project roots: {"folders": [{"path", "name"}]}
+    #[arg(long)]
+    workspace: Option<String>,
+
+    /// Workspace root name filter (for query/map/analyze/docs/unused/lsp/symbols mode)
+    #[arg(long)]
+    root: Option<String>,
 
     /// Database path (symbols.db)
     #[arg(short, long)]
     db: String,
 
-    /// Mode: index, map, query, structure, analyze, snapshot, diff
+    /// Mode: index, map, query, structure, analyze, snapshot, diff, docs, unused, lsp, symbols, set-summary, stats, invalidate, reindex-file, sandbox-diff, sandbox-discard, source, deprecated, rename-plan, check-callsites, sbom, validate
     #[arg(short, long, default_value = "index")]
     mode: String,
 
-    /// Query string (for query mode)
+    /// Query string (for query mode; name substring filter for symbols mode)
     #[arg(short, long)]
     query: Option<String>,
 
@@ -49,6 +58,10 @@ struct Args {
     #[arg(long)]
     ignore_dirs: Option<String>,
 
+    /// Snapshot JSON searched instead of the DB, e.g. to find where a deleted symbol used to live (for query mode)
+    #[arg(long)]
+    snapshot: Option<String>,
+
     /// Base snapshot path (for diff mode)
     #[arg(long)]
     base: Option<String>,
@@ -57,7 +70,7 @@ struct Args {
     #[arg(long)]
     target: Option<String>,
 
-    /// File path for line-based symbol lookup (for query mode)
+    /// File path for line-based symbol lookup (for query mode), to mark dirty (for invalidate mode) or to overlay (for reindex-file mode)
     #[arg(short, long)]
     file: Option<String>,
 
@@ -65,7 +78,7 @@ struct Args {
     #[arg(short, long)]
     line: Option<usize>,
 
-    /// Scope path filter (for map/index mode)
+    /// Scope path filter (for map/index/unused/symbols/deprecated mode; comma-separated path prefixes for snapshot mode)
     #[arg(long)]
     scope: Option<String>,
 
@@ -80,17 +93,203 @@ struct Args {
     /// Force full parse on huge repositories (disable bootstrap strategy)
     #[arg(long, default_value_t = false)]
     force_full: bool,
+
+    /// Only symbols carrying one of these decorators / annotations, comma separated, e.g. "GetMapping,PostMapping" (for query mode)
+    #[arg(long)]
+    annotation: Option<String>,
+
+    /// Only symbols of these kinds, comma separated; synonyms such as fn / func / procedure are accepted (for query/symbols mode)
+    #[arg(long)]
+    symbol_type: Option<String>,
+
+    /// Only symbols carrying all of these attributes, comma separated key or key=value, e.g. "async,visibility=public" (for query/symbols mode)
+    #[arg(long)]
+    attribute: Option<String>,
+
+    /// Symbol fields to keep, comma separated, e.g. "name,file_path,line_start" (for query/map mode)
+    #[arg(long)]
+    fields: Option<String>,
+
+    /// Output format: json, markdown (for docs mode); vscode (for symbols mode); cyclonedx, spdx (for sbom mode)
+    #[arg(long, default_value = "json")]
+    format: String,
+
+    /// Symbol canonical_id (for set-summary/invalidate/source/rename-plan/check-callsites mode)
+    #[arg(long)]
+    id: Option<String>,
+
+    /// New symbol name (for rename-plan mode)
+    #[arg(long)]
+    to: Option<String>,
+
+    /// Summary text (for set-summary mode)
+    #[arg(long)]
+    text: Option<String>,
+
+    /// JSONL input file with {"id", "summary"} records (for set-summary mode)
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Unsaved buffer contents, `-` reads stdin; defaults to the file on disk (for reindex-file mode)
+    #[arg(long)]
+    content: Option<String>,
+
+    /// Named sandbox layered over the index instead of the editor overlay (for reindex-file/query/map/analyze/docs/unused/lsp/symbols/sandbox-diff/sandbox-discard mode)
+    #[arg(long)]
+    sandbox: Option<String>,
+
+    /// Source lines shown before and after each call site, besides the call line itself (for query mode)
+    #[arg(long, default_value_t = 0)]
+    context_lines: usize,
+
+    /// Index from a tar / tar.gz / zip archive or git bundle instead of the filesystem (for index mode)
+    #[arg(long)]
+    archive: Option<String>,
+
+    /// File or FIFO signalled with the new generation on every DB change (for index/set-summary mode)
+    #[arg(long)]
+    notify: Option<String>,
+
+    /// Extraction feature flags: [lang:]calls|signatures|docs=on|off, comma separated (for index mode)
+    #[arg(long)]
+    features: Option<String>,
+
+    /// JSON file remapping emitted symbol types: {"*": {"impl": "class"}, "go": {"struct": "class"}} (for index/reindex-file mode and query --line)
+    #[arg(long)]
+    type_map: Option<String>,
+
+    /// Below this confidence a query returns status "ambiguous" with a resolution_token (for query mode)
+    #[arg(long, default_value_t = 0.6)]
+    min_confidence: f32,
+
+    /// Decimal places kept for candidate scores and confidence in the output (for query mode)
+    #[arg(long, default_value_t = 3)]
+    score_precision: u32,
+
+    /// resolution_token from an earlier ambiguous query (for query mode, with --pick)
+    #[arg(long)]
+    resolve: Option<String>,
+
+    /// 1-based candidate index to pick when resolving (for query mode)
+    #[arg(long)]
+    pick: Option<usize>,
+
+    /// Risk model JSON: {"weights": {affected, untested, churn, centrality}, "thresholds": {medium, high}, "churn_days"}; defaults 1 / 2 / 0.25 / 0.1, thresholds 3 / 10 (for analyze mode)
+    #[arg(long)]
+    risk_model: Option<String>,
+
+    /// Random-walk seed for the complexity score; fixed by default so runs are comparable (for analyze mode).
+    /// Also picks the file sample (for validate mode)
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+
+    /// Number of indexed files to re-parse and compare (for validate mode)
+    #[arg(long, default_value_t = 100)]
+    sample: usize,
+
+    /// Earlier analyze output (JSON) to diff against: affected set growth, complexity/risk delta (for analyze mode)
+    #[arg(long)]
+    compare_with: Option<String>,
+
+    /// Fan out calls on a base type / interface method to the overrides in every subtype, at lower confidence (for analyze mode)
+    #[arg(long, default_value_t = false)]
+    expand_dynamic: bool,
+
+    /// Path globs dropped from the graph at read time, comma separated, e.g. "third_party/**,**/vendor" (for analyze/map mode)
+    #[arg(long)]
+    prune_path: Option<String>,
+
+    /// Return whatever is indexed so far plus a completeness ratio while indexing runs (for map mode)
+    #[arg(long, default_value_t = false)]
+    allow_partial: bool,
+
+    /// Bypass the on-disk result cache (for query/map/analyze mode)
+    #[arg(long, default_value_t = false)]
+    no_cache: bool,
+
+    /// Parse cache directory, shareable across DB resets/worktrees (default: <db dir>/parse_cache, "off" to disable); entries of other crate or extractor versions are removed at index start
+    #[arg(long)]
+    parse_cache: Option<String>,
+
+    /// Max parsed files buffered ahead of the DB writer; bounds memory on large repos (for index mode)
+    #[arg(long, default_value_t = 256)]
+    channel_capacity: usize,
+
+    /// Number of slowest files (parse + write time) reported in the result (for index mode)
+    #[arg(long, default_value_t = 10)]
+    slow_files: usize,
+
+    /// Write per-phase and per-language timings to this file in folded-stack format, for flamegraph.pl / inferno (for index mode)
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Files written per DB commit; each commit emits a checkpoint event (for index mode)
+    #[arg(long, default_value_t = 300)]
+    commit_batch: usize,
+
+    /// Also commit once this many ms have passed since the last commit, 0 = batch size only (for index mode)
+    #[arg(long, default_value_t = 0)]
+    commit_interval_ms: u64,
+
+    /// Modes this process may run, comma separated; others are refused (narrows MPM_AST_ALLOW_MODES)
+    #[arg(long)]
+    allow_modes: Option<String>,
+
+    /// Token clients must send as initializationOptions.token, defaults to MPM_AST_AUTH_TOKEN (for lsp mode)
+    #[arg(long)]
+    auth_token: Option<String>,
+
+    /// Read the symbol and call-edge indexes in the background at startup and report readiness via mpm/health (for lsp mode)
+    #[arg(long)]
+    warm_up: bool,
+
+    /// Accept --file/--scope/--db/--output and other file arguments outside the project roots
+    #[arg(long)]
+    allow_outside: bool,
 }
 
 #[derive(Serialize)]
 struct IndexResult {
     status: String,
+    generation: i64,
     total_files: usize,
     parsed_files: usize,
     meta_files: usize,
     skipped_files: usize,
+    cached_files: usize,
+    partial_files: usize, // 🆕 库中含语法错误、只抽取到部分符号的文件数
+    error_nodes: usize,   // 🆕 本次解析遇到的 ERROR/MISSING 区域数
     strategy: String,
     elapsed_ms: u128,
+    peak_rss_bytes: u64,             // 🆕 进程峰值常驻内存，0 表示平台不支持
+    slowest_files: Vec<SlowFile>,    // 🆕 解析 + 写库最慢的前 N 个文件（--slow-files）
+    checkpoints: usize,              // 🆕 本次运行的提交次数（每次提交发一个检查点事件）
+    skip_efficiency: SkipEfficiency, // 🆕 增量跳过的命中情况
+}
+
+// 未变更文件靠什么被跳过：元数据（不读文件）还是读出来比哈希；
+// hash_skips 占比高说明文件系统 / 检出工具没有保留 mtime，每次都要重读
+#[derive(Serialize)]
+struct SkipEfficiency {
+    metadata_skips: usize,     // size + mtime 一致，未读取内容
+    hash_skips: usize,         // size / mtime 变了但内容哈希一致（白读一次）
+    unchanged_reparses: usize, // 内容哈希一致仍重新抽取（抽取器版本 / 特性开关变化、invalidate）
+    mtime_churn: f64,          // hash_skips / (metadata_skips + hash_skips)
+}
+
+#[derive(Serialize)]
+struct SlowFile {
+    file_path: String,
+    language: String,
+    size_bytes: u64,
+    parse_ms: f64, // 读取 + 哈希 + 抽取（解析缓存命中时只有读取）
+    write_ms: f64,
+}
+
+impl SlowFile {
+    fn total_ms(&self) -> f64 {
+        self.parse_ms + self.write_ms
+    }
 }
 
 // ============================================================================
@@ -98,17 +297,51 @@ struct IndexResult {
 // ============================================================================
 
 struct ParseResult {
+    root: String, // 🆕 工作区根名（单根为空串）
     file_path: String,
     file_hash: String,
     file_size: u64,
     file_mtime: i64,
     language: String,
     index_level: String,
+    extractor_version: String, // 🆕 空串表示未抽取（skip / meta）
+    grammar: String,           // 🆕 实际解析成功的语法（.js 可能回退到 tsx）
+    error_nodes: usize,        // 🆕 > 0 时文件标记为 partial
+    license: String,           // 🆕 文件头声明的许可证（SPDX 标识），空串表示未声明
+    copyright: String,         // 🆕 文件头版权行中的权利人
+    generated: String,         // 🆕 生成代码的来源（protobuf / grpc / generated），空串表示手写
     line_count: usize,
+    parse_us: u64, // 🆕 worker 侧耗时（微秒），skip / meta 为 0
+    symbols: Vec<PendingSymbol>,
+    calls: Vec<PendingCall>,
+    routes: Vec<PendingRoute>,
+    inherits: Vec<PendingInherit>,
+    imports: Vec<PendingImport>,
+    decorators: Vec<PendingDecorator>,
+    attributes: Vec<PendingAttribute>,
+}
+
+// 🆕 单文件抽取结果（解析缓存的存储单元）
+#[derive(Serialize, Deserialize)]
+struct ParsedFile {
     symbols: Vec<PendingSymbol>,
     calls: Vec<PendingCall>,
+    routes: Vec<PendingRoute>,
+    #[serde(default)]
+    inherits: Vec<PendingInherit>, // 🆕 extends / implements
+    #[serde(default)]
+    imports: Vec<PendingImport>, // 🆕 import / require / use / #include
+    #[serde(default)]
+    decorators: Vec<PendingDecorator>, // 🆕 Python 装饰器 / Java 注解（decorated_by）
+    #[serde(default)]
+    attributes: Vec<PendingAttribute>, // 🆕 语言特有的符号元数据（async / derive）
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    grammar: String, // 🆕 回退语法；空串表示按扩展名的默认语法
+    #[serde(default)]
+    error_nodes: usize, // 🆕 语法树中的 ERROR/MISSING 区域数（文本抽取器：未配平的块数）
 }
 
+#[derive(Serialize, Deserialize)]
 struct PendingSymbol {
     temp_id: usize,
     parent_temp_id: Option<usize>,
@@ -118,14 +351,105 @@ struct PendingSymbol {
     symbol_type: String,
     line_start: usize,
     line_end: usize,
-    text: String,
     signature: Option<String>, // 🆕 函数签名
+    #[serde(default)]
+    signature_shape: Option<SignatureShape>, // 🆕 结构化签名：参数名/类型 + 返回类型
+    #[serde(default)]
+    doc: Option<String>, // 🆕 docstring / 注释块（--features docs=off 可关闭）
+    #[serde(default)]
+    visibility: Option<String>, // 🆕 public | protected | internal | private；None 表示该语言不判断
+    #[serde(skip)]
+    anchor: Option<String>, // 🆕 取自当前内容，不进解析缓存
+}
+
+// 🆕 结构化签名：diff 据此区分参数增删、参数类型与返回类型变化
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct SignatureShape {
+    params: Vec<SignatureParam>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    returns: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    type_params: Vec<TypeParam>, // 🆕 泛型参数（<T: Trait> / [T any] / <K, V>），约束含 where 子句
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct TypeParam {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bounds: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct SignatureParam {
+    name: String,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    param_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>, // 🆕 optional（有默认值）| variadic（*args / ...rest）| kwargs（**kw）；必填参数省略
 }
 
+#[derive(Serialize, Deserialize)]
 struct PendingCall {
     caller_temp_id: usize,
     callee_name: String,
     line: usize,
+    kind: String, // 🆕 call | http | reference（作为实参传入的函数）| render（JSX 渲染的组件）
+    #[serde(default)]
+    receiver_type: Option<String>, // 🆕 obj.method() 推断出的 obj 类型（链接时收窄候选）
+    #[serde(default)]
+    args: Option<CallArgs>, // 🆕 实参形状（check-callsites 用）；有展开实参或无法解析时为 None
+}
+
+// 调用点的实参：位置实参个数 + 关键字实参名（Python f(a, b=1) / Ruby f(a, b: 1) / PHP f(a, b: 1)）
+#[derive(Serialize, Deserialize, Clone)]
+struct CallArgs {
+    positional: usize,
+    #[serde(default)]
+    keywords: Vec<String>,
+}
+
+// 🆕 继承关系：class A(B) / extends / implements / impl Trait for T（base_id 在 Linking 阶段回填）
+#[derive(Serialize, Deserialize)]
+struct PendingInherit {
+    child_temp_id: usize,
+    base_name: String,
+    kind: String, // extends | implements
+}
+
+// 🆕 装饰器：name 去掉 @ 与调用实参（@app.route("/") -> app.route）
+#[derive(Serialize, Deserialize)]
+struct PendingDecorator {
+    symbol_temp_id: usize,
+    name: String,
+    line: usize,
+}
+
+// 🆕 符号属性：通用 key / value 元数据，下游按任意属性筛选符号而不必改表结构
+#[derive(Serialize, Deserialize)]
+struct PendingAttribute {
+    symbol_temp_id: usize,
+    key: String,
+    value: String,
+}
+
+// 🆕 导入语句：imported_symbol 为 None 表示导入整个模块，"*" 表示通配；
+// alias 是本地绑定名（as / 默认导入 / 解构），kind 区分 import 与 JS 的 export … from 转出
+#[derive(Serialize, Deserialize)]
+struct PendingImport {
+    imported_path: String,
+    imported_symbol: Option<String>,
+    alias: Option<String>,
+    kind: String, // import | reexport
+    line: usize,
+}
+
+// 🆕 服务端路由定义：app.get("/x", handler) / @app.route("/x") / http.HandleFunc("/x", h)
+#[derive(Serialize, Deserialize)]
+struct PendingRoute {
+    http_method: String,
+    path: String,
+    handler_name: Option<String>,
+    line: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -140,6 +464,16 @@ struct Node {
     line_end: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summary: Option<String>, // 🆕 Agent 回写的摘要
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stable_id: Option<i64>, // 🆕 内容锚定 ID（文件移动后不变）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    anchor: Option<String>, // 🆕 编辑锚点（见 edit_anchor），编辑前用来检测并发修改
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    doc: Option<String>, // 🆕 docstring / 注释块（query 与 map --detail full 返回）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>, // 🆕 overlay | disk，仅在存在 reindex-file 覆盖层时输出
     #[serde(default)]
     calls: Vec<String>,
 }
@@ -160,7 +494,15 @@ fn init_db(conn: &Connection) -> Result<()> {
             line_count INTEGER DEFAULT 0,
             index_level TEXT DEFAULT 'symbol',
             indexed_at INTEGER DEFAULT 0,
-            updated_at INTEGER NOT NULL
+            updated_at INTEGER NOT NULL,
+            root TEXT DEFAULT '',
+            dirty INTEGER DEFAULT 0,
+            extractor_version TEXT DEFAULT '',
+            grammar TEXT DEFAULT '',
+            partial INTEGER DEFAULT 0,
+            license TEXT DEFAULT '',
+            copyright TEXT DEFAULT '',
+            generated TEXT DEFAULT ''
         )",
         [],
     )?;
@@ -179,6 +521,13 @@ fn init_db(conn: &Connection) -> Result<()> {
             line_end INTEGER,
             signature TEXT,
             parent_id INTEGER,
+            summary TEXT,
+            stable_id INTEGER,
+            doc TEXT,
+            anchor TEXT,
+            signature_shape TEXT,
+            visibility TEXT,
+            generated_from TEXT,
             FOREIGN KEY (file_id) REFERENCES files(file_id) ON DELETE CASCADE
         )",
         [],
@@ -192,11 +541,191 @@ fn init_db(conn: &Connection) -> Result<()> {
             callee_name TEXT NOT NULL,
             call_line INTEGER,
             callee_id TEXT,
+            kind TEXT DEFAULT 'call',
+            receiver_type TEXT,
+            arg_count INTEGER,
+            arg_keywords TEXT,
             FOREIGN KEY (caller_id) REFERENCES symbols(symbol_id) ON DELETE CASCADE
         )",
         [],
     )?;
 
+    // inherits：继承 / 实现关系（base_id 在 Linking 阶段回填）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS inherits (
+            inherit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            child_id INTEGER NOT NULL,
+            base_name TEXT NOT NULL,
+            base_id TEXT,
+            kind TEXT DEFAULT 'extends',
+            FOREIGN KEY (child_id) REFERENCES symbols(symbol_id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // overrides：重写方法 -> 最近一层父类型上的同名方法（canonical_id，Linking 阶段整体重算）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS overrides (
+            method_id TEXT NOT NULL,
+            base_id TEXT NOT NULL,
+            PRIMARY KEY (method_id, base_id)
+        )",
+        [],
+    )?;
+
+    // decorators：Python 装饰器 / Java 注解（symbol decorated_by name）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS decorators (
+            decorator_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            symbol_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            line INTEGER,
+            FOREIGN KEY (symbol_id) REFERENCES symbols(symbol_id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // attributes：符号的通用元数据（visibility / decorator / annotation / async / derive ...）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attributes (
+            attribute_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            symbol_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            FOREIGN KEY (symbol_id) REFERENCES symbols(symbol_id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // imports：import / require / use / #include 语句（路径保持源码原样）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS imports (
+            import_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id INTEGER NOT NULL,
+            imported_path TEXT NOT NULL,
+            imported_symbol TEXT,
+            alias TEXT,
+            kind TEXT DEFAULT 'import',
+            line INTEGER,
+            FOREIGN KEY (file_id) REFERENCES files(file_id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // routes：服务端路由定义（handler_id 在 Linking 阶段回填）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS routes (
+            route_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id INTEGER NOT NULL,
+            http_method TEXT NOT NULL,
+            path TEXT NOT NULL,
+            handler_name TEXT,
+            handler_id TEXT,
+            line INTEGER,
+            FOREIGN KEY (file_id) REFERENCES files(file_id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // meta：全库级键值（generation 等）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // roots：多根工作区的 root 名 -> 绝对路径（单根为空名）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS roots (
+            name TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            updated_at INTEGER DEFAULT 0,
+            license TEXT DEFAULT ''
+        )",
+        [],
+    )?;
+
+    // symbol_aliases：历史 canonical_id -> stable_id 映射（文件移动后旧 ID 仍可解析）
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS symbol_aliases (
+            canonical_id TEXT PRIMARY KEY,
+            stable_id INTEGER NOT NULL,
+            last_seen INTEGER DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // overlay：编辑器未保存缓冲区的符号（reindex-file 写入），读模式用它遮蔽该文件的落库符号；
+    // 文件保存并重新索引后自动清除。sandbox 为空是编辑器缓冲区，其余是 --sandbox 命名的沙箱
+    // 旧结构没有 sandbox 列：覆盖层只是缓冲区的临时副本，直接重建
+    let unlayered_overlay: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info('overlay_files'))
+                AND NOT EXISTS (SELECT 1 FROM pragma_table_info('overlay_files') WHERE name = 'sandbox')",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or(false);
+    if unlayered_overlay {
+        conn.execute_batch(
+            "DROP TABLE overlay_files; DROP TABLE IF EXISTS overlay_symbols; DROP TABLE IF EXISTS overlay_calls;",
+        )?;
+        println!("[Migration] Rebuilt overlay tables with sandbox column");
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS overlay_files (
+            sandbox TEXT NOT NULL DEFAULT '',
+            file_id INTEGER NOT NULL,
+            file_hash TEXT NOT NULL,
+            updated_at INTEGER DEFAULT 0,
+            PRIMARY KEY (sandbox, file_id)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS overlay_symbols (
+            symbol_id INTEGER PRIMARY KEY,
+            sandbox TEXT NOT NULL DEFAULT '',
+            file_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            qualified_name TEXT NOT NULL,
+            canonical_id TEXT NOT NULL,
+            scope_path TEXT,
+            symbol_type TEXT NOT NULL,
+            line_start INTEGER,
+            line_end INTEGER,
+            signature TEXT,
+            parent_id INTEGER,
+            summary TEXT,
+            stable_id INTEGER,
+            doc TEXT,
+            anchor TEXT,
+            signature_shape TEXT,
+            visibility TEXT,
+            generated_from TEXT
+        )",
+        [],
+    )?;
+    // overlay_calls：缓冲区里的调用边，caller_id 指向 overlay_symbols（负 ID），与 calls 同构
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS overlay_calls (
+            call_id INTEGER PRIMARY KEY,
+            sandbox TEXT NOT NULL DEFAULT '',
+            file_id INTEGER NOT NULL,
+            caller_id INTEGER NOT NULL,
+            callee_name TEXT NOT NULL,
+            call_line INTEGER,
+            callee_id TEXT,
+            kind TEXT DEFAULT 'call',
+            receiver_type TEXT,
+            arg_count INTEGER,
+            arg_keywords TEXT
+        )",
+        [],
+    )?;
+
     // Performance Indices
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_symbols_file ON symbols(file_id)",
@@ -238,6 +767,36 @@ fn init_db(conn: &Connection) -> Result<()> {
         println!("[Migration] Added symbols.scope_path column");
     }
 
+    // symbols.summary：Agent 回写的符号摘要（跨会话持久）
+    let summary_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='summary'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !summary_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN summary TEXT", [])?;
+        println!("[Migration] Added symbols.summary column");
+    }
+
+    // symbols.stable_id：内容锚定 ID（scope + signature 哈希），不随文件路径变化
+    let stable_id_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='stable_id'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !stable_id_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN stable_id INTEGER", [])?;
+        println!("[Migration] Added symbols.stable_id column");
+    }
+
     // 检查 calls.callee_id 是否存在
     let callee_id_exists: bool = conn
         .query_row(
@@ -253,48 +812,94 @@ fn init_db(conn: &Connection) -> Result<()> {
         println!("[Migration] Added calls.callee_id column");
     }
 
-    // files 增量字段：file_size, file_mtime
-    let file_size_exists: bool = conn
+    // calls.kind：区分普通调用 (call) 与跨服务 HTTP 调用 (http)
+    let call_kind_exists: bool = conn
         .query_row(
-            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='file_size'",
+            "SELECT COUNT(*) FROM pragma_table_info('calls') WHERE name='kind'",
             [],
             |row| row.get::<_, i32>(0),
         )
         .unwrap_or(0)
         > 0;
-    if !file_size_exists {
-        conn.execute(
-            "ALTER TABLE files ADD COLUMN file_size INTEGER DEFAULT 0",
-            [],
-        )?;
-        println!("[Migration] Added files.file_size column");
+
+    if !call_kind_exists {
+        conn.execute("ALTER TABLE calls ADD COLUMN kind TEXT DEFAULT 'call'", [])?;
+        println!("[Migration] Added calls.kind column");
     }
 
-    let file_mtime_exists: bool = conn
+    // calls.receiver_type：obj.method() 推断出的接收者类型
+    let receiver_type_exists: bool = conn
         .query_row(
-            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='file_mtime'",
+            "SELECT COUNT(*) FROM pragma_table_info('calls') WHERE name='receiver_type'",
             [],
             |row| row.get::<_, i32>(0),
         )
         .unwrap_or(0)
         > 0;
-    if !file_mtime_exists {
-        conn.execute(
-            "ALTER TABLE files ADD COLUMN file_mtime INTEGER DEFAULT 0",
-            [],
-        )?;
-        println!("[Migration] Added files.file_mtime column");
+
+    if !receiver_type_exists {
+        conn.execute("ALTER TABLE calls ADD COLUMN receiver_type TEXT", [])?;
+        println!("[Migration] Added calls.receiver_type column");
     }
 
-    let index_level_exists: bool = conn
+    // calls.arg_count / arg_keywords：调用点实参形状（check-callsites 校验签名兼容性）
+    let arg_count_exists: bool = conn
         .query_row(
-            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='index_level'",
+            "SELECT COUNT(*) FROM pragma_table_info('calls') WHERE name='arg_count'",
             [],
             |row| row.get::<_, i32>(0),
         )
         .unwrap_or(0)
         > 0;
-    if !index_level_exists {
+
+    if !arg_count_exists {
+        conn.execute("ALTER TABLE calls ADD COLUMN arg_count INTEGER", [])?;
+        conn.execute("ALTER TABLE calls ADD COLUMN arg_keywords TEXT", [])?;
+        println!("[Migration] Added calls.arg_count / arg_keywords columns");
+    }
+
+    // files 增量字段：file_size, file_mtime
+    let file_size_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='file_size'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+    if !file_size_exists {
+        conn.execute(
+            "ALTER TABLE files ADD COLUMN file_size INTEGER DEFAULT 0",
+            [],
+        )?;
+        println!("[Migration] Added files.file_size column");
+    }
+
+    let file_mtime_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='file_mtime'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+    if !file_mtime_exists {
+        conn.execute(
+            "ALTER TABLE files ADD COLUMN file_mtime INTEGER DEFAULT 0",
+            [],
+        )?;
+        println!("[Migration] Added files.file_mtime column");
+    }
+
+    let index_level_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='index_level'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+    if !index_level_exists {
         conn.execute(
             "ALTER TABLE files ADD COLUMN index_level TEXT DEFAULT 'symbol'",
             [],
@@ -318,6 +923,118 @@ fn init_db(conn: &Connection) -> Result<()> {
         println!("[Migration] Added files.indexed_at column");
     }
 
+    // files.root：多根工作区的 root 名（单根为空串）
+    let root_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='root'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !root_exists {
+        conn.execute("ALTER TABLE files ADD COLUMN root TEXT DEFAULT ''", [])?;
+        println!("[Migration] Added files.root column");
+    }
+
+    // files.dirty：invalidate 模式标记，下次索引无视哈希/解析缓存强制重解析
+    let dirty_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='dirty'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !dirty_exists {
+        conn.execute("ALTER TABLE files ADD COLUMN dirty INTEGER DEFAULT 0", [])?;
+        println!("[Migration] Added files.dirty column");
+    }
+
+    // files.extractor_version：抽取该文件时的 语法 + query 版本，升级后自动重解析
+    let extractor_version_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='extractor_version'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !extractor_version_exists {
+        conn.execute(
+            "ALTER TABLE files ADD COLUMN extractor_version TEXT DEFAULT ''",
+            [],
+        )?;
+        println!("[Migration] Added files.extractor_version column");
+    }
+
+    // files.grammar：实际解析成功的语法（.js 解析失败时可能回退到 tsx）
+    let grammar_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='grammar'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !grammar_exists {
+        conn.execute("ALTER TABLE files ADD COLUMN grammar TEXT DEFAULT ''", [])?;
+        println!("[Migration] Added files.grammar column");
+    }
+
+    // files.partial：语法树含 ERROR 节点，符号只抽取到一部分
+    let partial_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='partial'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !partial_exists {
+        conn.execute("ALTER TABLE files ADD COLUMN partial INTEGER DEFAULT 0", [])?;
+        println!("[Migration] Added files.partial column");
+    }
+
+    // files.license / files.copyright：文件头的许可证声明与版权人（来源统计）
+    for col in ["license", "copyright"] {
+        let exists: bool = conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name=?1",
+                [col],
+                |row| row.get::<_, i32>(0),
+            )
+            .unwrap_or(0)
+            > 0;
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE files ADD COLUMN {} TEXT DEFAULT ''", col),
+                [],
+            )?;
+            println!("[Migration] Added files.{} column", col);
+        }
+    }
+
+    // roots.license：根目录 LICENSE / COPYING 文件识别出的项目许可证
+    let root_license_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('roots') WHERE name='license'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !root_license_exists {
+        conn.execute("ALTER TABLE roots ADD COLUMN license TEXT DEFAULT ''", [])?;
+        println!("[Migration] Added roots.license column");
+    }
+
     // 新增索引（幂等）
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_symbols_scope_path ON symbols(scope_path)",
@@ -327,123 +1044,534 @@ fn init_db(conn: &Connection) -> Result<()> {
         "CREATE INDEX IF NOT EXISTS idx_calls_callee_id ON calls(callee_id)",
         [],
     )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_inherits_base ON inherits(base_name)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_imports_file ON imports(file_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_imports_path ON imports(imported_path)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_decorators_name ON decorators(name)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_attributes_symbol ON attributes(symbol_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_attributes_key ON attributes(key, value)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_routes_file ON routes(file_id)",
+        [],
+    )?;
+    // symbols.doc：索引期抽取的 docstring（默认开启，--features docs=off 关闭）
+    let doc_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='doc'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !doc_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN doc TEXT", [])?;
+        println!("[Migration] Added symbols.doc column");
+    }
+    // symbols.anchor：起止行上下文哈希，Go 编辑工具据此检测并发修改
+    let anchor_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='anchor'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !anchor_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN anchor TEXT", [])?;
+        println!("[Migration] Added symbols.anchor column");
+    }
+    // symbols.signature_shape：结构化签名 JSON {"params": [{"name", "type"}], "returns"}
+    let shape_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='signature_shape'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !shape_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN signature_shape TEXT", [])?;
+        println!("[Migration] Added symbols.signature_shape column");
+    }
+
+    // symbols.visibility：public | protected | internal | private（公开 API 面与破坏性变更检测）
+    let visibility_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('symbols') WHERE name='visibility'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !visibility_exists {
+        conn.execute("ALTER TABLE symbols ADD COLUMN visibility TEXT", [])?;
+        println!("[Migration] Added symbols.visibility column");
+    }
+
+    // files.generated / symbols.generated_from：生成的 protobuf / gRPC 桩代码及其对应的 .proto 定义
+    let generated_exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('files') WHERE name='generated'",
+            [],
+            |row| row.get::<_, i32>(0),
+        )
+        .unwrap_or(0)
+        > 0;
+
+    if !generated_exists {
+        conn.execute("ALTER TABLE files ADD COLUMN generated TEXT DEFAULT ''", [])?;
+        conn.execute("ALTER TABLE symbols ADD COLUMN generated_from TEXT", [])?;
+        println!("[Migration] Added files.generated / symbols.generated_from columns");
+    }
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_symbols_stable ON symbols(stable_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_symbols_canonical ON symbols(canonical_id)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_files_root ON files(root)",
+        [],
+    )?;
 
     Ok(())
 }
 
-fn calculate_hash(path: &Path) -> std::io::Result<String> {
-    let mut file = fs::File::open(path)?;
-    let mut hasher = Sha256::new();
-    std::io::copy(&mut file, &mut hasher)?;
-    Ok(hex::encode(hasher.finalize()))
-}
-
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let project_path = Path::new(&args.project);
+    let mut args = Args::parse();
+    args.query = args.query.as_deref().map(nfc);
+    check_mode_allowed(&args)?;
+    validate_paths(&args)?;
 
-    // Heartbeat setup
-    let mcp_data = project_path.join(".mcp-data");
-    let _ = fs::create_dir_all(&mcp_data);
-    let heartbeat_path = mcp_data.join("heartbeat");
+    let heartbeat_path = heartbeat_path(&args)?;
+    // 登记本次索引运行并清理崩溃进程留下的心跳；退出时（含出错返回）自动注销。
+    // 只读模式不写心跳，也就不登记：省掉对 runs.json 的并发读改写，lsp 的 stdout 也不会混进 [Cleanup] 行
+    let _run = (args.mode == "index").then(|| RunGuard::register(&args, &heartbeat_path));
 
     if args.mode == "index" {
         run_indexer(&args, &heartbeat_path)?;
-    } else if args.mode == "query" {
+    } else if args.mode == "query" && args.snapshot.is_some() {
+        // 快照与 DB generation 无关，不走结果缓存
         run_query(&args)?;
-    } else if args.mode == "map" {
+    } else if args.mode == "query" && args.line.is_some() {
+        // 行号定位要校验磁盘文件是否已改动，结果不随 generation 固定
+        run_query(&args)?;
+    } else if args.mode == "query" {
+        run_cached(&args, run_query)?;
+        // 调用点片段取自磁盘当前内容，不随 generation 固定：缓存命中时按当前文件重取
+        refresh_call_sites(&args)?;
+    } else if args.mode == "map" && args.allow_partial {
+        // 索引进行中 generation 尚未推进，部分结果不能进缓存
         run_map(&args)?;
+    } else if args.mode == "map" {
+        run_cached(&args, run_map)?;
     } else if args.mode == "analyze" {
-        run_analyze(&args)?;
+        run_cached(&args, run_analyze)?;
     } else if args.mode == "snapshot" {
         run_snapshot(&args)?;
     } else if args.mode == "diff" {
         run_diff(&args)?;
     } else if args.mode == "structure" {
         run_structure(&args)?;
+    } else if args.mode == "docs" {
+        run_docs(&args)?;
+    } else if args.mode == "unused" {
+        run_unused(&args)?;
+    } else if args.mode == "lsp" {
+        run_lsp(&args)?;
+    } else if args.mode == "symbols" {
+        run_symbols(&args)?;
+    } else if args.mode == "set-summary" {
+        run_set_summary(&args)?;
+    } else if args.mode == "stats" {
+        run_stats(&args)?;
+    } else if args.mode == "invalidate" {
+        run_invalidate(&args)?;
+    } else if args.mode == "reindex-file" {
+        run_reindex_file(&args)?;
+    } else if args.mode == "sandbox-diff" {
+        run_sandbox_diff(&args)?;
+    } else if args.mode == "sandbox-discard" {
+        run_sandbox_discard(&args)?;
+    } else if args.mode == "source" {
+        run_source(&args)?;
+    } else if args.mode == "deprecated" {
+        run_deprecated(&args)?;
+    } else if args.mode == "rename-plan" {
+        run_rename_plan(&args)?;
+    } else if args.mode == "check-callsites" {
+        run_check_callsites(&args)?;
+    } else if args.mode == "sbom" {
+        run_sbom(&args)?;
+    } else if args.mode == "validate" {
+        run_validate(&args)?;
     }
 
     Ok(())
 }
 
-fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
-    println!("Starting indexer for: {}", args.project);
-
-    // 1. Setup DB
-    let mut conn = Connection::open(&args.db)?;
-    init_db(&conn)?;
-
-    // Optimizations
-    conn.execute("PRAGMA synchronous = OFF", [])?;
-    // PRAGMA journal_mode returns the new mode (string), so we must use query_row, not execute
-    let _: String = conn
-        .query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))
-        .unwrap_or_default();
-    // Keep WAL growth bounded on large projects.
-    let _: i64 = conn
-        .query_row("PRAGMA wal_autocheckpoint = 1000", [], |r| r.get(0))
-        .unwrap_or(1000);
+// ============================================================================
+// Permission Model (权限模型)
+// 长驻的 lsp 进程由宿主拉起、直接面对客户端：模式白名单（--allow-modes / MPM_AST_ALLOW_MODES，
+// 两者都给时取交集，命令行只能收窄运维设定）、可选 token（initialize 时校验），
+// 源码读取始终限定在项目根内（见 resolve_source_path）
+// ============================================================================
 
-    // 2. Discover Files
-    let scan_root = if let Some(scope) = &args.scope {
-        let normalized = scope.trim().trim_start_matches("./").trim_matches('/');
-        if normalized.is_empty() {
-            PathBuf::from(&args.project)
-        } else {
-            Path::new(&args.project).join(normalized)
-        }
-    } else {
-        PathBuf::from(&args.project)
+fn allowed_modes(args: &Args) -> Option<HashSet<String>> {
+    let parse = |spec: &str| -> HashSet<String> {
+        spec.split(',')
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect()
     };
+    let from_env = std::env::var("MPM_AST_ALLOW_MODES").ok().map(|s| parse(&s));
+    let from_args = args.allow_modes.as_deref().map(parse);
+    match (from_env, from_args) {
+        (Some(env), Some(cli)) => Some(env.intersection(&cli).cloned().collect()),
+        (env, cli) => env.or(cli),
+    }
+}
 
-    let mut builder = WalkBuilder::new(&scan_root);
-    builder.hidden(false); // Process .git ? No, usually we want to ignore .git
-    builder.git_ignore(true); // Respect .gitignore
+fn check_mode_allowed(args: &Args) -> anyhow::Result<()> {
+    let Some(allowed) = allowed_modes(args) else {
+        return Ok(());
+    };
+    if !allowed.contains(&args.mode) {
+        let mut modes: Vec<String> = allowed.into_iter().collect();
+        modes.sort();
+        anyhow::bail!(
+            "mode '{}' is not allowed (allowed: {})",
+            args.mode,
+            modes.join(", ")
+        );
+    }
+    Ok(())
+}
 
-    // Default ignores to avoid indexing third-party/build artifacts even when caller forgets.
-    let default_ignores: HashSet<String> = [
-        ".git",
-        "node_modules",
-        "vendor",
-        "dist",
-        "build",
-        "out",
-        "target",
-        "__pycache__",
-        ".venv",
-        "venv",
-        "site-packages",
-        ".m2",
-        ".gradle",
-        ".idea",
-        ".vscode",
-        "coverage",
-        "_build",
-        ".next",
-        ".nuxt",
-        ".svelte-kit",
-    ]
-    .into_iter()
-    .map(|s| s.to_string())
-    .collect();
+fn auth_token(args: &Args) -> Option<String> {
+    args.auth_token
+        .clone()
+        .or_else(|| std::env::var("MPM_AST_AUTH_TOKEN").ok())
+        .filter(|t| !t.is_empty())
+}
 
-    {
-        let mut ignore_set = default_ignores;
-        if let Some(ignores) = &args.ignore_dirs {
-            for s in ignores
-                .split(',')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-            {
-                ignore_set.insert(s.to_string());
-            }
-        }
-        builder.filter_entry(move |entry| {
-            if !entry.file_type().map(|f| f.is_dir()).unwrap_or(false) {
-                return true;
-            }
-            !ignore_set.contains(entry.file_name().to_str().unwrap_or(""))
-        });
+// 用户传入的路径须落在某个项目根内（--allow-outside 放行）：--file / --scope 相对项目根，
+// 其余读写文件的参数（--db / --output / --notify / --content 等）相对工作目录；没有 --project（纯归档、diff）时不约束
+fn validate_paths(args: &Args) -> anyhow::Result<()> {
+    if args.allow_outside {
+        return Ok(());
+    }
+    let Ok(roots) = project_roots(args) else {
+        return Ok(());
+    };
+    let cwd = std::env::current_dir()?;
+    let real_roots: Vec<PathBuf> = roots
+        .iter()
+        .map(|r| sandbox_resolve(&cwd.join(&r.path)))
+        .collect();
+    let project_base = cwd.join(&roots[0].path);
+
+    let mut checks: Vec<(&str, PathBuf)> = vec![];
+    if let Some(file) = &args.file {
+        checks.push(("--file", project_base.join(file)));
+    }
+    for scope in parse_scopes(args.scope.as_deref()) {
+        checks.push(("--scope", project_base.join(scope)));
+    }
+    // --content - 读 stdin、--parse-cache off 关闭缓存，都不是路径
+    let content = args.content.as_ref().filter(|c| c.as_str() != "-");
+    let parse_cache = args.parse_cache.as_ref().filter(|c| c.as_str() != "off");
+    for (flag, value) in [
+        ("--db", Some(&args.db)),
+        ("--output", args.output.as_ref()),
+        ("--profile", args.profile.as_ref()),
+        ("--snapshot", args.snapshot.as_ref()),
+        ("--base", args.base.as_ref()),
+        ("--target", args.target.as_ref()),
+        ("--notify", args.notify.as_ref()),
+        ("--content", content),
+        ("--parse-cache", parse_cache),
+        ("--input", args.input.as_ref()),
+        ("--compare-with", args.compare_with.as_ref()),
+        ("--risk-model", args.risk_model.as_ref()),
+        ("--type-map", args.type_map.as_ref()),
+    ] {
+        if let Some(path) = value {
+            checks.push((flag, cwd.join(path)));
+        }
+    }
+
+    for (flag, path) in checks {
+        let resolved = sandbox_resolve(&path);
+        if !real_roots.iter().any(|root| resolved.starts_with(root)) {
+            anyhow::bail!(
+                "{} {} is outside the project root (pass --allow-outside to permit)",
+                flag,
+                resolved.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// 逐段解析：已存在的前缀随即 canonicalize（解开符号链接），`..` 作用在解开后的真实路径上
+// （与 OS 一致：link/.. 是链接目标的父目录）；不存在的尾部按词法拼接
+fn sandbox_resolve(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for part in path.components() {
+        match part {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::CurDir => {}
+            other => {
+                resolved.push(other);
+                if let Ok(real) = fs::canonicalize(&resolved) {
+                    resolved = real;
+                }
+            }
+        }
+    }
+    resolved
+}
+
+// 定长比较：耗时不随匹配前缀变化，不能逐字节试探 token
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Heartbeat setup（多根工作区写在第一个 root 下；纯归档输入写在 DB 旁）
+fn heartbeat_path(args: &Args) -> anyhow::Result<PathBuf> {
+    let mcp_data = match (project_roots(args), &args.archive) {
+        (Ok(roots), _) => roots[0].path.join(".mcp-data"),
+        (Err(_), Some(_)) => Path::new(&args.db)
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf(),
+        (Err(e), None) => return Err(e),
+    };
+    let _ = fs::create_dir_all(&mcp_data);
+    Ok(mcp_data.join("heartbeat"))
+}
+
+// ============================================================================
+// Run Registry (运行登记：.mcp-data/runs.json)
+// 每个索引进程启动时登记 (PID, 启动时间, 模式)，退出时注销；崩溃的进程注销不了，
+// 下一次启动时按 PID 存活判定清掉，连同它没写完的心跳（否则 Go 侧一直以为索引还在跑）
+// ============================================================================
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RunEntry {
+    pid: u32,
+    started_at: u64,
+    mode: String,
+    db: String,
+}
+
+// 心跳超过该秒数未更新且无法确认写入进程存活时视为遗留
+const STALE_HEARTBEAT_SECS: u64 = 120;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Some(false) 表示进程确定已退出；非 unix 平台无法探测，返回 None 交给心跳时间判断
+#[cfg(unix)]
+fn process_alive(pid: u32) -> Option<bool> {
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    Some(rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> Option<bool> {
+    None
+}
+
+fn read_runs(path: &Path) -> Vec<RunEntry> {
+    fs::read(path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice(&raw).ok())
+        .unwrap_or_default()
+}
+
+// 先写临时文件再 rename，并发进程不会读到半截 JSON
+fn write_runs(path: &Path, runs: &[RunEntry]) {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    if let Ok(json) = serde_json::to_vec_pretty(runs) {
+        if fs::write(&tmp, json).is_ok() && fs::rename(&tmp, path).is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+struct RunGuard {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl RunGuard {
+    fn register(args: &Args, heartbeat_path: &Path) -> RunGuard {
+        let path = heartbeat_path.with_file_name("runs.json");
+        let pid = std::process::id();
+        let mut runs = read_runs(&path);
+        let before = runs.len();
+        runs.retain(|r| r.pid != pid && process_alive(r.pid) != Some(false));
+        if runs.len() < before {
+            eprintln!(
+                "[Cleanup] Dropped {} dead run(s) from the run registry",
+                before - runs.len()
+            );
+        }
+        reap_stale_heartbeat(heartbeat_path, &runs);
+        runs.push(RunEntry {
+            pid,
+            started_at: unix_now(),
+            mode: args.mode.clone(),
+            db: args.db.clone(),
+        });
+        write_runs(&path, &runs);
+        RunGuard { path, pid }
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        let mut runs = read_runs(&self.path);
+        runs.retain(|r| r.pid != self.pid && process_alive(r.pid) != Some(false));
+        write_runs(&self.path, &runs);
+    }
+}
+
+// 未标记 done 的心跳：写入它的进程已退出（旧版本心跳不带 pid 时看登记表里有无存活的 index 运行），
+// 且无法确认存活时已超过 STALE_HEARTBEAT_SECS 未更新，才删除
+fn reap_stale_heartbeat(heartbeat_path: &Path, live_runs: &[RunEntry]) {
+    let Some(hb) = fs::read(heartbeat_path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok())
+    else {
+        return;
+    };
+    if hb["done"].as_bool().unwrap_or(false) {
+        return;
+    }
+    let age = unix_now().saturating_sub(hb["timestamp"].as_u64().unwrap_or(0));
+    let writer = hb["pid"].as_u64().map(|p| p as u32);
+    let alive = match writer.and_then(process_alive) {
+        Some(alive) => alive,
+        None => live_runs.iter().any(|r| r.mode == "index") || age < STALE_HEARTBEAT_SECS,
+    };
+    if !alive && fs::remove_file(heartbeat_path).is_ok() {
+        match writer {
+            Some(pid) => eprintln!(
+                "[Cleanup] Removed stale heartbeat left by dead run (pid {})",
+                pid
+            ),
+            None => eprintln!("[Cleanup] Removed stale heartbeat ({}s old)", age),
+        }
+    }
+}
+
+fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
+    println!("Starting indexer for: {}", args.project.join(", "));
+    let mut profile = Profile::new(args);
+    let mut lap = Instant::now();
+
+    // 1. Setup DB
+    let mut conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+
+    // Optimizations
+    conn.execute("PRAGMA synchronous = OFF", [])?;
+    // PRAGMA journal_mode returns the new mode (string), so we must use query_row, not execute
+    let _: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))
+        .unwrap_or_default();
+    // Keep WAL growth bounded on large projects.
+    let _: i64 = conn
+        .query_row("PRAGMA wal_autocheckpoint = 1000", [], |r| r.get(0))
+        .unwrap_or(1000);
+    lap = profile.lap(&["main", "open_db"], lap);
+
+    // 2. Discover Files
+    // 🆕 多根工作区：每个 root 独立遍历，file_path 以 "<root>/" 为前缀区分
+    // 🆕 --archive：归档本身作为唯一 root，文件内容直接在内存中读取
+    let roots = match &args.archive {
+        Some(archive) => vec![ProjectRoot {
+            name: String::new(),
+            path: PathBuf::from(archive),
+        }],
+        None => project_roots(args)?,
+    };
+    let multi_root = roots.len() > 1;
+    record_roots(&conn, &roots)?;
+
+    // Default ignores to avoid indexing third-party/build artifacts even when caller forgets.
+    let mut ignore_set: HashSet<String> = [
+        ".git",
+        "node_modules",
+        "vendor",
+        "dist",
+        "build",
+        "out",
+        "target",
+        "__pycache__",
+        ".venv",
+        "venv",
+        "site-packages",
+        ".m2",
+        ".gradle",
+        ".idea",
+        ".vscode",
+        "coverage",
+        "_build",
+        ".next",
+        ".nuxt",
+        ".svelte-kit",
+    ]
+    .into_iter()
+    .map(|s| s.to_string())
+    .collect();
+    if let Some(ignores) = &args.ignore_dirs {
+        for s in ignores
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+        {
+            ignore_set.insert(s.to_string());
+        }
     }
 
     let allowed_exts: HashSet<String> = args
@@ -456,23 +1584,70 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
         })
         .unwrap_or_default();
 
-    println!("Scanning directory...");
-    let entries: Vec<PathBuf> = builder
-        .build()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
-        .map(|e| e.path().to_path_buf())
-        .filter(|p| {
-            if allowed_exts.is_empty() {
-                return true;
-            }
-            p.extension()
-                .map(|e| allowed_exts.contains(e.to_str().unwrap_or("")))
-                .unwrap_or(false)
-        })
-        .collect();
+    let mut entries: Vec<SourceEntry> = vec![];
+    let mut archive_paths: Option<HashSet<String>> = None;
+    if let Some(archive) = &args.archive {
+        println!("Reading archive: {}", archive);
+        // 先按扩展名 / 忽略目录粗筛再读内容；顶层目录可能随后被剥掉，不拿它比对，
+        // --scope 同样要等剥掉之后才能判断，留给下面的精确过滤
+        let wanted = |path: &str| {
+            let inner = path.split_once('/').map_or(path, |(_, rest)| rest);
+            archive_entry_included(inner, "", &ignore_set, &allowed_exts)
+        };
+        let files = read_archive(Path::new(archive), &wanted)?;
+        archive_paths = Some(files.iter().map(|f| f.path.clone()).collect());
+        let scope = args
+            .scope
+            .as_deref()
+            .map(|s| s.trim().trim_start_matches("./").trim_matches('/'))
+            .unwrap_or("");
+        entries.extend(
+            files
+                .into_iter()
+                .filter(|f| archive_entry_included(&f.path, scope, &ignore_set, &allowed_exts))
+                .map(SourceEntry::Memory),
+        );
+    } else {
+        println!("Scanning directory...");
+        for (root_idx, root) in roots.iter().enumerate() {
+            let scan_root = match root_scan_path(root, args.scope.as_deref(), multi_root) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let mut builder = WalkBuilder::new(&scan_root);
+            builder.hidden(false); // Process .git ? No, usually we want to ignore .git
+            builder.git_ignore(true); // Respect .gitignore
+
+            let ignore_set = ignore_set.clone();
+            builder.filter_entry(move |entry| {
+                if !entry.file_type().map(|f| f.is_dir()).unwrap_or(false) {
+                    return true;
+                }
+                !ignore_set.contains(entry.file_name().to_str().unwrap_or(""))
+            });
+
+            entries.extend(
+                builder
+                    .build()
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+                    .map(|e| e.path().to_path_buf())
+                    .filter(|p| {
+                        if allowed_exts.is_empty() {
+                            return true;
+                        }
+                        p.extension()
+                            .map(|e| allowed_exts.contains(e.to_str().unwrap_or("")))
+                            .unwrap_or(false)
+                    })
+                    .map(|path| SourceEntry::Disk { root_idx, path }),
+            );
+        }
+    }
 
     println!("Found {} files", entries.len());
+    lap = profile.lap(&["main", "discover"], lap);
 
     // 3. Process Files (Linear for DB safety, Rayon can be used for parsing if we separate Read/Write)
     // To keep it simple and safe for MVP: Sync Loop but fast because Tree-sitter is fast.
@@ -486,10 +1661,11 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
 
     // We'll prepare the Query map in main thread, and pass ref to workers.
     let parsers_setup = get_parser_setup();
-    // parser_setup is HashMap<String, (Language, Query)>
+    // parser_setup is HashMap<String, (Language, Query, extractor_version)>
     // Query is not cloneable easily? It is.
     // We wrap it in Arc for cheap sharing.
     let parsers_arc = Arc::new(parsers_setup);
+    lap = profile.lap(&["main", "compile_queries"], lap);
 
     println!("Found {} files", entries.len());
 
@@ -500,12 +1676,14 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
         size: u64,
         mtime: i64,
         level: String,
+        dirty: bool,
+        version: String,
     }
 
     let mut db_files: HashMap<String, DbFileMeta> = HashMap::new();
     {
         let mut stmt = conn.prepare(
-            "SELECT file_path, file_hash, file_size, file_mtime, index_level FROM files",
+            "SELECT file_path, file_hash, file_size, file_mtime, index_level, dirty, extractor_version FROM files",
         )?;
         let rows = stmt.query_map([], |row| {
             Ok((
@@ -515,25 +1693,28 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
                 row.get::<_, i64>(3).unwrap_or(0),
                 row.get::<_, String>(4)
                     .unwrap_or_else(|_| "symbol".to_string()),
+                row.get::<_, Option<i64>>(5)?.unwrap_or(0) != 0,
+                row.get::<_, Option<String>>(6)?.unwrap_or_default(),
             ))
         })?;
-        for r in rows {
-            if let Ok((path, hash, size_i64, mtime, level)) = r {
-                let size = if size_i64 > 0 { size_i64 as u64 } else { 0 };
-                db_files.insert(
-                    path,
-                    DbFileMeta {
-                        hash,
-                        size,
-                        mtime,
-                        level,
-                    },
-                );
-            }
+        for (path, hash, size_i64, mtime, level, dirty, version) in rows.flatten() {
+            let size = if size_i64 > 0 { size_i64 as u64 } else { 0 };
+            db_files.insert(
+                path,
+                DbFileMeta {
+                    hash,
+                    size,
+                    mtime,
+                    level,
+                    dirty,
+                    version,
+                },
+            );
         }
     }
 
     let total = entries.len();
+    lap = profile.lap(&["main", "load_file_meta"], lap);
 
     let huge_threshold = std::env::var("MPM_AST_HUGE_FILE_THRESHOLD")
         .ok()
@@ -546,8 +1727,7 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
 
     let initial_build = db_files.is_empty();
     let has_meta_backlog = db_files.values().any(|f| f.level == "meta");
-    let use_bootstrap_strategy =
-        (initial_build && total > huge_threshold) || (has_meta_backlog && total > huge_threshold);
+    let use_bootstrap_strategy = (initial_build || has_meta_backlog) && total > huge_threshold;
     let force_full = args.force_full;
     let strategy = if force_full {
         "force_full"
@@ -562,7 +1742,9 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
     );
 
     // Channel for results
-    let (tx_chan, rx_chan) = mpsc::channel::<ParseResult>();
+    // 有界：解析远快于单线程写库，无界队列会把整仓的抽取结果堆在内存里；
+    // 队列满时 worker 阻塞在 send 上，等写库追上
+    let (tx_chan, rx_chan) = mpsc::sync_channel::<ParseResult>(args.channel_capacity.max(1));
 
     // 5. Parallel Processing
     // We use scoped thread or just rayon spawn. par_iter is blocking for the iterator, but we want to consume in main thread.
@@ -571,31 +1753,62 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
 
     // We can spawn a thread to drive the parallel processing, while main thread waits on RX.
     let entries_arc = Arc::new(entries);
+
+    // 抽取器版本与特性开关按文件比对（见 files.extractor_version）；
+    // 类型映射在写库时生效，变化后库里的 symbol_type 需要全部重写
+    let feature_flags = parse_feature_flags(args.features.as_deref())?;
+    let type_map = load_type_map(args.type_map.as_deref())?;
+    let type_map_tag = type_map.tag();
+    let last_type_map_tag: String = conn
+        .query_row("SELECT value FROM meta WHERE key = 'type_map'", [], |r| {
+            r.get(0)
+        })
+        .unwrap_or_default();
+    if type_map_tag != last_type_map_tag && !db_files.is_empty() {
+        println!(
+            "[TypeMap] Changed from '{}' to '{}', re-extracting all files",
+            last_type_map_tag, type_map_tag
+        );
+        db_files.clear();
+    }
     let db_files_arc = Arc::new(db_files);
-    let project_root = args.project.clone();
+    let roots_arc = Arc::new(roots.clone());
     let parse_counter = Arc::new(AtomicUsize::new(0));
     let parsed_counter = Arc::new(AtomicUsize::new(0));
     let meta_counter = Arc::new(AtomicUsize::new(0));
     let skipped_counter = Arc::new(AtomicUsize::new(0));
+    let hash_skip_counter = Arc::new(AtomicUsize::new(0));
+    let unchanged_reparse_counter = Arc::new(AtomicUsize::new(0));
+    let cache_hit_counter = Arc::new(AtomicUsize::new(0));
     let parse_counter_worker = Arc::clone(&parse_counter);
     let parsed_counter_worker = Arc::clone(&parsed_counter);
     let meta_counter_worker = Arc::clone(&meta_counter);
     let skipped_counter_worker = Arc::clone(&skipped_counter);
+    let hash_skip_counter_worker = Arc::clone(&hash_skip_counter);
+    let unchanged_reparse_counter_worker = Arc::clone(&unchanged_reparse_counter);
+    let cache_hit_counter_worker = Arc::clone(&cache_hit_counter);
+    let parse_cache_dir = resolve_parse_cache_dir(args);
+    if let Some(dir) = &parse_cache_dir {
+        let pruned = prune_parse_cache(dir, &parsers_arc);
+        if pruned > 0 {
+            println!("[ParseCache] Removed {} stale cache keys", pruned);
+        }
+    }
 
     let producer_handle = std::thread::spawn(move || {
         let parse_counter = parse_counter_worker;
         let parsed_counter = parsed_counter_worker;
         let meta_counter = meta_counter_worker;
         let skipped_counter = skipped_counter_worker;
-        entries_arc.par_iter().for_each(|path| {
-            let path_str = path
-                .strip_prefix(&project_root)
-                .unwrap_or(path)
-                .to_string_lossy()
-                .replace("\\", "/");
+        let hash_skip_counter = hash_skip_counter_worker;
+        let unchanged_reparse_counter = unchanged_reparse_counter_worker;
+        let cache_hit_counter = cache_hit_counter_worker;
+        entries_arc.par_iter().for_each(|entry| {
+            let root = &roots_arc[entry.root_idx()];
+            let path_str = entry.index_path(root);
 
             // Fast filters: extension whitelist + supported parser
-            let ext = path
+            let ext = Path::new(&path_str)
                 .extension()
                 .and_then(|e| e.to_str())
                 .unwrap_or("")
@@ -608,38 +1821,54 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
                 }
             }
 
-            let (lang, query) = match parsers_arc.get(&ext) {
-                Some(v) => v,
-                None => return,
+            let Some(version) = file_extractor_version(&parsers_arc, &ext) else {
+                return;
             };
+            let features = feature_flags.for_ext(&ext);
+            let file_version = format!("{}-{}", version, features.cache_tag());
 
             // Metadata-based skip (avoid reading file content when unchanged)
-            let (file_size, file_mtime) = match fs::metadata(path).and_then(|m| {
-                let size = m.len();
-                let mtime = m
-                    .modified()?
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs() as i64;
-                Ok((size, mtime))
-            }) {
-                Ok(v) => v,
-                Err(_) => return,
+            let (file_size, file_mtime) = match entry.metadata() {
+                Some(v) => v,
+                None => return,
             };
 
-            if let Some(old) = db_files_arc.get(&path_str) {
-                if old.level == "symbol" && old.size == file_size && old.mtime == file_mtime {
+            // 被 invalidate 标记的文件视同新文件，且不读解析缓存；
+            // 抽取器版本（语法 ABI / query / 特性开关）变了，哈希未变也要重新抽取
+            let old_meta = db_files_arc.get(&path_str);
+            let dirty = old_meta.is_some_and(|m| m.dirty);
+            let reusable = old_meta.filter(|m| !m.dirty && m.version == file_version);
+            if let Some(old) = reusable {
+                // 归档条目的 mtime 不可靠（zip 无时区 / git bundle 无 mtime），只走哈希判断
+                if entry.on_disk()
+                    && old.level == "symbol"
+                    && old.size == file_size
+                    && old.mtime == file_mtime
+                {
                     skipped_counter.fetch_add(1, Ordering::Relaxed);
                     let _ = tx_chan.send(ParseResult {
+                        root: root.name.clone(),
                         file_path: path_str,
                         file_hash: old.hash.clone(),
                         file_size,
                         file_mtime,
                         language: "skip".into(),
                         index_level: old.level.clone(),
+                        extractor_version: String::new(),
+                        grammar: String::new(),
+                        error_nodes: 0,
+                        license: String::new(),
+                        copyright: String::new(),
+                        generated: String::new(),
                         line_count: 0,
+                        parse_us: 0,
                         symbols: vec![],
                         calls: vec![],
+                        routes: vec![],
+                        inherits: vec![],
+                        imports: vec![],
+                        decorators: vec![],
+                        attributes: vec![],
                     });
                     return;
                 }
@@ -650,24 +1879,38 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
                 if seen >= bootstrap_parse_budget {
                     meta_counter.fetch_add(1, Ordering::Relaxed);
                     let _ = tx_chan.send(ParseResult {
+                        root: root.name.clone(),
                         file_path: path_str,
                         file_hash: format!("meta:{}:{}", file_size, file_mtime),
                         file_size,
                         file_mtime,
                         language: "meta".into(),
                         index_level: "meta".into(),
+                        extractor_version: String::new(),
+                        grammar: String::new(),
+                        error_nodes: 0,
+                        license: String::new(),
+                        copyright: String::new(),
+                        generated: String::new(),
                         line_count: 0,
+                        parse_us: 0,
                         symbols: vec![],
                         calls: vec![],
+                        routes: vec![],
+                        inherits: vec![],
+                        imports: vec![],
+                        decorators: vec![],
+                        attributes: vec![],
                     });
                     return;
                 }
             }
 
             // Read & hash only when needed
-            let content = match fs::read_to_string(path) {
-                Ok(c) => c,
-                Err(_) => return,
+            let started = Instant::now();
+            let content = match entry.read_content() {
+                Some(c) => c,
+                None => return,
             };
 
             let mut hasher = Sha256::new();
@@ -676,217 +1919,162 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
             let new_hash = hex::encode(result);
 
             // Check Skip by hash (handles metadata-only changes)
-            if let Some(old) = db_files_arc.get(&path_str) {
+            if let Some(old) = reusable {
                 if old.hash == new_hash {
                     skipped_counter.fetch_add(1, Ordering::Relaxed);
+                    hash_skip_counter.fetch_add(1, Ordering::Relaxed);
                     let _ = tx_chan.send(ParseResult {
+                        root: root.name.clone(),
                         file_path: path_str,
                         file_hash: new_hash,
                         file_size,
                         file_mtime,
                         language: "skip".into(),
                         index_level: old.level.clone(),
+                        extractor_version: String::new(),
+                        grammar: String::new(),
+                        error_nodes: 0,
+                        license: String::new(),
+                        copyright: String::new(),
+                        generated: String::new(),
                         line_count: 0,
+                        parse_us: 0,
                         symbols: vec![],
                         calls: vec![],
+                        routes: vec![],
+                        inherits: vec![],
+                        imports: vec![],
+                        decorators: vec![],
+                        attributes: vec![],
                     });
                     return;
                 }
             }
 
-            let mut parser = TsParser::new();
-            parser.set_language(*lang).unwrap();
-
-            let tree = parser.parse(&content, None).unwrap(); // handle err?
-
-            let mut cursor = QueryCursor::new();
-            let matches = cursor.matches(query, tree.root_node(), content.as_bytes());
-
-            let mut symbols = vec![];
-            let mut calls = vec![];
-            let mut node_id_map: HashMap<usize, usize> = HashMap::new(); // tree_node_id -> temp_id
-            let mut temp_counter = 0;
-
-            for m in matches {
-                let mut node_name: Option<String> = None;
-                let mut node_type: Option<&str> = None;
-                let mut def_node: Option<tree_sitter::Node> = None;
-                let mut name_node: Option<tree_sitter::Node> = None;
-                let mut callee_node: Option<tree_sitter::Node> = None;
+            if old_meta.is_some_and(|m| m.hash == new_hash) {
+                unchanged_reparse_counter.fetch_add(1, Ordering::Relaxed);
+            }
 
-                for capture in m.captures {
-                    let capture_name = &query.capture_names()[capture.index as usize];
-                    match capture_name.as_str() {
-                        "name" => {
-                            name_node = Some(capture.node);
-                            node_name = Some(
-                                content[capture.node.start_byte()..capture.node.end_byte()]
-                                    .to_string(),
-                            );
-                        }
-                        "callee" => {
-                            callee_node = Some(capture.node);
-                        }
-                        "def.func" => {
-                            node_type = Some("function");
-                            def_node = Some(capture.node);
-                        }
-                        "def.class" => {
-                            node_type = Some("class");
-                            def_node = Some(capture.node);
-                        }
-                        "ref.call" => {
-                            // Already handled by callee?
-                        }
-                        _ => {}
-                    }
+            // 解析缓存：(语法版本, 文件哈希) 命中则跳过 tree-sitter
+            let cache_path = parse_cache_dir
+                .as_ref()
+                .map(|dir| parse_cache_path(dir, &ext, &file_version, &new_hash));
+            let parsed = match cache_path
+                .as_deref()
+                .filter(|_| !dirty)
+                .and_then(load_parse_cache)
+            {
+                Some(hit) => {
+                    cache_hit_counter.fetch_add(1, Ordering::Relaxed);
+                    hit
                 }
-
-                if let (Some(name), Some(kind), Some(full_node)) = (node_name, node_type, def_node)
-                {
-                    // Definition
-                    let start = full_node.start_position().row + 1;
-                    let end = full_node.end_position().row + 1;
-
-                    temp_counter += 1;
-                    let tid = temp_counter;
-                    node_id_map.insert(full_node.id(), tid);
-
-                    // Find parent temp_id
-                    let mut parent_temp_id = None;
-                    let mut p_cursor = full_node.parent();
-                    while let Some(p) = p_cursor {
-                        if let Some(pid) = node_id_map.get(&p.id()) {
-                            parent_temp_id = Some(*pid);
-                            break;
-                        }
-                        p_cursor = p.parent();
-                    }
-
-                    // 🆕 构建 scope_path：沿 parent() 回溯收集类/模块名
-                    let mut scope_parts: Vec<String> = Vec::new();
-                    let mut scope_cursor = full_node.parent();
-                    while let Some(p) = scope_cursor {
-                        // 检查父节点是否是 class 或 module（通过 child 名为 name 的捕获）
-                        let node_kind = p.kind();
-                        if node_kind == "class_definition"
-                            || node_kind == "class"
-                            || node_kind == "function_definition"
-                            || node_kind == "method_declaration"
-                            || node_kind == "class_declaration"
-                            || node_kind == "interface_declaration"
-                            || node_kind == "struct_item"
-                            || node_kind == "impl_item"
-                            || node_kind == "mod_item"
-                            || node_kind == "trait_item"
-                        {
-                            // 尝试从子节点中找 name
-                            for i in 0..p.child_count() {
-                                let child = p.child(i).unwrap();
-                                let child_kind = child.kind();
-                                if child_kind == "identifier"
-                                    || child_kind == "type_identifier"
-                                    || child_kind == "name"
-                                    || child_kind == "field_identifier"
-                                {
-                                    let parent_name =
-                                        &content[child.start_byte()..child.end_byte()];
-                                    if parent_name != &name {
-                                        scope_parts.push(parent_name.to_string());
-                                    }
-                                    break;
-                                }
+                None => {
+                    // 单个文件抽取 panic 不能带崩整个 worker：记为部分解析的空结果，且不写缓存
+                    let extracted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        extract_with_fallback(&parsers_arc, &ext, &content, features)
+                    }));
+                    match extracted {
+                        Ok(parsed) => {
+                            if let Some(p) = &cache_path {
+                                store_parse_cache(p, &parsed);
                             }
+                            parsed
                         }
-                        scope_cursor = p.parent();
-                    }
-                    scope_parts.reverse();
-                    let scope_path = if scope_parts.is_empty() {
-                        name.clone()
-                    } else {
-                        format!("{}::{}", scope_parts.join("::"), name)
-                    };
-
-                    symbols.push(PendingSymbol {
-                        temp_id: tid,
-                        parent_temp_id,
-                        name: name.clone(),
-                        qualified_name: scope_path.clone(),
-                        scope_path,
-                        symbol_type: kind.to_string(),
-                        line_start: start,
-                        line_end: end,
-                        text: name,
-                        signature: if kind == "function" {
-                            let sig_text = &content[full_node.start_byte()..full_node.end_byte()];
-                            sig_text.lines().next().map(|s| s.trim().to_string())
-                        } else {
-                            None
-                        },
-                    });
-                } else if let Some(c_node) = callee_node {
-                    // Call
-                    let callee_name = content[c_node.start_byte()..c_node.end_byte()].to_string();
-                    // Find caller
-                    let mut p_cursor = c_node.parent();
-                    let mut caller_tid = 0;
-                    let line = c_node.start_position().row + 1;
-
-                    while let Some(p) = p_cursor {
-                        if let Some(pid) = node_id_map.get(&p.id()) {
-                            caller_tid = *pid;
-                            break;
+                        Err(_) => {
+                            println!(
+                                "[Warn] Extraction failed for {}, indexed as partial",
+                                path_str
+                            );
+                            ParsedFile {
+                                symbols: vec![],
+                                calls: vec![],
+                                routes: vec![],
+                                inherits: vec![],
+                                imports: vec![],
+                                decorators: vec![],
+                                attributes: vec![],
+                                grammar: String::new(),
+                                error_nodes: 1,
+                            }
                         }
-                        p_cursor = p.parent();
-                    }
-
-                    if caller_tid > 0 {
-                        calls.push(PendingCall {
-                            caller_temp_id: caller_tid,
-                            callee_name,
-                            line,
-                        });
                     }
                 }
-            }
+            };
 
-            let line_count = content.lines().count();
+            let lines: Vec<&str> = content.lines().collect();
+            let line_count = lines.len();
+            let mut parsed = parsed;
+            for sym in parsed.symbols.iter_mut() {
+                sym.anchor = Some(edit_anchor(&lines, sym.line_start, sym.line_end));
+            }
+            let (license, copyright) =
+                detect_license(&lines[..line_count.min(LICENSE_HEADER_LINES)]);
+            let generated =
+                detect_generated(&path_str, &lines[..line_count.min(LICENSE_HEADER_LINES)]);
             parsed_counter.fetch_add(1, Ordering::Relaxed);
 
             let _ = tx_chan.send(ParseResult {
+                root: root.name.clone(),
                 file_path: path_str,
                 file_hash: new_hash,
                 file_size,
                 file_mtime,
+                grammar: if parsed.grammar.is_empty() {
+                    ext.clone()
+                } else {
+                    parsed.grammar
+                },
+                error_nodes: parsed.error_nodes,
+                license,
+                copyright,
+                generated,
                 language: ext,
                 index_level: "symbol".into(),
+                extractor_version: file_version,
                 line_count,
-                symbols,
-                calls,
+                parse_us: started.elapsed().as_micros() as u64,
+                symbols: parsed.symbols,
+                calls: parsed.calls,
+                routes: parsed.routes,
+                inherits: parsed.inherits,
+                imports: parsed.imports,
+                decorators: parsed.decorators,
+                attributes: parsed.attributes,
             });
         });
     });
 
+    lap = profile.lap(&["main", "prepare"], lap);
+
     // 6. Consumer (Main Thread)
-    let batch_size: usize = 300;
     let mut tx = conn.transaction()?;
+    let mut checkpoint = Checkpoint::default();
 
     let upsert_file_sql =
-        "INSERT INTO files (file_path, file_hash, file_size, file_mtime, language, line_count, index_level, indexed_at, updated_at) 
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
-         ON CONFLICT(file_path) DO UPDATE SET file_hash=?2, file_size=?3, file_mtime=?4, language=?5, line_count=?6, index_level=?7, indexed_at=?8, updated_at=?9";
+        "INSERT INTO files (file_path, file_hash, file_size, file_mtime, language, line_count, index_level, indexed_at, updated_at, root, extractor_version, grammar, partial, license, copyright, generated) 
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+         ON CONFLICT(file_path) DO UPDATE SET file_hash=?2, file_size=?3, file_mtime=?4, language=?5, line_count=?6, index_level=?7, indexed_at=?8, updated_at=?9, root=?10, dirty=0, extractor_version=?11, grammar=?12, partial=?13, license=?14, copyright=?15, generated=?16";
     let ins_symbol_sql =
-        "INSERT INTO symbols (file_id, name, qualified_name, canonical_id, scope_path, symbol_type, line_start, line_end, signature)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+        "INSERT INTO symbols (file_id, name, qualified_name, canonical_id, scope_path, symbol_type, line_start, line_end, signature, parent_id, summary, stable_id, doc, anchor, signature_shape, visibility)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)";
+    let ins_call_sql = "INSERT INTO calls (caller_id, callee_name, call_line, kind, receiver_type, arg_count, arg_keywords) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+    let ins_route_sql =
+        "INSERT INTO routes (file_id, http_method, path, handler_name, line) VALUES (?1, ?2, ?3, ?4, ?5)";
 
     let mut stmt_upsert_file = tx.prepare(upsert_file_sql)?;
     let mut stmt_del_symbols = tx.prepare("DELETE FROM symbols WHERE file_id = ?1")?;
     let mut stmt_ins_symbol = tx.prepare(ins_symbol_sql)?;
-    let mut stmt_ins_call =
-        tx.prepare("INSERT INTO calls (caller_id, callee_name, call_line) VALUES (?1, ?2, ?3)")?;
+    let mut stmt_ins_call = tx.prepare(ins_call_sql)?;
+    let mut stmt_del_routes = tx.prepare("DELETE FROM routes WHERE file_id = ?1")?;
+    let mut stmt_ins_route = tx.prepare(ins_route_sql)?;
 
     let mut processed_count = 0;
+    let mut written_files = 0;
+    let mut error_nodes = 0;
     let mut changed_in_batch = 0;
+    let mut slowest: Vec<SlowFile> = vec![];
+    // 写库循环里除去写入和提交的时间，都是在等解析线程
+    let mut busy_us = 0u64;
 
     // Process results
     for res in rx_chan {
@@ -894,22 +2082,16 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
 
         // Heartbeat
         if processed_count % 10 == 0 {
-            let json = format!(
-                r#"{{"timestamp": {}, "processed": {}, "total": {}}}"#,
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs(),
-                processed_count,
-                total
-            );
-            let _ = fs::write(heartbeat_path, json);
+            checkpoint.write_heartbeat(heartbeat_path, processed_count, total);
         }
 
         // Handle Skip
         if res.language == "skip" {
             continue;
         }
+        written_files += 1;
+        error_nodes += res.error_nodes;
+        let write_started = Instant::now();
 
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -926,7 +2108,14 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
             res.line_count,
             &res.index_level,
             if res.index_level == "symbol" { now } else { 0 },
-            now
+            now,
+            &res.root,
+            &res.extractor_version,
+            &res.grammar,
+            res.error_nodes > 0,
+            &res.license,
+            &res.copyright,
+            &res.generated
         ])?;
 
         // 2. Lookup file id
@@ -936,17 +2125,19 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
             |r| r.get(0),
         )?;
 
-        // 3. Replace symbols/calls for this file
-        // meta level means metadata-only bootstrap: remove stale symbols and continue.
-        stmt_del_symbols.execute(params![file_id])?;
+        // meta level means metadata-only bootstrap: 旧符号（连同 Agent 写的 summary）原样保留，
+        // 下次按 symbol 级重解析时再整体替换
         if res.index_level == "meta" {
             changed_in_batch += 1;
-            if changed_in_batch >= batch_size {
+            if checkpoint.due(changed_in_batch, args) {
                 drop(stmt_upsert_file);
                 drop(stmt_del_symbols);
                 drop(stmt_ins_symbol);
                 drop(stmt_ins_call);
+                drop(stmt_del_routes);
+                drop(stmt_ins_route);
                 tx.commit()?;
+                checkpoint.committed(changed_in_batch, heartbeat_path, processed_count, total);
 
                 let _ = conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |r| {
                     Ok((
@@ -960,53 +2151,240 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
                 stmt_upsert_file = tx.prepare(upsert_file_sql)?;
                 stmt_del_symbols = tx.prepare("DELETE FROM symbols WHERE file_id = ?1")?;
                 stmt_ins_symbol = tx.prepare(ins_symbol_sql)?;
-                stmt_ins_call = tx.prepare(
-                    "INSERT INTO calls (caller_id, callee_name, call_line) VALUES (?1, ?2, ?3)",
-                )?;
+                stmt_ins_call = tx.prepare(ins_call_sql)?;
+                stmt_del_routes = tx.prepare("DELETE FROM routes WHERE file_id = ?1")?;
+                stmt_ins_route = tx.prepare(ins_route_sql)?;
                 changed_in_batch = 0;
             }
             continue;
         }
 
+        // 3. Replace symbols/calls for this file
+        // Agent 写入的 summary 按 canonical_id 带到新行上，避免重建索引时丢失
+        let kept_summaries: HashMap<String, String> = tx
+            .prepare_cached(
+                "SELECT canonical_id, summary FROM symbols WHERE file_id = ?1 AND summary IS NOT NULL",
+            )?
+            .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .flatten()
+            .collect();
+
+        tx.prepare_cached(
+            "DELETE FROM decorators WHERE symbol_id IN (SELECT symbol_id FROM symbols WHERE file_id = ?1)",
+        )?
+        .execute(params![file_id])?;
+        tx.prepare_cached(
+            "DELETE FROM attributes WHERE symbol_id IN (SELECT symbol_id FROM symbols WHERE file_id = ?1)",
+        )?
+        .execute(params![file_id])?;
+        stmt_del_symbols.execute(params![file_id])?;
+        stmt_del_routes.execute(params![file_id])?;
+        tx.prepare_cached("DELETE FROM imports WHERE file_id = ?1")?
+            .execute(params![file_id])?;
+        // 缓冲区已保存：落库内容与 overlay 一致时 overlay 不再需要
+        clear_overlay(&tx, file_id, None, Some(&res.file_hash))?;
+
         let mut temp_to_db_id: HashMap<usize, i64> = HashMap::new();
 
         for sym in &res.symbols {
-            let prefix = if sym.symbol_type == "class" {
-                "class"
-            } else {
-                "func"
-            };
-            let canonical_id = format!("{}:{}::{}", prefix, res.file_path, sym.name);
+            let name = anon_in_file(&sym.name, &res.file_path);
+            let scope_path = anon_in_file(&sym.scope_path, &res.file_path);
+            let prefix = canonical_prefix(&sym.symbol_type);
+            let canonical_id = format!("{}:{}::{}", prefix, res.file_path, name);
+            let stable_id =
+                stable_symbol_id(&sym.symbol_type, &scope_path, sym.signature.as_deref());
 
             stmt_ins_symbol.execute(params![
                 file_id,
-                sym.name,
-                sym.qualified_name,
+                name,
+                anon_in_file(&sym.qualified_name, &res.file_path),
                 canonical_id,
-                sym.scope_path,
-                sym.symbol_type,
+                scope_path,
+                type_map.resolve(&res.language, &sym.symbol_type),
                 sym.line_start,
                 sym.line_end,
-                sym.signature
+                sym.signature,
+                sym.parent_temp_id
+                    .and_then(|pid| temp_to_db_id.get(&pid).copied()),
+                kept_summaries.get(&canonical_id),
+                stable_id,
+                sym.doc,
+                sym.anchor,
+                sym.signature_shape
+                    .as_ref()
+                    .and_then(|shape| serde_json::to_string(shape).ok()),
+                sym.visibility
             ])?;
 
             let db_id = tx.last_insert_rowid();
             temp_to_db_id.insert(sym.temp_id, db_id);
+
+            tx.prepare_cached(
+                "INSERT INTO symbol_aliases (canonical_id, stable_id, last_seen) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(canonical_id) DO UPDATE SET stable_id = ?2, last_seen = ?3",
+            )?
+            .execute(params![canonical_id, stable_id, now])?;
         }
 
         for call in &res.calls {
             if let Some(caller_db_id) = temp_to_db_id.get(&call.caller_temp_id) {
-                stmt_ins_call.execute(params![*caller_db_id, call.callee_name, call.line])?;
+                stmt_ins_call.execute(params![
+                    *caller_db_id,
+                    anon_in_file(&call.callee_name, &res.file_path),
+                    call.line,
+                    call.kind,
+                    call.receiver_type,
+                    call.args.as_ref().map(|a| a.positional),
+                    call.args
+                        .as_ref()
+                        .filter(|a| !a.keywords.is_empty())
+                        .map(|a| a.keywords.join(","))
+                ])?;
+            }
+        }
+
+        for inherit in &res.inherits {
+            if let Some(child_db_id) = temp_to_db_id.get(&inherit.child_temp_id) {
+                tx.prepare_cached(
+                    "INSERT INTO inherits (child_id, base_name, kind) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params![
+                    *child_db_id,
+                    inherit.base_name,
+                    inherit.kind
+                ])?;
+            }
+        }
+
+        for decorator in &res.decorators {
+            if let Some(symbol_db_id) = temp_to_db_id.get(&decorator.symbol_temp_id) {
+                tx.prepare_cached(
+                    "INSERT INTO decorators (symbol_id, name, line) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params![
+                    *symbol_db_id,
+                    decorator.name,
+                    decorator.line
+                ])?;
+            }
+        }
+
+        // 属性：可见性与装饰器 / 注解对所有抽取器统一派生，其余来自各语言的抽取
+        let decorator_key = if res.language == "java" {
+            "annotation"
+        } else {
+            "decorator"
+        };
+        // 弃用标记：Rust #[deprecated] 来自抽取，文档注释与装饰器 / 注解在这里派生；
+        // 每个符号只记一条，优先带说明文字的
+        let mut deprecated: BTreeMap<usize, String> = BTreeMap::new();
+        let markers = res
+            .attributes
+            .iter()
+            .filter(|a| a.key == "deprecated")
+            .map(|a| (a.symbol_temp_id, a.value.clone()))
+            .chain(
+                res.symbols
+                    .iter()
+                    .filter_map(|s| Some((s.temp_id, doc_deprecation(s.doc.as_deref()?)?))),
+            )
+            .chain(
+                res.decorators
+                    .iter()
+                    .filter(|d| is_deprecation_decorator(&d.name))
+                    .map(|d| (d.symbol_temp_id, "true".to_string())),
+            );
+        for (temp_id, note) in markers {
+            let entry = deprecated.entry(temp_id).or_insert_with(|| note.clone());
+            if entry == "true" {
+                *entry = note;
+            }
+        }
+        let attributes = res
+            .symbols
+            .iter()
+            .filter_map(|s| Some((s.temp_id, "visibility", s.visibility.as_deref()?)))
+            .chain(
+                res.decorators
+                    .iter()
+                    .map(|d| (d.symbol_temp_id, decorator_key, d.name.as_str())),
+            )
+            .chain(
+                res.attributes
+                    .iter()
+                    .filter(|a| a.key != "deprecated")
+                    .map(|a| (a.symbol_temp_id, a.key.as_str(), a.value.as_str())),
+            )
+            .chain(
+                deprecated
+                    .iter()
+                    .map(|(temp_id, note)| (*temp_id, "deprecated", note.as_str())),
+            );
+        for (temp_id, key, value) in attributes {
+            if let Some(symbol_db_id) = temp_to_db_id.get(&temp_id) {
+                tx.prepare_cached(
+                    "INSERT INTO attributes (symbol_id, key, value) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params![*symbol_db_id, key, value])?;
+            }
+        }
+
+        for route in &res.routes {
+            stmt_ins_route.execute(params![
+                file_id,
+                route.http_method,
+                route.path,
+                route.handler_name,
+                route.line
+            ])?;
+        }
+
+        for import in &res.imports {
+            tx.prepare_cached(
+                "INSERT INTO imports (file_id, imported_path, imported_symbol, alias, kind, line)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                file_id,
+                import.imported_path,
+                import.imported_symbol,
+                import.alias,
+                import.kind,
+                import.line
+            ])?;
+        }
+
+        let write_us = write_started.elapsed().as_micros() as u64;
+        busy_us += write_us;
+        profile.add(&["workers", "parse", &res.language], res.parse_us);
+        profile.add(&["main", "write", &res.language], write_us);
+
+        if args.slow_files > 0 {
+            slowest.push(SlowFile {
+                file_path: res.file_path.clone(),
+                language: res.language.clone(),
+                size_bytes: res.file_size,
+                parse_ms: res.parse_us as f64 / 1000.0,
+                write_ms: write_us as f64 / 1000.0,
+            });
+            // 只保留候选前 N：攒到 2N 再排序截断，避免整仓文件都留在内存里
+            if slowest.len() >= args.slow_files * 2 {
+                slowest.sort_by(|a, b| b.total_ms().total_cmp(&a.total_ms()));
+                slowest.truncate(args.slow_files);
             }
         }
 
         changed_in_batch += 1;
-        if changed_in_batch >= batch_size {
+        if checkpoint.due(changed_in_batch, args) {
+            let commit_started = Instant::now();
             drop(stmt_upsert_file);
             drop(stmt_del_symbols);
             drop(stmt_ins_symbol);
             drop(stmt_ins_call);
+            drop(stmt_del_routes);
+            drop(stmt_ins_route);
             tx.commit()?;
+            checkpoint.committed(changed_in_batch, heartbeat_path, processed_count, total);
 
             let _ = conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |r| {
                 Ok((
@@ -1020,63 +2398,116 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
             stmt_upsert_file = tx.prepare(upsert_file_sql)?;
             stmt_del_symbols = tx.prepare("DELETE FROM symbols WHERE file_id = ?1")?;
             stmt_ins_symbol = tx.prepare(ins_symbol_sql)?;
-            stmt_ins_call = tx.prepare(
-                "INSERT INTO calls (caller_id, callee_name, call_line) VALUES (?1, ?2, ?3)",
-            )?;
+            stmt_ins_call = tx.prepare(ins_call_sql)?;
+            stmt_del_routes = tx.prepare("DELETE FROM routes WHERE file_id = ?1")?;
+            stmt_ins_route = tx.prepare(ins_route_sql)?;
             changed_in_batch = 0;
+            let commit_us = commit_started.elapsed().as_micros() as u64;
+            busy_us += commit_us;
+            profile.add(&["main", "commit"], commit_us);
         }
     }
 
     producer_handle.join().unwrap(); // Wait for producer to finish (should be done if channel closed)
+    profile.add(
+        &["main", "wait_for_parsers"],
+        (lap.elapsed().as_micros() as u64).saturating_sub(busy_us),
+    );
+    lap = Instant::now();
 
     drop(stmt_upsert_file);
     drop(stmt_del_symbols);
     drop(stmt_ins_symbol);
     drop(stmt_ins_call);
+    drop(stmt_del_routes);
+    drop(stmt_ins_route);
     tx.commit()?;
+    if changed_in_batch > 0 {
+        checkpoint.committed(changed_in_batch, heartbeat_path, processed_count, total);
+    }
+    lap = profile.lap(&["main", "commit"], lap);
+
+    let final_tx = conn.transaction()?;
+    let mut deleted_count = 0;
 
     // ========================================================================
-    // 🆕 Phase: Linking calls.callee_id（阶段 B）
-    // 规则：同文件优先；无匹配时保持 NULL
+    // 🆕 Phase: Clean up deleted files (增量清理阶段)
+    // 删除数据库中存在但文件系统中已不存在的文件记录
     // ========================================================================
-    let mut final_tx = conn.transaction()?;
-    {
-        let linked = final_tx.execute(
-            "UPDATE calls
-             SET callee_id = (
-                 SELECT s2.canonical_id
-                 FROM symbols sc
-                 JOIN symbols s2 ON s2.name = calls.callee_name
-                 WHERE sc.symbol_id = calls.caller_id
-                 ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
-                 LIMIT 1
-             )
-             WHERE callee_id IS NULL",
-            [],
-        )?;
-        println!("[Linking] Updated {} call edges with callee_id", linked);
+    // 本次的 root 集合与上次索引不同（单根改多根、增删 root）时，不在集合里的 root 的旧行一并清掉，
+    // 否则单根遗留的 root='' 行会与新的 "<root>/" 前缀行重复出现
+    let mut root_names: Vec<&str> = roots.iter().map(|r| r.name.as_str()).collect();
+    root_names.sort();
+    let root_set = root_names.join("\n");
+    let last_root_set: Option<String> = final_tx
+        .query_row("SELECT value FROM meta WHERE key = 'root_set'", [], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    let root_set_changed = last_root_set.as_deref() != Some(root_set.as_str());
+    if root_set_changed {
+        let old_roots: Vec<String> = final_tx
+            .prepare("SELECT name FROM roots")?
+            .query_map([], |r| r.get(0))?
+            .flatten()
+            .filter(|name: &String| !root_names.contains(&name.as_str()))
+            .collect();
+        for name in &old_roots {
+            final_tx.execute("DELETE FROM roots WHERE name = ?1", [name])?;
+        }
+        let removed = old_roots.len();
+        if removed > 0 {
+            println!(
+                "[Cleanup] Root set changed, dropping {} old root(s)",
+                removed
+            );
+        }
     }
+    final_tx.execute(
+        "INSERT INTO meta (key, value) VALUES ('root_set', ?1)
+         ON CONFLICT(key) DO UPDATE SET value = ?1",
+        params![root_set],
+    )?;
 
-    // ========================================================================
-    // 🆕 Phase: Clean up deleted files (增量清理阶段)
-    // 删除数据库中存在但文件系统中已不存在的文件记录
-    // ========================================================================
     {
-        let project_path = Path::new(&args.project);
-        let mut stmt = final_tx.prepare("SELECT file_id, file_path FROM files")?;
-        let rows: Vec<(i64, String)> = stmt
+        let mut stmt = final_tx.prepare("SELECT file_id, file_path, root FROM files")?;
+        let rows: Vec<(i64, String, String)> = stmt
             .query_map([], |row| {
-                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                ))
             })?
             .filter_map(|r| r.ok())
             .collect();
 
-        let mut deleted_count = 0;
-        for (file_id, rel_path) in rows {
-            let full_path = project_path.join(&rel_path);
-            if !full_path.exists() {
+        for (file_id, rel_path, root_name) in rows {
+            // 只清理本次参与索引的 root；root 集合没变时其他 root 的文件保持原样
+            let still_exists = match (roots.iter().find(|r| r.name == root_name), &archive_paths) {
+                (None, _) => !root_set_changed,
+                (Some(_), Some(paths)) => paths.contains(&rel_path),
+                (Some(root), None) => root
+                    .path
+                    .join(rel_path.strip_prefix(&root.prefix()).unwrap_or(&rel_path))
+                    .exists(),
+            };
+            if !still_exists {
                 // File was deleted from filesystem, remove from index
+                // 删除前先把 summary 迁移到 stable_id 相同的新位置（文件移动/重命名）
+                carry_over_moved_symbols(&final_tx, file_id)?;
+                final_tx.execute(
+                    "DELETE FROM decorators WHERE symbol_id IN (SELECT symbol_id FROM symbols WHERE file_id = ?1)",
+                    params![file_id],
+                )?;
+                final_tx.execute(
+                    "DELETE FROM attributes WHERE symbol_id IN (SELECT symbol_id FROM symbols WHERE file_id = ?1)",
+                    params![file_id],
+                )?;
                 final_tx.execute("DELETE FROM symbols WHERE file_id = ?1", params![file_id])?;
+                final_tx.execute("DELETE FROM routes WHERE file_id = ?1", params![file_id])?;
+                final_tx.execute("DELETE FROM imports WHERE file_id = ?1", params![file_id])?;
+                clear_overlay(&final_tx, file_id, None, None)?;
                 final_tx.execute("DELETE FROM files WHERE file_id = ?1", params![file_id])?;
                 deleted_count += 1;
             }
@@ -1088,9 +2519,223 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
                 deleted_count
             );
         }
+
+        // calls.callee_id / routes.handler_id 中失效的旧 canonical_id 经 stable_id 重定向
+        let remapped = remap_stale_references(&final_tx)?;
+        if remapped > 0 {
+            println!("[StableId] Remapped {} stale symbol references", remapped);
+        }
+    }
+    lap = profile.lap(&["main", "cleanup"], lap);
+
+    // ========================================================================
+    // 🆕 Phase: Linking calls.callee_id（阶段 B）
+    // 规则：接收者类型下的同名方法优先（同文件、同语言的类型优先），其次同文件；无匹配时保持 NULL。
+    // 接收者类型不在索引里（外部库类型）的调用不按名硬连
+    // ========================================================================
+    {
+        let by_receiver = final_tx.execute(
+            "UPDATE calls
+             SET callee_id = (
+                 SELECT s2.canonical_id
+                 FROM symbols sc
+                 JOIN symbols s2 ON s2.name = calls.callee_name
+                 JOIN files fc ON fc.file_id = sc.file_id
+                 JOIN files f2 ON f2.file_id = s2.file_id
+                 WHERE sc.symbol_id = calls.caller_id
+                   AND (s2.scope_path = calls.receiver_type || '::' || s2.name
+                        OR s2.scope_path GLOB '*[:.]' || calls.receiver_type || '::' || s2.name)
+                 ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0
+                               WHEN f2.language = fc.language THEN 1
+                               ELSE 2 END,
+                          s2.symbol_id ASC
+                 LIMIT 1
+             )
+             WHERE callee_id IS NULL AND kind = 'call' AND receiver_type IS NOT NULL",
+            [],
+        )?;
+        let linked = by_receiver
+            + final_tx.execute(
+                "UPDATE calls
+                 SET callee_id = (
+                     SELECT s2.canonical_id
+                     FROM symbols sc
+                     JOIN symbols s2 ON s2.name = calls.callee_name
+                     WHERE sc.symbol_id = calls.caller_id
+                     ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
+                     LIMIT 1
+                 )
+                 WHERE callee_id IS NULL AND kind = 'call'
+                   AND (receiver_type IS NULL
+                        OR EXISTS (SELECT 1 FROM symbols t WHERE t.name = calls.receiver_type))",
+                [],
+            )?;
+        println!("[Linking] Updated {} call edges with callee_id", linked);
+        lap = profile.lap(&["main", "link", "calls"], lap);
+
+        // 回调实参与 JSX 渲染：只认函数 / 方法 / 组件符号（同名变量、常量不算）；未命中的保持 NULL，读取时忽略
+        // 装饰器另外认类（装饰器类）
+        let references = final_tx.execute(
+            "UPDATE calls
+             SET callee_id = (
+                 SELECT s2.canonical_id
+                 FROM symbols sc
+                 JOIN symbols s2 ON s2.name = calls.callee_name
+                 WHERE sc.symbol_id = calls.caller_id
+                   AND (s2.canonical_id LIKE 'func:%'
+                        OR (calls.kind = 'decorator' AND s2.canonical_id LIKE 'class:%'))
+                 ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
+                 LIMIT 1
+             )
+             WHERE callee_id IS NULL AND kind IN ('reference', 'render', 'decorator')",
+            [],
+        )?;
+        println!(
+            "[Linking] Updated {} reference edges with callee_id",
+            references
+        );
+        // C/C++ 全局变量：函数体里的自由标识符只在 C 家族文件之间连到文件作用域变量 / 常量；
+        // static 全局只对本文件可见，同文件优先
+        let globals = final_tx.execute(
+            "UPDATE calls
+             SET callee_id = (
+                 SELECT s2.canonical_id
+                 FROM symbols sc
+                 JOIN symbols s2 ON s2.name = calls.callee_name
+                 JOIN files f2 ON f2.file_id = s2.file_id
+                 WHERE sc.symbol_id = calls.caller_id
+                   AND s2.symbol_type IN ('variable', 'constant')
+                   AND f2.language IN ('c', 'h', 'cpp', 'cc', 'hpp')
+                   AND (s2.file_id = sc.file_id OR s2.visibility IS NOT 'private')
+                 ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
+                 LIMIT 1
+             )
+             WHERE callee_id IS NULL AND kind = 'reference'
+               AND caller_id IN (
+                   SELECT s.symbol_id FROM symbols s JOIN files f ON f.file_id = s.file_id
+                   WHERE f.language IN ('c', 'h', 'cpp', 'cc', 'hpp')
+               )",
+            [],
+        )?;
+        if globals > 0 {
+            println!(
+                "[Linking] Linked {} C/C++ global variable references",
+                globals
+            );
+        }
+        // 引用边只保留连上符号的：helper(x) 里的参数 x 不是函数引用，不落库；
+        // 装饰器同理，@property / @app.route 这类项目外的装饰器不留边
+        let dropped = final_tx.execute(
+            "DELETE FROM calls WHERE callee_id IS NULL AND kind IN ('reference', 'decorator')",
+            [],
+        )?;
+        if dropped > 0 {
+            println!("[Linking] Dropped {} unresolved reference edges", dropped);
+        }
+        lap = profile.lap(&["main", "link", "references"], lap);
+
+        // 继承：父类型只认类级符号，同文件优先
+        final_tx.execute(
+            "UPDATE inherits
+             SET base_id = (
+                 SELECT s2.canonical_id
+                 FROM symbols sc
+                 JOIN symbols s2 ON s2.name = inherits.base_name
+                 WHERE sc.symbol_id = inherits.child_id AND s2.canonical_id LIKE 'class:%'
+                 ORDER BY CASE WHEN s2.file_id = sc.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
+                 LIMIT 1
+             )
+             WHERE base_id IS NULL",
+            [],
+        )?;
+        lap = profile.lap(&["main", "link", "inherits"], lap);
+
+        // Go 隐式实现：方法集覆盖接口的具体类型记一条 implements
+        let satisfied = link_go_interfaces(&final_tx)?;
+        if satisfied > 0 {
+            println!(
+                "[Linking] Linked {} Go types to the interfaces they satisfy",
+                satisfied
+            );
+        }
+        lap = profile.lap(&["main", "link", "go_interfaces"], lap);
+
+        // 重写：依赖上面的继承与 Go 隐式实现
+        let overrides = link_overrides(&final_tx)?;
+        if overrides > 0 {
+            println!("[Linking] Linked {} overriding methods", overrides);
+        }
+        lap = profile.lap(&["main", "link", "overrides"], lap);
+
+        // 路由 handler：同文件优先
+        final_tx.execute(
+            "UPDATE routes
+             SET handler_id = (
+                 SELECT s2.canonical_id
+                 FROM routes r
+                 JOIN symbols s2 ON s2.name = r.handler_name
+                 WHERE r.route_id = routes.route_id
+                 ORDER BY CASE WHEN s2.file_id = r.file_id THEN 0 ELSE 1 END, s2.symbol_id ASC
+                 LIMIT 1
+             )
+             WHERE handler_id IS NULL AND handler_name IS NOT NULL",
+            [],
+        )?;
+        lap = profile.lap(&["main", "link", "routes"], lap);
+
+        // HTTP 边：客户端 URL 字面量 -> 路由 handler（跨服务调用链）
+        let http_linked = link_http_calls(&final_tx)?;
+        println!(
+            "[Linking] Matched {} http call edges against routes",
+            http_linked
+        );
+        lap = profile.lap(&["main", "link", "http"], lap);
+
+        // 生成桩代码 -> .proto 定义（analyze 据此把影响归到 proto 上）
+        let stubs_linked = link_generated_stubs(&final_tx)?;
+        if stubs_linked > 0 {
+            println!(
+                "[Linking] Mapped {} generated stub symbols to .proto definitions",
+                stubs_linked
+            );
+        }
+        lap = profile.lap(&["main", "link", "stubs"], lap);
+    }
+
+    final_tx.execute(
+        "INSERT INTO meta (key, value) VALUES ('type_map', ?1)
+         ON CONFLICT(key) DO UPDATE SET value = ?1",
+        params![type_map_tag],
+    )?;
+
+    // 历史 ID 只保留 ALIAS_RETENTION_SECS：现存符号的别名不删（文件没改动时 last_seen 不会刷新）
+    let pruned_aliases = final_tx.execute(
+        "DELETE FROM symbol_aliases WHERE last_seen < ?1
+         AND canonical_id NOT IN (SELECT canonical_id FROM symbols)",
+        params![unix_now().saturating_sub(ALIAS_RETENTION_SECS)],
+    )?;
+    if pruned_aliases > 0 {
+        println!(
+            "[Cleanup] Pruned {} symbol aliases unseen for {} days",
+            pruned_aliases,
+            ALIAS_RETENTION_SECS / 86400
+        );
     }
 
+    // 有实际变更的索引提交才推进 generation，读模式的结果缓存据此失效
+    let db_changed = written_files > 0 || deleted_count > 0;
+    let generation = if db_changed {
+        let g = bump_generation(&final_tx)?;
+        println!("[Generation] DB generation is now {}", g);
+        g
+    } else {
+        read_generation(&final_tx)?
+    };
+
     final_tx.commit()?;
+    if db_changed {
+        notify_generation(args, generation);
+    }
 
     // Final checkpoint after full pass.
     let _ = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
@@ -1100,25 +2745,90 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
             r.get::<_, i64>(2)?,
         ))
     });
+    profile.lap(&["main", "finalize"], lap);
+    if let Some(path) = &args.profile {
+        profile.write(path)?;
+        println!("[Profile] Wrote folded stacks to {}", path);
+    }
 
     let parsed_files = parsed_counter.load(Ordering::Relaxed);
     let meta_files = meta_counter.load(Ordering::Relaxed);
     let skipped_files = skipped_counter.load(Ordering::Relaxed);
+    let cached_files = cache_hit_counter.load(Ordering::Relaxed);
+    let hash_skips = hash_skip_counter.load(Ordering::Relaxed);
+    let skip_efficiency = SkipEfficiency {
+        metadata_skips: skipped_files - hash_skips,
+        hash_skips,
+        unchanged_reparses: unchanged_reparse_counter.load(Ordering::Relaxed),
+        mtime_churn: if skipped_files > 0 {
+            hash_skips as f64 / skipped_files as f64
+        } else {
+            0.0
+        },
+    };
 
     println!(
-        "Indexing completed. Processed {} files. parsed={}, meta={}, skipped={}, strategy={}",
-        processed_count, parsed_files, meta_files, skipped_files, strategy
+        "Indexing completed. Processed {} files. parsed={} (cached={}), meta={}, skipped={}, strategy={}, peak_rss={}MB",
+        processed_count,
+        parsed_files,
+        cached_files,
+        meta_files,
+        skipped_files,
+        strategy,
+        peak_rss_bytes() / (1024 * 1024)
+    );
+    println!(
+        "[Efficiency] metadata_skips={}, hash_skips={}, unchanged_reparses={}",
+        skip_efficiency.metadata_skips,
+        skip_efficiency.hash_skips,
+        skip_efficiency.unchanged_reparses
+    );
+    // 少量 hash_skips 是正常的（touch / 切分支）；过半说明 mtime 基本不可信
+    if skip_efficiency.hash_skips >= 20 && skip_efficiency.mtime_churn > 0.5 {
+        println!(
+            "[Hint] {:.0}% of unchanged files had new size/mtime and were re-read; the filesystem or checkout tool may not preserve mtimes",
+            skip_efficiency.mtime_churn * 100.0
+        );
+    }
+    // 终态心跳：map --allow-partial 据 done 判断索引已结束
+    let _ = fs::write(
+        heartbeat_path,
+        format!(
+            r#"{{"timestamp": {}, "pid": {}, "processed": {}, "total": {}, "committed": {}, "checkpoint": {}, "done": true}}"#,
+            unix_now(),
+            std::process::id(),
+            processed_count,
+            total,
+            checkpoint.committed_files,
+            checkpoint.seq
+        ),
     );
     // Write Output
     if let Some(out_path) = &args.output {
+        let partial_files: usize =
+            conn.query_row("SELECT COUNT(*) FROM files WHERE partial = 1", [], |r| {
+                r.get(0)
+            })?;
         let result = IndexResult {
             status: "success".into(),
+            generation,
             total_files: total,
             parsed_files,
             meta_files,
             skipped_files,
+            cached_files,
+            partial_files,
+            error_nodes,
             strategy: strategy.to_string(),
             elapsed_ms: 0,
+            peak_rss_bytes: peak_rss_bytes(),
+            slowest_files: {
+                slowest.sort_by(|a, b| b.total_ms().total_cmp(&a.total_ms()));
+                slowest.truncate(args.slow_files);
+                slowest
+            },
+            checkpoints: checkpoint.seq,
+            skip_efficiency,
         };
         let f = fs::File::create(out_path)?;
         serde_json::to_writer(f, &result)?;
@@ -1127,507 +2837,13581 @@ fn run_indexer(args: &Args, heartbeat_path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Serialize)]
-struct QueryResult {
-    status: String,
-    query: String,
-    found_symbol: Option<Node>,
-    match_type: Option<String>, // 🆕 匹配类型：exact/prefix_suffix/substring/levenshtein/stem
-    candidates: Vec<CandidateMatch>, // 🆕 多候选列表
-    related_nodes: Vec<CallerInfo>,
+// 提交节奏与检查点：攒满 --commit-batch 个文件，或距上次提交超过 --commit-interval-ms 即提交；
+// 每次提交后 stdout 打一行 [Checkpoint] JSON，心跳里同步 committed / checkpoint，读端据此知道新数据已可见
+struct Checkpoint {
+    seq: usize,
+    committed_files: usize,
+    last_commit: Instant,
 }
 
-#[derive(Serialize)]
-struct CandidateMatch {
-    node: Node,
-    match_type: String,
-    score: f32, // 相似度分数 (0-1)
+impl Default for Checkpoint {
+    fn default() -> Self {
+        Checkpoint {
+            seq: 0,
+            committed_files: 0,
+            last_commit: Instant::now(),
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct CallerInfo {
-    node: Node,
-    call_type: String,
-}
+impl Checkpoint {
+    fn due(&self, pending: usize, args: &Args) -> bool {
+        pending >= args.commit_batch.max(1)
+            || (args.commit_interval_ms > 0
+                && pending > 0
+                && self.last_commit.elapsed().as_millis() >= args.commit_interval_ms as u128)
+    }
 
-// ============================================================================
-// Progressive Fallback Search (渐进式容错查询)
-// ============================================================================
-use strsim::levenshtein;
+    fn committed(&mut self, files: usize, heartbeat_path: &Path, processed: usize, total: usize) {
+        self.seq += 1;
+        self.committed_files += files;
+        self.last_commit = Instant::now();
+        println!(
+            r#"[Checkpoint] {{"seq": {}, "committed": {}, "processed": {}, "total": {}}}"#,
+            self.seq, self.committed_files, processed, total
+        );
+        self.write_heartbeat(heartbeat_path, processed, total);
+    }
 
-fn progressive_search(conn: &Connection, query_str: &str) -> Option<(Node, String)> {
-    let (best, _, _) = progressive_search_multi(conn, query_str);
-    best.map(|n| (n.0, n.1))
+    fn write_heartbeat(&self, heartbeat_path: &Path, processed: usize, total: usize) {
+        let json = format!(
+            r#"{{"timestamp": {}, "pid": {}, "processed": {}, "total": {}, "committed": {}, "checkpoint": {}}}"#,
+            unix_now(),
+            std::process::id(),
+            processed,
+            total,
+            self.committed_files,
+            self.seq
+        );
+        let _ = fs::write(heartbeat_path, json);
+    }
 }
 
-// 🆕 多候选渐进式搜索
-fn progressive_search_multi(
-    conn: &Connection,
-    query_str: &str,
-) -> (Option<(Node, String)>, Vec<CandidateMatch>, bool) {
-    let mut candidates: Vec<CandidateMatch> = vec![];
-    let max_candidates = 5;
+// --profile：按阶段 / 语言累计耗时（微秒），写成折叠栈，可直接喂给 flamegraph.pl / inferno-flamegraph。
+// main 栈是写库线程的墙钟时间；workers 栈是各解析线程的耗时之和，多核下会超过墙钟
+#[derive(Default)]
+struct Profile {
+    enabled: bool,
+    stacks: BTreeMap<String, u64>,
+}
 
-    // Layer 1: 精确匹配 (score = 1.0)
-    if let Some(node) = exact_match(conn, query_str) {
-        return (Some((node, "exact".to_string())), candidates, true);
+impl Profile {
+    fn new(args: &Args) -> Self {
+        Profile {
+            enabled: args.profile.is_some(),
+            ..Default::default()
+        }
     }
 
-    // Layer 2: 前缀/后缀匹配 (score = 0.9)
-    let prefix_matches = prefix_suffix_match_multi(conn, query_str, max_candidates);
-    for node in prefix_matches {
-        candidates.push(CandidateMatch {
-            node,
-            match_type: "prefix_suffix".to_string(),
-            score: 0.9,
-        });
-    }
-    if !candidates.is_empty() {
-        let best = candidates[0].node.clone();
-        return (Some((best, "prefix_suffix".to_string())), candidates, true);
+    fn add(&mut self, frames: &[&str], us: u64) {
+        if self.enabled && us > 0 {
+            *self.stacks.entry(frames.join(";")).or_default() += us;
+        }
     }
 
-    // Layer 3: 子串匹配 (score = 0.8)
-    let substring_matches = substring_match_multi(conn, query_str, max_candidates);
-    for node in substring_matches {
-        candidates.push(CandidateMatch {
-            node,
-            match_type: "substring".to_string(),
-            score: 0.8,
-        });
-    }
-    if !candidates.is_empty() {
-        let best = candidates[0].node.clone();
-        return (Some((best, "substring".to_string())), candidates, true);
+    // 记下 since 以来的耗时并返回新的起点，相邻阶段首尾相接
+    fn lap(&mut self, frames: &[&str], since: Instant) -> Instant {
+        self.add(frames, since.elapsed().as_micros() as u64);
+        Instant::now()
     }
 
-    // Layer 4: 编辑距离匹配 (score based on distance)
-    let lev_matches = levenshtein_match_multi(conn, query_str, 3, max_candidates);
-    for (node, dist) in lev_matches {
-        let score = 1.0 - (dist as f32 / 4.0); // distance 0=1.0, 1=0.75, 2=0.5, 3=0.25
-        candidates.push(CandidateMatch {
-            node,
-            match_type: format!("levenshtein_d{}", dist),
-            score,
-        });
-    }
-    if !candidates.is_empty() {
-        let best = candidates[0].node.clone();
-        return (Some((best, "levenshtein".to_string())), candidates, true);
+    fn write(&self, path: &str) -> anyhow::Result<()> {
+        let folded: String = self
+            .stacks
+            .iter()
+            .map(|(stack, us)| format!("{} {}\n", stack, us))
+            .collect();
+        fs::write(path, folded)?;
+        Ok(())
     }
+}
 
-    // Layer 5: 词根匹配 (score = 0.5)
-    let stem_matches = stem_match_multi(conn, query_str, max_candidates);
-    for node in stem_matches {
-        candidates.push(CandidateMatch {
-            node,
-            match_type: "stem".to_string(),
-            score: 0.5,
-        });
+// 进程峰值常驻内存（字节）：getrusage 的 ru_maxrss 在 Linux 上以 KB 计，macOS 上以字节计
+#[cfg(unix)]
+fn peak_rss_bytes() -> u64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return 0;
     }
-    if !candidates.is_empty() {
-        let best = candidates[0].node.clone();
-        return (Some((best, "stem".to_string())), candidates, true);
+    let max_rss = usage.ru_maxrss.max(0) as u64;
+    if cfg!(target_os = "macos") {
+        max_rss
+    } else {
+        max_rss * 1024
     }
-
-    (None, candidates, false)
-}
-
-// 🆕 修改：使用 canonical_id 而不是 symbol_id
-fn exact_match(conn: &Connection, query: &str) -> Option<Node> {
-    let mut stmt = conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name = ?1 LIMIT 1"
-    ).ok()?;
-    stmt.query_row([query], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    })
-    .ok()
-}
-
-// 🆕 修改：使用 canonical_id
-fn prefix_suffix_match(conn: &Connection, query: &str) -> Option<Node> {
-    let prefix_pattern = format!("{}%", query);
-    let suffix_pattern = format!("%{}", query);
-    let mut stmt = conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 OR name LIKE ?2 LIMIT 1"
-    ).ok()?;
-    stmt.query_row([prefix_pattern, suffix_pattern], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    })
-    .ok()
 }
 
-// 🆕 修改：使用 canonical_id
-fn substring_match(conn: &Connection, query: &str) -> Option<Node> {
-    let pattern = format!("%{}%", query);
-    let mut stmt = conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 LIMIT 1"
-    ).ok()?;
-    stmt.query_row([pattern], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    })
-    .ok()
+#[cfg(not(unix))]
+fn peak_rss_bytes() -> u64 {
+    0
 }
 
-// 🆕 修改：使用 canonical_id
-fn levenshtein_match(conn: &Connection, query: &str, max_distance: usize) -> Option<Node> {
-    // 获取所有符号名，在内存中计算编辑距离
-    let mut stmt = conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id"
-    ).ok()?;
-
-    let mut best: Option<(Node, usize)> = None;
-    let query_lower = query.to_lowercase();
+// Tree-sitter 抽取：单文件 -> 符号/调用/路由（纯函数，结果可缓存）
+fn extract_file(
+    lang: &Language,
+    query: &Query,
+    content: &str,
+    ext: &str,
+    features: ExtractFeatures,
+) -> ParsedFile {
+    let mut parser = TsParser::new();
+    parser.set_language(lang).unwrap();
+
+    let tree = parser.parse(content, None).unwrap(); // handle err?
+    let error_rows = error_regions(tree.root_node());
+    let parents = parent_map(tree.root_node());
+
+    let mut cursor = QueryCursor::new();
+    let mut matches = cursor.matches(query, tree.root_node(), content.as_bytes());
+
+    let mut symbols = vec![];
+    let mut calls = vec![];
+    let mut routes = vec![];
+    let mut inherits = vec![];
+    let mut decorators = vec![];
+    let mut attributes = vec![];
+    let mut imports = vec![];
+    let mut import_nodes: HashSet<usize> = HashSet::new();
+    let mut node_id_map: HashMap<usize, usize> = HashMap::new(); // tree_node_id -> temp_id
+                                                                 // 定义名节点：Elixir `def foo(a)` 的名字本身也是 call 节点，不能再记成调用
+    let mut def_name_nodes: HashSet<usize> = HashSet::new();
+    let mut pending_calls: Vec<(tree_sitter::Node, Option<tree_sitter::Node>)> = vec![];
+    let mut temp_counter = 0;
+    let js_exports = if matches!(ext, "js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx") {
+        js_exported_names(tree.root_node(), content)
+    } else {
+        HashSet::new()
+    };
 
-    let rows = stmt
-        .query_map([], |row| {
-            Ok(Node {
-                id: row.get::<_, String>(0)?, // 🆕 canonical_id
+    while let Some(m) = matches.next() {
+        let mut node_name: Option<String> = None;
+        let mut node_type: Option<&str> = None;
+        let mut def_node: Option<tree_sitter::Node> = None;
+        let mut callee_node: Option<tree_sitter::Node> = None;
+        let mut call_node: Option<tree_sitter::Node> = None;
+        let mut name_node: Option<tree_sitter::Node> = None;
+        let mut import_node: Option<tree_sitter::Node> = None;
+
+        for capture in m.captures {
+            let capture_name = query.capture_names()[capture.index as usize];
+            match capture_name {
+                "name" => {
+                    node_name = Some(
+                        content[capture.node.start_byte()..capture.node.end_byte()].to_string(),
+                    );
+                    name_node = Some(capture.node);
+                }
+                "callee" => {
+                    callee_node = Some(capture.node);
+                }
+                "def.func" => {
+                    node_type = Some("function");
+                    def_node = Some(capture.node);
+                }
+                "def.class" => {
+                    node_type = Some("class");
+                    def_node = Some(capture.node);
+                }
+                "def.const" => {
+                    node_type = Some("constant");
+                    def_node = Some(capture.node);
+                }
+                "def.field" => {
+                    node_type = Some("field");
+                    def_node = Some(capture.node);
+                }
+                "def.anon" => {
+                    // 作为实参传入的匿名函数：合成名 <anon@行号>，落库时补上文件路径
+                    node_type = Some("function");
+                    node_name = Some(format!("<anon@{}>", capture.node.start_position().row + 1));
+                    def_node = Some(capture.node);
+                }
+                "ref.call" => {
+                    // 调用节点本身：用于读取参数（URL 字面量 / 路由 handler）
+                    call_node = Some(capture.node);
+                }
+                "import" => {
+                    import_node = Some(capture.node);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(n) = import_node {
+            if import_nodes.insert(n.id()) {
+                imports.extend(import_entries(n, ext, content));
+            }
+            continue;
+        }
+
+        if let (Some(name), Some(coarse), Some(full_node)) = (node_name, node_type, def_node) {
+            // Definition（同一节点可能被多个模式命中，只取第一次）
+            if node_id_map.contains_key(&full_node.id()) {
+                continue;
+            }
+            let Some(kind) = classify_symbol(ext, coarse, full_node, &parents, &name, content)
+            else {
+                continue;
+            };
+            let start = full_node.start_position().row + 1;
+            let mut end = full_node.end_position().row + 1;
+            // 未闭合的定义会被错误恢复一路延伸到文件尾：定义以 ERROR/MISSING 收尾时，
+            // 截到其内首个错误区域的前一行
+            if full_node.has_error() && ends_in_error(full_node) {
+                if let Some(row) = error_rows.iter().find(|r| (start - 1..end).contains(*r)) {
+                    end = end.min((*row).max(start));
+                }
+            }
+
+            temp_counter += 1;
+            let tid = temp_counter;
+            node_id_map.insert(full_node.id(), tid);
+            if let Some(n) = name_node {
+                def_name_nodes.insert(n.id());
+            }
+
+            // Find parent temp_id
+            let parent_temp_id =
+                ancestors(full_node, &parents).find_map(|p| node_id_map.get(&p.id()).copied());
+
+            // 🆕 构建 scope_path：沿 parent() 回溯收集类/模块名
+            let mut scope_parts: Vec<String> = Vec::new();
+            // Ruby `class Tax::Calculator`：名字自带的命名空间前缀也并入 scope_path
+            if let Some(prefix) = full_node
+                .child_by_field_name("name")
+                .filter(|n| n.kind() == "scope_resolution")
+                .and_then(|n| n.child_by_field_name("scope"))
+            {
+                scope_parts.push(node_text(prefix, content).to_string());
+            }
+            for p in ancestors(full_node, &parents) {
+                // 检查父节点是否是 class 或 module（通过 child 名为 name 的捕获）
+                let node_kind = p.kind();
+                // Rust `impl Trait for Type`：方法挂在 Type 下，trait 由 inherits 记录
+                if node_kind == "impl_item" {
+                    if let Some(ty) = p
+                        .child_by_field_name("type")
+                        .and_then(|t| type_name(node_text(t, content)))
+                    {
+                        scope_parts.push(ty);
+                    }
+                    continue;
+                }
+                if node_kind == "class_definition"
+                    || node_kind == "class"
+                    || node_kind == "function_definition"
+                    || node_kind == "method_declaration"
+                    || node_kind == "class_declaration"
+                    || node_kind == "abstract_class_declaration"
+                    || node_kind == "interface_declaration"
+                    || node_kind == "struct_item"
+                    || node_kind == "enum_item"
+                    || node_kind == "enum_declaration"
+                    || node_kind == "type_spec"
+                    || node_kind == "type_alias_declaration"
+                    || node_kind == "class_specifier"
+                    || node_kind == "struct_specifier"
+                    || node_kind == "mod_item"
+                    || node_kind == "trait_item"
+                    || node_kind == "trait_declaration"
+                    || node_kind == "protocol_declaration"
+                    || node_kind == "struct_declaration"
+                    || node_kind == "record_declaration"
+                    || (node_kind == "module" && p.parent().is_some())
+                {
+                    // 尝试从子节点中找 name
+                    // （Swift extension 的名字是 user_type，Ruby 是 constant / A::B 的 scope_resolution）
+                    for i in 0..p.child_count() as u32 {
+                        let child = p.child(i).unwrap();
+                        let child_kind = child.kind();
+                        if child_kind == "identifier"
+                            || child_kind == "type_identifier"
+                            || child_kind == "name"
+                            || child_kind == "field_identifier"
+                            || child_kind == "user_type"
+                            || child_kind == "constant"
+                            || child_kind == "scope_resolution"
+                        {
+                            let parent_name = &content[child.start_byte()..child.end_byte()];
+                            if parent_name != name {
+                                scope_parts.push(parent_name.to_string());
+                            }
+                            break;
+                        }
+                    }
+                }
+                if let Some(alias) = elixir_module_alias(ext, p, content) {
+                    scope_parts.push(alias.to_string());
+                }
+            }
+            scope_parts.reverse();
+            let scope_path = if scope_parts.is_empty() {
+                name.clone()
+            } else if matches!(ext, "ex" | "exs") {
+                // Elixir：嵌套模块以 "." 相连（MyApp.Accounts），函数以 "::" 挂在模块下
+                let sep = if coarse == "class" { "." } else { "::" };
+                format!("{}{}{}", scope_parts.join("."), sep, name)
+            } else {
+                format!("{}::{}", scope_parts.join("::"), name)
+            };
+
+            if coarse == "class" {
+                for (base_name, kind) in base_types(full_node, content) {
+                    inherits.push(PendingInherit {
+                        child_temp_id: tid,
+                        base_name,
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            // 匿名回调：外层定义 -> 回调记一条 reference 边，调用链经回调不断开
+            if let (true, Some(outer), true) =
+                (name.starts_with("<anon@"), parent_temp_id, features.calls)
+            {
+                calls.push(PendingCall {
+                    caller_temp_id: outer,
+                    callee_name: name.clone(),
+                    line: start,
+                    kind: "reference".to_string(),
+                    receiver_type: None,
+                    args: None,
+                });
+            }
+            if let Some(decorated) = full_node
+                .parent()
+                .filter(|p| p.kind() == "decorated_definition")
+            {
+                let mut walker = decorated.walk();
+                for d in decorated.named_children(&mut walker) {
+                    if let Some(name) = decorator_name(d, content) {
+                        decorators.push(PendingDecorator {
+                            symbol_temp_id: tid,
+                            name,
+                            line: d.start_position().row + 1,
+                        });
+                    }
+                }
+            }
+            if ext == "java" {
+                for (name, line) in java_annotations(full_node, content) {
+                    decorators.push(PendingDecorator {
+                        symbol_temp_id: tid,
+                        name,
+                        line,
+                    });
+                }
+            }
+            for (key, value) in symbol_attributes(ext, full_node, content) {
+                attributes.push(PendingAttribute {
+                    symbol_temp_id: tid,
+                    key: key.to_string(),
+                    value,
+                });
+            }
+
+            symbols.push(PendingSymbol {
+                temp_id: tid,
+                parent_temp_id,
+                name: name.clone(),
+                qualified_name: scope_path.clone(),
+                scope_path,
+                symbol_type: kind.to_string(),
+                line_start: start,
+                line_end: end,
+                signature: if coarse == "field" {
+                    // 字段的签名即声明类型，diff 据此报告数据形状变化
+                    field_type_text(full_node, content).filter(|_| features.signatures)
+                } else if full_node.kind() == "type_alias_declaration" {
+                    // 类型别名的签名是右侧类型（折叠成一行），别名改动在 snapshot / diff 里可见
+                    full_node
+                        .child_by_field_name("value")
+                        .filter(|_| features.signatures)
+                        .map(|v| {
+                            node_text(v, content)
+                                .split_whitespace()
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        })
+                } else if coarse != "class" && features.signatures {
+                    // C/C++ 全局变量捕获的是声明符，签名取整条声明（带类型）
+                    let sig_node = c_global_declaration(ext, full_node).unwrap_or(full_node);
+                    let sig_text = &content[sig_node.start_byte()..sig_node.end_byte()];
+                    sig_text.lines().next().map(|s| s.trim().to_string())
+                } else {
+                    None
+                },
+                doc: None,
+                visibility: symbol_visibility(
+                    ext,
+                    full_node,
+                    &parents,
+                    &name,
+                    content,
+                    &js_exports,
+                )
+                .map(str::to_string),
+                anchor: None,
+                signature_shape: if coarse == "function" && features.signatures {
+                    signature_shape(full_node, content)
+                } else {
+                    None
+                },
+            });
+        } else if let (Some(c_node), true) = (callee_node, features.calls) {
+            // 调用延后到所有定义登记完再归属：匹配的产出顺序不保证定义先于其内部的调用
+            pending_calls.push((c_node, call_node));
+        }
+    }
+
+    for (c_node, call_node) in pending_calls {
+        // Call（跳过定义名自身，如 Elixir `def foo(a)` 中的 foo(a)）
+        if def_name_nodes.contains(&c_node.id()) {
+            continue;
+        }
+        // Rust 路径前缀（derive(serde::Serialize) 里的 serde）不是被调用者
+        if ext == "rs" && c_node.next_sibling().is_some_and(|n| n.kind() == "::") {
+            continue;
+        }
+        let callee_name = content[c_node.start_byte()..c_node.end_byte()].to_string();
+        // Find caller（Rust 属性宏 / derive、Python 装饰器记在被修饰的条目上；#[tokio::main] fn main 不连自环）
+        let attribute_target = decoration_target(c_node, call_node, ext);
+        if attribute_target
+            .and_then(|t| t.child_by_field_name("name"))
+            .is_some_and(|n| node_text(n, content) == callee_name)
+        {
+            continue;
+        }
+        let caller = attribute_target
+            .and_then(|t| node_id_map.get(&t.id()).map(|&tid| (t, tid)))
+            .or_else(|| {
+                ancestors(c_node, &parents)
+                    .find_map(|p| node_id_map.get(&p.id()).map(|&tid| (p, tid)))
+            });
+        let caller_tid = caller.map_or(0, |(_, tid)| tid);
+        let line = c_node.start_position().row + 1;
+
+        // 🆕 URL/Endpoint 交叉引用：客户端请求 -> http 边；服务端注册 -> routes
+        let endpoint =
+            call_node.and_then(|n| classify_endpoint_call(n, c_node, &callee_name, content));
+        match endpoint {
+            Some(EndpointRef::Client { http_method, path }) if caller_tid > 0 => {
+                calls.push(PendingCall {
+                    caller_temp_id: caller_tid,
+                    callee_name: format!("{} {}", http_method, path),
+                    line,
+                    kind: "http".to_string(),
+                    receiver_type: None,
+                    args: None,
+                });
+            }
+            Some(EndpointRef::Route {
+                http_method,
+                path,
+                handler_name,
+            }) => {
+                routes.push(PendingRoute {
+                    http_method,
+                    path,
+                    handler_name,
+                    line,
+                });
+            }
+            _ => {}
+        }
+
+        if let Some((_, tid)) = caller {
+            // 回调实参：reference 边，链接时只落到函数符号
+            for arg in call_node.map_or(vec![], |n| callback_arguments(n, c_node, content)) {
+                calls.push(PendingCall {
+                    caller_temp_id: tid,
+                    callee_name: node_text(arg, content).to_string(),
+                    line: arg.start_position().row + 1,
+                    kind: "reference".to_string(),
+                    receiver_type: None,
+                    args: None,
+                });
+            }
+        }
+
+        if let Some((def, tid)) = caller {
+            // obj.method()：在调用者定义内、调用点之前推断 obj 的类型（装饰器在定义之前，不推断）
+            let receiver_type = receiver_identifier(c_node, &parents, content).and_then(|recv| {
+                let owner = enclosing_type(&symbols, tid);
+                // self.repo：所在类型登记过带类型的字段时直接用字段声明
+                if let (Some(field), Some(owner)) = (self_field(recv), owner) {
+                    if let Some(t) = field_symbol_type(&symbols, owner, field) {
+                        return Some(t);
+                    }
+                }
+                let before = content.get(def.start_byte()..c_node.start_byte())?;
+                let fields = enclosing_type_text(def, &parents, &node_id_map, &symbols, content);
+                infer_receiver_type(recv, before, owner, Some(fields), ext)
+            });
+            // Python 装饰器不是调用：单独记 decorator 边，链接不上（@property、@app.route 等
+            // 项目外的装饰器）就丢弃，不进 callers / callees / impact 的调用图
+            let kind = if ext == "py" && attribute_target.is_some() {
+                "decorator"
+            } else {
+                "call"
+            };
+            calls.push(PendingCall {
+                caller_temp_id: tid,
+                callee_name,
+                line,
+                kind: kind.to_string(),
+                receiver_type,
+                args: call_node.and_then(|n| call_arguments(n, content)),
+            });
+        }
+    }
+
+    // JSX 渲染：外层定义 -> <Foo /> 记 render 边，链接时只落到函数 / 组件符号
+    if features.calls && matches!(ext, "js" | "mjs" | "cjs" | "jsx" | "tsx") {
+        for (name_node, component) in jsx_component_tags(tree.root_node(), content) {
+            let Some(caller_tid) =
+                ancestors(name_node, &parents).find_map(|p| node_id_map.get(&p.id()).copied())
+            else {
+                continue;
+            };
+            calls.push(PendingCall {
+                caller_temp_id: caller_tid,
+                callee_name: component,
+                line: name_node.start_position().row + 1,
+                kind: "render".to_string(),
+                receiver_type: None,
+                args: None,
+            });
+        }
+    }
+
+    // C/C++：函数体里的自由标识符（非参数、非局部变量、非被调用者）记 reference 边，
+    // 链接阶段连到全局变量 / 常量与宏，analyze 据此算出共享全局状态的影响面
+    if features.calls && matches!(ext, "c" | "h" | "cpp" | "cc" | "hpp") {
+        let mut locals: HashMap<usize, HashSet<String>> = HashMap::new();
+        // 作为实参传出的名字已有回调 reference 边，不重复记
+        let mut seen: HashSet<(usize, String)> = calls
+            .iter()
+            .filter(|c| c.kind == "reference")
+            .map(|c| (c.caller_temp_id, c.callee_name.clone()))
+            .collect();
+        for ident in c_identifier_refs(tree.root_node()) {
+            let Some(func) = ancestors(ident, &parents).find(|p| p.kind() == "function_definition")
+            else {
+                continue;
+            };
+            let Some(&caller_tid) = node_id_map.get(&func.id()) else {
+                continue;
+            };
+            let name = node_text(ident, content);
+            let declared = locals
+                .entry(func.id())
+                .or_insert_with(|| c_local_names(func, content));
+            if declared.contains(name) || !seen.insert((caller_tid, name.to_string())) {
+                continue;
+            }
+            calls.push(PendingCall {
+                caller_temp_id: caller_tid,
+                callee_name: name.to_string(),
+                line: ident.start_position().row + 1,
+                kind: "reference".to_string(),
+                receiver_type: None,
+                args: None,
+            });
+        }
+    }
+
+    // docstring / 注释块：索引期落库，query / map --detail full 直接返回
+    if features.docs && !symbols.is_empty() {
+        let lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&lines, sym.line_start, ext);
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes,
+        inherits,
+        imports,
+        decorators,
+        attributes,
+        grammar: String::new(),
+        error_nodes: error_rows.len(),
+    }
+}
+
+// 🆕 语法错误区域：ERROR / MISSING 节点的起始行（0-based，按出现顺序）。
+// 只下钻 has_error() 的子树，ERROR 内部不再细分
+fn error_regions(root: tree_sitter::Node) -> Vec<usize> {
+    let mut rows = vec![];
+    if !root.has_error() {
+        return rows;
+    }
+    let mut cursor = root.walk();
+    'walk: loop {
+        let node = cursor.node();
+        let is_error = node.is_error() || node.is_missing();
+        if is_error {
+            rows.push(node.start_position().row);
+        }
+        if !is_error && node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                continue 'walk;
+            }
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+    rows
+}
+
+// 节点的最后一个后代是 ERROR / MISSING（典型：缺失的 `}` / `end`）
+fn ends_in_error(node: tree_sitter::Node) -> bool {
+    let mut n = node;
+    while let Some(last) = n
+        .child_count()
+        .checked_sub(1)
+        .and_then(|i| n.child(i as u32))
+    {
+        if last.is_error() || last.is_missing() {
+            return true;
+        }
+        n = last;
+    }
+    false
+}
+
+// 结构化签名：多数语法的参数表是 parameters 字段，C/C++ 挂在 function_declarator 下，
+// JS 单参数箭头函数是 parameter 字段；返回类型依次认 return_type / result（Go）/ type（C、Java）
+fn signature_shape(def: tree_sitter::Node, content: &str) -> Option<SignatureShape> {
+    // const f = (a) => ... / exports.f = function (a) {...}：函数体在赋值右侧
+    let func = match def.kind() {
+        "lexical_declaration" | "variable_declaration" | "expression_statement" => {
+            let mut walker = def.walk();
+            let value = def.named_children(&mut walker).find_map(|c| {
+                c.child_by_field_name("value")
+                    .or(c.child_by_field_name("right"))
+            })?;
+            value
+        }
+        _ => def,
+    };
+    let mut declarator = func;
+    while declarator.kind() != "function_declarator" {
+        match declarator.child_by_field_name("declarator") {
+            Some(d) => declarator = d,
+            None => break,
+        }
+    }
+    let params = func
+        .child_by_field_name("parameters")
+        .or_else(|| declarator.child_by_field_name("parameters"))
+        .or_else(|| func.child_by_field_name("parameter"))?;
+    let returns = ["return_type", "result", "type"]
+        .iter()
+        .find_map(|f| func.child_by_field_name(f))
+        .map(|t| type_text(t, content));
+
+    let params = if matches!(params.kind(), "identifier" | "simple_identifier") {
+        vec![SignatureParam {
+            name: node_text(params, content).to_string(),
+            param_type: None,
+            kind: None,
+        }]
+    } else {
+        let mut walker = params.walk();
+        params
+            .named_children(&mut walker)
+            .filter(|p| !p.kind().contains("comment"))
+            .flat_map(|p| signature_params(p, content))
+            .collect()
+    };
+    let type_params = type_params(func, content);
+    Some(SignatureShape {
+        params,
+        returns,
+        type_params,
+    })
+}
+
+// 泛型参数表：多数语法是 type_parameters 字段（Swift 无字段名），参数名是第一个子节点，其后为约束；
+// Go `[K, V any]` 一个声明多个名字；where 子句（Rust / C# / Swift）里的约束并到同名参数上
+fn type_params(func: tree_sitter::Node, content: &str) -> Vec<TypeParam> {
+    let mut walker = func.walk();
+    let Some(list) = func.child_by_field_name("type_parameters").or_else(|| {
+        func.named_children(&mut walker)
+            .find(|c| c.kind() == "type_parameters")
+    }) else {
+        return vec![];
+    };
+    // 名字之后的原文即约束：`T: Clone` / `T extends Foo` / `N: usize`
+    let rest = |node: tree_sitter::Node, name: tree_sitter::Node| {
+        let text = content[name.end_byte()..node.end_byte()]
+            .trim()
+            .trim_start_matches(':')
+            .trim_start_matches("extends ")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!text.is_empty()).then_some(text)
+    };
+
+    let mut params: Vec<TypeParam> = vec![];
+    let mut walker = list.walk();
+    for mut param in list
+        .named_children(&mut walker)
+        .filter(|p| !p.kind().contains("comment"))
+    {
+        if param.kind() == "type_parameter_declaration" {
+            let bounds = param
+                .child_by_field_name("type")
+                .map(|t| type_text(t, content));
+            let mut w = param.walk();
+            for name in param.children_by_field_name("name", &mut w) {
+                params.push(TypeParam {
+                    name: node_text(name, content).to_string(),
+                    bounds: bounds.clone(),
+                });
+            }
+            continue;
+        }
+        // Python 3.12 `[T: (int, str), *Ts]`：每项包在 type 里
+        while param.kind() == "type" && param.named_child_count() == 1 {
+            param = param.named_child(0).unwrap_or(param);
+        }
+        let Some(name) = param
+            .named_child(0)
+            .filter(|_| param.kind() != "splat_type")
+        else {
+            params.push(TypeParam {
+                name: type_text(param, content),
+                bounds: None,
+            });
+            continue;
+        };
+        params.push(TypeParam {
+            name: type_text(name, content),
+            bounds: rest(param, name),
+        });
+    }
+
+    let mut walker = func.walk();
+    for clause in func.named_children(&mut walker) {
+        let predicates: Vec<tree_sitter::Node> = match clause.kind() {
+            "type_parameter_constraints_clause" => vec![clause],
+            "where_clause" | "type_constraints" => {
+                let mut w = clause.walk();
+                clause
+                    .named_children(&mut w)
+                    .filter(|c| c.kind() != "where_keyword")
+                    .collect()
+            }
+            _ => continue,
+        };
+        for mut predicate in predicates {
+            while predicate.named_child_count() == 1 {
+                predicate = predicate.named_child(0).unwrap_or(predicate);
+            }
+            let Some(target) = predicate.named_child(0) else {
+                continue;
+            };
+            let (target_name, Some(extra)) = (type_text(target, content), rest(predicate, target))
+            else {
+                continue;
+            };
+            if let Some(p) = params.iter_mut().find(|p| p.name == target_name) {
+                p.bounds = Some(match p.bounds.take() {
+                    Some(b) => format!("{}, {}", b, extra),
+                    None => extra,
+                });
+            }
+        }
+    }
+    params
+}
+
+fn signature_params(param: tree_sitter::Node, content: &str) -> Vec<SignatureParam> {
+    // Python *args / **kwargs：星号是名字的一部分
+    if param.kind().ends_with("splat_pattern") {
+        return vec![SignatureParam {
+            name: node_text(param, content).to_string(),
+            param_type: None,
+            kind: Some(
+                match param.kind() {
+                    "dictionary_splat_pattern" => "kwargs",
+                    _ => "variadic",
+                }
+                .to_string(),
+            ),
+        }];
+    }
+    let kind = param_kind(param);
+    let param_type = match param.kind() {
+        // Go `s ...string`
+        "variadic_parameter_declaration" => param
+            .child_by_field_name("type")
+            .map(|t| format!("...{}", type_text(t, content))),
+        // Java `String... rest`：类型是第一个子节点，没有 type 字段
+        "spread_parameter" => param
+            .named_child(0)
+            .map(|t| format!("{}...", type_text(t, content))),
+        _ => param
+            .child_by_field_name("type")
+            .map(|t| type_text(t, content)),
+    };
+    // Go `a, b int`：一个声明多个名字
+    let mut walker = param.walk();
+    let mut names: Vec<String> = param
+        .children_by_field_name("name", &mut walker)
+        .map(|n| node_text(n, content).to_string())
+        .collect();
+    if names.is_empty() {
+        let name_node = ["pattern", "left", "declarator"]
+            .iter()
+            .find_map(|f| param.child_by_field_name(f))
+            .map(|mut n| {
+                // C `int *p`：沿 declarator 链取到最内层的标识符
+                while let Some(inner) = n.child_by_field_name("declarator") {
+                    n = inner;
+                }
+                n
+            })
+            .or_else(|| {
+                // Java `String... rest`：名字在 variable_declarator 里
+                let mut walker = param.walk();
+                let declarator = param
+                    .named_children(&mut walker)
+                    .find(|c| c.kind() == "variable_declarator")?;
+                declarator.child_by_field_name("name")
+            })
+            .or_else(|| {
+                // Python typed_parameter：名字是第一个非类型子节点；*args / self 等取原文
+                let first = param.named_child(0)?;
+                (Some(first) != param.child_by_field_name("type")).then_some(first)
+            });
+        names.push(match name_node {
+            Some(n) if n.id() != param.id() => node_text(n, content).to_string(),
+            _ => node_text(param, content).to_string(),
+        });
+    }
+    names
+        .into_iter()
+        .map(|name| SignatureParam {
+            name,
+            param_type: param_type.clone(),
+            kind: kind.map(str::to_string),
+        })
+        .collect()
+}
+
+// 形参能否省略 / 是否收集多余实参：各语法的默认值、剩余参数节点名不同，逐个认
+fn param_kind(param: tree_sitter::Node) -> Option<&'static str> {
+    match param.kind() {
+        "default_parameter"
+        | "typed_default_parameter"
+        | "optional_parameter"
+        | "optional_parameter_declaration"
+        | "assignment_pattern" => Some("optional"),
+        "rest_pattern"
+        | "variadic_parameter_declaration"
+        | "spread_parameter"
+        | "variadic_parameter"
+        | "splat_parameter" => Some("variadic"),
+        "hash_splat_parameter" => Some("kwargs"),
+        // TS `a = 1` / `...rest: T[]`、PHP `$a = 1` / `...$rest`
+        _ if param.child_by_field_name("value").is_some()
+            || param.child_by_field_name("default_value").is_some() =>
+        {
+            Some("optional")
+        }
+        // C# `int x = 1`：默认值没有字段名
+        "parameter"
+            if (0..param.child_count() as u32)
+                .any(|i| param.child(i).is_some_and(|c| c.kind() == "=")) =>
+        {
+            Some("optional")
+        }
+        _ if param
+            .child_by_field_name("pattern")
+            .is_some_and(|p| p.kind() == "rest_pattern")
+            || param.child(0).is_some_and(|c| c.kind() == "...") =>
+        {
+            Some("variadic")
+        }
+        _ => None,
+    }
+}
+
+// 类型文本：去掉 TS type_annotation 的前导冒号、Rust/Python 的 `->`，空白折叠成一个空格
+fn type_text(node: tree_sitter::Node, content: &str) -> String {
+    node_text(node, content)
+        .trim_start_matches(':')
+        .trim_start_matches("->")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// 匿名回调的合成名 <anon@行号> -> <anon@文件:行号>。解析缓存按内容共享，
+// 文件路径只能在落库 / 现场重解析时补上
+fn anon_in_file(text: &str, file_path: &str) -> String {
+    text.replace("<anon@", &format!("<anon@{}:", file_path))
+}
+
+// 字段声明的类型文本（TS 的 type_annotation 带前导冒号）
+fn field_type_text(node: tree_sitter::Node, content: &str) -> Option<String> {
+    // C# 的类型在 field_declaration 下的 variable_declaration 上
+    let mut walker = node.walk();
+    let ty = node.child_by_field_name("type").or_else(|| {
+        node.named_children(&mut walker)
+            .find(|c| c.kind() == "variable_declaration")?
+            .child_by_field_name("type")
+    })?;
+    Some(
+        node_text(ty, content)
+            .trim_start_matches(':')
+            .trim()
+            .to_string(),
+    )
+}
+
+// 被修饰的条目：Rust `#[derive(..)]` / `#[attr]` 是条目的前一个兄弟节点（跳过其余属性与注释）；
+// Python 的 decorator 挂在 decorated_definition 下，条目是其 definition
+fn decoration_target<'t>(
+    callee: tree_sitter::Node<'t>,
+    call: Option<tree_sitter::Node<'t>>,
+    ext: &str,
+) -> Option<tree_sitter::Node<'t>> {
+    if let Some(attr) = call.filter(|n| n.kind() == "attribute_item") {
+        let mut next = attr.next_named_sibling();
+        while let Some(n) = next {
+            if !matches!(
+                n.kind(),
+                "attribute_item" | "line_comment" | "block_comment"
+            ) {
+                return Some(n);
+            }
+            next = n.next_named_sibling();
+        }
+        return None;
+    }
+    if ext != "py" {
+        return None;
+    }
+    let mut parent = callee.parent();
+    while let Some(p) = parent {
+        match p.kind() {
+            "decorator" => {
+                return p
+                    .parent()
+                    .filter(|d| d.kind() == "decorated_definition")
+                    .and_then(|d| d.child_by_field_name("definition"));
+            }
+            "block" | "module" | "function_definition" | "class_definition" | "lambda" => {
+                return None
+            }
+            _ => parent = p.parent(),
+        }
+    }
+    None
+}
+
+// 装饰器名：去掉 @ 与调用实参（@app.route("/") -> app.route，@login_required -> login_required）
+fn decorator_name(decorator: tree_sitter::Node, content: &str) -> Option<String> {
+    if decorator.kind() != "decorator" {
+        return None;
+    }
+    let expr = decorator.named_child(0)?;
+    let expr = match expr.kind() {
+        "call" => expr.child_by_field_name("function")?,
+        _ => expr,
+    };
+    Some(node_text(expr, content).to_string())
+}
+
+// 语言特有的符号属性：async 函数 / 方法（Rust、Python、JS/TS、C#、Swift），Rust #[derive(..)] 的各项
+// 与 #[deprecated] 的说明
+fn symbol_attributes(
+    ext: &str,
+    def: tree_sitter::Node,
+    content: &str,
+) -> Vec<(&'static str, String)> {
+    let mut attrs = vec![];
+    if is_async_definition(def, content) {
+        attrs.push(("async", "true".to_string()));
+    }
+    if ext == "rs" {
+        let mut prev = def.prev_named_sibling();
+        while let Some(p) = prev.filter(|p| {
+            matches!(
+                p.kind(),
+                "attribute_item" | "line_comment" | "block_comment"
+            )
+        }) {
+            let derive = p
+                .named_child(0)
+                .filter(|a| a.kind() == "attribute")
+                .filter(|a| {
+                    a.named_child(0)
+                        .is_some_and(|n| node_text(n, content) == "derive")
+                });
+            let deprecated = p
+                .named_child(0)
+                .filter(|a| a.kind() == "attribute")
+                .filter(|a| {
+                    a.named_child(0)
+                        .is_some_and(|n| node_text(n, content) == "deprecated")
+                });
+            if let Some(attr) = deprecated {
+                attrs.push((
+                    "deprecated",
+                    rust_deprecation_note(node_text(attr, content)),
+                ));
+            }
+            if let Some(args) = derive.and_then(|a| a.child_by_field_name("arguments")) {
+                let mut walker = args.walk();
+                // serde::Serialize 取末段；路径前缀后面紧跟 ::
+                for id in args.named_children(&mut walker) {
+                    if id.kind() == "identifier"
+                        && id.next_sibling().is_none_or(|n| n.kind() != "::")
+                    {
+                        attrs.push(("derive", node_text(id, content).to_string()));
+                    }
+                }
+            }
+            prev = p.prev_named_sibling();
+        }
+    }
+    attrs
+}
+
+// #[deprecated = "说明"] / #[deprecated(since = "1.2", note = "说明")]；没有说明时为 "true"
+fn rust_deprecation_note(attr: &str) -> String {
+    let quoted = |text: &str| {
+        let start = text.find('"')? + 1;
+        let end = start + text[start..].find('"')?;
+        Some(text[start..end].to_string())
+    };
+    let note = match attr.find("note") {
+        Some(i) => quoted(&attr[i..]),
+        None if attr.contains('(') => None,
+        None => quoted(attr),
+    };
+    note.filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "true".to_string())
+}
+
+// 文档注释里的弃用标记：@deprecated 说明（JSDoc / Javadoc / PHPDoc）、Go 的 `Deprecated: 说明` 段落、
+// Sphinx 的 `.. deprecated:: 版本`；返回说明文字，没有说明时为 "true"
+fn doc_deprecation(doc: &str) -> Option<String> {
+    doc.lines().map(str::trim).find_map(|line| {
+        let rest = line
+            .strip_prefix("@deprecated")
+            .filter(|r| !r.starts_with(is_ident_char))
+            .or_else(|| line.strip_prefix("Deprecated:"))
+            .or_else(|| line.strip_prefix(".. deprecated::"))?
+            .trim();
+        Some(if rest.is_empty() { "true" } else { rest }.to_string())
+    })
+}
+
+// @Deprecated（Java）、@deprecated / @typing_extensions.deprecated / @warnings.deprecated（Python、TS）
+fn is_deprecation_decorator(name: &str) -> bool {
+    matches!(name.rsplit('.').next(), Some("Deprecated" | "deprecated"))
+}
+
+// async 关键字：直接子节点（JS/TS、Python、Swift），修饰符节点里（Rust function_modifiers、C# modifier），
+// 或 `const f = async () => {}` 绑定的函数值上
+fn is_async_definition(def: tree_sitter::Node, content: &str) -> bool {
+    let def = match def.kind() {
+        "lexical_declaration" | "variable_declaration" => def
+            .named_child(0)
+            .and_then(|d| d.child_by_field_name("value"))
+            .unwrap_or(def),
+        "variable_declarator" => def.child_by_field_name("value").unwrap_or(def),
+        _ => def,
+    };
+    let body = def.child_by_field_name("body").map(|b| b.id());
+    let mut walker = def.walk();
+    let found = def
+        .children(&mut walker)
+        .take_while(|c| Some(c.id()) != body)
+        .any(|c| match c.kind() {
+            "async" => true,
+            "function_modifiers" | "modifier" | "modifiers" => node_text(c, content)
+                .split_whitespace()
+                .any(|w| w == "async"),
+            _ => false,
+        });
+    found
+}
+
+// Java 注解：声明的 modifiers 里的 @X / @X(..)，名字保留书写形式（@org.x.Y -> org.x.Y）；
+// 字段符号落在 variable_declarator 上，注解在外层 field_declaration
+fn java_annotations(def: tree_sitter::Node, content: &str) -> Vec<(String, usize)> {
+    let decl = match def.kind() {
+        "variable_declarator" => def.parent().unwrap_or(def),
+        _ => def,
+    };
+    let mut walker = decl.walk();
+    let Some(modifiers) = decl
+        .named_children(&mut walker)
+        .find(|c| c.kind() == "modifiers")
+    else {
+        return vec![];
+    };
+    let mut walker = modifiers.walk();
+    modifiers
+        .named_children(&mut walker)
+        .filter(|a| matches!(a.kind(), "marker_annotation" | "annotation"))
+        .filter_map(|a| {
+            let name = a.child_by_field_name("name")?;
+            Some((
+                node_text(name, content).to_string(),
+                a.start_position().row + 1,
+            ))
+        })
+        .collect()
+}
+
+// ============================================================================
+// Receiver Types (接收者类型推断)
+// obj.method() 只能记到方法名，所有带同名方法的类型都会成为候选；
+// 从调用前的源码推断 obj 的类型记到边上，链接阶段优先落到该类型下的方法
+// ============================================================================
+
+// 各语法里成员调用"对象部分"的字段名（callee 自身若也在这些字段上会被排除）
+const RECEIVER_FIELDS: &[&str] = &[
+    "object", "operand", "value", "argument", "receiver", "scope", "path", "target", "left",
+];
+
+// 成员调用的接收者：只取简单标识符（含 PHP 的 $x）或自身字段（self.x / this.x / $this->x / @x），
+// 其余链式 / 下标 / 调用结果放弃推断
+fn receiver_identifier<'t>(
+    callee: tree_sitter::Node<'t>,
+    parents: &ParentMap<'t>,
+    content: &'t str,
+) -> Option<&'t str> {
+    let mut owner = *parents.get(&callee.id())?;
+    // Swift：navigation_suffix 外面一层才是 navigation_expression
+    if owner.kind() == "navigation_suffix" {
+        owner = *parents.get(&owner.id())?;
+    }
+    let recv = RECEIVER_FIELDS
+        .iter()
+        .filter_map(|f| owner.child_by_field_name(f))
+        .find(|n| n.id() != callee.id())?;
+    let text = node_text(recv, content);
+    if self_field(text).is_some() {
+        return Some(text);
+    }
+    let ident = text.strip_prefix('$').unwrap_or(text);
+    let simple = ident
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && ident.chars().all(is_ident_char);
+    simple.then_some(text)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// self.repo / this.repo / this->repo / $this->repo / @repo（Ruby 实例变量）-> 字段名
+fn self_field(recv: &str) -> Option<&str> {
+    let field = ["self.", "this.", "this->", "$this->", "@"]
+        .iter()
+        .find_map(|p| recv.strip_prefix(p))?;
+    (!field.is_empty() && field.chars().all(is_ident_char)).then_some(field)
+}
+
+// 已登记的字段符号，签名即声明类型（Rust / Go struct 字段、Java / C# / TS 带类型的字段）
+fn field_symbol_type(symbols: &[PendingSymbol], owner: &str, field: &str) -> Option<String> {
+    let scope = format!("{}::{}", owner, field);
+    symbols
+        .iter()
+        .filter(|s| canonical_prefix(&s.symbol_type) != "func" && s.name == field)
+        .filter(|s| s.scope_path == scope || s.scope_path.ends_with(&format!("::{}", scope)))
+        .find_map(|s| s.signature.as_deref().and_then(type_name))
+}
+
+// 方法体里可以直接用字段名（不写 this.）的语言
+const BARE_FIELD_EXTS: &[&str] = &["java", "cs", "swift", "cpp", "cc", "cxx", "hpp", "hh"];
+
+// 调用者所在类型定义的源码，字段声明与构造器赋值都在其中；
+// 不在类型定义内（Rust impl、Go 接收者方法）时退回整个文件
+fn enclosing_type_text<'t>(
+    def: tree_sitter::Node<'t>,
+    parents: &ParentMap<'t>,
+    node_id_map: &HashMap<usize, usize>,
+    symbols: &[PendingSymbol],
+    content: &'t str,
+) -> &'t str {
+    ancestors(def, parents)
+        .find(|p| {
+            node_id_map
+                .get(&p.id())
+                .and_then(|&tid| symbols.get(tid.wrapping_sub(1)))
+                .is_some_and(|s| canonical_prefix(&s.symbol_type) == "class")
+        })
+        .map_or(content, |class| node_text(class, content))
+}
+
+// 调用者所在的类型：最近的类级父符号；Rust impl / Go 接收者等不成符号时取 scope_path 上一级
+fn enclosing_type(symbols: &[PendingSymbol], tid: usize) -> Option<&str> {
+    // temp_id 与登记顺序一致（从 1 起）
+    let by_id = |id: usize| symbols.get(id.wrapping_sub(1)).filter(|s| s.temp_id == id);
+    let caller = by_id(tid)?;
+    let mut cur = caller.parent_temp_id;
+    while let Some(s) = cur.and_then(by_id) {
+        if canonical_prefix(&s.symbol_type) == "class" {
+            return s.name.rsplit(['.', ':']).next();
+        }
+        cur = s.parent_temp_id;
+    }
+    caller.scope_path.rsplit("::").nth(1)
+}
+
+// 推断接收者类型：self/this -> 所在类型；首字母大写 -> 类型本身（静态调用）；
+// 否则在调用者定义起点到调用点之间找该变量最近一次可识别的声明 / 构造。
+// 自身字段（self.repo）与方法体里直接使用的字段名在 fields（所在类型的源码）里找
+// 字段声明、构造器赋值或同名的带类型构造参数
+fn infer_receiver_type(
+    recv: &str,
+    before: &str,
+    enclosing: Option<&str>,
+    fields: Option<&str>,
+    ext: &str,
+) -> Option<String> {
+    if let Some(field) = self_field(recv) {
+        let fields = fields?;
+        // PHP 类型化属性 / 构造器提升写作 `Repo $repo`
+        let php_property = (ext == "php")
+            .then(|| last_declared_type(&format!("${}", field), fields, ext))
+            .flatten();
+        return php_property.or_else(|| last_declared_type(field, fields, ext));
+    }
+    if matches!(recv, "self" | "this" | "$this" | "cls" | "Self" | "static") {
+        return enclosing.map(|t| t.to_string());
+    }
+    // 全大写视为常量（LOGGER.info()），不当类型
+    if recv.starts_with(|c: char| c.is_ascii_uppercase())
+        && recv.chars().any(|c| c.is_ascii_lowercase())
+    {
+        return Some(recv.to_string());
+    }
+    let declared = last_declared_type(recv, before, ext).or_else(|| {
+        let fields = fields.filter(|_| BARE_FIELD_EXTS.contains(&ext))?;
+        last_declared_type(recv, fields, ext)
+    })?;
+    // Rust 泛型参数（<S: Store> / where S: Store）换成它的 trait 约束
+    if ext == "rs" {
+        if let Some(bound) = last_declared_type(&declared, before, ext) {
+            return Some(bound);
+        }
+    }
+    Some(declared)
+}
+
+// text 中 name 最后一次可识别的声明 / 构造给出的类型
+fn last_declared_type(name: &str, text: &str, ext: &str) -> Option<String> {
+    let mut found = None;
+    let mut from = 0;
+    while let Some(i) = text[from..].find(name).map(|i| from + i) {
+        from = i + name.len();
+        let bounded = !text[..i].ends_with(|c: char| is_ident_char(c) || c == '$')
+            && !text[from..].starts_with(is_ident_char);
+        if bounded {
+            if let Some(t) = declared_type(&text[..i], &text[from..], ext) {
+                found = Some(t);
+            }
+        }
+    }
+    found
+}
+
+// 变量出现处是否是声明：x: Foo / x = Foo(...) / x := &Foo{} / Foo x; / x *Foo（Go）
+fn declared_type(prefix: &str, rest: &str, ext: &str) -> Option<String> {
+    let after = rest.trim_start();
+    if let Some(t) = after
+        .strip_prefix(':')
+        .filter(|t| !t.starts_with([':', '=']))
+    {
+        return type_name(t);
+    }
+    let assigned = after.strip_prefix(":=").or_else(|| {
+        after
+            .strip_prefix('=')
+            .filter(|t| !t.starts_with(['=', '>']))
+    });
+    if let Some(t) = assigned.and_then(constructed_type) {
+        return Some(t);
+    }
+    // Go：参数 / var 声明的类型写在变量后，同一行内（r *Repo、var repo db.Repo）
+    let same_line = !rest[..rest.len() - after.len()].contains('\n');
+    if ext == "go" && same_line && rest.starts_with([' ', '\t']) {
+        if let Some(t) =
+            type_name(after).filter(|_| after.starts_with(|c: char| c == '*' || c.is_alphabetic()))
+        {
+            return Some(t);
+        }
+    }
+    // Objective-C 参数 / 强转：`(Repo *)repo`
+    if matches!(ext, "m" | "mm") {
+        let head = prefix.trim_end();
+        if let Some(t) = head
+            .strip_suffix(')')
+            .and_then(|inner| inner.rfind('(').map(|o| &inner[o + 1..]))
+            .and_then(type_name)
+        {
+            return Some(t);
+        }
+    }
+    // Java / C# / C++：类型写在变量前（泛型参数与指针 / 引用修饰跳过）
+    if after.is_empty() || after.starts_with(['=', ';', ',', ')']) {
+        let mut head = prefix
+            .trim_end()
+            .trim_end_matches(['*', '&', '?'])
+            .trim_end();
+        if head.ends_with('>') {
+            let mut depth = 0;
+            for (i, c) in head.char_indices().rev() {
+                match c {
+                    '>' => depth += 1,
+                    '<' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    head = head[..i].trim_end();
+                    break;
+                }
+            }
+        }
+        let start = head
+            .rfind(|c: char| !is_ident_char(c) && !matches!(c, '.' | ':' | '\\'))
+            .map_or(0, |i| i + 1);
+        return type_name(&head[start..]);
+    }
+    None
+}
+
+// 类型表达式的末段名：去掉 & / * / mut / 命名空间前缀与泛型；只认首字母大写的类型名
+fn type_name(text: &str) -> Option<String> {
+    let (path, _) = type_path(text);
+    let last = path.rsplit(['.', ':', '\\']).next()?;
+    last.starts_with(|c: char| c.is_ascii_uppercase())
+        .then(|| last.to_string())
+}
+
+// 读取开头的类型路径（A::B / a.B / A\B），返回 (路径, 剩余文本)
+fn type_path(text: &str) -> (&str, &str) {
+    let mut t = text.trim_start();
+    loop {
+        let stripped = t
+            .trim_start_matches(['&', '*', '?'])
+            .trim_start_matches("mut ")
+            .trim_start_matches("dyn ")
+            .trim_start_matches("impl ")
+            .trim_start_matches("new ")
+            .trim_start();
+        if stripped.len() == t.len() {
+            break;
+        }
+        t = stripped;
+    }
+    let end = t
+        .find(|c: char| !is_ident_char(c) && !matches!(c, '.' | ':' | '\\'))
+        .unwrap_or(t.len());
+    (&t[..end], &t[end..])
+}
+
+// 赋值右侧的构造：Foo() / new Foo<T>() / &Foo{} / Foo::new() / Foo.new / NewFoo()（Go）
+fn constructed_type(rhs: &str) -> Option<String> {
+    let (path, rest) = type_path(rhs);
+    let mut segs = path.rsplit(['.', ':', '\\']).filter(|s| !s.is_empty());
+    let last = segs.next()?;
+    if last.starts_with(|c: char| c.is_ascii_uppercase()) {
+        if !rest.starts_with(['(', '{', '<']) {
+            return None;
+        }
+        let name = match last.strip_prefix("New") {
+            Some(t) if t.starts_with(|c: char| c.is_ascii_uppercase()) => t,
+            _ => last,
+        };
+        return Some(name.to_string());
+    }
+    // 工厂 / 关联函数：Foo::new()、Foo.create()、Foo.new
+    segs.next()
+        .filter(|s| s.starts_with(|c: char| c.is_ascii_uppercase()))
+        .map(|s| s.to_string())
+}
+
+// 作为实参传入的函数引用：router.get("/x", handler) / map(f, xs) / sorted(xs, key=by_name)。
+// 只取裸标识符；是否真是函数留到链接阶段按符号表判定
+fn callback_arguments<'t>(
+    call: tree_sitter::Node<'t>,
+    callee: tree_sitter::Node<'t>,
+    content: &'t str,
+) -> Vec<tree_sitter::Node<'t>> {
+    let mut walker = call.walk();
+    // Swift 的实参表挂在 call_suffix 下
+    let Some(args) = call.child_by_field_name("arguments").or_else(|| {
+        call.named_children(&mut walker)
+            .find(|c| c.kind() == "call_suffix")
+            .and_then(|s| s.named_child(0))
+    }) else {
+        return vec![];
+    };
+    let mut walker = args.walk();
+    args.named_children(&mut walker)
+        .filter_map(|arg| match arg.kind() {
+            // 包装节点：PHP argument / Swift value_argument / Python key=value
+            "argument" | "value_argument" | "keyword_argument" => arg
+                .child_by_field_name("value")
+                .or_else(|| arg.named_child(arg.named_child_count().checked_sub(1)? as u32)),
+            _ => Some(arg),
+        })
+        .filter(|n| matches!(n.kind(), "identifier" | "simple_identifier"))
+        .filter(|n| node_text(*n, content) != node_text(callee, content))
+        .collect()
+}
+
+// 调用点的实参形状；*xs / ...xs / xs... 这类展开实参个数不定，整个调用不记
+fn call_arguments(call: tree_sitter::Node, content: &str) -> Option<CallArgs> {
+    let mut walker = call.walk();
+    let args = call.child_by_field_name("arguments").or_else(|| {
+        call.named_children(&mut walker)
+            .find(|c| c.kind() == "call_suffix")
+            .and_then(|s| s.named_child(0))
+    })?;
+    // Python f(x for x in xs)：生成器表达式本身就是唯一实参
+    if args.kind() == "generator_expression" {
+        return Some(CallArgs {
+            positional: 1,
+            keywords: vec![],
+        });
+    }
+    if !(args.kind().ends_with("arguments") || args.kind().ends_with("argument_list")) {
+        return None;
+    }
+    let mut res = CallArgs {
+        positional: 0,
+        keywords: vec![],
+    };
+    let mut walker = args.walk();
+    for arg in args.named_children(&mut walker) {
+        match arg.kind() {
+            k if k.contains("comment") => {}
+            "list_splat"
+            | "dictionary_splat"
+            | "spread_element"
+            | "splat_argument"
+            | "hash_splat_argument"
+            | "variadic_argument" => return None,
+            // Ruby &blk 传的是块，不占位置
+            "block_argument" => {}
+            "keyword_argument" => res
+                .keywords
+                .push(node_text(arg.child_by_field_name("name")?, content).to_string()),
+            // Ruby b: 1
+            "pair" => {
+                let key = arg.child_by_field_name("key")?;
+                match key.kind() {
+                    "hash_key_symbol" | "simple_symbol" => res
+                        .keywords
+                        .push(node_text(key, content).trim_matches(':').to_string()),
+                    _ => res.positional += 1,
+                }
+            }
+            // PHP 命名实参 f(b: 1)
+            "argument" if arg.child_by_field_name("name").is_some() => {
+                let name = arg.child_by_field_name("name")?;
+                res.keywords.push(node_text(name, content).to_string());
+            }
+            "argument" if arg.child(0).is_some_and(|c| c.kind() == "...") => return None,
+            _ => res.positional += 1,
+        }
+    }
+    Some(res)
+}
+
+// 类型定义的父类型 / 接口：只取类型名末段（去掉包路径与泛型参数）。
+// 各语法的继承子句节点名不同，逐个认；Swift 不区分父类与协议，一律记 extends
+fn base_types(def: tree_sitter::Node, content: &str) -> Vec<(String, &'static str)> {
+    let mut bases = vec![];
+    let mut push = |node: tree_sitter::Node, kind: &'static str| {
+        let text = node_text(node, content);
+        let head = text.split(['<', '[', '(', '{']).next().unwrap_or("").trim();
+        let name = head.rsplit(['.', ':', '\\']).next().unwrap_or("").trim();
+        if name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            bases.push((name.to_string(), kind));
+        }
+    };
+    // Rust：impl Trait for Type（符号名是 Type）
+    if def.kind() == "impl_item" {
+        if let Some(t) = def.child_by_field_name("trait") {
+            push(t, "implements");
+        }
+        return bases;
+    }
+    // Go：接口里内嵌的接口（type Named interface { Shape; Name() string }）；`int | float64` 这类约束不算
+    if def.kind() == "type_spec" {
+        if let Some(iface) = def
+            .child_by_field_name("type")
+            .filter(|t| t.kind() == "interface_type")
+        {
+            let mut walker = iface.walk();
+            for elem in iface.named_children(&mut walker) {
+                if elem.kind() == "type_elem" && elem.named_child_count() == 1 {
+                    if let Some(t) = elem
+                        .named_child(0)
+                        .filter(|t| matches!(t.kind(), "type_identifier" | "qualified_type"))
+                    {
+                        push(t, "extends");
+                    }
+                }
+            }
+        }
+        return bases;
+    }
+    let mut walker = def.walk();
+    let clauses: Vec<tree_sitter::Node> = def.named_children(&mut walker).collect();
+    for clause in clauses {
+        let kind = match clause.kind() {
+            "implements_clause" | "super_interfaces" | "class_interface_clause" => "implements",
+            "argument_list" if def.kind() == "class_definition" => "extends", // Python
+            "class_heritage"
+            | "extends_clause"
+            | "superclass"
+            | "base_class_clause"
+            | "base_clause"
+            | "extends_type_clause"
+            | "extends_interfaces" => "extends",
+            "inheritance_specifier" => {
+                push(clause, "extends");
+                continue;
+            }
+            _ => continue,
+        };
+        let mut stack = vec![clause];
+        while let Some(node) = stack.pop() {
+            let mut w = node.walk();
+            for child in node.named_children(&mut w) {
+                match child.kind() {
+                    // TS：class_heritage 里再套 extends_clause / implements_clause
+                    "extends_clause" => stack.push(child),
+                    "implements_clause" => {
+                        let mut iw = child.walk();
+                        for t in child.named_children(&mut iw) {
+                            push(t, "implements");
+                        }
+                    }
+                    "type_list" => stack.push(child),
+                    "keyword_argument" | "type_arguments" | "comment" | "access_specifier" => {}
+                    _ => push(child, kind),
+                }
+            }
+        }
+    }
+    bases
+}
+
+// ============================================================================
+// Imports (导入语句抽取)
+// 每种语法的导入节点拆成 (模块路径, 取出的名字, 本地别名)；路径保持源码原样，不做解析
+// ============================================================================
+
+fn import_entries(node: tree_sitter::Node, ext: &str, content: &str) -> Vec<PendingImport> {
+    let line = node.start_position().row + 1;
+    let text = |n: tree_sitter::Node| node_text(n, content).to_string();
+    let mut entries: Vec<(String, Option<String>, Option<String>)> = vec![];
+    let mut kind = "import";
+
+    match (node.kind(), ext) {
+        // Python：import a.b as c / from .m import x as y, *
+        ("import_statement", "py") | ("import_from_statement", _) => {
+            let module = node.child_by_field_name("module_name").map(text);
+            let mut walker = node.walk();
+            for name in node.children_by_field_name("name", &mut walker) {
+                let (target, alias) = match name.kind() {
+                    "aliased_import" => (
+                        name.child_by_field_name("name")
+                            .map(text)
+                            .unwrap_or_default(),
+                        name.child_by_field_name("alias").map(text),
+                    ),
+                    _ => (text(name), None),
+                };
+                match &module {
+                    Some(m) => entries.push((m.clone(), Some(target), alias)),
+                    None => entries.push((target, None, alias)),
+                }
+            }
+            let mut walker = node.walk();
+            let wildcard = node
+                .named_children(&mut walker)
+                .any(|c| c.kind() == "wildcard_import");
+            if let (Some(m), true) = (&module, wildcard) {
+                entries.push((m.clone(), Some("*".to_string()), None));
+            }
+        }
+        // JS / TS：import d, { a as b } from "m" / import * as ns from "m" / import "m" /
+        // export { a } from "m" / import x = require("m")
+        ("import_statement", _) | ("export_statement", _) => {
+            if node.kind() == "export_statement" {
+                kind = "reexport";
+            }
+            let mut walker = node.walk();
+            let require = node
+                .named_children(&mut walker)
+                .find(|c| c.kind() == "import_require_clause");
+            let source = node
+                .child_by_field_name("source")
+                .or_else(|| require.and_then(|r| r.child_by_field_name("source")))
+                .and_then(|s| string_literal_text(s, content))
+                .unwrap_or_default();
+            if let Some(r) = require {
+                let mut walker = r.walk();
+                let local = r
+                    .named_children(&mut walker)
+                    .find(|c| c.kind() == "identifier")
+                    .map(text);
+                entries.push((source, None, local));
+            } else {
+                let mut stack = vec![node];
+                while let Some(n) = stack.pop() {
+                    let mut walker = n.walk();
+                    for child in n.named_children(&mut walker) {
+                        match child.kind() {
+                            "import_clause" | "named_imports" | "export_clause" => {
+                                stack.push(child)
+                            }
+                            // import_clause 下的裸标识符是默认导入
+                            "identifier" if n.kind() == "import_clause" => entries.push((
+                                source.clone(),
+                                Some("default".to_string()),
+                                Some(text(child)),
+                            )),
+                            "namespace_import" | "namespace_export" => {
+                                let mut w = child.walk();
+                                let local = child.named_children(&mut w).next().map(text);
+                                entries.push((source.clone(), Some("*".to_string()), local));
+                            }
+                            "import_specifier" | "export_specifier" => {
+                                let name = child.child_by_field_name("name").map(text);
+                                let alias = child.child_by_field_name("alias").map(text);
+                                entries.push((source.clone(), name, alias));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                // 副作用导入 / export * from
+                if entries.is_empty() {
+                    let star = node.kind() == "export_statement";
+                    entries.push((source, star.then(|| "*".to_string()), None));
+                }
+            }
+        }
+        // CommonJS：const x = require("m") / const { a, b: c } = require("m")
+        ("call_expression", _) => {
+            let Some(path) = node
+                .child_by_field_name("arguments")
+                .and_then(|a| a.named_child(0))
+                .and_then(|s| string_literal_text(s, content))
+            else {
+                return vec![];
+            };
+            let pattern = node
+                .parent()
+                .filter(|p| p.kind() == "variable_declarator")
+                .and_then(|p| p.child_by_field_name("name"));
+            match pattern {
+                Some(p) if p.kind() == "object_pattern" => {
+                    let mut walker = p.walk();
+                    for prop in p.named_children(&mut walker) {
+                        match prop.kind() {
+                            "shorthand_property_identifier_pattern" => {
+                                entries.push((path.clone(), Some(text(prop)), None))
+                            }
+                            "pair_pattern" => entries.push((
+                                path.clone(),
+                                prop.child_by_field_name("key").map(text),
+                                prop.child_by_field_name("value").map(text),
+                            )),
+                            _ => {}
+                        }
+                    }
+                }
+                Some(p) if p.kind() == "identifier" => entries.push((path, None, Some(text(p)))),
+                _ => entries.push((path, None, None)),
+            }
+        }
+        // Go：import alias "path"（alias 可为 . 或 _）
+        ("import_spec", _) => {
+            if let Some(path) = node
+                .child_by_field_name("path")
+                .and_then(|p| string_literal_text(p, content))
+            {
+                entries.push((path, None, node.child_by_field_name("name").map(text)));
+            }
+        }
+        // Rust：use a::b::{c, d as e, f::*}
+        ("use_declaration", _) => {
+            if let Some(arg) = node.child_by_field_name("argument") {
+                rust_use_entries(arg, "", content, &mut entries);
+            }
+        }
+        // Java：import a.b.C; / import a.b.*; / import static a.B.m;
+        ("import_declaration", "java") => {
+            let mut walker = node.walk();
+            let children: Vec<tree_sitter::Node> = node.named_children(&mut walker).collect();
+            let path = children
+                .iter()
+                .find(|c| matches!(c.kind(), "identifier" | "scoped_identifier"))
+                .map(|c| text(*c));
+            if let Some(path) = path {
+                if children.iter().any(|c| c.kind() == "asterisk") {
+                    entries.push((path, Some("*".to_string()), None));
+                } else {
+                    let (module, name) = path.rsplit_once('.').unwrap_or(("", &path));
+                    entries.push((module.to_string(), Some(name.to_string()), None));
+                }
+            }
+        }
+        // C#：using System.Text; / using static System.Math; / using Json = Newtonsoft.Json;
+        ("using_directive", _) => {
+            let alias = node.child_by_field_name("name").map(text);
+            let mut walker = node.walk();
+            let target = node
+                .named_children(&mut walker)
+                .filter(|c| Some(c.id()) != node.child_by_field_name("name").map(|n| n.id()))
+                .find(|c| matches!(c.kind(), "identifier" | "qualified_name" | "generic_name"));
+            if let Some(t) = target {
+                entries.push((text(t), None, alias));
+            }
+        }
+        // Swift：import Foundation / import struct Foo.Bar
+        ("import_declaration", _) => {
+            let mut walker = node.walk();
+            let module = node
+                .named_children(&mut walker)
+                .find(|c| c.kind() == "identifier");
+            if let Some(id) = module {
+                entries.push((text(id), None, None));
+            }
+        }
+        // C / C++：#include "x.h" / <x.h>；Objective-C 的 #import 在 C 语法里是 preproc_call
+        ("preproc_include", _) | ("preproc_call", _) => {
+            let target = node
+                .child_by_field_name("path")
+                .or_else(|| node.child_by_field_name("argument"))
+                .map(text)
+                .unwrap_or_default();
+            let path = target.trim().trim_matches(['"', '<', '>']).to_string();
+            if node.kind() == "preproc_include" {
+                kind = "include";
+            }
+            entries.push((path, None, None));
+        }
+        // Ruby：require "x" / require_relative "x"
+        ("call", "rb") => {
+            if let Some(path) = node
+                .child_by_field_name("arguments")
+                .and_then(|a| a.named_child(0))
+                .and_then(|s| string_literal_text(s, content))
+            {
+                entries.push((path, None, None));
+            }
+        }
+        // PHP：use A\B\C as D; / use A\{B, C as D}; / require_once "x.php"
+        ("namespace_use_declaration", _) => {
+            let mut walker = node.walk();
+            let children: Vec<tree_sitter::Node> = node.named_children(&mut walker).collect();
+            let prefix = children
+                .iter()
+                .find(|c| c.kind() == "namespace_name")
+                .map(|c| text(*c));
+            let mut clauses = vec![];
+            for child in children {
+                match child.kind() {
+                    "namespace_use_clause" => clauses.push(child),
+                    "namespace_use_group" => {
+                        let mut w = child.walk();
+                        clauses.extend(child.named_children(&mut w));
+                    }
+                    _ => {}
+                }
+            }
+            for clause in clauses {
+                let mut w = clause.walk();
+                let Some(name) = clause
+                    .named_children(&mut w)
+                    .find(|c| matches!(c.kind(), "name" | "qualified_name"))
+                    .map(text)
+                else {
+                    continue;
+                };
+                let full = match &prefix {
+                    Some(p) => format!("{}\\{}", p, name),
+                    None => name,
+                };
+                let full = full.trim_start_matches('\\');
+                let (module, symbol) = full.rsplit_once('\\').unwrap_or(("", full));
+                entries.push((
+                    module.to_string(),
+                    Some(symbol.to_string()),
+                    clause.child_by_field_name("alias").map(text),
+                ));
+            }
+        }
+        (
+            "require_expression"
+            | "require_once_expression"
+            | "include_expression"
+            | "include_once_expression",
+            _,
+        ) => {
+            // __DIR__ . "/x.php" 之类的拼接只取字面量部分
+            let mut stack = vec![node];
+            while let Some(n) = stack.pop() {
+                if let Some(path) = string_literal_text(n, content) {
+                    entries.push((path, None, None));
+                    break;
+                }
+                let mut w = n.walk();
+                let children: Vec<tree_sitter::Node> = n.named_children(&mut w).collect();
+                stack.extend(children.into_iter().rev());
+            }
+        }
+        // Elixir：alias Foo.Bar（本地名 Bar）/ alias Foo.Bar, as: B / import / require / use
+        ("call", _) => {
+            let keyword = node.child_by_field_name("target").map(text);
+            let mut walker = node.walk();
+            let Some(args) = node
+                .named_children(&mut walker)
+                .find(|c| c.kind() == "arguments")
+            else {
+                return vec![];
+            };
+            let Some(module) = args.named_child(0).map(text) else {
+                return vec![];
+            };
+            let explicit = node_text(args, content)
+                .split_once("as:")
+                .map(|(_, rest)| rest.trim().trim_end_matches(')').trim().to_string());
+            let alias = match keyword.as_deref() {
+                Some("alias") => {
+                    explicit.or_else(|| module.rsplit('.').next().map(|s| s.to_string()))
+                }
+                _ => None,
+            };
+            entries.push((module, None, alias));
+        }
+        _ => {}
+    }
+
+    entries
+        .into_iter()
+        .filter(|(path, symbol, _)| !path.is_empty() || symbol.is_some())
+        .map(|(imported_path, imported_symbol, alias)| PendingImport {
+            imported_path,
+            imported_symbol,
+            alias,
+            kind: kind.to_string(),
+            line,
+        })
+        .collect()
+}
+
+// Rust use 树展开：前缀沿 scoped_use_list 累积，末段作为导入名
+fn rust_use_entries(
+    node: tree_sitter::Node,
+    prefix: &str,
+    content: &str,
+    entries: &mut Vec<(String, Option<String>, Option<String>)>,
+) {
+    let join = |p: &str| {
+        if prefix.is_empty() {
+            p.to_string()
+        } else {
+            format!("{}::{}", prefix, p)
+        }
+    };
+    let split = |full: String| -> (String, Option<String>) {
+        match full.rsplit_once("::") {
+            Some((module, name)) => (module.to_string(), Some(name.to_string())),
+            None => (full, None),
+        }
+    };
+    match node.kind() {
+        "use_as_clause" => {
+            let path = node
+                .child_by_field_name("path")
+                .map(|p| join(node_text(p, content)))
+                .unwrap_or_default();
+            let (module, name) = split(path);
+            let alias = node
+                .child_by_field_name("alias")
+                .map(|a| node_text(a, content).to_string());
+            entries.push((module, name, alias));
+        }
+        "scoped_use_list" => {
+            let inner = node
+                .child_by_field_name("path")
+                .map(|p| join(node_text(p, content)))
+                .unwrap_or_else(|| prefix.to_string());
+            if let Some(list) = node.child_by_field_name("list") {
+                rust_use_entries(list, &inner, content, entries);
+            }
+        }
+        "use_list" => {
+            let mut walker = node.walk();
+            for child in node.named_children(&mut walker) {
+                rust_use_entries(child, prefix, content, entries);
+            }
+        }
+        "use_wildcard" => {
+            let mut walker = node.walk();
+            let path = node
+                .named_children(&mut walker)
+                .next()
+                .map(|p| join(node_text(p, content)))
+                .unwrap_or_else(|| prefix.to_string());
+            entries.push((path, Some("*".to_string()), None));
+        }
+        // use a::{self} 导入模块本身
+        "self" if !prefix.is_empty() => entries.push((prefix.to_string(), None, None)),
+        _ => {
+            let (module, name) = split(join(node_text(node, content)));
+            entries.push((module, name, None));
+        }
+    }
+}
+
+// 扩展名对应的抽取器版本（tree-sitter / 文本扫描 / SFC / Objective-C）；None 表示不支持该扩展名
+fn file_extractor_version(parsers: &ParserSetup, ext: &str) -> Option<String> {
+    match (
+        parsers.get(ext),
+        text_extractor(ext),
+        sfc_extractor(ext),
+        objc_extractor(ext),
+    ) {
+        (Some((_, _, v)), _, _, _) => Some(v.clone()),
+        (None, Some((_, rev)), _, _) => Some(text_extractor_version(ext, rev)),
+        (None, None, Some(rev), _) => Some(sfc_extractor_version(parsers, ext, rev)),
+        (None, None, None, Some((host, rev))) => {
+            Some(objc_extractor_version(parsers, host, ext, rev))
+        }
+        (None, None, None, None) => None,
+    }
+}
+
+// 单文件抽取入口：按扩展名分派到语法 / SFC / Objective-C / 文本扫描器，
+// 再把落库的名字统一成 NFC（见 normalize_identifiers）
+fn extract_with_fallback(
+    parsers: &ParserSetup,
+    ext: &str,
+    content: &str,
+    features: ExtractFeatures,
+) -> ParsedFile {
+    let mut parsed = extract_by_grammar(parsers, ext, content, features);
+    normalize_identifiers(&mut parsed);
+    parsed
+}
+
+// .js 常混有 Flow 类型注解等 JS 语法解析不了的方言：出错时用 TSX 语法重试，
+// 重试无错才采用，否则保留原结果（部分树）
+fn extract_by_grammar(
+    parsers: &ParserSetup,
+    ext: &str,
+    content: &str,
+    features: ExtractFeatures,
+) -> ParsedFile {
+    let Some((lang, query, _)) = parsers.get(ext) else {
+        if sfc_extractor(ext).is_some() {
+            return extract_sfc(parsers, content, features);
+        }
+        if objc_extractor(ext).is_some() {
+            return extract_objc(parsers, ext, content, features);
+        }
+        let (extract, _) = text_extractor(ext).expect("only supported extensions are dispatched");
+        return extract(content, features);
+    };
+    let parsed = extract_file(lang, query, content, ext, features);
+    if parsed.error_nodes == 0 || !matches!(ext, "js" | "mjs" | "cjs" | "jsx") {
+        return parsed;
+    }
+    let Some((tsx_lang, tsx_query, _)) = parsers.get("tsx") else {
+        return parsed;
+    };
+    let mut retry = extract_file(tsx_lang, tsx_query, content, ext, features);
+    if retry.error_nodes > 0 {
+        return parsed;
+    }
+    retry.grammar = "tsx".to_string();
+    retry
+}
+
+// ============================================================================
+// Identifier Normalization (标识符 Unicode 规范化)
+// 同一个 é / 가 在源码里可能是 NFC 也可能是 NFD 字节序列（macOS 文件系统、输入法差异），
+// 落库的名字和查询串统一成 NFC，精确匹配与调用链接才不会因编码形式不同而失配
+// ============================================================================
+
+fn nfc(s: &str) -> String {
+    if is_nfc_quick(s.chars()) == IsNormalized::Yes {
+        return s.to_string();
+    }
+    s.nfc().collect()
+}
+
+fn normalize_in_place(s: &mut String) {
+    if is_nfc_quick(s.chars()) != IsNormalized::Yes {
+        *s = s.nfc().collect();
+    }
+}
+
+fn normalize_identifiers(parsed: &mut ParsedFile) {
+    for sym in &mut parsed.symbols {
+        normalize_in_place(&mut sym.name);
+        normalize_in_place(&mut sym.qualified_name);
+        normalize_in_place(&mut sym.scope_path);
+    }
+    for call in &mut parsed.calls {
+        normalize_in_place(&mut call.callee_name);
+        if let Some(receiver) = &mut call.receiver_type {
+            normalize_in_place(receiver);
+        }
+    }
+    for inherit in &mut parsed.inherits {
+        normalize_in_place(&mut inherit.base_name);
+    }
+    for decorator in &mut parsed.decorators {
+        normalize_in_place(&mut decorator.name);
+    }
+}
+
+// 前 n 个字符（不是字节）；不足 n 个字符返回 None。多字节字符不会被从中间截断
+fn char_prefix(s: &str, n: usize) -> Option<&str> {
+    match s.char_indices().nth(n) {
+        Some((end, _)) => Some(&s[..end]),
+        None if s.chars().count() == n => Some(s),
+        None => None,
+    }
+}
+
+// ============================================================================
+// Single-File Components (Vue SFC：抽出 <script> 块交给 JS/TS 语法)
+// ============================================================================
+
+// 扩展名 -> 修订号；改脚本块识别逻辑时递增。宿主语法（js + ts）没编译进来时不支持，文件跳过
+fn sfc_extractor(ext: &str) -> Option<u32> {
+    match ext {
+        "vue" if cfg!(all(feature = "javascript", feature = "typescript")) => Some(1),
+        _ => None,
+    }
+}
+
+// 宿主语法（js + ts）任一变化都要重抽
+fn sfc_extractor_version(parsers: &ParserSetup, ext: &str, rev: u32) -> String {
+    let host = |e: &str| parsers.get(e).map(|p| p.2.as_str()).unwrap_or("");
+    format!("{}+{}-sfc-{}{}", host("js"), host("ts"), ext, rev)
+}
+
+// <script> 块之外的内容逐字节换成空格（保留换行）：脚本在原位置被解析，
+// 行号与字节偏移和 .vue 文件一一对应，无需再换算。返回 (遮罩后的文本, 宿主扩展名)
+fn sfc_script_mask(content: &str) -> Option<(String, &'static str)> {
+    let lower = content.to_ascii_lowercase();
+    let mut regions = vec![];
+    let mut host = "js";
+    let mut pos = 0;
+    while let Some(open) = lower[pos..].find("<script").map(|i| pos + i) {
+        let Some(tag_end) = lower[open..].find('>').map(|i| open + i) else {
+            break;
+        };
+        let attrs = &lower[open + "<script".len()..tag_end];
+        // <script setup lang="ts"> / lang='tsx'；一个块是 TS 就整体按 TS 解析
+        for (lang, ext) in [("tsx", "tsx"), ("ts", "ts")] {
+            if attrs.contains(&format!("lang=\"{}\"", lang))
+                || attrs.contains(&format!("lang='{}'", lang))
+            {
+                if host != "tsx" {
+                    host = ext;
+                }
+                break;
+            }
+        }
+        let body_start = tag_end + 1;
+        let body_end = lower[body_start..]
+            .find("</script")
+            .map_or(content.len(), |i| body_start + i);
+        regions.push(body_start..body_end);
+        pos = body_end;
+    }
+    if regions.is_empty() {
+        return None;
+    }
+    // 区域边界都落在 ASCII（'>' 之后 / '<' 之前），不会切开多字节字符
+    let masked: Vec<u8> = content
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| {
+            if b == b'\n' || regions.iter().any(|r| r.contains(&i)) {
+                b
+            } else {
+                b' '
+            }
+        })
+        .collect();
+    Some((String::from_utf8(masked).ok()?, host))
+}
+
+fn extract_sfc(parsers: &ParserSetup, content: &str, features: ExtractFeatures) -> ParsedFile {
+    let Some((masked, host)) = sfc_script_mask(content) else {
+        // 纯模板 / 纯样式组件
+        return ParsedFile {
+            symbols: vec![],
+            calls: vec![],
+            routes: vec![],
+            inherits: vec![],
+            imports: vec![],
+            decorators: vec![],
+            attributes: vec![],
+            grammar: String::new(),
+            error_nodes: 0,
+        };
+    };
+    let mut parsed = extract_with_fallback(parsers, host, &masked, features);
+    if parsed.grammar.is_empty() {
+        parsed.grammar = host.to_string();
+    }
+    parsed
+}
+
+// ============================================================================
+// Text Extractors (无可用 tree-sitter 语法的语言：词法扫描 + 块配对)
+// 离线构建拿不到对应 grammar crate 时的退路，产出与 extract_file 相同的 ParsedFile
+// ============================================================================
+
+type TextExtractFn = fn(&str, ExtractFeatures) -> ParsedFile;
+
+// 扩展名 -> (抽取函数, 修订号)；修订号进 extractor_version，改扫描逻辑时递增
+fn text_extractor(ext: &str) -> Option<(TextExtractFn, u32)> {
+    match ext {
+        "lua" => Some((extract_lua, 1)),
+        "hs" => Some((extract_haskell, 1)),
+        "sql" => Some((extract_sql, 1)),
+        "proto" => Some((extract_proto, 1)),
+        "tf" => Some((extract_hcl, 1)),
+        _ => None,
+    }
+}
+
+fn text_extractor_version(ext: &str, rev: u32) -> String {
+    format!("v{}-text-{}{}", PARSE_CACHE_SCHEMA, ext, rev)
+}
+
+#[derive(Debug, PartialEq)]
+enum LuaTok {
+    Ident(String),
+    Str,
+    Punct(char),
+}
+
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// `[[` / `[==[` 长括号的等号层级
+fn lua_long_bracket(chars: &[char], i: usize) -> Option<usize> {
+    if chars.get(i) != Some(&'[') {
+        return None;
+    }
+    let level = chars[i + 1..].iter().take_while(|c| **c == '=').count();
+    (chars.get(i + 1 + level) == Some(&'[')).then_some(level)
+}
+
+// 跳过长括号内容，返回闭合后的位置
+fn lua_skip_long_bracket(chars: &[char], mut i: usize, level: usize, line: &mut usize) -> usize {
+    i += level + 2;
+    while i < chars.len() {
+        if chars[i] == '\n' {
+            *line += 1;
+        } else if chars[i] == ']'
+            && chars[i + 1..].iter().take_while(|c| **c == '=').count() >= level
+            && chars.get(i + 1 + level) == Some(&']')
+        {
+            return i + level + 2;
+        }
+        i += 1;
+    }
+    i
+}
+
+// 词法切分：丢弃注释与数字，字符串折叠为 Str（只需判断 f "x" 形式的调用）
+fn lua_tokens(content: &str) -> Vec<(LuaTok, usize)> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut toks = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            i += 2;
+            match lua_long_bracket(&chars, i) {
+                Some(level) => i = lua_skip_long_bracket(&chars, i, level, &mut line),
+                None => {
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                }
+            }
+        } else if let Some(level) = lua_long_bracket(&chars, i) {
+            toks.push((LuaTok::Str, line));
+            i = lua_skip_long_bracket(&chars, i, level, &mut line);
+        } else if c == '"' || c == '\'' {
+            toks.push((LuaTok::Str, line));
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                if chars[i] == '\\' {
+                    i += 1;
+                    if chars.get(i) == Some(&'\n') {
+                        line += 1;
+                    }
+                }
+                i += 1;
+            }
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push((LuaTok::Ident(chars[start..i].iter().collect()), line));
+        } else if c.is_ascii_digit() {
+            // 数字字面量（含 0x1F / 1e10 / 3.14），遇到 `..` 拼接符停下
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '.')
+                && !(chars[i] == '.' && chars.get(i + 1) == Some(&'.'))
+            {
+                i += 1;
+            }
+        } else {
+            toks.push((LuaTok::Punct(c), line));
+            i += 1;
+        }
+    }
+    toks
+}
+
+// `x = function` / `local x = function` / `M.a.b = function`：回看赋值左侧的名字路径
+fn lua_assigned_name(toks: &[(LuaTok, usize)], func_idx: usize) -> Vec<String> {
+    let is_punct =
+        |i: usize, c: char| matches!(toks.get(i), Some((LuaTok::Punct(p), _)) if *p == c);
+    if func_idx < 2 || !is_punct(func_idx - 1, '=') {
+        return vec![];
+    }
+    // 排除 == ~= <= >=
+    if ['=', '~', '<', '>']
+        .iter()
+        .any(|c| is_punct(func_idx - 2, *c))
+    {
+        return vec![];
+    }
+    let mut path = vec![];
+    let mut j = func_idx - 2;
+    loop {
+        match &toks[j].0 {
+            LuaTok::Ident(name) if !LUA_KEYWORDS.contains(&name.as_str()) => {
+                path.push(name.clone())
+            }
+            _ => break,
+        }
+        if j >= 2 && is_punct(j - 1, '.') {
+            j -= 2;
+        } else {
+            break;
+        }
+    }
+    path.reverse();
+    path
+}
+
+// Lua：function foo() / local function foo() / function M.foo() / function M:foo() /
+// x = function() ... end；块由 function/if/do/repeat 开启、end/until 关闭
+fn extract_lua(content: &str, features: ExtractFeatures) -> ParsedFile {
+    let toks = lua_tokens(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let ident = |i: usize| match toks.get(i) {
+        Some((LuaTok::Ident(s), _)) => Some(s.as_str()),
+        _ => None,
+    };
+    let is_punct =
+        |i: usize, c: char| matches!(toks.get(i), Some((LuaTok::Punct(p), _)) if *p == c);
+
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    // 块栈：Some(下标) 表示具名函数体
+    let mut blocks: Vec<Option<usize>> = vec![];
+
+    let mut i = 0;
+    while i < toks.len() {
+        let line = toks[i].1;
+        let Some(word) = ident(i) else {
+            i += 1;
+            continue;
+        };
+        match word {
+            "function" => {
+                let mut path = vec![];
+                let mut is_method = false;
+                let mut j = i + 1;
+                while let Some(name) = ident(j) {
+                    path.push(name.to_string());
+                    j += 1;
+                    if is_punct(j, '.') || is_punct(j, ':') {
+                        is_method = is_punct(j, ':');
+                        j += 1;
+                    } else {
+                        break;
+                    }
+                }
+                if path.is_empty() {
+                    path = lua_assigned_name(&toks, i);
+                }
+                let enclosing = blocks.iter().rev().find_map(|b| *b);
+                let sym_idx = path.pop().map(|name| {
+                    // 表前缀（M.foo / M:foo）即作用域；无前缀的嵌套函数归到外层函数下
+                    let scope = if !path.is_empty() {
+                        path.join("::")
+                    } else {
+                        enclosing
+                            .map(|idx| symbols[idx].scope_path.clone())
+                            .unwrap_or_default()
+                    };
+                    let scope_path = if scope.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}::{}", scope, name)
+                    };
+                    symbols.push(PendingSymbol {
+                        temp_id: symbols.len() + 1,
+                        parent_temp_id: enclosing.map(|idx| symbols[idx].temp_id),
+                        name,
+                        qualified_name: scope_path.clone(),
+                        scope_path,
+                        symbol_type: if is_method { "method" } else { "function" }.to_string(),
+                        line_start: line,
+                        line_end: line,
+                        signature: features
+                            .signatures
+                            .then(|| lines.get(line - 1).map(|l| l.trim().to_string()))
+                            .flatten(),
+                        doc: None,
+                        visibility: None,
+                        anchor: None,
+                        signature_shape: None,
+                    });
+                    symbols.len() - 1
+                });
+                blocks.push(sym_idx);
+                i = j;
+                continue;
+            }
+            "if" | "do" | "repeat" => blocks.push(None),
+            "end" | "until" => {
+                if let Some(Some(idx)) = blocks.pop() {
+                    symbols[idx].line_end = line;
+                }
+            }
+            kw if LUA_KEYWORDS.contains(&kw) => {}
+            name => {
+                // 调用：f(...) / f "s" / f {...}；a.b.c() 与 obj:m() 逐段扫描，自然落在最后一段
+                let has_args = is_punct(i + 1, '(')
+                    || is_punct(i + 1, '{')
+                    || matches!(toks.get(i + 1), Some((LuaTok::Str, _)));
+                if has_args && features.calls {
+                    if let Some(caller) = blocks.iter().rev().find_map(|b| *b) {
+                        calls.push(PendingCall {
+                            caller_temp_id: symbols[caller].temp_id,
+                            callee_name: name.to_string(),
+                            line,
+                            kind: "call".to_string(),
+                            receiver_type: None,
+                            args: None,
+                        });
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, "lua");
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits: vec![],
+        imports: vec![],
+        decorators: vec![],
+        attributes: vec![],
+        grammar: String::new(),
+        error_nodes: blocks.len(), // 未配平的块
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum HsTok {
+    Var(String), // 小写开头标识符，可带模块限定（Map.insert）
+    Con(String), // 大写开头：类型 / 构造器 / 类型类
+    Op(String),
+    Lit,
+    Punct(char),
+}
+
+struct HsToken {
+    tok: HsTok,
+    line: usize,
+    col: usize,
+    bol: bool, // 行首 token：Haskell 靠缩进（layout）划分声明
+}
+
+const HS_KEYWORDS: &[&str] = &[
+    "case", "class", "data", "default", "deriving", "do", "else", "family", "forall", "foreign",
+    "if", "import", "in", "infix", "infixl", "infixr", "instance", "let", "mdo", "module",
+    "newtype", "of", "then", "type", "where",
+];
+
+fn hs_is_symbol(c: char) -> bool {
+    "!#$%&*+./<=>?@\\^|-~:".contains(c)
+}
+
+// 词法切分：丢弃注释（-- 与可嵌套的 {- -}，含 pragma），字符串/字符/数字折叠为 Lit
+fn hs_tokens(content: &str) -> Vec<HsToken> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut toks: Vec<HsToken> = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    let mut line_begin = 0;
+    let mut last_line = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let start_line = line;
+        let tok = if c == '\n' {
+            line += 1;
+            line_begin = i + 1;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '{' && chars.get(i + 1) == Some(&'-') {
+            let mut depth = 0;
+            while i < chars.len() {
+                if chars[i] == '{' && chars.get(i + 1) == Some(&'-') {
+                    depth += 1;
+                    i += 2;
+                } else if chars[i] == '-' && chars.get(i + 1) == Some(&'}') {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    if chars[i] == '\n' {
+                        line += 1;
+                        line_begin = i + 1;
+                    }
+                    i += 1;
+                }
+            }
+            continue;
+        } else if c.is_alphabetic() || c == '_' {
+            // 限定名：Data.Map.insert 整体作为一个 token
+            loop {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '\'')
+                {
+                    i += 1;
+                }
+                let segment_upper = chars[start..i]
+                    .iter()
+                    .rposition(|c| *c == '.')
+                    .map_or(chars[start], |p| chars[start + p + 1])
+                    .is_uppercase();
+                let continues = chars.get(i) == Some(&'.')
+                    && chars
+                        .get(i + 1)
+                        .is_some_and(|c| c.is_alphabetic() || *c == '_');
+                if segment_upper && continues {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let last = text.rsplit('.').next().unwrap_or(&text);
+            if last.starts_with(|c: char| c.is_uppercase()) {
+                HsTok::Con(text)
+            } else {
+                HsTok::Var(text)
+            }
+        } else if c.is_ascii_digit() {
+            // 数字字面量（0x1F / 1e10 / 3.14），遇到 `..` 区间停下
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                && (chars[i] != '.' || chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+            {
+                i += 1;
+            }
+            HsTok::Lit
+        } else if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' && chars[i] != '\n' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            HsTok::Lit
+        } else if c == '\'' {
+            // 'a' / '\n' 是字符字面量；其余（TH 的 'name / ''Type）直接跳过
+            if chars.get(i + 1) == Some(&'\\') || chars.get(i + 2) == Some(&'\'') {
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' && chars[i] != '\n' {
+                    i += 1;
+                }
+                i += 1;
+                HsTok::Lit
+            } else {
+                i += 1;
+                continue;
+            }
+        } else if hs_is_symbol(c) {
+            while i < chars.len() && hs_is_symbol(chars[i]) {
+                i += 1;
+            }
+            let op: String = chars[start..i].iter().collect();
+            if op.len() >= 2 && op.chars().all(|c| c == '-') {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            HsTok::Op(op)
+        } else if "()[],;{}`".contains(c) {
+            i += 1;
+            HsTok::Punct(c)
+        } else {
+            i += 1;
+            continue;
+        };
+        toks.push(HsToken {
+            tok,
+            line: start_line,
+            col: start - line_begin,
+            bol: start_line != last_line,
+        });
+        last_line = start_line;
+    }
+    toks
+}
+
+// 按缩进切分声明：遇到列号不大于 indent 的行首 token 即开始新声明
+fn hs_split_decls(toks: &[HsToken], indent: usize) -> Vec<&[HsToken]> {
+    let mut decls = vec![];
+    let mut start = 0;
+    for (i, t) in toks.iter().enumerate().skip(1) {
+        if t.bol && t.col <= indent {
+            decls.push(&toks[start..i]);
+            start = i;
+        }
+    }
+    if !toks.is_empty() {
+        decls.push(&toks[start..]);
+    }
+    decls
+}
+
+fn hs_is_var(t: Option<&HsToken>, name: &str) -> bool {
+    matches!(t, Some(HsToken { tok: HsTok::Var(v), .. }) if v == name)
+}
+
+fn hs_is_op(t: Option<&HsToken>, op: &str) -> bool {
+    matches!(t, Some(HsToken { tok: HsTok::Op(o), .. }) if o == op)
+}
+
+// 绑定/签名声明的名字：`foo x = ...` / `foo, bar :: T` / `(<+>) a b = ...` / 中缀定义 `a <+> b = ...`；
+// 返回 (名字, 是否为类型签名, 左侧名字部分的 token 数)
+fn hs_binding_names(decl: &[HsToken]) -> (Vec<String>, bool, usize) {
+    let mut names = vec![];
+    let mut i = 0;
+    loop {
+        match decl.get(i).map(|t| &t.tok) {
+            Some(HsTok::Var(v)) if !HS_KEYWORDS.contains(&v.as_str()) && !v.contains('.') => {
+                names.push(v.clone());
+                i += 1;
+            }
+            Some(HsTok::Punct('(')) => match (decl.get(i + 1), decl.get(i + 2)) {
+                (
+                    Some(HsToken {
+                        tok: HsTok::Op(op), ..
+                    }),
+                    Some(t),
+                ) if t.tok == HsTok::Punct(')') => {
+                    names.push(op.clone());
+                    i += 3;
+                }
+                _ => break,
+            },
+            _ => break,
+        }
+        if decl.get(i).is_some_and(|t| t.tok == HsTok::Punct(',')) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    let is_sig = hs_is_op(decl.get(i), "::");
+    if is_sig {
+        return (names, true, i);
+    }
+    names.truncate(1);
+    if names.len() == 1 && i == 1 {
+        match (decl.get(1).map(|t| &t.tok), decl.get(2).map(|t| &t.tok)) {
+            (Some(HsTok::Op(op)), _) if !matches!(op.as_str(), "=" | "|" | "@" | "!") => {
+                return (vec![op.clone()], false, 2);
+            }
+            (Some(HsTok::Punct('`')), Some(HsTok::Var(v))) => {
+                return (vec![v.clone()], false, 4);
+            }
+            _ => {}
+        }
+    }
+    (names, false, i)
+}
+
+// 类型声明头部的名字：跳过 family/instance 修饰与 `Ctx =>` 上下文，取第一个大写名
+fn hs_head_names(decl: &[HsToken]) -> Vec<String> {
+    let end = decl
+        .iter()
+        .position(|t| {
+            hs_is_op(Some(t), "=") || hs_is_var(Some(t), "where") || hs_is_op(Some(t), "::")
+        })
+        .unwrap_or(decl.len());
+    let head = &decl[1..end.max(1)];
+    let from = head
+        .iter()
+        .position(|t| hs_is_op(Some(t), "=>"))
+        .map_or(0, |p| p + 1);
+    head[from..]
+        .iter()
+        .filter_map(|t| match &t.tok {
+            HsTok::Con(c) => Some(c.rsplit('.').next().unwrap_or(c).to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+// `data Color = Red | Green | Blue`：所有构造器都无参数时视为枚举
+fn hs_is_enum(decl: &[HsToken]) -> bool {
+    let Some(eq) = decl.iter().position(|t| hs_is_op(Some(t), "=")) else {
+        return false;
+    };
+    let end = decl
+        .iter()
+        .position(|t| hs_is_var(Some(t), "deriving"))
+        .unwrap_or(decl.len());
+    decl[eq + 1..end.max(eq + 1)]
+        .split(|t| hs_is_op(Some(t), "|"))
+        .all(|alt| {
+            matches!(
+                alt,
+                [HsToken {
+                    tok: HsTok::Con(_),
+                    ..
+                }]
+            )
+        })
+}
+
+// 应用表达式近似调用：处于表达式起点（行首 / 运算符 / 开括号 / then、in 等关键字之后）
+// 且后面紧跟实参（或 $、. 组合）的小写名记为调用；`x `elem` xs` 的反引号中缀同样算调用
+fn hs_calls(decl: &[HsToken], skip: usize) -> Vec<(String, usize)> {
+    let mut calls = vec![];
+    for i in skip..decl.len() {
+        let HsTok::Var(name) = &decl[i].tok else {
+            continue;
+        };
+        if HS_KEYWORDS.contains(&name.as_str()) {
+            continue;
+        }
+        let prev = i.checked_sub(1).map(|p| &decl[p].tok);
+        let infix = prev == Some(&HsTok::Punct('`'));
+        // where / let 后是局部绑定的定义而非调用
+        if matches!(prev, Some(HsTok::Var(v)) if v == "where" || v == "let") {
+            continue;
+        }
+        let at_start = decl[i].bol
+            || match prev {
+                None => true,
+                Some(HsTok::Op(op)) => op != "::" && op != "\\" && op != "@",
+                Some(HsTok::Punct(p)) => "([,;{".contains(*p),
+                Some(HsTok::Var(v)) => {
+                    matches!(
+                        v.as_str(),
+                        "if" | "then" | "else" | "in" | "of" | "do" | "mdo"
+                    )
+                }
+                _ => false,
+            };
+        let applied = match decl.get(i + 1).map(|t| &t.tok) {
+            Some(HsTok::Var(v)) => !HS_KEYWORDS.contains(&v.as_str()),
+            Some(HsTok::Con(_)) | Some(HsTok::Lit) => true,
+            Some(HsTok::Punct(p)) => *p == '(' || *p == '[',
+            Some(HsTok::Op(op)) => matches!(op.as_str(), "$" | "$!" | "<$>" | "."),
+            None => false,
+        };
+        if infix || (at_start && applied) {
+            let callee = name.rsplit('.').next().unwrap_or(name);
+            calls.push((callee.to_string(), decl[i].line));
+        }
+    }
+    calls
+}
+
+// 一组绑定声明（顶层或 class/instance 的 where 体）→ 函数/方法符号，同名的相邻子句合并为一个符号
+fn hs_bindings(
+    decls: &[&[HsToken]],
+    owner: Option<usize>,
+    symbols: &mut Vec<PendingSymbol>,
+    calls: &mut Vec<PendingCall>,
+    lines: &[&str],
+    features: ExtractFeatures,
+) {
+    let mut seen: HashMap<String, (usize, bool)> = HashMap::new(); // 名字 -> (符号下标, 已有定义体)
+    let mut last: Option<String> = None;
+    for decl in decls {
+        let (names, is_sig, lhs_len) = hs_binding_names(decl);
+        let (Some(first), Some(end_tok)) = (decl.first(), decl.last()) else {
+            continue;
+        };
+        for name in &names {
+            let adjacent = last.as_deref() == Some(name.as_str());
+            let idx = match seen.get(name) {
+                Some(&(idx, has_body)) if adjacent || (!has_body && !is_sig) => {
+                    if !adjacent {
+                        symbols[idx].line_start = first.line;
+                    }
+                    symbols[idx].line_end = end_tok.line;
+                    idx
+                }
+                _ => {
+                    let scope = owner.map(|o| symbols[o].scope_path.clone());
+                    let scope_path = match &scope {
+                        Some(s) => format!("{}::{}", s, name),
+                        None => name.clone(),
+                    };
+                    let is_test = owner.is_none()
+                        && (name.starts_with("prop_")
+                            || name.starts_with("case_")
+                            || name.starts_with("test")
+                            || name == "spec");
+                    let kind = match (owner, is_test) {
+                        (Some(_), _) => "method",
+                        (None, true) => "test",
+                        (None, false) => "function",
+                    };
+                    symbols.push(PendingSymbol {
+                        temp_id: symbols.len() + 1,
+                        parent_temp_id: owner.map(|o| symbols[o].temp_id),
+                        name: name.clone(),
+                        qualified_name: scope_path.clone(),
+                        scope_path,
+                        symbol_type: kind.to_string(),
+                        line_start: first.line,
+                        line_end: end_tok.line,
+                        signature: features
+                            .signatures
+                            .then(|| lines.get(first.line - 1).map(|l| l.trim().to_string()))
+                            .flatten(),
+                        doc: None,
+                        visibility: None,
+                        anchor: None,
+                        signature_shape: None,
+                    });
+                    symbols.len() - 1
+                }
+            };
+            seen.entry(name.clone())
+                .and_modify(|e| e.1 |= !is_sig)
+                .or_insert((idx, !is_sig));
+            last = Some(name.clone());
+            if !is_sig && features.calls {
+                for (callee_name, line) in hs_calls(decl, lhs_len) {
+                    calls.push(PendingCall {
+                        caller_temp_id: symbols[idx].temp_id,
+                        callee_name,
+                        line,
+                        kind: "call".to_string(),
+                        receiver_type: None,
+                        args: None,
+                    });
+                }
+            }
+        }
+        if names.is_empty() {
+            last = None;
+        }
+    }
+}
+
+// Haskell：顶层函数绑定（签名 + 各子句合并）、data/newtype/type 声明、
+// class（接口，方法来自签名）与 instance（impl，名字取实例类型）；调用由应用表达式近似
+fn extract_haskell(content: &str, features: ExtractFeatures) -> ParsedFile {
+    let toks = hs_tokens(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    let mut unbalanced = 0;
+
+    let decls = hs_split_decls(&toks, 0);
+    let mut bindings: Vec<&[HsToken]> = vec![];
+    for decl in &decls {
+        let depth: i32 = decl
+            .iter()
+            .map(|t| match t.tok {
+                HsTok::Punct('(' | '[' | '{') => 1,
+                HsTok::Punct(')' | ']' | '}') => -1,
+                _ => 0,
+            })
+            .sum();
+        if depth != 0 {
+            unbalanced += 1;
+        }
+
+        let keyword = match &decl[0].tok {
+            HsTok::Var(v) if HS_KEYWORDS.contains(&v.as_str()) => v.as_str(),
+            _ => {
+                bindings.push(decl);
+                continue;
+            }
+        };
+        // 类型声明打断相邻子句的合并：先落地之前积累的绑定
+        hs_bindings(&bindings, None, &mut symbols, &mut calls, &lines, features);
+        bindings.clear();
+
+        let heads = hs_head_names(decl);
+        let (name, kind) = match keyword {
+            "data" | "newtype" => {
+                let kind = if keyword == "data" && hs_is_enum(decl) {
+                    "enum"
+                } else {
+                    "struct"
+                };
+                (heads.first().cloned(), kind)
+            }
+            "type" => (heads.first().cloned(), "type"),
+            "class" => (heads.first().cloned(), "interface"),
+            // instance Show (Tree a)：与 Rust impl 一致，按实例类型命名
+            "instance" => (heads.get(1).or(heads.first()).cloned(), "impl"),
+            _ => (None, ""),
+        };
+        let Some(name) = name else {
+            continue;
+        };
+        let (first, end_tok) = (&decl[0], &decl[decl.len() - 1]);
+        symbols.push(PendingSymbol {
+            temp_id: symbols.len() + 1,
+            parent_temp_id: None,
+            name: name.clone(),
+            qualified_name: name.clone(),
+            scope_path: name,
+            symbol_type: kind.to_string(),
+            line_start: first.line,
+            line_end: end_tok.line,
+            signature: None,
+            doc: None,
+            visibility: None,
+            anchor: None,
+            signature_shape: None,
+        });
+        let owner = symbols.len() - 1;
+        if matches!(keyword, "class" | "instance") {
+            if let Some(w) = decl.iter().position(|t| hs_is_var(Some(t), "where")) {
+                let body = &decl[w + 1..];
+                if let Some(indent) = body.first().map(|t| t.col) {
+                    let members = hs_split_decls(body, indent);
+                    hs_bindings(
+                        &members,
+                        Some(owner),
+                        &mut symbols,
+                        &mut calls,
+                        &lines,
+                        features,
+                    );
+                }
+            }
+        }
+    }
+    hs_bindings(&bindings, None, &mut symbols, &mut calls, &lines, features);
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, "hs");
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits: vec![],
+        imports: vec![],
+        decorators: vec![],
+        attributes: vec![],
+        grammar: String::new(),
+        error_nodes: unbalanced, // 括号未配平的声明数
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum SqlTok {
+    Ident(String), // 关键字与标识符；"x" / `x` / [x] 引号标识符去掉引号
+    Lit,           // 字符串 / 数字 / $1 参数
+    Punct(char),
+    Semi,   // `;`：BEGIN..END / $$ 函数体之外才结束语句
+    Term,   // DELIMITER 自定义分隔符 / T-SQL 独占一行的 GO：无条件结束语句
+    Dollar, // PostgreSQL $$ / $tag$ 函数体边界
+}
+
+struct SqlToken {
+    tok: SqlTok,
+    text: String,
+    line: usize,
+}
+
+// 调用扫描时排除的关键字与内置类型：它们后面跟 `(` 不是函数调用
+const SQL_KEYWORDS: &str =
+    "all and any array as begin between bigint char character check constraint create \
+    decimal declare default distinct else end exists filter float foreign from if in \
+    index insert int integer into join key not numeric on or over primary references \
+    return returns select set table then union unique update using values varchar view \
+    when where while with";
+
+fn sql_is_keyword(word: &str) -> bool {
+    let word = word.to_lowercase();
+    SQL_KEYWORDS.split_whitespace().any(|k| k == word)
+}
+
+fn sql_tokens(content: &str) -> Vec<SqlToken> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut toks: Vec<SqlToken> = vec![];
+    let mut delimiter: Vec<char> = vec![';'];
+    let mut i = 0;
+    let mut line = 1;
+    let mut at_line_start = true;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let start_line = line;
+        if c == '\n' {
+            line += 1;
+            at_line_start = true;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let line_start = at_line_start;
+        at_line_start = false;
+
+        // 行首指令：MySQL 客户端的 DELIMITER、T-SQL 的批分隔符 GO
+        if line_start && c.is_alphabetic() {
+            let mut j = i;
+            while j < chars.len() && chars[j].is_alphanumeric() {
+                j += 1;
+            }
+            let word: String = chars[i..j].iter().collect::<String>().to_lowercase();
+            let rest_end = chars[j..]
+                .iter()
+                .position(|&ch| ch == '\n')
+                .map_or(chars.len(), |p| j + p);
+            let rest: String = chars[j..rest_end].iter().collect();
+            if word == "delimiter" && !rest.trim().is_empty() {
+                delimiter = rest.trim().chars().collect();
+                i = rest_end;
+                continue;
+            }
+            if word == "go" && rest.trim().chars().all(|ch| ch.is_ascii_digit()) {
+                toks.push(SqlToken {
+                    tok: SqlTok::Term,
+                    text: String::new(),
+                    line,
+                });
+                i = rest_end;
+                continue;
+            }
+        }
+
+        if delimiter != [';'] && chars[i..].starts_with(&delimiter) {
+            toks.push(SqlToken {
+                tok: SqlTok::Term,
+                text: String::new(),
+                line,
+            });
+            i += delimiter.len();
+            continue;
+        }
+
+        let tok = if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if c == '\'' {
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == '\'' {
+                    // '' 是转义的单引号
+                    if chars.get(i + 1) == Some(&'\'') {
+                        i += 2;
+                        continue;
+                    }
+                    break;
+                }
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            SqlTok::Lit
+        } else if c == '"' || c == '`' || c == '[' {
+            let close = match c {
+                '[' => ']',
+                q => q,
+            };
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&ch| ch == close || ch == '\n')
+                .map(|p| i + 1 + p);
+            match end {
+                Some(end) if chars[end] == close && end > i + 1 => {
+                    let name: String = chars[i + 1..end].iter().collect();
+                    i = end + 1;
+                    SqlTok::Ident(name)
+                }
+                // int[] / 未闭合的引号：按标点处理
+                _ => {
+                    i += 1;
+                    SqlTok::Punct(c)
+                }
+            }
+        } else if c == '$' {
+            // $$ / $tag$ 是函数体边界；$1 是位置参数
+            let mut j = i + 1;
+            while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_') {
+                j += 1;
+            }
+            if chars.get(j) == Some(&'$') && !chars.get(i + 1).is_some_and(|d| d.is_ascii_digit()) {
+                i = j + 1;
+                SqlTok::Dollar
+            } else {
+                i = j;
+                SqlTok::Lit
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '@' || c == '#' {
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_$@#".contains(chars[i])) {
+                i += 1;
+            }
+            SqlTok::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            SqlTok::Lit
+        } else if c == ';' {
+            i += 1;
+            SqlTok::Semi
+        } else {
+            i += 1;
+            SqlTok::Punct(c)
+        };
+        toks.push(SqlToken {
+            text: chars[start..i].iter().collect(),
+            tok,
+            line: start_line,
+        });
+    }
+    toks
+}
+
+fn sql_word(t: Option<&SqlToken>) -> Option<String> {
+    match t {
+        Some(SqlToken {
+            tok: SqlTok::Ident(s),
+            ..
+        }) => Some(s.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn sql_is_word(t: Option<&SqlToken>, word: &str) -> bool {
+    sql_word(t).is_some_and(|w| w == word)
+}
+
+// 按 `;` / 分隔符切语句；BEGIN..END、CASE..END 与 $$ 函数体内的 `;` 不切
+fn sql_split_statements(toks: &[SqlToken]) -> Vec<&[SqlToken]> {
+    let mut stmts = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut in_dollar = false;
+    for (i, t) in toks.iter().enumerate() {
+        let split = match &t.tok {
+            SqlTok::Dollar => {
+                in_dollar = !in_dollar;
+                false
+            }
+            SqlTok::Term => true,
+            SqlTok::Semi => depth == 0 && !in_dollar,
+            SqlTok::Ident(_) if !in_dollar => {
+                match sql_word(Some(t)).as_deref() {
+                    // BEGIN TRANSACTION / BEGIN; 是事务语句，不开块
+                    Some("begin")
+                        if !matches!(
+                            toks.get(i + 1).map(|n| &n.tok),
+                            Some(SqlTok::Semi | SqlTok::Term)
+                        ) && !sql_word(toks.get(i + 1)).is_some_and(|w| {
+                            matches!(w.as_str(), "transaction" | "tran" | "work")
+                        }) =>
+                    {
+                        depth += 1
+                    }
+                    Some("case") if !sql_is_word(toks.get(i.wrapping_sub(1)), "end") => depth += 1,
+                    // END IF / END LOOP 结束的是未计数的控制块
+                    Some("end")
+                        if !sql_word(toks.get(i + 1)).is_some_and(|w| {
+                            matches!(w.as_str(), "if" | "loop" | "while" | "repeat" | "for")
+                        }) =>
+                    {
+                        depth = depth.saturating_sub(1)
+                    }
+                    _ => {}
+                }
+                false
+            }
+            _ => false,
+        };
+        if split {
+            if i > start {
+                stmts.push(&toks[start..i]);
+            }
+            start = i + 1;
+            depth = 0;
+            in_dollar = false;
+        }
+    }
+    if start < toks.len() {
+        stmts.push(&toks[start..]);
+    }
+    stmts
+}
+
+// 限定名 schema.name：返回 (各段, 之后的下标)
+fn sql_qualified_name(toks: &[SqlToken], mut i: usize) -> (Vec<String>, usize) {
+    let mut parts = vec![];
+    while let Some(SqlToken {
+        tok: SqlTok::Ident(name),
+        ..
+    }) = toks.get(i)
+    {
+        parts.push(name.clone());
+        i += 1;
+        if matches!(toks.get(i).map(|t| &t.tok), Some(SqlTok::Punct('.'))) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    (parts, i)
+}
+
+// 以单个空格重建语句片段，括号 / 逗号 / 点号两侧不加空格：用作签名，列定义变化即签名变化
+fn sql_render(toks: &[SqlToken]) -> String {
+    let mut out = String::new();
+    let mut prev = "";
+    for t in toks {
+        let tight = matches!(t.text.as_str(), "," | ")" | "." | "(") || matches!(prev, "(" | ".");
+        if !out.is_empty() && !tight {
+            out.push(' ');
+        }
+        out.push_str(&t.text);
+        prev = &t.text;
+    }
+    out
+}
+
+fn extract_sql(content: &str, features: ExtractFeatures) -> ParsedFile {
+    let toks = sql_tokens(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    let mut unbalanced = 0;
+
+    for stmt in sql_split_statements(&toks) {
+        let depth: i32 = stmt
+            .iter()
+            .map(|t| match t.tok {
+                SqlTok::Punct('(') => 1,
+                SqlTok::Punct(')') => -1,
+                _ => 0,
+            })
+            .sum();
+        if depth != 0 {
+            unbalanced += 1;
+        }
+        if !sql_is_word(stmt.first(), "create") {
+            continue;
+        }
+
+        // CREATE [OR REPLACE | OR ALTER] [TEMP | MATERIALIZED | DEFINER = ... ] <kind> [IF NOT EXISTS] name
+        let Some(kind_at) = stmt.iter().take(16).position(|t| {
+            sql_word(Some(t)).is_some_and(|w| {
+                matches!(
+                    w.as_str(),
+                    "table"
+                        | "view"
+                        | "function"
+                        | "procedure"
+                        | "proc"
+                        | "index"
+                        | "trigger"
+                        | "type"
+                        | "schema"
+                        | "sequence"
+                )
+            })
+        }) else {
+            continue;
+        };
+        let kind = match sql_word(stmt.get(kind_at)).as_deref() {
+            Some("table") => "table",
+            Some("view") => "view",
+            Some("function") => "function",
+            Some("procedure" | "proc") => "procedure",
+            _ => continue,
+        };
+        let mut at = kind_at + 1;
+        if sql_is_word(stmt.get(at), "if") {
+            at += if sql_is_word(stmt.get(at + 1), "not") {
+                3
+            } else {
+                2
+            };
+        }
+        let (mut path, after_name) = sql_qualified_name(stmt, at);
+        let Some(name) = path.pop() else {
+            continue;
+        };
+        let scope_path = path
+            .iter()
+            .chain(std::iter::once(&name))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("::");
+
+        // 签名：表取完整列定义；视图 / 函数 / 过程取到 AS / 函数体为止
+        let header_end = if kind == "table" {
+            stmt.len()
+        } else {
+            stmt.iter()
+                .skip(after_name)
+                .position(|t| {
+                    t.tok == SqlTok::Dollar
+                        || sql_word(Some(t)).is_some_and(|w| {
+                            matches!(w.as_str(), "as" | "begin" | "language" | "return")
+                        })
+                })
+                .map_or(stmt.len(), |p| after_name + p)
+        };
+        symbols.push(PendingSymbol {
+            temp_id: symbols.len() + 1,
+            parent_temp_id: None,
+            name,
+            qualified_name: scope_path.clone(),
+            scope_path,
+            symbol_type: kind.to_string(),
+            line_start: stmt[0].line,
+            line_end: stmt[stmt.len() - 1].line,
+            signature: features.signatures.then(|| sql_render(&stmt[..header_end])),
+            doc: None,
+            visibility: None,
+            anchor: None,
+            signature_shape: None,
+        });
+        let caller = symbols.len();
+
+        // 引用：FROM / JOIN / INTO / UPDATE / REFERENCES 的表名，及函数体里的函数调用
+        if !features.calls {
+            continue;
+        }
+        let body_start = if kind == "table" {
+            after_name
+        } else {
+            header_end
+        };
+        let mut i = body_start;
+        while i < stmt.len() {
+            let Some(word) = sql_word(stmt.get(i)) else {
+                i += 1;
+                continue;
+            };
+            let is_ref = match kind {
+                "table" => word == "references",
+                _ => matches!(
+                    word.as_str(),
+                    "from" | "join" | "into" | "update" | "references"
+                ),
+            };
+            if is_ref {
+                let (target, next) = sql_qualified_name(stmt, i + 1);
+                if let Some(table) = target.last() {
+                    if !sql_is_keyword(table) {
+                        calls.push(PendingCall {
+                            caller_temp_id: caller,
+                            callee_name: table.clone(),
+                            line: stmt[i + 1].line,
+                            kind: "call".to_string(),
+                            receiver_type: None,
+                            args: None,
+                        });
+                    }
+                }
+                i = next.max(i + 1);
+                continue;
+            }
+            // 表定义里 `VARCHAR(255)` / `CHECK (...)` 不是调用，只看 REFERENCES
+            if kind != "table"
+                && matches!(stmt.get(i + 1).map(|t| &t.tok), Some(SqlTok::Punct('(')))
+                && !sql_is_keyword(&word)
+            {
+                let SqlTok::Ident(callee) = &stmt[i].tok else {
+                    unreachable!()
+                };
+                calls.push(PendingCall {
+                    caller_temp_id: caller,
+                    callee_name: callee.clone(),
+                    line: stmt[i].line,
+                    kind: "call".to_string(),
+                    receiver_type: None,
+                    args: None,
+                });
+            }
+            i += 1;
+        }
+    }
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, "sql");
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits: vec![],
+        imports: vec![],
+        decorators: vec![],
+        attributes: vec![],
+        grammar: String::new(),
+        error_nodes: unbalanced, // 括号未配平的语句数
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ProtoTok {
+    Ident(String), // 含点号的全名：google.protobuf.Empty / .pkg.Msg
+    Lit(String),   // 字段编号 / 字符串：原文保留，编号变化也体现在签名里
+    Punct(char),
+}
+
+const PROTO_SCALARS: &[&str] = &[
+    "double", "float", "int32", "int64", "uint32", "uint64", "sint32", "sint64", "fixed32",
+    "fixed64", "sfixed32", "sfixed64", "bool", "string", "bytes",
+];
+
+fn proto_tokens(content: &str) -> Vec<(ProtoTok, usize)> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut toks = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let start_line = line;
+        let tok = if c == '\n' {
+            line += 1;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            ProtoTok::Lit(chars[start..i.min(chars.len())].iter().collect())
+        } else if c.is_alphabetic()
+            || c == '_'
+            || (c == '.' && chars.get(i + 1).is_some_and(|n| n.is_alphabetic()))
+        {
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            ProtoTok::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit() || c == '-' {
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            ProtoTok::Lit(chars[start..i.min(chars.len())].iter().collect())
+        } else {
+            i += 1;
+            ProtoTok::Punct(c)
+        };
+        toks.push((tok, start_line));
+    }
+    toks
+}
+
+// 语句 token 以单个空格连接，`<` `>` `,` `(` `)` 两侧收紧：作签名用
+fn proto_render(stmt: &[&(ProtoTok, usize)], content_of: impl Fn(&ProtoTok) -> String) -> String {
+    let mut out = String::new();
+    let mut prev = String::new();
+    for (tok, _) in stmt {
+        let text = content_of(tok);
+        let tight = matches!(text.as_str(), "," | ">" | ")" | "<" | "]")
+            || (text == "(" && prev != "returns")
+            || matches!(prev.as_str(), "<" | "(" | "[");
+        if !out.is_empty() && !tight {
+            out.push(' ');
+        }
+        out.push_str(&text);
+        prev = text;
+    }
+    out
+}
+
+fn extract_proto(content: &str, features: ExtractFeatures) -> ParsedFile {
+    let toks = proto_tokens(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    // 块栈：Some(下标) 为 message / enum / service / rpc 选项块
+    let mut blocks: Vec<Option<usize>> = vec![];
+    // message / enum 的成员行，收尾时拼进签名：字段增删改即签名变化
+    let mut members: HashMap<usize, Vec<String>> = HashMap::new();
+    let text = |tok: &ProtoTok| match tok {
+        ProtoTok::Ident(s) => s.clone(),
+        ProtoTok::Punct(c) => c.to_string(),
+        ProtoTok::Lit(s) => s.clone(),
+    };
+    let short = |name: &str| name.rsplit('.').next().unwrap_or(name).to_string();
+
+    let mut stmt: Vec<&(ProtoTok, usize)> = vec![];
+    for tok in &toks {
+        let (end, line) = match tok {
+            (ProtoTok::Punct(c @ ('{' | ';' | '}')), line) => (*c, *line),
+            _ => {
+                stmt.push(tok);
+                continue;
+            }
+        };
+        let head = stmt.first().map(|(t, _)| text(t)).unwrap_or_default();
+        let enclosing = blocks.iter().rev().find_map(|b| *b);
+        let ident = |i: usize| match stmt.get(i) {
+            Some((ProtoTok::Ident(s), _)) => Some(s.clone()),
+            _ => None,
+        };
+
+        let mut opened = None;
+        match (head.as_str(), end) {
+            ("message" | "enum" | "service", '{') | ("rpc", _) => {
+                if let Some(name) = ident(1) {
+                    let kind = match head.as_str() {
+                        "message" => "struct",
+                        "enum" => "enum",
+                        "service" => "interface",
+                        _ => "method",
+                    };
+                    let scope_path = match enclosing {
+                        Some(idx) => format!("{}::{}", symbols[idx].scope_path, name),
+                        None => name.clone(),
+                    };
+                    let signature = if kind == "method" {
+                        proto_render(&stmt, text)
+                    } else {
+                        format!("{} {}", head, name)
+                    };
+                    symbols.push(PendingSymbol {
+                        temp_id: symbols.len() + 1,
+                        parent_temp_id: enclosing.map(|idx| symbols[idx].temp_id),
+                        name,
+                        qualified_name: scope_path.clone(),
+                        scope_path,
+                        symbol_type: kind.to_string(),
+                        line_start: stmt[0].1,
+                        line_end: line,
+                        signature: features.signatures.then_some(signature),
+                        doc: None,
+                        visibility: None,
+                        anchor: None,
+                        signature_shape: None,
+                    });
+                    let idx = symbols.len() - 1;
+                    // rpc Get(GetRequest) returns (stream User)：请求 / 响应类型即引用边
+                    if kind == "method" && features.calls {
+                        for (t, l) in stmt.iter().skip(2) {
+                            if let ProtoTok::Ident(name) = t {
+                                if !matches!(name.as_str(), "returns" | "stream") {
+                                    calls.push(PendingCall {
+                                        caller_temp_id: symbols[idx].temp_id,
+                                        callee_name: short(name),
+                                        line: *l,
+                                        kind: "call".to_string(),
+                                        receiver_type: None,
+                                        args: None,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                    opened = Some(idx);
+                }
+            }
+            // 字段 `repeated Role roles = 2;` / `map<string, Tag> tags = 3;`，枚举值 `ADMIN = 1;`
+            (kw, ';')
+                if !matches!(
+                    kw,
+                    "option"
+                        | "reserved"
+                        | "extensions"
+                        | "syntax"
+                        | "edition"
+                        | "package"
+                        | "import"
+                ) && stmt.iter().any(|(t, _)| *t == ProtoTok::Punct('=')) =>
+            {
+                if let Some(owner) = enclosing
+                    .filter(|idx| matches!(symbols[*idx].symbol_type.as_str(), "struct" | "enum"))
+                {
+                    let decl_end = stmt
+                        .iter()
+                        .position(|(t, _)| *t == ProtoTok::Punct('['))
+                        .unwrap_or(stmt.len());
+                    members
+                        .entry(owner)
+                        .or_default()
+                        .push(proto_render(&stmt[..decl_end], text));
+                    let eq = stmt
+                        .iter()
+                        .position(|(t, _)| *t == ProtoTok::Punct('='))
+                        .unwrap_or(0);
+                    // 字段名在 `=` 前一位，其前为类型（含 map<K, V>）
+                    if features.calls && symbols[owner].symbol_type == "struct" {
+                        for (t, l) in stmt.iter().take(eq.saturating_sub(1)) {
+                            if let ProtoTok::Ident(name) = t {
+                                if !PROTO_SCALARS.contains(&name.as_str())
+                                    && !matches!(
+                                        name.as_str(),
+                                        "repeated" | "optional" | "required" | "map"
+                                    )
+                                {
+                                    calls.push(PendingCall {
+                                        caller_temp_id: symbols[owner].temp_id,
+                                        callee_name: short(name),
+                                        line: *l,
+                                        kind: "call".to_string(),
+                                        receiver_type: None,
+                                        args: None,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        match end {
+            '{' => blocks.push(opened),
+            '}' => {
+                if let Some(Some(idx)) = blocks.pop() {
+                    symbols[idx].line_end = line;
+                }
+            }
+            _ => {}
+        }
+        stmt.clear();
+    }
+
+    if features.signatures {
+        for (idx, fields) in members {
+            if let Some(sig) = symbols[idx].signature.as_mut() {
+                *sig = format!("{} {{ {}; }}", sig, fields.join("; "));
+            }
+        }
+    }
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, "proto");
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits: vec![],
+        imports: vec![],
+        decorators: vec![],
+        attributes: vec![],
+        grammar: String::new(),
+        error_nodes: blocks.len(), // 未闭合的块
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum HclTok {
+    Ident(String), // 含点号的遍历表达式：var.region / aws_s3_bucket.logs.arn
+    Str(String),   // 字符串字面量（块标签）；插值 ${...} 另行切成 token 跟在其后
+    Punct(char),
+}
+
+fn hcl_tokens(content: &str, first_line: usize) -> Vec<(HclTok, usize)> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut toks = vec![];
+    let mut i = 0;
+    let mut line = first_line;
+    // 字符串 / heredoc 内的 ${...}：递归切词，行号从插值所在行起算
+    let interpolations = |text: &[char], line: usize, toks: &mut Vec<(HclTok, usize)>| {
+        let mut j = 0;
+        let mut at_line = line;
+        while j < text.len() {
+            if text[j] == '\n' {
+                at_line += 1;
+            }
+            if text[j] == '$' && text.get(j + 1) == Some(&'{') {
+                let mut depth = 0;
+                let start = j + 2;
+                let mut k = j + 1;
+                while k < text.len() {
+                    match text[k] {
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    k += 1;
+                }
+                let inner: String = text[start..k.min(text.len())].iter().collect();
+                toks.extend(hcl_tokens(&inner, at_line));
+                at_line += inner.matches('\n').count();
+                j = k + 1;
+                continue;
+            }
+            j += 1;
+        }
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        let start_line = line;
+        let tok = if c == '\n' {
+            line += 1;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '#' || (c == '/' && chars.get(i + 1) == Some(&'/')) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if c == '"' {
+            let start = i + 1;
+            i += 1;
+            let mut depth = 0;
+            while i < chars.len() && (chars[i] != '"' || depth > 0) && chars[i] != '\n' {
+                match chars[i] {
+                    '\\' => i += 1,
+                    '{' if i > 0 && chars[i - 1] == '$' => depth += 1,
+                    '}' if depth > 0 => depth -= 1,
+                    _ => {}
+                }
+                i += 1;
+            }
+            let text = &chars[start..i.min(chars.len())];
+            i += 1;
+            toks.push((HclTok::Str(text.iter().collect()), start_line));
+            interpolations(text, start_line, &mut toks);
+            continue;
+        } else if c == '<' && chars.get(i + 1) == Some(&'<') {
+            // heredoc：<<EOF / <<-EOF，直到只含结束标记的行
+            let mut j = i + 2;
+            if chars.get(j) == Some(&'-') {
+                j += 1;
+            }
+            let marker_start = j;
+            while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_') {
+                j += 1;
+            }
+            let marker: String = chars[marker_start..j].iter().collect();
+            if marker.is_empty() {
+                i += 2;
+                continue;
+            }
+            let body_start = j;
+            let mut body_end = chars.len();
+            let mut end = chars.len();
+            let mut k = j;
+            let mut body_lines = 0;
+            while k < chars.len() {
+                if chars[k] == '\n' {
+                    body_lines += 1;
+                    let next_end = chars[k + 1..]
+                        .iter()
+                        .position(|&ch| ch == '\n')
+                        .map_or(chars.len(), |p| k + 1 + p);
+                    let next: String = chars[k + 1..next_end].iter().collect();
+                    if next.trim() == marker {
+                        body_end = k;
+                        end = next_end;
+                        break;
+                    }
+                }
+                k += 1;
+            }
+            interpolations(&chars[body_start..body_end], line, &mut toks);
+            line += body_lines;
+            i = end;
+            continue;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_-.".contains(chars[i])) {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            HclTok::Ident(text.trim_end_matches('.').to_string())
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            continue;
+        } else {
+            i += 1;
+            HclTok::Punct(c)
+        };
+        toks.push((tok, start_line));
+    }
+    toks
+}
+
+// 遍历表达式 -> 被引用对象的地址：var.x / local.x / module.x / data.t.n / 资源 t.n
+fn hcl_reference(traversal: &str) -> Option<String> {
+    let parts: Vec<&str> = traversal.split('.').collect();
+    let take = match parts[0] {
+        "var" | "local" | "module" => 2,
+        "data" => 3,
+        // 资源类型总是 <provider>_<type>；each / count / path / self 等是内置对象
+        root if root.contains('_') => 2,
+        _ => return None,
+    };
+    (parts.len() >= take && parts[..take].iter().all(|p| !p.is_empty()))
+        .then(|| parts[..take].join("."))
+}
+
+fn extract_hcl(content: &str, features: ExtractFeatures) -> ParsedFile {
+    let toks = hcl_tokens(content, 1);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    let mut depth = 0usize;
+    let mut block: Option<(usize, bool)> = None; // (符号下标, 是否 locals)
+    let mut local: Option<usize> = None;
+    let mut seen: HashSet<(usize, String)> = HashSet::new();
+
+    let push = |symbols: &mut Vec<PendingSymbol>, address: String, kind: &str, line: usize| {
+        symbols.push(PendingSymbol {
+            temp_id: symbols.len() + 1,
+            parent_temp_id: None,
+            name: address.clone(),
+            qualified_name: address.clone(),
+            scope_path: address,
+            symbol_type: kind.to_string(),
+            line_start: line,
+            line_end: line,
+            signature: features
+                .signatures
+                .then(|| lines.get(line - 1).map(|l| l.trim().to_string()))
+                .flatten(),
+            doc: None,
+            visibility: None,
+            anchor: None,
+            signature_shape: None,
+        });
+        symbols.len() - 1
+    };
+
+    let mut i = 0;
+    while i < toks.len() {
+        let (tok, line) = (&toks[i].0, toks[i].1);
+        match tok {
+            // 顶层块：resource "t" "n" { / data "t" "n" { / module "n" { / variable / output / locals {
+            HclTok::Ident(kw) if depth == 0 => {
+                let mut labels = vec![];
+                let mut j = i + 1;
+                while let Some((HclTok::Str(label) | HclTok::Ident(label), _)) = toks.get(j) {
+                    labels.push(label.clone());
+                    j += 1;
+                }
+                if !matches!(toks.get(j), Some((HclTok::Punct('{'), _))) {
+                    i += 1;
+                    continue;
+                }
+                let target = match (kw.as_str(), labels.as_slice()) {
+                    ("resource", [t, n]) => Some((format!("{}.{}", t, n), "resource")),
+                    ("data", [t, n]) => Some((format!("data.{}.{}", t, n), "data")),
+                    ("module", [n]) => Some((format!("module.{}", n), "module")),
+                    ("variable", [n]) => Some((format!("var.{}", n), "variable")),
+                    ("output", [n]) => Some((format!("output.{}", n), "output")),
+                    _ => None,
+                };
+                block = target
+                    .map(|(address, kind)| (push(&mut symbols, address, kind, line), false))
+                    .or((kw == "locals").then_some((usize::MAX, true)));
+                depth = 1;
+                i = j + 1;
+                continue;
+            }
+            HclTok::Punct('{' | '[' | '(') => depth += 1,
+            HclTok::Punct('}' | ']' | ')') => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    match block.take() {
+                        // 最后一个 local 止于 locals 块的 `}` 之前
+                        Some((_, true)) => {
+                            if let Some(last) = local {
+                                symbols[last].line_end =
+                                    toks[i - 1].1.max(symbols[last].line_start);
+                            }
+                        }
+                        Some((idx, false)) => symbols[idx].line_end = line,
+                        None => {}
+                    }
+                    local = None;
+                }
+            }
+            // locals { name = expr }：每个属性是一个 local.<name>
+            HclTok::Ident(name)
+                if depth == 1
+                    && matches!(block, Some((_, true)))
+                    && matches!(toks.get(i + 1), Some((HclTok::Punct('='), _))) =>
+            {
+                if let Some(prev) = local {
+                    let prev_end = toks[i - 1].1;
+                    symbols[prev].line_end = prev_end.max(symbols[prev].line_start);
+                }
+                local = Some(push(
+                    &mut symbols,
+                    format!("local.{}", name),
+                    "constant",
+                    line,
+                ));
+            }
+            HclTok::Ident(traversal) if features.calls => {
+                let owner = match block {
+                    Some((_, true)) => local,
+                    Some((idx, false)) => Some(idx),
+                    None => None,
+                };
+                if let (Some(owner), Some(target)) = (owner, hcl_reference(traversal)) {
+                    // 依赖是集合：同一块内重复引用只记第一次
+                    if target != symbols[owner].name && seen.insert((owner, target.clone())) {
+                        calls.push(PendingCall {
+                            caller_temp_id: symbols[owner].temp_id,
+                            callee_name: target,
+                            line,
+                            kind: "call".to_string(),
+                            receiver_type: None,
+                            args: None,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, "tf");
+        }
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits: vec![],
+        imports: vec![],
+        decorators: vec![],
+        attributes: vec![],
+        grammar: String::new(),
+        error_nodes: usize::from(depth > 0), // 文件末尾仍有未闭合的块
+    }
+}
+
+// ============================================================================
+// Objective-C (.m / .mm：@interface / @implementation 块文本扫描，块外的纯 C / C++ 交给宿主语法)
+// ============================================================================
+
+// 扩展名 -> (块外部分的宿主语法, 修订号)；宿主语法没编译进来（feature 关闭）时不支持，文件跳过
+fn objc_extractor(ext: &str) -> Option<(&'static str, u32)> {
+    match ext {
+        "m" if cfg!(feature = "c") => Some(("c", 1)),
+        "mm" if cfg!(feature = "cpp") => Some(("cpp", 1)),
+        _ => None,
+    }
+}
+
+// 宿主语法变化也要重抽
+fn objc_extractor_version(parsers: &ParserSetup, host: &str, ext: &str, rev: u32) -> String {
+    let host_version = parsers.get(host).map(|p| p.2.as_str()).unwrap_or("");
+    format!("{}+{}", host_version, text_extractor_version(ext, rev))
+}
+
+#[derive(Debug, PartialEq)]
+enum ObjcTok {
+    Ident(String),
+    Lit, // 字符串 / 字符 / 数字
+    Punct(char),
+}
+
+struct ObjcToken {
+    tok: ObjcTok,
+    line: usize,
+    start: usize, // 字节偏移
+    end: usize,
+}
+
+// 不会是消息接收者 / 函数名的关键字
+const OBJC_KEYWORDS: &str = "if else for while do switch case return sizeof typeof __typeof__ \
+    in break continue goto default";
+
+fn objc_is_keyword(word: &str) -> bool {
+    OBJC_KEYWORDS.split_whitespace().any(|k| k == word)
+}
+
+fn objc_tokens(content: &str) -> Vec<ObjcToken> {
+    let chars: Vec<(usize, char)> = content.char_indices().collect();
+    let byte_at = |i: usize| chars.get(i).map_or(content.len(), |c| c.0);
+    let mut toks = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    let mut line_head = true; // 本行至今只有空白：`#` 开头是预处理指令
+    while i < chars.len() {
+        let c = chars[i].1;
+        let start = i;
+        let start_line = line;
+        let tok = if c == '\n' {
+            line += 1;
+            line_head = true;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '#' && line_head {
+            // 预处理指令整行跳过（含 `\` 续行）
+            while i < chars.len() && chars[i].1 != '\n' {
+                if chars[i].1 == '\\' && chars.get(i + 1).is_some_and(|n| n.1 == '\n') {
+                    line += 1;
+                    i += 1;
+                }
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1).is_some_and(|n| n.1 == '/') {
+            while i < chars.len() && chars[i].1 != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1).is_some_and(|n| n.1 == '*') {
+            i += 2;
+            while i < chars.len()
+                && !(chars[i].1 == '*' && chars.get(i + 1).is_some_and(|n| n.1 == '/'))
+            {
+                if chars[i].1 == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i].1 != c && chars[i].1 != '\n' {
+                if chars[i].1 == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            ObjcTok::Lit
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '_') {
+                i += 1;
+            }
+            ObjcTok::Ident(content[byte_at(start)..byte_at(i)].to_string())
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '.') {
+                i += 1;
+            }
+            ObjcTok::Lit
+        } else {
+            i += 1;
+            ObjcTok::Punct(c)
+        };
+        line_head = false;
+        toks.push(ObjcToken {
+            tok,
+            line: start_line,
+            start: byte_at(start),
+            end: byte_at(i),
+        });
+    }
+    toks
+}
+
+// 从 open（`(` / `[` / `{`）起找配对的闭括号下标；只数同种括号
+fn objc_matching(toks: &[ObjcToken], open: usize) -> Option<usize> {
+    let (o, c) = match toks[open].tok {
+        ObjcTok::Punct('(') => ('(', ')'),
+        ObjcTok::Punct('[') => ('[', ']'),
+        ObjcTok::Punct('{') => ('{', '}'),
+        _ => return None,
+    };
+    let mut depth = 0usize;
+    for (i, t) in toks.iter().enumerate().skip(open) {
+        match t.tok {
+            ObjcTok::Punct(p) if p == o => depth += 1,
+            ObjcTok::Punct(p) if p == c => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn objc_directive(toks: &[ObjcToken], i: usize) -> Option<&str> {
+    match (&toks[i].tok, toks.get(i + 1).map(|t| &t.tok)) {
+        (ObjcTok::Punct('@'), Some(ObjcTok::Ident(word))) => Some(word),
+        _ => None,
+    }
+}
+
+// 选择子：`foo` / `initWithName:age:`（`?:` 三元里的冒号不算）
+fn objc_selector(toks: &[ObjcToken]) -> Option<String> {
+    let mut parts = String::new();
+    let mut depth = 0usize;
+    let mut ternary = 0usize;
+    let mut first = None;
+    for (i, t) in toks.iter().enumerate() {
+        match &t.tok {
+            ObjcTok::Punct('(' | '[' | '{') => depth += 1,
+            ObjcTok::Punct(')' | ']' | '}') => depth = depth.saturating_sub(1),
+            ObjcTok::Punct('?') if depth == 0 => ternary += 1,
+            ObjcTok::Punct(':') if depth == 0 && ternary > 0 => ternary -= 1,
+            ObjcTok::Punct(':') if depth == 0 => {
+                if let Some(ObjcTok::Ident(part)) = i.checked_sub(1).map(|p| &toks[p].tok) {
+                    parts.push_str(part);
+                }
+                parts.push(':');
+            }
+            ObjcTok::Ident(word) if depth == 0 && first.is_none() => first = Some(word.clone()),
+            _ => {}
+        }
+    }
+    if parts.is_empty() {
+        first
+    } else {
+        Some(parts)
+    }
+}
+
+// 消息发送 `[recv sel:arg ...]`：返回 (接收者 token 区间, 选择子, 选择子所在行)。
+// 接收者是第一个完整的操作数，其后紧跟的标识符开始选择子
+fn objc_message(toks: &[ObjcToken], open: usize, close: usize) -> Option<(usize, String, usize)> {
+    let operand_end = |t: &ObjcTok| match t {
+        ObjcTok::Ident(w) => !objc_is_keyword(w),
+        ObjcTok::Lit | ObjcTok::Punct(')' | ']') => true,
+        _ => false,
+    };
+    let mut depth = 0usize;
+    let mut last: Option<&ObjcTok> = None;
+    for i in open + 1..close {
+        let t = &toks[i].tok;
+        if depth == 0 && matches!(t, ObjcTok::Ident(_)) && last.is_some_and(operand_end) {
+            let selector = objc_selector(&toks[i..close])?;
+            return Some((i, selector, toks[i].line));
+        }
+        match t {
+            ObjcTok::Punct('(' | '[' | '{') => depth += 1,
+            ObjcTok::Punct(')' | ']' | '}') => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth == 0 {
+            last = Some(t);
+        }
+    }
+    None
+}
+
+// @ 块与 `@class X;` / `@protocol X;` 前置声明逐字节换成空格（保留换行），
+// 块外 `@"..."` 等字面量只抹掉 `@`：剩下的纯 C 在原位置交给宿主语法
+fn objc_host_mask(content: &str, toks: &[ObjcToken], blocks: &[(usize, usize)]) -> String {
+    let mut regions: Vec<std::ops::Range<usize>> = blocks
+        .iter()
+        .map(|&(open, close)| toks[open].start..toks[close].end)
+        .collect();
+    let mut i = 0;
+    while i < toks.len() {
+        if toks[i].tok == ObjcTok::Punct('@') {
+            let forward = matches!(objc_directive(toks, i), Some("class" | "protocol"))
+                && matches!(toks.get(i + 2).map(|t| &t.tok), Some(ObjcTok::Ident(_)));
+            let end = if forward {
+                toks[i..]
+                    .iter()
+                    .position(|t| t.tok == ObjcTok::Punct(';'))
+                    .map_or(toks[i].end, |p| toks[i + p].end)
+            } else {
+                toks[i].end
+            };
+            regions.push(toks[i].start..end);
+        }
+        i += 1;
+    }
+    let masked: Vec<u8> = content
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| {
+            if b != b'\n' && regions.iter().any(|r| r.contains(&i)) {
+                b' '
+            } else {
+                b
+            }
+        })
+        .collect();
+    // 区域边界都落在 ASCII token 上，不会切开多字节字符
+    String::from_utf8(masked).unwrap_or_else(|_| content.to_string())
+}
+
+// 调用者：符号 temp_id、所在类（self 的类型）、定义起点（推断接收者类型的文本起点）
+struct ObjcCaller<'a> {
+    temp_id: usize,
+    class: Option<&'a str>,
+    def_start: usize,
+}
+
+// 方法 / 函数体内的调用：消息发送 `[recv sel:...]`；with_functions 时也记 C 函数调用
+fn objc_body_calls(
+    toks: &[ObjcToken],
+    body: std::ops::Range<usize>,
+    content: &str,
+    caller: &ObjcCaller,
+    ext: &str,
+    with_functions: bool,
+    calls: &mut Vec<PendingCall>,
+) {
+    for b in body {
+        let prev = b.checked_sub(1).map(|p| &toks[p].tok);
+        match &toks[b].tok {
+            ObjcTok::Punct('[') => {
+                // 下标 `a[i]` 前面是操作数；消息发送前面是运算符 / 语句起点 / return
+                let subscript = match prev {
+                    Some(ObjcTok::Ident(w)) => !objc_is_keyword(w),
+                    Some(ObjcTok::Lit | ObjcTok::Punct(')' | ']')) => true,
+                    _ => false,
+                };
+                let Some(close) = objc_matching(toks, b).filter(|_| !subscript) else {
+                    continue;
+                };
+                let Some((sel_at, callee, line)) = objc_message(toks, b, close) else {
+                    continue;
+                };
+                let receiver_type = match &toks[b + 1..sel_at] {
+                    [ObjcToken {
+                        tok: ObjcTok::Ident(recv),
+                        ..
+                    }] => infer_receiver_type(
+                        recv,
+                        &content[caller.def_start..toks[b].start],
+                        caller.class,
+                        None,
+                        ext,
+                    ),
+                    // [[Foo alloc] init...] / [[Foo new] ...]
+                    [ObjcToken {
+                        tok: ObjcTok::Punct('['),
+                        ..
+                    }, ObjcToken {
+                        tok: ObjcTok::Ident(class),
+                        ..
+                    }, ObjcToken {
+                        tok: ObjcTok::Ident(ctor),
+                        ..
+                    }, ObjcToken {
+                        tok: ObjcTok::Punct(']'),
+                        ..
+                    }] if matches!(ctor.as_str(), "alloc" | "new") => Some(class.clone()),
+                    _ => None,
+                };
+                calls.push(PendingCall {
+                    caller_temp_id: caller.temp_id,
+                    callee_name: callee,
+                    line,
+                    kind: "call".to_string(),
+                    receiver_type,
+                    args: None,
+                });
+            }
+            // C 函数调用（`@selector(...)` / `@encode(...)` 除外）
+            ObjcTok::Ident(callee)
+                if with_functions
+                    && toks.get(b + 1).map(|t| &t.tok) == Some(&ObjcTok::Punct('('))
+                    && !objc_is_keyword(callee)
+                    && prev != Some(&ObjcTok::Punct('@')) =>
+            {
+                calls.push(PendingCall {
+                    caller_temp_id: caller.temp_id,
+                    callee_name: callee.clone(),
+                    line: toks[b].line,
+                    kind: "call".to_string(),
+                    receiver_type: None,
+                    args: None,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+fn extract_objc(
+    parsers: &ParserSetup,
+    ext: &str,
+    content: &str,
+    features: ExtractFeatures,
+) -> ParsedFile {
+    let host = objc_extractor(ext).map_or("c", |(h, _)| h);
+    let toks = objc_tokens(content);
+    let lines: Vec<&str> = content.lines().collect();
+    let mut symbols: Vec<PendingSymbol> = vec![];
+    let mut calls = vec![];
+    let mut blocks: Vec<(usize, usize)> = vec![]; // (`@` 下标, `end` 下标)
+    let mut inherits = vec![];
+    let mut unclosed = 0;
+
+    let mut i = 0;
+    while i < toks.len() {
+        let kind = match objc_directive(&toks, i) {
+            Some("interface" | "implementation") => "class",
+            // `@protocol Foo;` 是前置声明，`@protocol(Foo)` 是表达式
+            Some("protocol")
+                if !matches!(
+                    toks.get(i + 3).map(|t| &t.tok),
+                    Some(ObjcTok::Punct(';' | ','))
+                ) && matches!(toks.get(i + 2).map(|t| &t.tok), Some(ObjcTok::Ident(_))) =>
+            {
+                "interface"
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let Some(ObjcTok::Ident(name)) = toks.get(i + 2).map(|t| &t.tok) else {
+            i += 1;
+            continue;
+        };
+        let name = name.clone();
+        let class_idx = symbols.len();
+        symbols.push(PendingSymbol {
+            temp_id: class_idx + 1,
+            parent_temp_id: None,
+            name: name.clone(),
+            qualified_name: name.clone(),
+            scope_path: name.clone(),
+            symbol_type: kind.to_string(),
+            line_start: toks[i].line,
+            line_end: toks[i].line,
+            signature: None,
+            doc: None,
+            visibility: None,
+            anchor: None,
+            signature_shape: None,
+        });
+
+        // 头部：`: Super` 父类，`<P, Q>` 采纳的协议（协议继承协议记 extends）
+        let mut j = i + 3;
+        if toks.get(j).map(|t| &t.tok) == Some(&ObjcTok::Punct(':')) {
+            if let Some(ObjcTok::Ident(base)) = toks.get(j + 1).map(|t| &t.tok) {
+                inherits.push(PendingInherit {
+                    child_temp_id: class_idx + 1,
+                    base_name: base.clone(),
+                    kind: "extends".to_string(),
+                });
+            }
+            j += 2;
+        }
+        if toks.get(j).map(|t| &t.tok) == Some(&ObjcTok::Punct('<')) {
+            let protocol_kind = if kind == "interface" {
+                "extends"
+            } else {
+                "implements"
+            };
+            while let Some(t) = toks.get(j).filter(|t| t.tok != ObjcTok::Punct('>')) {
+                if let ObjcTok::Ident(base) = &t.tok {
+                    inherits.push(PendingInherit {
+                        child_temp_id: class_idx + 1,
+                        base_name: base.clone(),
+                        kind: protocol_kind.to_string(),
+                    });
+                }
+                j += 1;
+            }
+        }
+
+        // 块体：方法声明 / 定义；实例变量块 `{ ... }` 与 @property 等其余内容跳过
+        let open = i;
+        let mut close = None;
+        while j < toks.len() {
+            if objc_directive(&toks, j) == Some("end") {
+                close = Some(j + 1);
+                break;
+            }
+            match toks[j].tok {
+                ObjcTok::Punct('{') => {
+                    j = objc_matching(&toks, j).map_or(toks.len(), |e| e + 1);
+                    continue;
+                }
+                ObjcTok::Punct('-' | '+')
+                    if matches!(toks.get(j + 1).map(|t| &t.tok), Some(ObjcTok::Punct('('))) => {}
+                _ => {
+                    j += 1;
+                    continue;
+                }
+            }
+            // 方法头到 `;`（声明）或 `{`（定义）为止
+            let head_start = j;
+            let mut k = j + 1;
+            let mut depth = 0usize;
+            while k < toks.len() {
+                match toks[k].tok {
+                    ObjcTok::Punct('(') => depth += 1,
+                    ObjcTok::Punct(')') => depth = depth.saturating_sub(1),
+                    ObjcTok::Punct(';' | '{') if depth == 0 => break,
+                    _ => {}
+                }
+                if objc_directive(&toks, k) == Some("end") {
+                    break;
+                }
+                k += 1;
+            }
+            // 跳过返回类型后再取选择子
+            let sel_from = objc_matching(&toks, head_start + 1).map_or(k, |e| e + 1);
+            let Some(selector) = objc_selector(&toks[sel_from.min(k)..k]) else {
+                j = k.max(j + 1);
+                continue;
+            };
+            let body = (toks.get(k).map(|t| &t.tok) == Some(&ObjcTok::Punct('{')))
+                .then(|| objc_matching(&toks, k))
+                .flatten();
+            let head_end = k.saturating_sub(1).max(head_start);
+            let end_line = match body {
+                Some(e) => toks[e].line,
+                None => toks.get(k).map_or(toks[head_end].line, |t| t.line),
+            };
+            let method_tid = symbols.len() + 1;
+            symbols.push(PendingSymbol {
+                temp_id: method_tid,
+                parent_temp_id: Some(class_idx + 1),
+                name: selector.clone(),
+                qualified_name: format!("{}::{}", name, selector),
+                scope_path: format!("{}::{}", name, selector),
+                symbol_type: "method".to_string(),
+                line_start: toks[head_start].line,
+                line_end: end_line,
+                signature: features.signatures.then(|| {
+                    content[toks[head_start].start..toks[head_end].end]
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }),
+                doc: None,
+                visibility: None,
+                anchor: None,
+                signature_shape: None,
+            });
+
+            let Some(body_end) = body else {
+                j = k + 1;
+                continue;
+            };
+            if features.calls {
+                let caller = ObjcCaller {
+                    temp_id: method_tid,
+                    class: Some(&name),
+                    def_start: toks[head_start].start,
+                };
+                objc_body_calls(
+                    &toks,
+                    k + 1..body_end,
+                    content,
+                    &caller,
+                    ext,
+                    true,
+                    &mut calls,
+                );
+            }
+            j = body_end + 1;
+        }
+
+        match close {
+            Some(end) => {
+                symbols[class_idx].line_end = toks[end].line;
+                blocks.push((open, end));
+                i = end + 1;
+            }
+            None => {
+                // 缺 @end：块延伸到文件尾
+                symbols[class_idx].line_end = lines.len().max(toks[i].line);
+                unclosed += 1;
+                blocks.push((open, toks.len() - 1));
+                i = toks.len();
+            }
+        }
+    }
+
+    if features.docs && !symbols.is_empty() {
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        for sym in symbols.iter_mut() {
+            sym.doc = extract_doc_comment(&owned, sym.line_start, ext);
+        }
+    }
+
+    // 块外的纯 C / C++ 部分：遮罩后在原位置解析，temp_id 接在 ObjC 符号之后
+    let mut grammar = String::new();
+    let mut imports = vec![];
+    if parsers.contains_key(host) {
+        let masked = objc_host_mask(content, &toks, &blocks);
+        let hosted = extract_with_fallback(parsers, host, &masked, features);
+        imports = hosted.imports;
+        let offset = symbols.len();
+        calls.extend(hosted.calls.into_iter().map(|mut c| {
+            c.caller_temp_id += offset;
+            c
+        }));
+        inherits.extend(hosted.inherits.into_iter().map(|mut h| {
+            h.child_temp_id += offset;
+            h
+        }));
+        for mut sym in hosted.symbols {
+            sym.temp_id += offset;
+            sym.parent_temp_id = sym.parent_temp_id.map(|p| p + offset);
+            // 宿主语法把消息发送解析成 ERROR：按行号回到 token 流里补记（C 函数调用宿主已记过）
+            if features.calls && canonical_prefix(&sym.symbol_type) == "func" {
+                let body: Vec<usize> = (0..toks.len())
+                    .filter(|&t| (sym.line_start..=sym.line_end).contains(&toks[t].line))
+                    .filter(|&t| !blocks.iter().any(|&(o, c)| (o..=c).contains(&t)))
+                    .collect();
+                if let (Some(&from), Some(&to)) = (body.first(), body.last()) {
+                    let caller = ObjcCaller {
+                        temp_id: sym.temp_id,
+                        class: None,
+                        def_start: toks[from].start,
+                    };
+                    objc_body_calls(
+                        &toks,
+                        from..to + 1,
+                        content,
+                        &caller,
+                        ext,
+                        false,
+                        &mut calls,
+                    );
+                }
+            }
+            symbols.push(sym);
+        }
+        unclosed += hosted.error_nodes;
+        grammar = host.to_string();
+    }
+
+    ParsedFile {
+        symbols,
+        calls,
+        routes: vec![],
+        inherits,
+        imports,
+        decorators: vec![],
+        attributes: vec![],
+        grammar,
+        error_nodes: unclosed,
+    }
+}
+
+// ============================================================================
+// Workspace Roots (多根工作区)
+// 单根：root 名为空，file_path 保持相对路径（兼容旧库）
+// 多根：file_path = "<root>/<相对路径>"，files.root 记录所属 root
+// ============================================================================
+
+#[derive(Clone, Debug)]
+struct ProjectRoot {
+    name: String,
+    path: PathBuf,
+}
+
+impl ProjectRoot {
+    fn prefix(&self) -> String {
+        if self.name.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.name)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WorkspaceManifest {
+    #[serde(default, alias = "roots")]
+    folders: Vec<WorkspaceFolder>,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceFolder {
+    path: String,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+// --project（可重复）+ --workspace 清单合并；多根时 root 名取清单 name 或目录名，重名追加序号
+fn project_roots(args: &Args) -> anyhow::Result<Vec<ProjectRoot>> {
+    let mut raw: Vec<(Option<String>, PathBuf)> = args
+        .project
+        .iter()
+        .map(|p| (None, PathBuf::from(p)))
+        .collect();
+
+    if let Some(manifest_path) = &args.workspace {
+        let content = fs::read_to_string(manifest_path)?;
+        let manifest: WorkspaceManifest = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("{}: {}", manifest_path, e))?;
+        let base = Path::new(manifest_path)
+            .parent()
+            .unwrap_or_else(|| Path::new("."));
+        for folder in manifest.folders {
+            raw.push((folder.name, base.join(&folder.path)));
+        }
+    }
+
+    if raw.is_empty() {
+        anyhow::bail!("at least one --project or a --workspace manifest is required");
+    }
+    if raw.len() == 1 {
+        let (_, path) = raw.remove(0);
+        return Ok(vec![ProjectRoot {
+            name: String::new(),
+            path,
+        }]);
+    }
+
+    let mut roots: Vec<ProjectRoot> = vec![];
+    for (name, path) in raw {
+        let base_name = name
+            .or_else(|| {
+                fs::canonicalize(&path)
+                    .ok()
+                    .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            })
+            .unwrap_or_else(|| "root".to_string())
+            .replace(['/', '\\'], "_");
+        let mut unique = base_name.clone();
+        let mut n = 2;
+        while roots.iter().any(|r| r.name == unique) {
+            unique = format!("{}-{}", base_name, n);
+            n += 1;
+        }
+        roots.push(ProjectRoot { name: unique, path });
+    }
+    Ok(roots)
+}
+
+fn record_roots(conn: &Connection, roots: &[ProjectRoot]) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for root in roots {
+        let abs = fs::canonicalize(&root.path).unwrap_or_else(|_| root.path.clone());
+        let license = project_license(&abs);
+        conn.execute(
+            "INSERT INTO roots (name, path, updated_at, license) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(name) DO UPDATE SET path = ?2, updated_at = ?3, license = ?4",
+            params![root.name, abs.to_string_lossy(), now, license],
+        )?;
+    }
+    Ok(())
+}
+
+// --scope 在多根时写作 "<root>/<子目录>"；不属于该 root 时返回 None（跳过）
+fn root_scan_path(root: &ProjectRoot, scope: Option<&str>, multi_root: bool) -> Option<PathBuf> {
+    let normalized = scope
+        .map(|s| s.trim().trim_start_matches("./").trim_matches('/'))
+        .unwrap_or("");
+    if normalized.is_empty() {
+        return Some(root.path.clone());
+    }
+    if !multi_root {
+        return Some(root.path.join(normalized));
+    }
+    if normalized == root.name {
+        return Some(root.path.clone());
+    }
+    normalized
+        .strip_prefix(&root.prefix())
+        .map(|sub| root.path.join(sub))
+}
+
+// 索引中的 file_path -> 磁盘路径
+fn resolve_source_path(roots: &[ProjectRoot], file_path: &str) -> PathBuf {
+    for root in roots.iter().filter(|r| !r.name.is_empty()) {
+        if let Some(rel) = file_path.strip_prefix(&root.prefix()) {
+            return confined_join(&root.path, rel);
+        }
+    }
+    confined_join(&roots[0].path, file_path)
+}
+
+// 只拼接普通路径段：带 `..` 或绝对路径的 file_path 不能越出项目根
+fn confined_join(root: &Path, rel: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for part in Path::new(rel).components() {
+        if let std::path::Component::Normal(seg) = part {
+            path.push(seg);
+        }
+    }
+    path
+}
+
+// symbols 的完整列序：overlay_symbols 与之同构，迁移过的旧库列序不同，拼 UNION 时显式列出
+const SYMBOL_COLUMNS: &str = "symbol_id, file_id, name, qualified_name, canonical_id, scope_path, symbol_type, line_start, line_end, signature, parent_id, summary, stable_id, doc, anchor, signature_shape, visibility, generated_from";
+// calls 的完整列序（overlay_calls 同理）
+const CALL_COLUMNS: &str = "call_id, caller_id, callee_name, call_line, callee_id, kind, receiver_type, arg_count, arg_keywords";
+
+// --root / --prune-path 过滤：用同名 TEMP VIEW 遮蔽 files/symbols（SQLite 优先解析 temp schema），
+// 只读模式下的所有查询因此自动限定在该 root 内、并跳过被剪掉的子树。
+// 给出 overlay 层且该层有 reindex-file 写入的符号时，symbols / calls 中这些文件的落库行换成该层的行
+fn apply_read_filter(
+    conn: &Connection,
+    root: Option<&str>,
+    prune: &[String],
+    overlay: Option<&str>,
+) -> Result<()> {
+    let mut conds = vec![];
+    if let Some(root) = root {
+        conds.push(format!("root = '{}'", root.replace('\'', "''")));
+    }
+    for glob in prune {
+        // 模式本身命中文件，或命中目录时连同整个子树
+        conds.push(format!(
+            "NOT (file_path GLOB '{0}' OR file_path GLOB '{0}/*')",
+            glob.replace('\'', "''")
+        ));
+    }
+    let overlay = overlay.filter(|layer| has_overlay(conn, layer));
+    if conds.is_empty() && overlay.is_none() {
+        return Ok(());
+    }
+    let source = match overlay {
+        Some(layer) => {
+            let layer = layer.replace('\'', "''");
+            // 被覆盖文件里发出的调用边换成缓冲区的边；指向这些文件的边按 canonical_id 照常命中
+            conn.execute_batch(&format!(
+                "CREATE TEMP VIEW calls AS
+                     SELECT {0} FROM main.calls
+                     WHERE caller_id NOT IN (
+                         SELECT symbol_id FROM main.symbols WHERE file_id IN (
+                             SELECT file_id FROM main.overlay_files WHERE sandbox = '{1}'))
+                     UNION ALL SELECT {0} FROM main.overlay_calls WHERE sandbox = '{1}';",
+                CALL_COLUMNS, layer
+            ))?;
+            format!(
+                "(SELECT {0} FROM main.symbols
+                    WHERE file_id NOT IN (SELECT file_id FROM main.overlay_files WHERE sandbox = '{1}')
+                  UNION ALL SELECT {0} FROM main.overlay_symbols WHERE sandbox = '{1}')",
+                SYMBOL_COLUMNS, layer
+            )
+        }
+        None => "main.symbols".to_string(),
+    };
+    if conds.is_empty() {
+        return conn.execute_batch(&format!(
+            "CREATE TEMP VIEW symbols AS SELECT * FROM {};",
+            source
+        ));
+    }
+    conn.execute_batch(&format!(
+        "CREATE TEMP VIEW files AS SELECT * FROM main.files WHERE {};
+         CREATE TEMP VIEW symbols AS
+             SELECT * FROM {} WHERE file_id IN (SELECT file_id FROM temp.files);",
+        conds.join(" AND "),
+        source
+    ))
+}
+
+// 删除文件的 overlay；不给 sandbox 时清除所有层，给出 file_hash 时只删内容与之相同的层
+fn clear_overlay(
+    conn: &Connection,
+    file_id: i64,
+    sandbox: Option<&str>,
+    file_hash: Option<&str>,
+) -> Result<()> {
+    for table in ["overlay_symbols", "overlay_calls"] {
+        conn.prepare_cached(&format!(
+            "DELETE FROM {} WHERE file_id = ?1 AND sandbox IN (
+                SELECT sandbox FROM overlay_files
+                WHERE file_id = ?1 AND (?2 IS NULL OR sandbox = ?2) AND (?3 IS NULL OR file_hash = ?3))",
+            table
+        ))?
+        .execute(params![file_id, sandbox, file_hash])?;
+    }
+    conn.prepare_cached(
+        "DELETE FROM overlay_files
+         WHERE file_id = ?1 AND (?2 IS NULL OR sandbox = ?2) AND (?3 IS NULL OR file_hash = ?3)",
+    )?
+    .execute(params![file_id, sandbox, file_hash])?;
+    Ok(())
+}
+
+// 读写哪一层覆盖：--sandbox 命名的沙箱，否则是编辑器缓冲区（空名）
+fn overlay_layer(args: &Args) -> &str {
+    args.sandbox.as_deref().unwrap_or("")
+}
+
+// 该层存在覆盖时返回被覆盖文件的路径集合，读模式据此给结果里的符号标注 source；
+// 没有覆盖层返回 None，输出与以往完全一致
+fn overlay_paths(conn: &Connection, layer: &str) -> Option<HashSet<String>> {
+    if !has_overlay(conn, layer) {
+        return None;
+    }
+    let mut stmt = conn
+        .prepare(
+            "SELECT f.file_path FROM main.overlay_files o JOIN main.files f ON f.file_id = o.file_id
+             WHERE o.sandbox = ?1",
+        )
+        .ok()?;
+    let paths = stmt
+        .query_map([layer], |r| r.get(0))
+        .ok()?
+        .flatten()
+        .collect();
+    Some(paths)
+}
+
+fn symbol_source(file_path: &str, overlaid: &HashSet<String>) -> String {
+    if overlaid.contains(file_path) {
+        "overlay".to_string()
+    } else {
+        "disk".to_string()
+    }
+}
+
+fn tag_source<'a>(nodes: impl IntoIterator<Item = &'a mut Node>, overlaid: &HashSet<String>) {
+    for node in nodes {
+        node.source = Some(symbol_source(&node.file_path, overlaid));
+    }
+}
+
+// 只读模式不建表：旧库没有 overlay 表，或表为空时都不启用遮蔽
+fn has_overlay(conn: &Connection, layer: &str) -> bool {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM main.overlay_files WHERE sandbox = ?1)",
+        [layer],
+        |r| r.get::<_, bool>(0),
+    )
+    .unwrap_or(false)
+}
+
+// --prune-path 的 glob 转成 SQLite GLOB：`*` 本就跨越 `/`，`**` 折叠为 `*`；
+// 以 `**/` 开头的模式额外匹配仓库根下的同名目录
+fn prune_globs(spec: Option<&str>) -> Vec<String> {
+    let mut globs = vec![];
+    for raw in spec.unwrap_or_default().split(',') {
+        let glob = raw.trim().replace('\\', "/");
+        let glob = glob.trim_end_matches('/').replace("**", "*");
+        if glob.is_empty() {
+            continue;
+        }
+        if let Some(rest) = glob.strip_prefix("*/") {
+            globs.push(rest.to_string());
+        }
+        globs.push(glob);
+    }
+    globs
+}
+
+// ============================================================================
+// Archive Input (归档输入：tar / tar.gz / zip / git bundle)
+// CI 里代码常以制品形式下发：直接在内存中读取归档条目，不解压到磁盘。
+// git bundle 例外：bundle 只是一个 packfile，没有仓库就无法按对象读取，只能先克隆为临时 bare 仓库
+// ============================================================================
+
+struct ArchiveFile {
+    path: String,
+    mtime: i64,
+    content: Vec<u8>,
+}
+
+// 索引输入的统一抽象：磁盘文件（按 root 遍历）或归档中的内存文件
+enum SourceEntry {
+    Disk { root_idx: usize, path: PathBuf },
+    Memory(ArchiveFile),
+}
+
+impl SourceEntry {
+    fn root_idx(&self) -> usize {
+        match self {
+            SourceEntry::Disk { root_idx, .. } => *root_idx,
+            SourceEntry::Memory(_) => 0,
+        }
+    }
+
+    fn on_disk(&self) -> bool {
+        matches!(self, SourceEntry::Disk { .. })
+    }
+
+    fn index_path(&self, root: &ProjectRoot) -> String {
+        match self {
+            SourceEntry::Disk { path, .. } => format!(
+                "{}{}",
+                root.prefix(),
+                path.strip_prefix(&root.path)
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .replace("\\", "/")
+            ),
+            SourceEntry::Memory(f) => f.path.clone(),
+        }
+    }
+
+    fn metadata(&self) -> Option<(u64, i64)> {
+        match self {
+            SourceEntry::Disk { path, .. } => {
+                let m = fs::metadata(path).ok()?;
+                let mtime = m
+                    .modified()
+                    .ok()?
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs() as i64;
+                Some((m.len(), mtime))
+            }
+            SourceEntry::Memory(f) => Some((f.content.len() as u64, f.mtime)),
+        }
+    }
+
+    fn read_content(&self) -> Option<String> {
+        match self {
+            SourceEntry::Disk { path, .. } => decode_source(&fs::read(path).ok()?),
+            SourceEntry::Memory(f) => decode_source(&f.content),
+        }
+    }
+}
+
+// 🆕 源码解码：Windows 编辑器常写出带 BOM 的 UTF-8 或 UTF-16 文件，直接按 UTF-8 读会整文件跳过。
+// 按 BOM 转成 UTF-8 并去掉 BOM 本身；换行符逐个对应，行号与原文件一致。无 BOM 时仍要求合法 UTF-8
+fn decode_source(bytes: &[u8]) -> Option<String> {
+    let utf16 = |data: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        if !data.len().is_multiple_of(2) {
+            return None;
+        }
+        let units = data.chunks_exact(2).map(|b| from_bytes([b[0], b[1]]));
+        char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .ok()
+    };
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec()).ok(),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => String::from_utf8(bytes.to_vec()).ok(),
+    }
+}
+
+// wanted 为假的条目只记路径（参与顶层目录判断），不读内容
+fn read_archive(path: &Path, wanted: &dyn Fn(&str) -> bool) -> anyhow::Result<Vec<ArchiveFile>> {
+    let lower = path.to_string_lossy().to_lowercase();
+    let mut skipped = vec![];
+    let mut files = if lower.ends_with(".zip") {
+        read_zip_archive(path, wanted, &mut skipped)?
+    } else if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+        let f = fs::File::open(path)?;
+        read_tar_archive(flate2::read::GzDecoder::new(f), wanted, &mut skipped)?
+    } else if lower.ends_with(".tar") {
+        read_tar_archive(fs::File::open(path)?, wanted, &mut skipped)?
+    } else if lower.ends_with(".bundle") {
+        read_git_bundle(path, wanted, &mut skipped)?
+    } else {
+        anyhow::bail!(
+            "unsupported archive format: {} (expected .tar, .tar.gz, .tgz, .zip or .bundle)",
+            path.display()
+        );
+    };
+
+    // GitHub/CI 制品常带单一顶层目录（repo-<sha>/...），去掉后路径与源码树一致
+    strip_common_root(&mut files, &skipped);
+    Ok(files)
+}
+
+fn read_tar_archive<R: std::io::Read>(
+    reader: R,
+    wanted: &dyn Fn(&str) -> bool,
+    skipped: &mut Vec<String>,
+) -> anyhow::Result<Vec<ArchiveFile>> {
+    use std::io::Read;
+
+    let mut archive = tar::Archive::new(reader);
+    let mut files = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().replace("\\", "/");
+        let path = path.trim_start_matches("./").to_string();
+        if !wanted(&path) {
+            skipped.push(path);
+            continue;
+        }
+        let mtime = entry.header().mtime().unwrap_or(0) as i64;
+        let mut content = vec![];
+        entry.read_to_end(&mut content)?;
+        files.push(ArchiveFile {
+            path,
+            mtime,
+            content,
+        });
+    }
+    Ok(files)
+}
+
+fn read_zip_archive(
+    path: &Path,
+    wanted: &dyn Fn(&str) -> bool,
+    skipped: &mut Vec<String>,
+) -> anyhow::Result<Vec<ArchiveFile>> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    let mut files = vec![];
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name().replace("\\", "/");
+        let name = name.trim_start_matches("./").to_string();
+        if !wanted(&name) {
+            skipped.push(name);
+            continue;
+        }
+        let mut content = vec![];
+        entry.read_to_end(&mut content)?;
+        files.push(ArchiveFile {
+            path: name,
+            mtime: 0,
+            content,
+        });
+    }
+    Ok(files)
+}
+
+// git bundle：克隆为临时 bare 仓库（只有对象，不检出工作区），
+// 再用 ls-tree + cat-file --batch 流式读取 HEAD 中需要的 blob
+fn read_git_bundle(
+    path: &Path,
+    wanted: &dyn Fn(&str) -> bool,
+    skipped: &mut Vec<String>,
+) -> anyhow::Result<Vec<ArchiveFile>> {
+    use std::process::Command;
+
+    let bare = std::env::temp_dir().join(format!("ast_bundle_{}", rand::random::<u32>()));
+    let status = Command::new("git")
+        .args(["clone", "--bare", "--quiet"])
+        .arg(path)
+        .arg(&bare)
+        .status()?;
+    if !status.success() {
+        let _ = fs::remove_dir_all(&bare);
+        anyhow::bail!("git clone of bundle failed: {}", path.display());
+    }
+
+    let result = (|| -> anyhow::Result<Vec<ArchiveFile>> {
+        let listing = Command::new("git")
+            .arg("-C")
+            .arg(&bare)
+            .args(["ls-tree", "-r", "-z", "HEAD"])
+            .output()?;
+        if !listing.status.success() {
+            anyhow::bail!("git ls-tree failed for bundle: {}", path.display());
+        }
+
+        // "<mode> blob <sha>\t<path>\0"
+        let mut blobs: Vec<(String, String)> = vec![];
+        for record in listing.stdout.split(|b| *b == 0) {
+            let record = String::from_utf8_lossy(record);
+            let Some((meta, file_path)) = record.split_once('\t') else {
+                continue;
+            };
+            let mut parts = meta.split_whitespace();
+            if let (Some(_mode), Some("blob"), Some(sha)) =
+                (parts.next(), parts.next(), parts.next())
+            {
+                if wanted(file_path) {
+                    blobs.push((sha.to_string(), file_path.to_string()));
+                } else {
+                    skipped.push(file_path.to_string());
+                }
+            }
+        }
+        cat_file_blobs(&bare, &blobs)
+    })();
+
+    let _ = fs::remove_dir_all(&bare);
+    result
+}
+
+// cat-file --batch 按顺序吐出 blob；读取出错时结束子进程并回收，不留僵尸进程
+fn cat_file_blobs(repo: &Path, blobs: &[(String, String)]) -> anyhow::Result<Vec<ArchiveFile>> {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["cat-file", "--batch"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let shas: Vec<String> = blobs.iter().map(|(sha, _)| sha.clone()).collect();
+    let writer = std::thread::spawn(move || {
+        for sha in shas {
+            if writeln!(stdin, "{}", sha).is_err() {
+                break;
+            }
+        }
+    });
+
+    // 输出格式："<sha> blob <size>\n<content>\n"
+    let mut reader = BufReader::new(child.stdout.take().expect("piped stdout"));
+    let files = (|| -> anyhow::Result<Vec<ArchiveFile>> {
+        let mut files = vec![];
+        for (_, file_path) in blobs {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            let size: usize = header
+                .split_whitespace()
+                .nth(2)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("unexpected cat-file header: {}", header.trim()))?;
+            let mut content = vec![0u8; size];
+            reader.read_exact(&mut content)?;
+            let mut newline = [0u8; 1];
+            reader.read_exact(&mut newline)?;
+            files.push(ArchiveFile {
+                path: file_path.clone(),
+                mtime: 0,
+                content,
+            });
+        }
+        Ok(files)
+    })();
+    if files.is_err() {
+        let _ = child.kill();
+    }
+    // 关掉读端：被 kill 的子进程不再阻塞写线程
+    drop(reader);
+    let _ = writer.join();
+    let status = child.wait()?;
+    let files = files?;
+    if !status.success() {
+        anyhow::bail!("git cat-file exited with {}", status);
+    }
+    Ok(files)
+}
+
+// skipped：未读内容的条目路径，同样要落在公共顶层目录下
+fn strip_common_root(files: &mut [ArchiveFile], skipped: &[String]) {
+    let Some(first) = files.first() else {
+        return;
+    };
+    let Some((top, _)) = first.path.split_once('/') else {
+        return;
+    };
+    let prefix = format!("{}/", top);
+    if files.iter().all(|f| f.path.starts_with(&prefix))
+        && skipped.iter().all(|p| p.starts_with(&prefix))
+    {
+        for f in files.iter_mut() {
+            f.path = f.path[prefix.len()..].to_string();
+        }
+    }
+}
+
+// 归档条目过滤：--scope 前缀、忽略目录（任一路径段命中）、扩展名白名单
+fn archive_entry_included(
+    path: &str,
+    scope: &str,
+    ignore_set: &HashSet<String>,
+    allowed_exts: &HashSet<String>,
+) -> bool {
+    let in_scope = scope.is_empty() || path == scope || path.starts_with(&format!("{}/", scope));
+    if !in_scope {
+        return false;
+    }
+    let mut segments: Vec<&str> = path.split('/').collect();
+    segments.pop();
+    if segments.iter().any(|seg| ignore_set.contains(*seg)) {
+        return false;
+    }
+    if allowed_exts.is_empty() {
+        return true;
+    }
+    Path::new(path)
+        .extension()
+        .map(|e| allowed_exts.contains(e.to_str().unwrap_or("")))
+        .unwrap_or(false)
+}
+
+// ============================================================================
+// Generation & Query Result Cache (变更代数 / 读模式结果缓存)
+// Agent 经常重复同一查询：结果 JSON 以 (参数, generation) 为键落盘，
+// 索引提交推进 generation 后旧缓存整体作废
+// ============================================================================
+
+const QUERY_CACHE_MAX_ENTRIES: usize = 256;
+
+fn bump_generation(conn: &Connection) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES ('generation', '1')
+         ON CONFLICT(key) DO UPDATE SET value = CAST(value AS INTEGER) + 1",
+        [],
+    )?;
+    read_generation(conn)
+}
+
+// 旧库没有 meta 表时视为 generation 0
+fn read_generation(conn: &Connection) -> Result<i64> {
+    let value: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'generation'", [], |r| {
+            r.get(0)
+        })
+        .optional()
+        .or_else(|e| match e {
+            rusqlite::Error::SqliteFailure(_, Some(ref msg)) if msg.contains("no such table") => {
+                Ok(None)
+            }
+            e => Err(e),
+        })?;
+    Ok(value.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+// --notify：普通文件写入新 generation（tmp + rename，便于 fsnotify 监听）；
+// FIFO 以非阻塞方式写入，没有读端时直接跳过，不阻塞索引
+fn notify_generation(args: &Args, generation: i64) {
+    let Some(target) = &args.notify else {
+        return;
+    };
+    let path = Path::new(target);
+    let payload = format!("{}\n", generation);
+
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+        if let Ok(meta) = fs::metadata(path) {
+            if meta.file_type().is_fifo() {
+                if let Ok(mut f) = fs::OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(path)
+                {
+                    let _ = f.write_all(payload.as_bytes());
+                }
+                return;
+            }
+        }
+    }
+
+    let tmp = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+    if fs::write(&tmp, payload).is_err() || fs::rename(&tmp, path).is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+fn query_cache_key(args: &Args) -> String {
+    let fields = [
+        args.mode.clone(),
+        args.project.join("|"),
+        args.query.clone().unwrap_or_default(),
+        args.file.clone().unwrap_or_default(),
+        args.line.map(|l| l.to_string()).unwrap_or_default(),
+        args.scope.clone().unwrap_or_default(),
+        args.detail.clone(),
+        args.direction.clone(),
+        args.root.clone().unwrap_or_default(),
+        args.min_confidence.to_string(),
+        format!("{}/{}", SCORE_VERSION, args.score_precision),
+        args.resolve.clone().unwrap_or_default(),
+        args.pick.map(|p| p.to_string()).unwrap_or_default(),
+        args.seed.to_string(),
+        file_fingerprint(args.risk_model.as_deref()),
+        churn_fingerprint(args),
+        args.prune_path.clone().unwrap_or_default(),
+        args.expand_dynamic.to_string(),
+        args.fields.clone().unwrap_or_default(),
+        args.annotation.clone().unwrap_or_default(),
+        args.symbol_type.clone().unwrap_or_default(),
+        args.attribute.clone().unwrap_or_default(),
+        args.sandbox.clone().unwrap_or_default(),
+        args.context_lines.to_string(),
+        // 基线文件内容变化也要失效缓存
+        file_fingerprint(args.compare_with.as_deref()),
+    ];
+    let mut hasher = Sha256::new();
+    for f in &fields {
+        hasher.update(f.as_bytes());
+        hasher.update([0u8]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+// 参数指向的文件：路径 + 内容哈希，文件改了缓存就失效
+fn file_fingerprint(path: Option<&str>) -> String {
+    path.map(|p| {
+        let digest = fs::read(p).map(|d| hex::encode(Sha256::digest(&d)));
+        format!("{}:{}", p, digest.unwrap_or_default())
+    })
+    .unwrap_or_default()
+}
+
+// analyze 的 churn 因子来自 git log --since：各 root 的 HEAD 变了（新提交）或跨天（窗口滑动）都要失效缓存
+fn churn_fingerprint(args: &Args) -> String {
+    if args.mode != "analyze" {
+        return String::new();
+    }
+    let heads: Vec<String> = project_roots(args)
+        .unwrap_or_default()
+        .iter()
+        .map(|root| {
+            std::process::Command::new("git")
+                .arg("-C")
+                .arg(&root.path)
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|o| o.status.success())
+                .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+                .unwrap_or_default()
+        })
+        .collect();
+    format!("{}@{}", heads.join("|"), unix_now() / 86400)
+}
+
+fn run_cached(args: &Args, run: fn(&Args) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let out_path = match &args.output {
+        Some(p) if !args.no_cache => p,
+        _ => return run(args),
+    };
+    let generation = read_generation(&Connection::open(&args.db)?)?;
+    let cache_root = Path::new(&args.db)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .join("query_cache");
+    let gen_dir = cache_root.join(generation.to_string());
+    let entry = gen_dir.join(format!("{}.json", query_cache_key(args)));
+
+    if let Ok(data) = fs::read(&entry) {
+        fs::write(out_path, data)?;
+        return Ok(());
+    }
+
+    run(args)?;
+
+    // 写缓存失败不影响主流程
+    if fs::create_dir_all(&gen_dir).is_ok() {
+        let tmp = entry.with_extension(format!("tmp{}", rand::random::<u32>()));
+        if fs::copy(out_path, &tmp).is_ok() && fs::rename(&tmp, &entry).is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        prune_query_cache(&cache_root, &gen_dir);
+    }
+    Ok(())
+}
+
+// 删除旧 generation 目录；当前目录超过上限时按修改时间淘汰最旧条目
+fn prune_query_cache(cache_root: &Path, current: &Path) {
+    if let Ok(dirs) = fs::read_dir(cache_root) {
+        for d in dirs.flatten() {
+            if d.path() != current {
+                let _ = fs::remove_dir_all(d.path());
+            }
+        }
+    }
+
+    let mut entries: Vec<(SystemTime, PathBuf)> = match fs::read_dir(current) {
+        Ok(rd) => rd
+            .flatten()
+            .filter_map(|e| {
+                let modified = e.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, e.path()))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    if entries.len() <= QUERY_CACHE_MAX_ENTRIES {
+        return;
+    }
+    entries.sort();
+    let excess = entries.len() - QUERY_CACHE_MAX_ENTRIES;
+    for (_, path) in entries.into_iter().take(excess) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// ============================================================================
+// Feature Flags (按语言的抽取开关)
+// --features calls=off,py:docs=off,go:signatures=off
+// 超大仓库可关闭调用抽取换取速度；docstring 默认不在索引期抽取
+// ============================================================================
+
+#[derive(Clone, Copy, Debug)]
+struct ExtractFeatures {
+    calls: bool,
+    signatures: bool,
+    docs: bool,
+}
+
+impl Default for ExtractFeatures {
+    fn default() -> Self {
+        ExtractFeatures {
+            calls: true,
+            signatures: true,
+            docs: true,
+        }
+    }
+}
+
+impl ExtractFeatures {
+    fn cache_tag(&self) -> String {
+        format!(
+            "c{}s{}d{}",
+            self.calls as u8, self.signatures as u8, self.docs as u8
+        )
+    }
+
+    fn set(&mut self, name: &str, on: bool) -> anyhow::Result<()> {
+        match name {
+            "calls" => self.calls = on,
+            "signatures" | "signature" | "sig" => self.signatures = on,
+            "docs" | "doc" | "docstrings" => self.docs = on,
+            other => anyhow::bail!(
+                "unknown feature '{}' (expected calls, signatures, docs)",
+                other
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct FeatureFlags {
+    global: ExtractFeatures,
+    // 按扩展名覆盖（只记录被显式设置的项，未设置的沿用 global）
+    per_ext: HashMap<String, Vec<(String, bool)>>,
+}
+
+impl FeatureFlags {
+    fn for_ext(&self, ext: &str) -> ExtractFeatures {
+        let mut f = self.global;
+        if let Some(overrides) = self.per_ext.get(ext) {
+            for (name, on) in overrides {
+                let _ = f.set(name, *on);
+            }
+        }
+        f
+    }
+}
+
+// 语言名 -> 扩展名（也可直接写扩展名，如 py:docs=off）
+fn language_exts(lang: &str) -> Vec<String> {
+    let exts: &[&str] = match lang {
+        "python" => &["py"],
+        "javascript" | "js" => &["js", "mjs", "cjs", "jsx"],
+        "typescript" | "ts" => &["ts", "tsx"],
+        "golang" => &["go"],
+        "rust" => &["rs"],
+        "c" => &["c", "h"],
+        "cpp" | "c++" => &["cpp", "cc", "hpp"],
+        "csharp" | "c#" => &["cs"],
+        "haskell" => &["hs"],
+        _ => return vec![lang.to_string()],
+    };
+    exts.iter().map(|e| e.to_string()).collect()
+}
+
+fn parse_feature_flags(spec: Option<&str>) -> anyhow::Result<FeatureFlags> {
+    let mut flags = FeatureFlags::default();
+    let Some(spec) = spec else {
+        return Ok(flags);
+    };
+    for item in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let (key, value) = item.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("invalid feature flag '{}' (expected name=on|off)", item)
+        })?;
+        let on = match value.trim().to_lowercase().as_str() {
+            "on" | "true" | "1" | "yes" => true,
+            "off" | "false" | "0" | "no" => false,
+            other => anyhow::bail!("invalid value '{}' for feature '{}'", other, key),
+        };
+        match key.trim().split_once(':') {
+            Some((lang, name)) => {
+                // 先校验名称，避免拼写错误被静默忽略
+                ExtractFeatures::default().set(name, on)?;
+                for ext in language_exts(&lang.to_lowercase()) {
+                    flags
+                        .per_ext
+                        .entry(ext)
+                        .or_default()
+                        .push((name.to_string(), on));
+                }
+            }
+            None => flags.global.set(key.trim(), on)?,
+        }
+    }
+    Ok(flags)
+}
+
+// ============================================================================
+// Symbol Taxonomy (细粒度符号类型)
+// query 只区分 def.func / def.class / def.const 三大类，这里按语法节点与上下文细分：
+//   function | method | test | component | macro
+//   class | struct | interface | trait | enum | impl | module | type
+//   constant | variant | field
+// 取值稳定，下游按此过滤；--type-map 可按语言改写输出名称
+// ============================================================================
+
+// canonical_id 前缀只看大类，保证细分/改写类型不会改变已有 ID
+// 🆕 编辑锚点 "pre:post"：pre = 起始行及其前一行、post = 结束行及其后一行，
+// 各自以 "\n" 连接（不含行尾 \r）后取 SHA-256 前 12 位十六进制。
+// Go 编辑工具按同一规则对磁盘文件重算，不一致说明索引后文件被改过，应拒绝套用补丁
+fn edit_anchor(lines: &[&str], line_start: usize, line_end: usize) -> String {
+    let digest = |from: usize, to: usize| {
+        let to = to.min(lines.len());
+        let text = lines.get(from.min(to)..to).unwrap_or(&[]).join("\n");
+        hex::encode(Sha256::digest(text.as_bytes()))[..12].to_string()
+    };
+    format!(
+        "{}:{}",
+        digest(line_start.saturating_sub(2), line_start),
+        digest(line_end.saturating_sub(1), line_end + 1)
+    )
+}
+
+fn canonical_prefix(symbol_type: &str) -> &'static str {
+    match symbol_type {
+        "class" | "struct" | "interface" | "trait" | "enum" | "impl" | "module" | "type"
+        | "table" | "view" | "resource" | "data" => "class",
+        "constant" | "variant" | "field" | "variable" | "output" => "const",
+        _ => "func",
+    }
+}
+
+fn node_text<'a>(node: tree_sitter::Node, content: &'a str) -> &'a str {
+    &content[node.start_byte()..node.end_byte()]
+}
+
+// Elixir `defmodule Foo.Bar do` / `defprotocol` / `defimpl`：返回模块别名
+fn elixir_module_alias<'a>(
+    ext: &str,
+    node: tree_sitter::Node,
+    content: &'a str,
+) -> Option<&'a str> {
+    if !matches!(ext, "ex" | "exs") || node.kind() != "call" {
+        return None;
+    }
+    let target = node_text(node.child_by_field_name("target")?, content);
+    if !matches!(target, "defmodule" | "defprotocol" | "defimpl") {
+        return None;
+    }
+    let args = node
+        .named_children(&mut node.walk())
+        .find(|c| c.kind() == "arguments")?;
+    let alias = args.named_child(0).filter(|a| a.kind() == "alias")?;
+    Some(node_text(alias, content))
+}
+
+// 位于文件顶层（允许 export 包一层）
+fn is_top_level(node: tree_sitter::Node) -> bool {
+    match node.parent() {
+        Some(p) if p.kind() == "export_statement" => {
+            p.parent().is_some_and(|g| g.parent().is_none())
+        }
+        Some(p) => p.parent().is_none(),
+        None => true,
+    }
+}
+
+// 祖先回溯的深度上限：生成代码可能嵌套上万层，回溯不设上限时单个病态文件就能拖死 worker。
+// 超过上限的外层容器不再计入 scope_path / 父符号
+const MAX_ANCESTOR_DEPTH: usize = 256;
+
+// 节点 id -> 父节点。parent() 每次都要从根重新下行（O(深度)），深层嵌套下反复回溯是平方级；
+// 这里用显式栈一次遍历建好（不递归），之后每步回溯 O(1)
+type ParentMap<'t> = HashMap<usize, tree_sitter::Node<'t>>;
+
+fn parent_map(root: tree_sitter::Node<'_>) -> ParentMap<'_> {
+    let mut parents = HashMap::new();
+    let mut stack = vec![root];
+    let mut walker = root.walk();
+    while let Some(node) = stack.pop() {
+        for child in node.children(&mut walker) {
+            parents.insert(child.id(), node);
+            stack.push(child);
+        }
+    }
+    parents
+}
+
+// 由近及远的祖先节点（最多 MAX_ANCESTOR_DEPTH 层）
+fn ancestors<'a, 't>(
+    node: tree_sitter::Node<'t>,
+    parents: &'a ParentMap<'t>,
+) -> impl Iterator<Item = tree_sitter::Node<'t>> + 'a {
+    std::iter::successors(parents.get(&node.id()).copied(), |p| {
+        parents.get(&p.id()).copied()
+    })
+    .take(MAX_ANCESTOR_DEPTH)
+}
+
+// 最近的定义容器是类/impl/trait（而不是外层函数）
+fn is_inside_type(node: tree_sitter::Node, parents: &ParentMap) -> bool {
+    for p in ancestors(node, parents) {
+        match p.kind() {
+            "class_definition"
+            | "class_declaration"
+            | "class_body"
+            | "class"
+            | "impl_item"
+            | "trait_item"
+            | "trait_declaration"
+            | "class_specifier"
+            | "struct_specifier"
+            | "protocol_declaration" => return true,
+            // Ruby module；Python 的根节点也叫 module，需排除
+            "module" if p.parent().is_some() => return true,
+            "function_definition"
+            | "function_item"
+            | "function_declaration"
+            | "method_definition"
+            | "arrow_function" => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+// React 类组件：extends Component / PureComponent（含 React.Component）
+fn is_class_component(node: tree_sitter::Node, content: &str) -> bool {
+    base_types(node, content).iter().any(|(base, kind)| {
+        *kind == "extends" && matches!(base.as_str(), "Component" | "PureComponent")
+    })
+}
+
+// 子树中的组件标签 <Foo> / <Foo /> / <ui.Button />，返回 (名字节点, 组件名)；
+// 小写开头的是 DOM 元素（div / motion.div），不算组件
+fn jsx_component_tags<'t>(
+    root: tree_sitter::Node<'t>,
+    content: &str,
+) -> Vec<(tree_sitter::Node<'t>, String)> {
+    let mut tags = vec![];
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        if matches!(
+            node.kind(),
+            "jsx_opening_element" | "jsx_self_closing_element"
+        ) {
+            if let Some(name) = node.child_by_field_name("name") {
+                let text = node_text(name, content);
+                let last = text.rsplit('.').next().unwrap_or(text);
+                if last.starts_with(|c: char| c.is_ascii_uppercase()) {
+                    tags.push((name, last.to_string()));
+                }
+            }
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.node() == root {
+                return tags;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return tags;
+            }
+        }
+    }
+}
+
+// 子树中是否含 JSX 节点（游标先序遍历，不递归，深层嵌套也不会爆栈）
+fn contains_jsx(node: tree_sitter::Node) -> bool {
+    let mut cursor = node.walk();
+    loop {
+        if cursor.node().kind().starts_with("jsx_") {
+            return true;
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.node() == node {
+                return false;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return false;
+            }
+        }
+    }
+}
+
+fn is_test_symbol(
+    ext: &str,
+    node: tree_sitter::Node,
+    parents: &ParentMap,
+    name: &str,
+    content: &str,
+) -> bool {
+    match ext {
+        "py" => name.starts_with("test"),
+        // XCTest：XCTestCase 子类中的 test* 方法
+        "swift" => name.starts_with("test") && is_inside_type(node, parents),
+        // Minitest / Test::Unit：测试类中的 test_* 方法
+        "rb" => name.starts_with("test_") && is_inside_type(node, parents),
+        // PHPUnit：TestCase 子类中的 test* 方法
+        "php" => name.starts_with("test") && is_inside_type(node, parents),
+        "go" => {
+            ["Test", "Benchmark", "Fuzz"]
+                .iter()
+                .any(|p| name.starts_with(p))
+                && node
+                    .child_by_field_name("parameters")
+                    .is_some_and(|params| node_text(params, content).contains("*testing."))
+        }
+        "rs" => {
+            // #[test] / #[tokio::test] 等属性位于函数前的兄弟节点
+            let mut prev = node.prev_named_sibling();
+            while let Some(p) = prev {
+                if p.kind() != "attribute_item" {
+                    break;
+                }
+                let attr = node_text(p, content);
+                if attr.contains("test]") || attr.contains("test(") {
+                    return true;
+                }
+                prev = p.prev_named_sibling();
+            }
+            false
+        }
+        "java" => {
+            let mut walker = node.walk();
+            let found = node
+                .children(&mut walker)
+                .find(|c| c.kind() == "modifiers")
+                .is_some_and(|m| node_text(m, content).contains("@Test"));
+            found
+        }
+        // NUnit / xUnit / MSTest：[Test] [TestCase(..)] [Fact] [Theory] [TestMethod]
+        "cs" => {
+            let mut walker = node.walk();
+            let found = node
+                .children(&mut walker)
+                .filter(|c| c.kind() == "attribute_list")
+                .flat_map(|list| {
+                    let mut w = list.walk();
+                    list.named_children(&mut w).collect::<Vec<_>>()
+                })
+                .filter_map(|attr| attr.child_by_field_name("name"))
+                .any(|n| {
+                    let name = node_text(n, content);
+                    let name = name.rsplit('.').next().unwrap_or(name);
+                    matches!(
+                        name.trim_end_matches("Attribute"),
+                        "Test" | "TestCase" | "TestCaseSource" | "Fact" | "Theory" | "TestMethod"
+                    )
+                });
+            found
+        }
+        // Jest / Mocha / Vitest：传给 it / test / describe（含 .only / .skip / .each(..)）的回调
+        "js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx" => {
+            let mut up = ancestors(node, parents);
+            let (Some(args), Some(call)) = (up.next(), up.next()) else {
+                return false;
+            };
+            if args.kind() != "arguments" || call.kind() != "call_expression" {
+                return false;
+            }
+            let mut callee = call.child_by_field_name("function");
+            // it.each([...])('title', fn) / it.only('title', fn)
+            while let Some(c) = callee {
+                callee = match c.kind() {
+                    "call_expression" => c.child_by_field_name("function"),
+                    "member_expression" => c.child_by_field_name("object"),
+                    _ => break,
+                };
+            }
+            callee.is_some_and(|c| {
+                c.kind() == "identifier"
+                    && matches!(
+                        node_text(c, content),
+                        "it" | "test"
+                            | "describe"
+                            | "suite"
+                            | "xit"
+                            | "xdescribe"
+                            | "fit"
+                            | "fdescribe"
+                    )
+            })
+        }
+        _ => false,
+    }
+}
+
+// 返回 None 表示该命中不作为符号（如非全大写的 Python 模块级赋值）
+fn classify_symbol(
+    ext: &str,
+    coarse: &str,
+    node: tree_sitter::Node,
+    parents: &ParentMap,
+    name: &str,
+    content: &str,
+) -> Option<&'static str> {
+    let is_js_family = matches!(ext, "js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx");
+    let starts_upper = name.chars().next().is_some_and(|c| c.is_ascii_uppercase());
+
+    match coarse {
+        "field" => Some("field"),
+        "constant" => {
+            // 枚举成员（TS 无初始值的成员直接是 enum_body 下的 property_identifier）
+            if matches!(
+                node.kind(),
+                "enum_variant" | "enum_constant" | "enum_assignment" | "property_identifier"
+            ) {
+                return Some("variant");
+            }
+            // C/C++ 的常量捕获只有声明：文件作用域的才是全局变量，函数内的局部变量不入索引
+            if matches!(ext, "c" | "h" | "cpp" | "cc" | "hpp") {
+                return c_global_declaration(ext, node)
+                    .and_then(|decl| c_global_kind(decl, node, content));
+            }
+            let all_caps = name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+            match ext {
+                // 没有 const 关键字的语言只认 UPPER_CASE 约定
+                "py" => all_caps.then_some("constant"),
+                _ if is_js_family => (all_caps && is_top_level(node)).then_some("constant"),
+                _ => Some("constant"),
+            }
+        }
+        "class"
+            if is_js_family
+                && matches!(
+                    node.kind(),
+                    "class_declaration" | "abstract_class_declaration"
+                )
+                && is_class_component(node, content) =>
+        {
+            Some("component")
+        }
+        "class" => Some(match node.kind() {
+            "struct_item" | "struct_specifier" | "struct_declaration" => "struct",
+            "enum_item" | "enum_specifier" | "enum_declaration" => "enum",
+            "interface_declaration" => "interface",
+            "trait_item" | "trait_declaration" => "trait",
+            // Elixir：defmodule / defprotocol 都是 call 节点
+            "call" => match node
+                .child_by_field_name("target")
+                .map(|t| node_text(t, content))
+            {
+                Some("defprotocol") => "interface",
+                _ => "module",
+            },
+            "impl_item" => "impl",
+            "mod_item" | "namespace_definition" | "internal_module" | "module" => "module",
+            "type_alias_declaration" => "type",
+            "protocol_declaration" => "interface",
+            // Swift 的 class/struct/enum/extension/actor 共用 class_declaration
+            "class_declaration" if ext == "swift" => {
+                match node
+                    .child_by_field_name("declaration_kind")
+                    .map(|k| k.kind())
+                {
+                    Some("struct") => "struct",
+                    Some("enum") => "enum",
+                    Some("extension") => "impl",
+                    _ => "class",
+                }
+            }
+            "type_spec" => match node.child_by_field_name("type").map(|t| t.kind()) {
+                Some("struct_type") => "struct",
+                Some("interface_type") => "interface",
+                _ => "type",
+            },
+            _ => "class",
+        }),
+        _ => {
+            if node.kind() == "lexical_declaration" && !is_top_level(node) {
+                return None;
+            }
+            if node.kind() == "macro_definition" {
+                return Some("macro");
+            }
+            if matches!(node.kind(), "preproc_def" | "preproc_function_def") {
+                return (!is_include_guard(node, content)).then_some("macro");
+            }
+            if is_test_symbol(ext, node, parents, name, content) {
+                return Some("test");
+            }
+            if is_js_family && starts_upper && contains_jsx(node) {
+                return Some("component");
+            }
+            if is_property(ext, node, content) {
+                return Some("property");
+            }
+            let is_method = match node.kind() {
+                "method_definition"
+                | "method_signature"
+                | "abstract_method_signature"
+                | "method_declaration"
+                | "method_elem"
+                | "constructor_declaration"
+                | "init_declaration"
+                | "protocol_function_declaration"
+                | "singleton_method" => true,
+                "function_definition" | "function_item" => is_inside_type(node, parents),
+                "function_signature_item" => true, // 只捕获 trait 里的
+                "function_declaration" if ext == "swift" => is_inside_type(node, parents),
+                "method" => is_inside_type(node, parents), // Ruby 顶层 def 视为函数
+                _ => false,
+            };
+            // C++ 类外定义 Foo::bar() 的声明符是 qualified_identifier
+            let is_qualified_cpp = node
+                .child_by_field_name("declarator")
+                .and_then(|d| d.child_by_field_name("declarator"))
+                .is_some_and(|d| matches!(d.kind(), "qualified_identifier" | "field_identifier"));
+            Some(if is_method || is_qualified_cpp {
+                "method"
+            } else {
+                "function"
+            })
+        }
+    }
+}
+
+// C/C++ 全局变量：捕获的声明符所在的 declaration 位于文件作用域（可隔着 #if / extern "C" / namespace）
+fn c_global_declaration<'t>(
+    ext: &str,
+    node: tree_sitter::Node<'t>,
+) -> Option<tree_sitter::Node<'t>> {
+    if !matches!(ext, "c" | "h" | "cpp" | "cc" | "hpp") {
+        return None;
+    }
+    let decl = node.parent().filter(|p| p.kind() == "declaration")?;
+    let mut scope = decl.parent()?;
+    loop {
+        match scope.kind() {
+            "translation_unit" => return Some(decl),
+            "preproc_if"
+            | "preproc_ifdef"
+            | "preproc_else"
+            | "preproc_elif"
+            | "preproc_elifdef"
+            | "declaration_list"
+            | "linkage_specification"
+            | "namespace_definition" => {
+                scope = scope.parent()?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// extern 声明只是引用别处的定义，不单独成符号；const 限定且不是指针的记 constant
+fn c_global_kind(
+    decl: tree_sitter::Node,
+    declarator: tree_sitter::Node,
+    content: &str,
+) -> Option<&'static str> {
+    let mut walker = decl.walk();
+    let mut is_extern = false;
+    let mut is_const = false;
+    for c in decl.children(&mut walker) {
+        match (c.kind(), node_text(c, content)) {
+            ("storage_class_specifier", "extern") => is_extern = true,
+            ("type_qualifier", "const" | "constexpr") => is_const = true,
+            _ => {}
+        }
+    }
+    if is_extern {
+        return None;
+    }
+    let target = match declarator.kind() {
+        "init_declarator" => declarator.child_by_field_name("declarator")?,
+        _ => declarator,
+    };
+    Some(if is_const && target.kind() != "pointer_declarator" {
+        "constant"
+    } else {
+        "variable"
+    })
+}
+
+// 函数体内的标识符引用：跳过声明符位置的名字和被调用者（调用已记 call 边）
+fn c_identifier_refs(root: tree_sitter::Node) -> Vec<tree_sitter::Node> {
+    let mut refs = vec![];
+    let mut cursor = root.walk();
+    loop {
+        let node = cursor.node();
+        if node.kind() == "identifier" {
+            let declared_or_called = node.parent().is_some_and(|p| match p.kind() {
+                "declaration" | "parameter_declaration" => true,
+                "call_expression" => p.child_by_field_name("function") == Some(node),
+                _ => p.child_by_field_name("declarator") == Some(node),
+            });
+            if !declared_or_called {
+                refs.push(node);
+            }
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return refs;
+            }
+        }
+    }
+}
+
+// 函数内声明的名字（参数与局部变量），同名的引用指的是它们而不是全局变量
+fn c_local_names(func: tree_sitter::Node, content: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut cursor = func.walk();
+    loop {
+        let node = cursor.node();
+        if matches!(
+            node.kind(),
+            "declaration"
+                | "parameter_declaration"
+                | "optional_parameter_declaration"
+                | "for_range_loop"
+        ) {
+            let mut walker = node.walk();
+            for d in node.children_by_field_name("declarator", &mut walker) {
+                let mut d = d;
+                while d.kind() != "identifier" {
+                    match d.child_by_field_name("declarator") {
+                        Some(inner) => d = inner,
+                        None => break,
+                    }
+                }
+                if d.kind() == "identifier" {
+                    names.insert(node_text(d, content).to_string());
+                }
+            }
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.node() == func {
+                return names;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+            if !cursor.goto_parent() {
+                return names;
+            }
+        }
+    }
+}
+
+// 头文件保护宏：#ifndef X_H 块里无值的 #define X_H
+fn is_include_guard(node: tree_sitter::Node, content: &str) -> bool {
+    if node.kind() != "preproc_def" || node.child_by_field_name("value").is_some() {
+        return false;
+    }
+    let Some(guard) = node.parent().filter(|p| p.kind() == "preproc_ifdef") else {
+        return false;
+    };
+    let defined = node
+        .child_by_field_name("name")
+        .map(|n| node_text(n, content));
+    let tested = guard
+        .child_by_field_name("name")
+        .map(|n| node_text(n, content));
+    defined.is_some() && defined == tested
+}
+
+// 属性：JS/TS get / set 访问器、Python @property（及其 setter / deleter）、C# 属性声明
+fn is_property(ext: &str, node: tree_sitter::Node, content: &str) -> bool {
+    match node.kind() {
+        "property_declaration" => ext == "cs",
+        "method_definition" => {
+            let mut walker = node.walk();
+            let accessor = node
+                .children(&mut walker)
+                .any(|c| matches!(c.kind(), "get" | "set"));
+            accessor
+        }
+        "function_definition" if ext == "py" => {
+            let Some(decorated) = node.parent().filter(|p| p.kind() == "decorated_definition")
+            else {
+                return false;
+            };
+            let mut walker = decorated.walk();
+            let found = decorated
+                .named_children(&mut walker)
+                .filter_map(|d| decorator_name(d, content))
+                .any(|name| {
+                    matches!(
+                        name.as_str(),
+                        "property" | "cached_property" | "functools.cached_property"
+                    ) || [".setter", ".getter", ".deleter"]
+                        .iter()
+                        .any(|s| name.ends_with(s))
+                });
+            found
+        }
+        _ => false,
+    }
+}
+
+// --type-map：{"*": {...}, "<lang 或扩展名>": {"struct": "class"}}，语言级覆盖通配
+#[derive(Default)]
+struct TypeMap {
+    global: HashMap<String, String>,
+    per_ext: HashMap<String, HashMap<String, String>>,
+    raw: String,
+}
+
+impl TypeMap {
+    fn resolve<'a>(&'a self, ext: &str, symbol_type: &'a str) -> &'a str {
+        self.per_ext
+            .get(ext)
+            .and_then(|m| m.get(symbol_type))
+            .or_else(|| self.global.get(symbol_type))
+            .map(|s| s.as_str())
+            .unwrap_or(symbol_type)
+    }
+
+    // 映射内容参与抽取签名，改动后触发重新写库
+    fn tag(&self) -> String {
+        if self.raw.is_empty() {
+            return String::new();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.raw.as_bytes());
+        format!("tm{}", &hex::encode(hasher.finalize())[..12])
+    }
+}
+
+fn load_type_map(path: Option<&str>) -> anyhow::Result<TypeMap> {
+    let Some(path) = path else {
+        return Ok(TypeMap::default());
+    };
+    let raw = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read type map {}: {}", path, e))?;
+    let parsed: HashMap<String, HashMap<String, String>> = serde_json::from_str(&raw)
+        .map_err(|e| anyhow::anyhow!("invalid type map {}: {}", path, e))?;
+    let mut map = TypeMap {
+        raw,
+        ..Default::default()
+    };
+    for (lang, entries) in parsed {
+        if lang == "*" {
+            map.global.extend(entries);
+        } else {
+            for ext in language_exts(&lang.to_lowercase()) {
+                map.per_ext.entry(ext).or_default().extend(entries.clone());
+            }
+        }
+    }
+    Ok(map)
+}
+
+// ============================================================================
+// Visibility (符号可见性：public | protected | internal | private)
+// internal = 包 / crate / 程序集内可见（Java 包私有、Rust pub(crate)、C# internal、Swift 默认）；
+// 成员的可见性只看自身修饰符，对外是否可达还要看外层类型（公开 API 面 = 自身及所有外层都是 public）
+// ============================================================================
+
+// JS/TS 模块里后置导出的名字：`export { a, b as c }` 与 `module.exports = { a, b }`
+fn js_exported_names(root: tree_sitter::Node, content: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut walker = root.walk();
+    for stmt in root.named_children(&mut walker) {
+        match stmt.kind() {
+            "export_statement" if stmt.child_by_field_name("declaration").is_none() => {
+                let mut w = stmt.walk();
+                for clause in stmt
+                    .named_children(&mut w)
+                    .filter(|c| c.kind() == "export_clause")
+                {
+                    let mut cw = clause.walk();
+                    for spec in clause.named_children(&mut cw) {
+                        if let Some(local) = spec.child_by_field_name("name") {
+                            names.insert(node_text(local, content).to_string());
+                        }
+                    }
+                }
+            }
+            "expression_statement" => {
+                let Some(assign) = stmt
+                    .named_child(0)
+                    .filter(|a| a.kind() == "assignment_expression")
+                else {
+                    continue;
+                };
+                let (Some(left), Some(right)) = (
+                    assign.child_by_field_name("left"),
+                    assign.child_by_field_name("right"),
+                ) else {
+                    continue;
+                };
+                if node_text(left, content) != "module.exports" || right.kind() != "object" {
+                    continue;
+                }
+                let mut w = right.walk();
+                for prop in right.named_children(&mut w) {
+                    match prop.kind() {
+                        "shorthand_property_identifier" => {
+                            names.insert(node_text(prop, content).to_string());
+                        }
+                        "pair" => {
+                            if let Some(value) = prop
+                                .child_by_field_name("value")
+                                .filter(|v| v.kind() == "identifier")
+                            {
+                                names.insert(node_text(value, content).to_string());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+// 定义节点的直接子节点中，第一个种类命中 kinds 的节点的文本
+fn child_text_of_kind<'a>(
+    node: tree_sitter::Node,
+    kinds: &[&str],
+    content: &'a str,
+) -> Option<&'a str> {
+    let mut walker = node.walk();
+    let found = node
+        .children(&mut walker)
+        .find(|c| kinds.contains(&c.kind()))
+        .map(|c| node_text(c, content));
+    found
+}
+
+// 不支持的语言（Ruby 的 private 段、文本扫描的语言）返回 None
+fn symbol_visibility(
+    ext: &str,
+    node: tree_sitter::Node,
+    parents: &ParentMap,
+    name: &str,
+    content: &str,
+    js_exports: &HashSet<String>,
+) -> Option<&'static str> {
+    if name.starts_with("<anon@") {
+        return Some("private");
+    }
+    let container = ancestors(node, parents).map(|p| p.kind()).find(|k| {
+        matches!(
+            *k,
+            "class_body"
+                | "interface_body"
+                | "enum_body"
+                | "object_type"
+                | "declaration_list"
+                | "field_declaration_list"
+                | "enum_variant_list"
+                | "enumerator_list"
+                | "function_declaration"
+                | "function_definition"
+                | "function_item"
+                | "method_definition"
+                | "arrow_function"
+                | "statement_block"
+                | "block"
+        )
+    });
+    match ext {
+        "go" => Some(if name.starts_with(|c: char| c.is_uppercase()) {
+            "public"
+        } else {
+            "private"
+        }),
+        "py" => {
+            // 函数内嵌套的定义在模块外不可见
+            let nested = ancestors(node, parents)
+                .map(|p| p.kind())
+                .find(|k| matches!(*k, "function_definition" | "class_definition"))
+                == Some("function_definition");
+            if nested
+                || (name.starts_with('_') && !(name.starts_with("__") && name.ends_with("__")))
+            {
+                Some("private")
+            } else {
+                Some("public")
+            }
+        }
+        "rs" => {
+            // trait 成员、trait 实现里的方法、枚举变体随外层类型可见
+            let in_trait_or_impl_trait = ancestors(node, parents).take(2).any(|p| {
+                p.kind() == "trait_item"
+                    || (p.kind() == "impl_item" && p.child_by_field_name("trait").is_some())
+            });
+            if node.kind() == "enum_variant" || in_trait_or_impl_trait {
+                return Some("public");
+            }
+            if node.kind() == "impl_item" {
+                return None;
+            }
+            if node.kind() == "macro_definition" {
+                let exported = node
+                    .prev_named_sibling()
+                    .filter(|p| p.kind() == "attribute_item")
+                    .is_some_and(|p| node_text(p, content).contains("macro_export"));
+                return Some(if exported { "public" } else { "private" });
+            }
+            Some(
+                match child_text_of_kind(node, &["visibility_modifier"], content) {
+                    Some("pub") => "public",
+                    Some(_) => "internal", // pub(crate) / pub(super) / pub(in path)
+                    None => "private",
+                },
+            )
+        }
+        "js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx" => {
+            let member = matches!(
+                container,
+                Some("class_body" | "interface_body" | "object_type" | "enum_body")
+            );
+            if member {
+                if name.starts_with('#') {
+                    return Some("private");
+                }
+                return Some(
+                    match child_text_of_kind(node, &["accessibility_modifier"], content) {
+                        Some("private") => "private",
+                        Some("protected") => "protected",
+                        _ => "public",
+                    },
+                );
+            }
+            // `obj.fn = () => {}` / `exports.fn = ...` 挂在对象上，随对象可见
+            let exported = node
+                .parent()
+                .is_some_and(|p| p.kind() == "export_statement")
+                || node.kind() == "expression_statement"
+                || (is_top_level(node) && js_exports.contains(name));
+            Some(if exported { "public" } else { "private" })
+        }
+        "java" => {
+            let modifiers = {
+                let mut walker = node.walk();
+                let m = node.children(&mut walker).find(|c| c.kind() == "modifiers");
+                m
+            };
+            let declared = modifiers.and_then(|m| {
+                let mut walker = m.walk();
+                let found = m
+                    .children(&mut walker)
+                    .find(|c| matches!(c.kind(), "public" | "protected" | "private"))
+                    .map(|c| c.kind());
+                found
+            });
+            Some(match declared {
+                Some("public") => "public",
+                Some("protected") => "protected",
+                Some(_) => "private",
+                // 接口成员与枚举常量隐式 public，其余默认包私有
+                None if node.kind() == "enum_constant" || container == Some("interface_body") => {
+                    "public"
+                }
+                None => "internal",
+            })
+        }
+        "cs" => {
+            let mut walker = node.walk();
+            let modifiers: Vec<&str> = node
+                .children(&mut walker)
+                .filter(|c| c.kind() == "modifier")
+                .map(|c| node_text(c, content))
+                .collect();
+            let in_interface = ancestors(node, parents)
+                .nth(1)
+                .is_some_and(|p| p.kind() == "interface_declaration");
+            Some(if modifiers.contains(&"public") {
+                "public"
+            } else if modifiers.contains(&"protected") {
+                "protected"
+            } else if modifiers.contains(&"internal") {
+                "internal"
+            } else if modifiers.contains(&"private") {
+                "private"
+            } else if node.kind() == "enum_member_declaration" || in_interface {
+                "public"
+            } else if container == Some("declaration_list")
+                && ancestors(node, parents)
+                    .nth(1)
+                    .is_some_and(|p| p.kind() != "namespace_declaration")
+            {
+                // 类型成员默认 private，顶层 / 命名空间下的类型默认 internal
+                "private"
+            } else {
+                "internal"
+            })
+        }
+        "c" | "h" | "cpp" | "cc" | "hpp" => {
+            // 宏写在头文件里才会被别的翻译单元看到
+            if matches!(node.kind(), "preproc_def" | "preproc_function_def") {
+                return Some(if matches!(ext, "h" | "hpp") {
+                    "public"
+                } else {
+                    "private"
+                });
+            }
+            if container == Some("field_declaration_list") {
+                // C++ 成员：向前找最近的 public: / protected: / private:，没有则 class 默认 private、struct 默认 public
+                let mut prev = node.prev_sibling();
+                while let Some(p) = prev {
+                    if p.kind() == "access_specifier" {
+                        return Some(match node_text(p, content) {
+                            "public" => "public",
+                            "protected" => "protected",
+                            _ => "private",
+                        });
+                    }
+                    prev = p.prev_sibling();
+                }
+                let is_class = ancestors(node, parents)
+                    .nth(1)
+                    .is_some_and(|p| p.kind() == "class_specifier");
+                return Some(if is_class { "private" } else { "public" });
+            }
+            // 全局变量的 static 写在外层 declaration 上，捕获的是其中的声明符
+            let decl = c_global_declaration(ext, node).unwrap_or(node);
+            let is_static = {
+                let mut walker = decl.walk();
+                let found = decl.children(&mut walker).any(|c| {
+                    c.kind() == "storage_class_specifier" && node_text(c, content) == "static"
+                });
+                found
+            };
+            Some(if is_static { "private" } else { "public" })
+        }
+        "swift" => {
+            let modifier = {
+                let mut walker = node.walk();
+                let m = node
+                    .children(&mut walker)
+                    .find(|c| c.kind() == "modifiers")
+                    .and_then(|m| child_text_of_kind(m, &["visibility_modifier"], content));
+                m
+            };
+            Some(match modifier.map(|m| m.split('(').next().unwrap_or(m)) {
+                Some("public" | "open") => "public",
+                Some("private" | "fileprivate") => "private",
+                _ => "internal",
+            })
+        }
+        "php" => Some(
+            match child_text_of_kind(node, &["visibility_modifier"], content) {
+                Some(m) if m.eq_ignore_ascii_case("private") => "private",
+                Some(m) if m.eq_ignore_ascii_case("protected") => "protected",
+                _ => "public",
+            },
+        ),
+        "ex" | "exs" => {
+            let keyword = node
+                .child_by_field_name("target")
+                .map(|t| node_text(t, content));
+            Some(match keyword {
+                Some("defp" | "defmacrop" | "defguardp") => "private",
+                _ => "public",
+            })
+        }
+        _ => None,
+    }
+}
+
+// ============================================================================
+// Parse Cache (内容寻址解析缓存)
+// 以 (抽取器版本, 语法 ABI, 扩展名, 文件哈希) 为键保存抽取结果；
+// DB 重建或另一个 worktree 索引相同内容时直接复用，跳过 tree-sitter
+// ============================================================================
+
+// extract_file 逻辑变化时递增，使旧缓存与已落库的行自然失效（query 文本变化由指纹覆盖）
+const PARSE_CACHE_SCHEMA: u32 = 26;
+
+// 每种语言的抽取器版本：schema + 语法 ABI + 语法指纹 + query 文本指纹，按文件记录在 files.extractor_version
+// 语法指纹取节点种类 / 字段 / 解析状态数（及 ABI 15 的 semver），升级 grammar crate 即变化
+fn extractor_version(lang: &Language, query_src: &str) -> String {
+    let mut grammar = Sha256::new();
+    grammar.update(
+        format!(
+            "{}:{}:{}",
+            lang.node_kind_count(),
+            lang.field_count(),
+            lang.parse_state_count()
+        )
+        .as_bytes(),
+    );
+    if let Some(m) = lang.metadata() {
+        grammar.update(
+            format!(
+                ":{}.{}.{}",
+                m.major_version, m.minor_version, m.patch_version
+            )
+            .as_bytes(),
+        );
+    }
+    let mut query = Sha256::new();
+    query.update(query_src.as_bytes());
+    format!(
+        "v{}-abi{}-g{}-q{}",
+        PARSE_CACHE_SCHEMA,
+        lang.abi_version(),
+        &hex::encode(grammar.finalize())[..6],
+        &hex::encode(query.finalize())[..8]
+    )
+}
+
+fn resolve_parse_cache_dir(args: &Args) -> Option<PathBuf> {
+    match args.parse_cache.as_deref() {
+        Some("off") | Some("none") => None,
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => {
+            let db_dir = Path::new(&args.db)
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            Some(db_dir.join("parse_cache"))
+        }
+    }
+}
+
+fn parse_cache_path(dir: &Path, ext: &str, file_version: &str, file_hash: &str) -> PathBuf {
+    // file_version 已含 schema / 语法 ABI / query 指纹 / 特性开关
+    let grammar_key = format!("{}-{}-{}", env!("CARGO_PKG_VERSION"), ext, file_version);
+    let shard = &file_hash[..2.min(file_hash.len())];
+    dir.join(grammar_key)
+        .join(shard)
+        .join(format!("{}.json", file_hash))
+}
+
+// 键目录 <crate 版本>-<ext>-<抽取器版本>-<特性>：crate 版本或该扩展名当前抽取器版本（含 schema）对不上的
+// 整个删掉，旧键永远不会再命中。特性组合不比对（不同 --features 的运行可共用缓存）
+fn prune_parse_cache(dir: &Path, parsers: &ParserSetup) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let crate_prefix = format!("{}-", env!("CARGO_PKG_VERSION"));
+    let mut pruned = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let current = name
+            .strip_prefix(&crate_prefix)
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(ext, version)| {
+                let current = file_extractor_version(parsers, ext)?;
+                Some(version.starts_with(&format!("{}-", current)))
+            })
+            .unwrap_or(false);
+        if !current && entry.path().is_dir() && fs::remove_dir_all(entry.path()).is_ok() {
+            pruned += 1;
+        }
+    }
+    pruned
+}
+
+fn load_parse_cache(path: &Path) -> Option<ParsedFile> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+// 先写临时文件再 rename：并发 worker / 多个 worktree 同时写同一键也不会读到半截文件
+fn store_parse_cache(path: &Path, parsed: &ParsedFile) {
+    let Some(parent) = path.parent() else {
+        return;
+    };
+    if fs::create_dir_all(parent).is_err() {
+        return;
+    }
+    let tmp = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+    let ok = serde_json::to_vec(parsed)
+        .ok()
+        .map(|data| fs::write(&tmp, data).is_ok())
+        .unwrap_or(false);
+    if !ok || fs::rename(&tmp, path).is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+// ============================================================================
+// Endpoint Cross-Referencing (URL 字面量 ↔ 路由表)
+// ============================================================================
+
+enum EndpointRef {
+    /// fetch("/api/x") / axios.get(...) / requests.get(...) / http.Get(...)
+    Client { http_method: String, path: String },
+    /// app.get("/api/x", handler) / @app.route("/api/x") / http.HandleFunc("/api/x", h)
+    Route {
+        http_method: String,
+        path: String,
+        handler_name: Option<String>,
+    },
+}
+
+// 接收者名命中即视为 HTTP 客户端调用（this.http / self.client 取最后一段）
+const HTTP_CLIENT_RECEIVERS: &[&str] = &[
+    "axios",
+    "http",
+    "https",
+    "requests",
+    "httpx",
+    "session",
+    "client",
+    "httpclient",
+    "$http",
+    "superagent",
+    "ky",
+    "got",
+    "resty",
+];
+
+// 服务端路由注册函数名（小写比较）
+const ROUTE_REGISTRARS: &[&str] = &[
+    "get",
+    "post",
+    "put",
+    "delete",
+    "patch",
+    "head",
+    "options",
+    "all",
+    "any",
+    "route",
+    "handle",
+    "handlefunc",
+];
+
+// 路由注册的接收者名（小写比较，取最后一段）：get / all / handle 这类名字太常见，
+// cache.get("/key", fallback) 之类只有接收者像路由器时才算路由；userRouter / admin_bp 按后缀命中
+const ROUTER_RECEIVERS: &[&str] = &[
+    "app",
+    "router",
+    "r",
+    "mux",
+    "http",
+    "api",
+    "bp",
+    "blueprint",
+    "server",
+    "srv",
+    "e",
+    "g",
+    "group",
+    "rg",
+    "engine",
+    "routes",
+    "route",
+    "web",
+    "fastify",
+];
+const ROUTER_RECEIVER_SUFFIXES: &[&str] = &[
+    "router",
+    "app",
+    "mux",
+    "_bp",
+    "blueprint",
+    "group",
+    "routes",
+];
+
+fn is_router_receiver(receiver: &str) -> bool {
+    let lower = receiver.to_lowercase();
+    ROUTER_RECEIVERS.contains(&lower.as_str())
+        || ROUTER_RECEIVER_SUFFIXES.iter().any(|s| lower.ends_with(s))
+}
+
+fn http_verb(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "get" => Some("GET"),
+        "post" | "postform" => Some("POST"),
+        "put" => Some("PUT"),
+        "delete" | "del" => Some("DELETE"),
+        "patch" => Some("PATCH"),
+        "head" => Some("HEAD"),
+        "options" => Some("OPTIONS"),
+        _ => None,
+    }
+}
+
+fn classify_endpoint_call(
+    call: tree_sitter::Node,
+    callee: tree_sitter::Node,
+    callee_name: &str,
+    content: &str,
+) -> Option<EndpointRef> {
+    let args_node = call.child_by_field_name("arguments")?;
+    let mut walker = args_node.walk();
+    let args: Vec<tree_sitter::Node> = args_node
+        .named_children(&mut walker)
+        .filter(|n| n.kind() != "comment")
+        .collect();
+    let literal = string_literal_text(*args.first()?, content)?;
+    let lower = callee_name.to_lowercase();
+
+    let receiver = call_receiver(call, callee, content);
+    let is_client = match &receiver {
+        Some(receiver) => {
+            HTTP_CLIENT_RECEIVERS.contains(&receiver.to_lowercase().as_str())
+                && (http_verb(&lower).is_some() || lower == "request")
+        }
+        None => lower == "fetch" || lower == "axios",
+    };
+    if is_client {
+        let path = normalize_url_path(&literal)?;
+        let http_method = http_verb(&lower).unwrap_or("ANY").to_string();
+        return Some(EndpointRef::Client { http_method, path });
+    }
+
+    // 没有接收者的裸调用是 Ktor / Sinatra 式的路由 DSL，路由器隐含在外层块里
+    if !ROUTE_REGISTRARS.contains(&lower.as_str())
+        || receiver.is_some_and(|r| !is_router_receiver(&r))
+    {
+        return None;
+    }
+
+    // Go 1.22 ServeMux 模式："GET /items/{id}"
+    let (http_method, raw_path) = match literal.split_once(' ') {
+        Some((m, p)) if http_verb(m).is_some() => (http_verb(m), p.trim()),
+        _ => (http_verb(&lower), literal.as_str()),
+    };
+    if !raw_path.starts_with('/') {
+        return None;
+    }
+    let path = normalize_url_path(raw_path)?;
+
+    // Python 装饰器路由：handler 即被装饰的函数
+    let decorated = call
+        .parent()
+        .filter(|p| p.kind() == "decorator")
+        .and_then(|d| d.parent())
+        .filter(|p| p.kind() == "decorated_definition");
+    let handler_name = match decorated {
+        Some(def) => def
+            .child_by_field_name("definition")
+            .and_then(|d| d.child_by_field_name("name"))
+            .map(|n| content[n.start_byte()..n.end_byte()].to_string()),
+        None => {
+            // 非装饰器形式至少需要 (path, handler) 两个参数，避免误判 dict.get("/x")
+            if args.len() < 2 {
+                return None;
+            }
+            handler_identifier(*args.last()?, content)
+        }
+    };
+
+    Some(EndpointRef::Route {
+        http_method: http_method.unwrap_or("ANY").to_string(),
+        path,
+        handler_name,
+    })
+}
+
+// 调用接收者（obj.method() 中的 obj），只保留最后一段
+fn call_receiver(
+    call: tree_sitter::Node,
+    callee: tree_sitter::Node,
+    content: &str,
+) -> Option<String> {
+    let parent = callee.parent()?;
+    let object = if parent.id() == call.id() {
+        // Java method_invocation: object 字段直接挂在调用节点上
+        call.child_by_field_name("object")?
+    } else {
+        parent
+            .child_by_field_name("object")
+            .or_else(|| parent.child_by_field_name("operand"))
+            .or_else(|| parent.child_by_field_name("value"))?
+    };
+    let text = &content[object.start_byte()..object.end_byte()];
+    text.rsplit(['.', ':', '>'])
+        .next()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn handler_identifier(node: tree_sitter::Node, content: &str) -> Option<String> {
+    let ident = if node.kind() == "identifier" {
+        Some(node)
+    } else {
+        ["property", "attribute", "field", "name"]
+            .iter()
+            .find_map(|f| node.child_by_field_name(f))
+            .filter(|n| n.kind().ends_with("identifier"))
+    }?;
+    Some(content[ident.start_byte()..ident.end_byte()].to_string())
+}
+
+// 字符串字面量内容（去引号/前缀；模板插值 ${x} 统一替换为 {}）
+fn string_literal_text(node: tree_sitter::Node, content: &str) -> Option<String> {
+    if !node.kind().contains("string") {
+        return None;
+    }
+    let raw = &content[node.start_byte()..node.end_byte()];
+    let start = raw.find(['"', '\'', '`'])?;
+    let quote = raw[start..].chars().next()?;
+    let end = raw.rfind(quote)?;
+    if end <= start {
+        return None;
+    }
+    let inner = raw[start..=end].trim_matches(quote);
+
+    let mut out = String::with_capacity(inner.len());
+    let mut rest = inner;
+    while let Some(pos) = rest.find("${") {
+        out.push_str(&rest[..pos]);
+        match rest[pos..].find('}') {
+            Some(close) => {
+                out.push_str("{}");
+                rest = &rest[pos + close + 1..];
+            }
+            None => {
+                rest = &rest[pos..];
+                break;
+            }
+        }
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+// 归一化 URL 路径：去 scheme/host/query，参数段 (:id / {id} / <int:id>) 统一为 {}
+fn normalize_url_path(raw: &str) -> Option<String> {
+    let mut s = raw.trim();
+    if let Some(pos) = s.find("://") {
+        let rest = &s[pos + 3..];
+        s = rest.find('/').map(|i| &rest[i..]).unwrap_or("/");
+    }
+    // ${BASE_URL}/users -> /users
+    if s.starts_with('{') {
+        s = &s[s.find('}')? + 1..];
+    }
+    let s = s.split(['?', '#']).next().unwrap_or("");
+    if !s.starts_with('/') {
+        return None;
+    }
+    let segments: Vec<&str> = s
+        .split('/')
+        .filter(|seg| !seg.is_empty())
+        .map(|seg| {
+            if seg.starts_with(':') || seg.starts_with('<') || seg.contains('{') {
+                "{}"
+            } else {
+                seg
+            }
+        })
+        .collect();
+    Some(format!("/{}", segments.join("/")))
+}
+
+fn route_path_matches(route: &str, target: &str) -> bool {
+    let r: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+    let t: Vec<&str> = target.split('/').filter(|s| !s.is_empty()).collect();
+    for (i, seg) in r.iter().enumerate() {
+        // 末尾通配：/static/* 匹配任意子路径
+        if (*seg == "*" || *seg == "**") && i == r.len() - 1 {
+            return t.len() >= i;
+        }
+        let Some(other) = t.get(i) else {
+            return false;
+        };
+        if *seg == "{}" || *seg == "*" || *other == "{}" {
+            continue;
+        }
+        if seg != other {
+            return false;
+        }
+    }
+    r.len() == t.len()
+}
+
+// 将 kind='http' 的调用边回填到路由 handler（精确段优先于参数段）
+fn link_http_calls(conn: &Connection) -> Result<usize> {
+    let routes: Vec<(String, String, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT http_method, path, handler_id FROM routes
+             WHERE handler_id IS NOT NULL ORDER BY route_id",
+        )?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        rows.flatten().collect()
+    };
+    if routes.is_empty() {
+        return Ok(0);
+    }
+
+    let pending: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT call_id, callee_name FROM calls WHERE kind = 'http' AND callee_id IS NULL",
+        )?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.flatten().collect()
+    };
+
+    let mut update = conn.prepare("UPDATE calls SET callee_id = ?1 WHERE call_id = ?2")?;
+    let mut linked = 0;
+    for (call_id, target) in pending {
+        let (method, path) = target.split_once(' ').unwrap_or(("ANY", target.as_str()));
+        let hit = routes
+            .iter()
+            .filter(|(m, p, _)| {
+                (m == "ANY" || method == "ANY" || m == method) && route_path_matches(p, path)
+            })
+            .min_by_key(|(_, p, _)| p.split('/').filter(|s| *s == "{}" || *s == "*").count());
+        if let Some((_, _, handler_id)) = hit {
+            update.execute(params![handler_id, call_id])?;
+            linked += 1;
+        }
+    }
+    Ok(linked)
+}
+
+// ============================================================================
+// Generated Code (生成代码：protobuf / gRPC 桩 -> .proto 定义)
+// ============================================================================
+
+// 按文件名与文件头标记识别生成代码；protoc 插件产物单独标出，便于回链到 .proto
+fn detect_generated(path: &str, header: &[&str]) -> String {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let head = header.join("\n");
+    let grpc = name.contains("_grpc.pb.")
+        || name.contains(".grpc.pb.")
+        || name.contains("_pb2_grpc.")
+        || name.contains("_grpc_pb.")
+        || head.contains("protoc-gen-go-grpc")
+        || head.contains("gRPC Python protocol compiler");
+    let protobuf = name.contains(".pb.")
+        || name.contains("_pb2.")
+        || name.contains("_pb.")
+        || head.contains("protoc-gen-")
+        || head.contains("by the protocol buffer compiler");
+    let marked = (head.contains("Code generated") && head.contains("DO NOT EDIT"))
+        || head.contains("@generated")
+        || head.contains("<auto-generated");
+    if grpc {
+        "grpc".to_string()
+    } else if protobuf && (marked || head.contains("protoc")) {
+        "protobuf".to_string()
+    } else if marked {
+        "generated".to_string()
+    } else {
+        String::new()
+    }
+}
+
+// protoc 插件围绕 proto 名字拼出的前后缀：NewUserServiceClient / add_UserServiceServicer_to_server / UserService_GetUser_Handler
+const STUB_PREFIXES: &[&str] = &["add_", "New", "Register", "Unimplemented", "Unsafe"];
+const STUB_SUFFIXES: &[&str] = &[
+    "_to_server",
+    "_Handler",
+    "_FullMethodName",
+    "_ServiceDesc",
+    "Client",
+    "Server",
+    "Servicer",
+    "Stub",
+];
+
+// 桩符号名 -> 候选 proto 名（由近到远：原名、逐层剥掉前后缀）；Go 的未导出实现类型首字母大写后再比
+fn stub_base_names(name: &str) -> Vec<String> {
+    let capitalized = |s: &str| {
+        let mut chars = s.chars();
+        chars
+            .next()
+            .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+            .unwrap_or_default()
+    };
+    let mut base = name.trim_start_matches('_');
+    let mut out = vec![name.to_string()];
+    loop {
+        out.push(base.to_string());
+        out.push(capitalized(base));
+        let stripped = STUB_PREFIXES
+            .iter()
+            .find_map(|p| base.strip_prefix(p))
+            .or_else(|| STUB_SUFFIXES.iter().find_map(|s| base.strip_suffix(s)))
+            .filter(|rest| !rest.is_empty());
+        match stripped {
+            Some(rest) => base = rest,
+            None => {
+                out.dedup();
+                return out;
+            }
+        }
+    }
+}
+
+// Go 方法的作用域链不带接收者：从签名 `func (c *userServiceClient) GetUser(...)` 取出类型名
+fn go_receiver_type(signature: &str) -> Option<&str> {
+    let inner = signature.strip_prefix("func (")?.split(')').next()?;
+    let ty = inner.split_whitespace().last()?.trim_start_matches('*');
+    Some(ty.split('[').next().unwrap_or(ty))
+}
+
+// 为生成文件里的符号回填 generated_from（proto 定义的 canonical_id）；每次索引整体重算
+fn link_generated_stubs(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "UPDATE symbols SET generated_from = NULL WHERE generated_from IS NOT NULL",
+        [],
+    )?;
+
+    // proto 类型按短名与嵌套名（Outer::Inner -> Outer_Inner，与 Go 生成类型同名）索引；rpc 按 (service, rpc) 索引
+    let mut types: HashMap<String, (String, bool)> = HashMap::new();
+    let mut rpcs: HashMap<(String, String), String> = HashMap::new();
+    {
+        let mut stmt = conn.prepare(
+            "SELECT s.canonical_id, s.name, COALESCE(s.scope_path, s.name), s.symbol_type
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE f.language = 'proto' ORDER BY s.symbol_id",
+        )?;
+        let rows = stmt.query_map([], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+            ))
+        })?;
+        for (id, name, scope_path, kind) in rows.flatten() {
+            if kind == "method" {
+                if let Some((service, _)) = scope_path.rsplit_once("::") {
+                    rpcs.entry((service.to_string(), name)).or_insert(id);
+                }
+                continue;
+            }
+            let service = kind == "interface";
+            types
+                .entry(scope_path.replace("::", "_"))
+                .or_insert((id.clone(), service));
+            types.entry(name).or_insert((id, service));
+        }
+    }
+    if types.is_empty() {
+        return Ok(0);
+    }
+
+    let stubs: Vec<(i64, String, String, Option<String>)> = {
+        let mut stmt = conn.prepare(
+            "SELECT s.symbol_id, s.name, COALESCE(s.scope_path, s.name), s.signature
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE f.generated IN ('protobuf', 'grpc')",
+        )?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+        rows.flatten().collect()
+    };
+
+    let resolve = |segments: &[&str]| -> Option<String> {
+        let (head, rest) = segments.split_first()?;
+        for base in stub_base_names(head) {
+            if let Some((id, service)) = types.get(&base) {
+                // 服务桩上的方法（UserServiceClient::GetUser）落到对应 rpc，其余成员落到类型本身
+                let rpc = rest
+                    .iter()
+                    .filter(|_| *service)
+                    .find_map(|m| rpcs.get(&(base.clone(), m.to_string())));
+                return Some(rpc.unwrap_or(id).clone());
+            }
+            // UserService_GetUser（Go handler / 方法全名常量）
+            if let Some((service, method)) = base.split_once('_') {
+                if let Some(id) = rpcs.get(&(service.to_string(), method.to_string())) {
+                    return Some(id.clone());
+                }
+            }
+        }
+        None
+    };
+
+    let mut update = conn.prepare("UPDATE symbols SET generated_from = ?1 WHERE symbol_id = ?2")?;
+    let mut linked = 0;
+    for (symbol_id, name, scope_path, signature) in stubs {
+        let mut segments: Vec<&str> = scope_path.split("::").collect();
+        if let Some(receiver) = signature.as_deref().and_then(go_receiver_type) {
+            segments.insert(0, receiver);
+        }
+        // 作用域链上逐层尝试：外层是服务 / 消息类型时以其为准，模块级函数退回到自身名字
+        let target = (0..segments.len())
+            .find_map(|i| resolve(&segments[i..]))
+            .or_else(|| resolve(&[name.as_str()]));
+        if let Some(proto_id) = target {
+            update.execute(params![proto_id, symbol_id])?;
+            linked += 1;
+        }
+    }
+    Ok(linked)
+}
+
+// ============================================================================
+// Go Interfaces (Go 接口方法集 -> 隐式实现)
+// Go 没有 implements 声明：方法集覆盖接口全部方法（含内嵌接口）的类型即实现了该接口
+// ============================================================================
+
+fn link_go_interfaces(conn: &Connection) -> Result<usize> {
+    // 推断出来的行每次整体重算（Go 源码里不会有显式 implements）
+    conn.execute(
+        "DELETE FROM inherits WHERE kind = 'implements' AND child_id IN (
+             SELECT s.symbol_id FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE f.language = 'go')",
+        [],
+    )?;
+
+    let package_of = |path: &str| {
+        path.rsplit_once('/')
+            .map(|(d, _)| d.to_string())
+            .unwrap_or_default()
+    };
+
+    // 接口：(canonical_id, 名字, 包目录) 与各自声明的方法名
+    let mut interfaces: Vec<(i64, String, String, String)> = vec![];
+    let mut declared: HashMap<i64, BTreeSet<String>> = HashMap::new();
+    let mut embedded: HashMap<i64, Vec<String>> = HashMap::new();
+    // 具体类型 (包目录, 类型名) -> (symbol_id, 方法名集合)
+    let mut types: HashMap<(String, String), (i64, BTreeSet<String>)> = HashMap::new();
+    let mut receivers: Vec<(String, String, String)> = vec![];
+    {
+        let mut stmt = conn.prepare(
+            "SELECT s.symbol_id, s.canonical_id, s.name, s.symbol_type, s.parent_id, s.signature, f.file_path
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE f.language = 'go' ORDER BY s.symbol_id",
+        )?;
+        let rows = stmt.query_map([], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, Option<i64>>(4)?,
+                r.get::<_, Option<String>>(5)?,
+                r.get::<_, String>(6)?,
+            ))
+        })?;
+        let mut members: Vec<(i64, String)> = vec![];
+        for (symbol_id, canonical_id, name, symbol_type, parent_id, signature, file_path) in
+            rows.flatten()
+        {
+            let package = package_of(&file_path);
+            if symbol_type == "interface" {
+                interfaces.push((symbol_id, canonical_id, name, package));
+            } else if let Some(parent) = parent_id {
+                members.push((parent, name));
+            } else if let Some(receiver) = signature.as_deref().and_then(go_receiver_type) {
+                receivers.push((package, receiver.to_string(), name));
+            } else if canonical_id.starts_with("class:") {
+                types.insert((package, name), (symbol_id, BTreeSet::new()));
+            }
+        }
+        let interface_ids: HashSet<i64> = interfaces.iter().map(|i| i.0).collect();
+        for (parent, name) in members {
+            if interface_ids.contains(&parent) {
+                declared.entry(parent).or_default().insert(name);
+            }
+        }
+        let mut stmt = conn.prepare(
+            "SELECT child_id, base_name FROM inherits WHERE kind = 'extends' AND child_id IN (
+                 SELECT s.symbol_id FROM symbols s JOIN files f ON s.file_id = f.file_id
+                 WHERE f.language = 'go' AND s.symbol_type = 'interface')",
+        )?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
+        for (child, base) in rows.flatten() {
+            embedded.entry(child).or_default().push(base);
+        }
+    }
+    for (package, receiver, name) in receivers {
+        if let Some((_, methods)) = types.get_mut(&(package, receiver)) {
+            methods.insert(name);
+        }
+    }
+
+    // 展开内嵌接口；内嵌了索引外的接口（io.Reader 等）时方法集不完整，整个接口跳过
+    let by_name: HashMap<&str, i64> = interfaces
+        .iter()
+        .map(|(id, _, name, _)| (name.as_str(), *id))
+        .collect();
+    let method_set = |root: i64| -> Option<BTreeSet<String>> {
+        let mut all = BTreeSet::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            all.extend(declared.get(&id).into_iter().flatten().cloned());
+            for base in embedded.get(&id).into_iter().flatten() {
+                let short = base.rsplit('.').next().unwrap_or(base);
+                stack.push(*by_name.get(short)?);
+            }
+        }
+        Some(all)
+    };
+
+    let mut insert = conn.prepare(
+        "INSERT INTO inherits (child_id, base_name, base_id, kind) VALUES (?1, ?2, ?3, 'implements')",
+    )?;
+    let mut linked = 0;
+    for (id, canonical_id, name, _) in &interfaces {
+        // 空接口（interface{} / any 的别名）谁都满足，不记
+        let Some(required) = method_set(*id).filter(|m| !m.is_empty()) else {
+            continue;
+        };
+        for (type_id, methods) in types.values() {
+            if required.is_subset(methods) {
+                insert.execute(params![type_id, name, canonical_id])?;
+                linked += 1;
+            }
+        }
+    }
+    Ok(linked)
+}
+
+// ============================================================================
+// Overrides (重写方法 -> 被重写的基方法)
+// 方法所属类型沿继承表向上，每条分支停在第一个定义了同名方法的父类型上
+// ============================================================================
+
+fn link_overrides(conn: &Connection) -> Result<usize> {
+    conn.execute("DELETE FROM overrides", [])?;
+    let mut supertypes: HashMap<String, Vec<String>> = HashMap::new();
+    for (base, children) in load_subtypes(conn)? {
+        for child in children {
+            supertypes.entry(child).or_default().push(base.clone());
+        }
+    }
+    if supertypes.is_empty() {
+        return Ok(0);
+    }
+    let (methods, _) = load_method_owners(conn)?;
+
+    let mut pairs: BTreeSet<(&str, &str)> = BTreeSet::new();
+    for ((owner, name), ids) in &methods {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut queue: Vec<&str> = supertypes
+            .get(owner)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        while let Some(base) = queue.pop() {
+            if !seen.insert(base) {
+                continue;
+            }
+            match methods.get(&(base.to_string(), name.clone())) {
+                Some(base_ids) => {
+                    for id in ids {
+                        // 同文件同名方法共用 canonical_id，重写与基方法本就是同一个节点，不记（已知局限，
+                        // 见 README Overrides；要区分得改 canonical_id 的构成）
+                        for base_id in base_ids.iter().filter(|b| *b != id) {
+                            pairs.insert((id, base_id));
+                        }
+                    }
+                }
+                None => queue.extend(
+                    supertypes
+                        .get(base)
+                        .into_iter()
+                        .flatten()
+                        .map(String::as_str),
+                ),
+            }
+        }
+    }
+
+    let mut insert =
+        conn.prepare("INSERT OR IGNORE INTO overrides (method_id, base_id) VALUES (?1, ?2)")?;
+    for (method_id, base_id) in &pairs {
+        insert.execute(params![method_id, base_id])?;
+    }
+    Ok(pairs.len())
+}
+
+// 重写方法 -> 全部（传递）基方法；旧库没有 overrides 表时为空
+fn load_overrides(conn: &Connection) -> HashMap<String, Vec<String>> {
+    let mut direct: HashMap<String, Vec<String>> = HashMap::new();
+    if let Ok(mut stmt) = conn.prepare("SELECT method_id, base_id FROM overrides ORDER BY 1, 2") {
+        if let Ok(rows) =
+            stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))
+        {
+            for (method, base) in rows.flatten() {
+                direct.entry(method).or_default().push(base);
+            }
+        }
+    }
+    let mut all = HashMap::new();
+    for method in direct.keys() {
+        let mut bases = vec![];
+        let mut stack = vec![method.as_str()];
+        while let Some(m) = stack.pop() {
+            for base in direct.get(m).into_iter().flatten() {
+                if base != method && !bases.contains(base) {
+                    bases.push(base.clone());
+                    stack.push(base);
+                }
+            }
+        }
+        all.insert(method.clone(), bases);
+    }
+    all
+}
+
+#[derive(Serialize)]
+struct QueryResult {
+    status: String,
+    generation: i64,
+    query: String,
+    found_symbol: Option<Node>,
+    match_type: Option<String>, // 🆕 匹配类型：exact/prefix_suffix/substring/levenshtein/stem
+    candidates: Vec<CandidateMatch>, // 🆕 多候选列表
+    confidence: f32,
+    score_version: u32, // 🆕 打分契约版本（见 SCORE_VERSION），分段或公式变化时递增
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolution_token: Option<String>, // status=ambiguous 时返回，配合 --resolve/--pick
+    related_nodes: Vec<CallerInfo>,
+    reparsed: bool, // 🆕 行号定位时文件已在索引后改动，found_symbol 来自现场重解析
+}
+
+#[derive(Serialize)]
+struct CandidateMatch {
+    node: Node,
+    match_type: String,
+    score: f32, // 相似度分数 (0-1)，分段见 SCORE_VERSION
+}
+
+#[derive(Serialize, Deserialize)]
+struct CallerInfo {
+    node: Node,
+    call_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receiver_type: Option<String>, // 🆕 obj.method() 边上推断出的接收者类型
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confidence: Option<f32>, // 🆕 推测边（--expand-dynamic）的置信度；确定的边为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    call_site: Option<CallSite>, // 🆕 调用点所在行（query 的 related_nodes 逐条给出）
+}
+
+#[derive(Serialize, Deserialize)]
+struct CallSite {
+    line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>, // 调用所在行；stale 时省略
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    before: Vec<String>, // --context-lines 指定的前后若干行
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<String>,
+    #[serde(default)]
+    stale: bool, // 文件在索引后改动过（哈希不符），行号可能已错位，不给片段
+}
+
+// 调用点片段：磁盘内容（解码后）与索引时的 file_hash 一致才取行，每个文件只读一次
+fn call_site_snippet(
+    sources: &mut HashMap<String, Option<Vec<String>>>,
+    roots: &[ProjectRoot],
+    file_path: &str,
+    file_hash: &str,
+    line: usize,
+    context_lines: usize,
+) -> CallSite {
+    let lines = sources.entry(file_path.to_string()).or_insert_with(|| {
+        let content = fs::read(resolve_source_path(roots, file_path))
+            .ok()
+            .and_then(|b| decode_source(&b))?;
+        (hex::encode(Sha256::digest(content.as_bytes())) == file_hash)
+            .then(|| content.lines().map(str::to_string).collect())
+    });
+    let Some(lines) = lines.as_ref().filter(|l| (1..=l.len()).contains(&line)) else {
+        return CallSite {
+            line,
+            text: None,
+            before: vec![],
+            after: vec![],
+            stale: true,
+        };
+    };
+    let idx = line - 1;
+    CallSite {
+        line,
+        text: Some(lines[idx].clone()),
+        before: lines[idx.saturating_sub(context_lines)..idx].to_vec(),
+        after: lines[idx + 1..(idx + 1 + context_lines).min(lines.len())].to_vec(),
+        stale: false,
+    }
+}
+
+// 重写 --output 里 related_nodes 的调用点片段；--fields 去掉 file_path 时按节点 id 回查所在文件，
+// 两者都没有的调用点无法校验，按 stale 处理
+fn refresh_call_sites(args: &Args) -> anyhow::Result<()> {
+    let Some(out_path) = &args.output else {
+        return Ok(());
+    };
+    let mut value: serde_json::Value = serde_json::from_slice(&fs::read(out_path)?)?;
+    let Some(related) = value["related_nodes"].as_array_mut() else {
+        return Ok(());
+    };
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+    let mut file_stmt = conn.prepare(
+        "SELECT f.file_path,
+                coalesce((SELECT o.file_hash FROM main.overlay_files o WHERE o.file_id = f.file_id AND o.sandbox = ?3), f.file_hash)
+         FROM files f
+         WHERE f.file_path = ?1 OR f.file_id = (SELECT file_id FROM symbols WHERE canonical_id = ?2 LIMIT 1)
+         LIMIT 1",
+    )?;
+    let roots = project_roots(args).unwrap_or_default();
+    let mut sources: HashMap<String, Option<Vec<String>>> = HashMap::new();
+    for item in related.iter_mut() {
+        let Some(line) = item["call_site"]["line"].as_u64() else {
+            continue;
+        };
+        let node = &item["node"];
+        let file = file_stmt
+            .query_row(
+                params![
+                    node["file_path"].as_str(),
+                    node["id"].as_str(),
+                    overlay_layer(args)
+                ],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        let site = match file {
+            Some((file_path, file_hash)) => call_site_snippet(
+                &mut sources,
+                &roots,
+                &file_path,
+                &file_hash,
+                line as usize,
+                args.context_lines,
+            ),
+            None => CallSite {
+                line: line as usize,
+                text: None,
+                before: vec![],
+                after: vec![],
+                stale: true,
+            },
+        };
+        item["call_site"] = serde_json::to_value(site)?;
+    }
+    fs::write(out_path, serde_json::to_vec(&value)?)?;
+    Ok(())
+}
+
+// ============================================================================
+// Stable IDs (内容锚定 ID，文件移动后存活)
+// canonical_id 含文件路径，移动文件即全部失效；stable_id 只取
+// symbol_type + scope_path + signature 的哈希，配合 symbol_aliases 追溯旧 ID
+// ============================================================================
+
+// 旧 canonical_id 的别名保留时长：超过后引用方多半已更新，不再为它追溯继任者
+const ALIAS_RETENTION_SECS: u64 = 90 * 86400;
+
+fn stable_symbol_id(symbol_type: &str, scope_path: &str, signature: Option<&str>) -> i64 {
+    let mut hasher = Sha256::new();
+    hasher.update(symbol_type.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(scope_path.as_bytes());
+    hasher.update([0u8]);
+    // 签名空白归一化：缩进/换行风格变化不影响 ID
+    if let Some(sig) = signature {
+        hasher.update(
+            sig.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .as_bytes(),
+        );
+    }
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    // 保持正数，便于 SQLite INTEGER 与 JSON 消费方处理
+    i64::from_be_bytes(bytes) & i64::MAX
+}
+
+// 文件即将从索引移除：summary 迁移到 stable_id 唯一对应的新符号上
+// stable_id 在其他文件中出现多次（如多个 main）时无法判定去向，保持不动
+fn carry_over_moved_symbols(conn: &Connection, file_id: i64) -> Result<usize> {
+    conn.execute(
+        "UPDATE symbols
+         SET summary = (
+             SELECT o.summary FROM symbols o
+             WHERE o.file_id = ?1 AND o.stable_id = symbols.stable_id AND o.summary IS NOT NULL
+             LIMIT 1
+         )
+         WHERE file_id != ?1
+           AND summary IS NULL
+           AND stable_id IN (SELECT stable_id FROM symbols WHERE file_id = ?1 AND summary IS NOT NULL)
+           AND (SELECT COUNT(*) FROM symbols x WHERE x.stable_id = symbols.stable_id AND x.file_id != ?1) = 1",
+        params![file_id],
+    )
+}
+
+// 引用表中指向已消失 canonical_id 的边：经 symbol_aliases -> stable_id 找到唯一继任者；
+// 找不到则置 NULL，交给 Linking 阶段按名称重新链接
+fn remap_stale_references(conn: &Connection) -> Result<usize> {
+    let successor = "SELECT MIN(s.canonical_id)
+         FROM symbol_aliases a JOIN symbols s ON s.stable_id = a.stable_id
+         WHERE a.canonical_id = {col}
+         GROUP BY a.stable_id
+         HAVING COUNT(*) = 1";
+
+    let mut changed = conn.execute(
+        &format!(
+            "UPDATE calls SET callee_id = ({})
+             WHERE callee_id IS NOT NULL
+               AND NOT EXISTS (SELECT 1 FROM symbols WHERE canonical_id = calls.callee_id)",
+            successor.replace("{col}", "calls.callee_id")
+        ),
+        [],
+    )?;
+    changed += conn.execute(
+        &format!(
+            "UPDATE routes SET handler_id = ({})
+             WHERE handler_id IS NOT NULL
+               AND NOT EXISTS (SELECT 1 FROM symbols WHERE canonical_id = routes.handler_id)",
+            successor.replace("{col}", "routes.handler_id")
+        ),
+        [],
+    )?;
+    Ok(changed)
+}
+
+// 外部保存的 ID 解析为当前 canonical_id：
+// 现存 canonical_id / 数字 stable_id / 历史 canonical_id（经 symbol_aliases）
+// stable_id 对应多个符号（如 ObjC 的 @interface Foo 与 @implementation Foo）时报歧义，不替调用方挑
+fn resolve_canonical_id(conn: &Connection, id: &str) -> anyhow::Result<Option<String>> {
+    let by_canonical = conn
+        .query_row(
+            "SELECT canonical_id FROM symbols WHERE canonical_id = ?1",
+            [id],
+            |r| r.get::<_, String>(0),
+        )
+        .ok();
+    if by_canonical.is_some() {
+        return Ok(by_canonical);
+    }
+
+    let stable_id: i64 = match id.parse::<i64>() {
+        Ok(n) => n,
+        Err(_) => match conn.query_row(
+            "SELECT stable_id FROM symbol_aliases WHERE canonical_id = ?1",
+            [id],
+            |r| r.get(0),
+        ) {
+            Ok(n) => n,
+            Err(_) => return Ok(None),
+        },
+    };
+    let candidates: Vec<(String, String, i64)> = conn
+        .prepare(
+            "SELECT s.canonical_id, f.file_path, s.line_start
+             FROM symbols s JOIN files f ON f.file_id = s.file_id
+             WHERE s.stable_id = ?1 ORDER BY f.file_path, s.line_start",
+        )?
+        .query_map([stable_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+        .flatten()
+        .collect();
+    if candidates.len() > 1 {
+        let listed: Vec<String> = candidates
+            .iter()
+            .map(|(canonical, file, line)| format!("{} ({}:{})", canonical, file, line))
+            .collect();
+        anyhow::bail!("symbol id '{}' is ambiguous: {}", id, listed.join(", "));
+    }
+    Ok(candidates
+        .into_iter()
+        .next()
+        .map(|(canonical, _, _)| canonical))
+}
+
+// ============================================================================
+// Disambiguation (低置信度澄清协议)
+// 查询置信度低于 --min-confidence 时返回 status=ambiguous + resolution_token；
+// 调用方 --resolve <token> --pick N 取回选中项。token 自包含（查询词 + 候选集哈希），
+// 不落盘；候选集随索引变化时拒绝解析，避免选错
+// ============================================================================
+
+// 最高分被 N 个候选并列时按 N 摊薄：五个同名 init 的精确匹配也算不确定
+fn query_confidence(found: bool, candidates: &[CandidateMatch]) -> f32 {
+    if !found {
+        return 0.0;
+    }
+    let Some(top) = candidates.first() else {
+        return 1.0;
+    };
+    let tied = candidates
+        .iter()
+        .filter(|c| (c.score - top.score).abs() < f32::EPSILON)
+        .count();
+    top.score / tied as f32
+}
+
+fn candidate_set_hash(query: &str, candidates: &[CandidateMatch]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(query.as_bytes());
+    for c in candidates {
+        hasher.update([0u8]);
+        hasher.update(c.node.id.as_bytes());
+    }
+    hex::encode(hasher.finalize())[..16].to_string()
+}
+
+fn resolution_token(query: &str, candidates: &[CandidateMatch]) -> String {
+    format!(
+        "{}.{}",
+        candidate_set_hash(query, candidates),
+        hex::encode(query.as_bytes())
+    )
+}
+
+fn resolve_candidates(
+    conn: &Connection,
+    token: &str,
+) -> anyhow::Result<(String, Vec<CandidateMatch>)> {
+    let (hash, encoded) = token
+        .split_once('.')
+        .ok_or_else(|| anyhow::anyhow!("malformed resolution token"))?;
+    let query = hex::decode(encoded)
+        .ok()
+        .and_then(|b| String::from_utf8(b).ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed resolution token"))?;
+    let (_, candidates, _) = progressive_search_multi(conn, &query);
+    if candidate_set_hash(&query, &candidates) != hash {
+        anyhow::bail!(
+            "resolution token is stale: candidates for '{}' changed, query again",
+            query
+        );
+    }
+    Ok((query, candidates))
+}
+
+// ============================================================================
+// Progressive Fallback Search (渐进式容错查询)
+// ============================================================================
+use strsim::levenshtein;
+
+// 同名/同分候选的排序：被调用次数（权重 2）+ 调用次数，其次文件最近修改，最后 rowid
+// 五个 init 并列时，优先给出图中最"中心"、最近在改的那个
+const IMPORTANCE_ORDER: &str = "ORDER BY (
+        (SELECT COUNT(*) FROM calls c WHERE c.callee_id = symbols.canonical_id) * 2
+        + (SELECT COUNT(*) FROM calls c WHERE c.caller_id = symbols.symbol_id
+           AND (c.kind NOT IN ('reference', 'render', 'decorator') OR c.callee_id IS NOT NULL))
+    ) DESC, files.file_mtime DESC, symbols.symbol_id";
+
+// 打分契约：每层占一段互不重叠的分数，靠前的层总是高于靠后的层，同层内越接近越高，
+// 因此跨层、跨版本都可以直接比较分数。调整分段或公式时递增 SCORE_VERSION
+// （v1 未成文：编辑距离 1.0 - d/4 与词根 0.5 交叠）
+const SCORE_VERSION: u32 = 2;
+const SCORE_EXACT: f32 = 1.0; // 同名；装饰器名命中同样是确定匹配
+const SCORE_DECORATOR: f32 = 0.95;
+const SCORE_PREFIX_SUFFIX: f32 = 0.9;
+const SCORE_SUBSTRING: f32 = 0.8;
+const SCORE_STEM: f32 = 0.4;
+
+// 编辑距离 d = 0..=3（d = 0 为仅大小写不同）落在 (0.5, 0.7]，介于子串与词根之间
+fn levenshtein_score(dist: usize) -> f32 {
+    0.7 - 0.06 * dist.min(3) as f32
+}
+
+// 输出前按 --score-precision 截位；判定 ambiguous 用的是未截位的原值
+fn round_score(score: f32, precision: u32) -> f32 {
+    let scale = 10f64.powi(precision.min(6) as i32);
+    ((score as f64 * scale).round() / scale) as f32
+}
+
+// 🆕 多候选渐进式搜索
+fn progressive_search_multi(
+    conn: &Connection,
+    query_str: &str,
+) -> (Option<(Node, String)>, Vec<CandidateMatch>, bool) {
+    let mut candidates: Vec<CandidateMatch> = vec![];
+    let max_candidates = 5;
+
+    // Layer 1: 精确匹配 (SCORE_EXACT)；同名多个时全部作为候选返回
+    let exact_matches = exact_match_multi(conn, query_str, max_candidates);
+    if let Some(best) = exact_matches.first().cloned() {
+        if exact_matches.len() > 1 {
+            for node in exact_matches {
+                candidates.push(CandidateMatch {
+                    node,
+                    match_type: "exact".to_string(),
+                    score: SCORE_EXACT,
+                });
+            }
+        }
+        return (Some((best, "exact".to_string())), candidates, true);
+    }
+
+    // Layer 1.5: 装饰器匹配 (SCORE_DECORATOR)：query 为装饰器名（app.route）时返回被它装饰的符号
+    let decorated = decorated_by_match(conn, query_str, max_candidates);
+    if let Some(best) = decorated.first().cloned() {
+        if decorated.len() > 1 {
+            for node in decorated {
+                candidates.push(CandidateMatch {
+                    node,
+                    match_type: "decorator".to_string(),
+                    score: SCORE_DECORATOR,
+                });
+            }
+        }
+        return (Some((best, "decorator".to_string())), candidates, true);
+    }
+
+    // Layer 2: 前缀/后缀匹配 (SCORE_PREFIX_SUFFIX)
+    let prefix_matches = prefix_suffix_match_multi(conn, query_str, max_candidates);
+    for node in prefix_matches {
+        candidates.push(CandidateMatch {
+            node,
+            match_type: "prefix_suffix".to_string(),
+            score: SCORE_PREFIX_SUFFIX,
+        });
+    }
+    if !candidates.is_empty() {
+        let best = candidates[0].node.clone();
+        return (Some((best, "prefix_suffix".to_string())), candidates, true);
+    }
+
+    // Layer 3: 子串匹配 (SCORE_SUBSTRING)
+    let substring_matches = substring_match_multi(conn, query_str, max_candidates);
+    for node in substring_matches {
+        candidates.push(CandidateMatch {
+            node,
+            match_type: "substring".to_string(),
+            score: SCORE_SUBSTRING,
+        });
+    }
+    if !candidates.is_empty() {
+        let best = candidates[0].node.clone();
+        return (Some((best, "substring".to_string())), candidates, true);
+    }
+
+    // Layer 4: 编辑距离匹配 (levenshtein_score)
+    let lev_matches = levenshtein_match_multi(conn, query_str, 3, max_candidates);
+    for (node, dist) in lev_matches {
+        candidates.push(CandidateMatch {
+            node,
+            match_type: format!("levenshtein_d{}", dist),
+            score: levenshtein_score(dist),
+        });
+    }
+    if !candidates.is_empty() {
+        let best = candidates[0].node.clone();
+        return (Some((best, "levenshtein".to_string())), candidates, true);
+    }
+
+    // Layer 5: 词根匹配 (SCORE_STEM)
+    let stem_matches = stem_match_multi(conn, query_str, max_candidates);
+    for node in stem_matches {
+        candidates.push(CandidateMatch {
+            node,
+            match_type: "stem".to_string(),
+            score: SCORE_STEM,
+        });
+    }
+    if !candidates.is_empty() {
+        let best = candidates[0].node.clone();
+        return (Some((best, "stem".to_string())), candidates, true);
+    }
+
+    (None, candidates, false)
+}
+
+// 🆕 修改：使用 canonical_id 而不是 symbol_id
+fn exact_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
+    let sql = format!(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id
+         WHERE name = ?1 {} LIMIT ?2",
+        IMPORTANCE_ORDER
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    let rows = match stmt.query_map(params![query, limit as i64], |row| {
+        Ok(Node {
+            id: row.get::<_, String>(0)?, // 🆕 canonical_id
+            name: row.get(1)?,
+            qualified_name: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            line_end: row.get(5)?,
+            node_type: row.get(6)?,
+            signature: None,
+            summary: None,
+            stable_id: None,
+            anchor: row.get(7)?,
+            doc: None,
+            source: None,
+            calls: vec![],
+        })
+    }) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    rows.filter_map(|r| r.ok()).collect()
+}
+
+// ============================================================================
+// Multi-Candidate Match Functions (多候选匹配函数)
+// ============================================================================
+
+// 被名为 query 的装饰器修饰的符号（Flask / FastAPI 路由 handler 等）
+fn decorated_by_match(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
+    let mut stmt = match conn.prepare(
+        "SELECT DISTINCT canonical_id, symbols.name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM decorators d
+         JOIN symbols ON d.symbol_id = symbols.symbol_id
+         JOIN files ON symbols.file_id = files.file_id
+         WHERE d.name = ?1 ORDER BY file_path, line_start LIMIT ?2",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    let rows = match stmt.query_map(params![query, limit as i64], |row| {
+        Ok(Node {
+            id: row.get::<_, String>(0)?,
+            name: row.get(1)?,
+            qualified_name: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            line_end: row.get(5)?,
+            node_type: row.get(6)?,
+            signature: None,
+            summary: None,
+            stable_id: None,
+            anchor: row.get(7)?,
+            doc: None,
+            source: None,
+            calls: vec![],
+        })
+    }) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    rows.filter_map(|r| r.ok()).collect()
+}
+
+// --attribute key / key=value（逗号分隔，须全部满足）：返回满足条件的 canonical_id；未指定返回 None
+fn attribute_filter(
+    conn: &Connection,
+    spec: Option<&str>,
+) -> anyhow::Result<Option<HashSet<String>>> {
+    let conditions: Vec<(&str, Option<&str>)> = spec
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|c| match c.split_once('=') {
+            Some((k, v)) => (k.trim(), Some(v.trim())),
+            None => (c, None),
+        })
+        .collect();
+    if conditions.is_empty() {
+        return Ok(None);
+    }
+    let mut matched: Option<HashSet<String>> = None;
+    for (key, value) in conditions {
+        let mut stmt = conn.prepare_cached(
+            "SELECT DISTINCT s.canonical_id FROM attributes a
+             JOIN symbols s ON a.symbol_id = s.symbol_id
+             WHERE a.key = ?1 AND (?2 IS NULL OR a.value = ?2)",
+        )?;
+        let ids: HashSet<String> = stmt
+            .query_map(params![key, value], |r| r.get(0))?
+            .flatten()
+            .collect();
+        matched = Some(match matched {
+            Some(prev) => prev.intersection(&ids).cloned().collect(),
+            None => ids,
+        });
+    }
+    Ok(matched)
+}
+
+// --annotation 的名字列表：逗号分隔，允许带 @ 前缀
+fn annotation_names(spec: Option<&str>) -> Vec<String> {
+    spec.unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().trim_start_matches('@').to_string())
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+// --symbol-type 的同义词：agent 会把 fn / func / procedure / method 混着用，查询时统一映射到内部类型
+// fn / func 这类泛称同时命中函数与方法；映射之外的值按原样匹配（兼容 --type-map 自定义的类型名）
+const SYMBOL_TYPE_SYNONYMS: &[(&[&str], &[&str])] = &[
+    (
+        &[
+            "function",
+            "fn",
+            "func",
+            "fun",
+            "def",
+            "defn",
+            "procedure",
+            "proc",
+            "sub",
+            "subroutine",
+            "routine",
+            "callable",
+        ],
+        &["function", "method"],
+    ),
+    (
+        &["method", "meth", "member_function", "receiver"],
+        &["method"],
+    ),
+    (&["class", "cls"], &["class"]),
+    (&["struct", "record", "structure"], &["struct"]),
+    (
+        &["interface", "iface", "protocol", "trait"],
+        &["interface", "trait"],
+    ),
+    (&["enum", "enumeration"], &["enum"]),
+    (&["variant", "enum_member", "case"], &["variant"]),
+    (&["type", "typedef", "alias", "type_alias"], &["type"]),
+    (&["constant", "const"], &["constant"]),
+    (&["variable", "var", "let", "global"], &["variable"]),
+    (
+        &["field", "attribute", "attr", "member"],
+        &["field", "property"],
+    ),
+    (
+        &["property", "prop", "getter", "setter", "accessor"],
+        &["property"],
+    ),
+    (&["module", "mod", "namespace", "package"], &["module"]),
+    (&["component", "widget"], &["component"]),
+    (&["test", "spec", "test_case"], &["test"]),
+];
+
+fn symbol_type_filter(spec: Option<&str>) -> HashSet<String> {
+    let mut kinds = HashSet::new();
+    for requested in spec.unwrap_or_default().split(',') {
+        let requested = requested.trim().to_lowercase().replace(['-', ' '], "_");
+        if requested.is_empty() {
+            continue;
+        }
+        if let Some((_, internal)) = SYMBOL_TYPE_SYNONYMS
+            .iter()
+            .find(|(aliases, _)| aliases.contains(&requested.as_str()))
+        {
+            kinds.extend(internal.iter().map(|k| k.to_string()));
+        }
+        kinds.insert(requested);
+    }
+    kinds
+}
+
+// 带任一注解 / 装饰器的全部符号；全限定写法（@org.x.Transactional）也按简单名命中
+fn annotated_with(conn: &Connection, names: &[String]) -> Vec<Node> {
+    let mut stmt = match conn.prepare(
+        "SELECT DISTINCT canonical_id, symbols.name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM decorators d
+         JOIN symbols ON d.symbol_id = symbols.symbol_id
+         JOIN files ON symbols.file_id = files.file_id
+         WHERE d.name = ?1 OR d.name LIKE '%.' || ?1 ORDER BY file_path, line_start",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    let mut seen = HashSet::new();
+    let mut nodes = vec![];
+    for name in names {
+        let Ok(rows) = stmt.query_map([name], |row| {
+            Ok(Node {
+                id: row.get::<_, String>(0)?,
+                name: row.get(1)?,
+                qualified_name: row.get(2)?,
+                file_path: row.get(3)?,
+                line_start: row.get(4)?,
+                line_end: row.get(5)?,
+                node_type: row.get(6)?,
+                signature: None,
+                summary: None,
+                stable_id: None,
+                anchor: row.get(7)?,
+                doc: None,
+                source: None,
+                calls: vec![],
+            })
+        }) else {
+            continue;
+        };
+        for node in rows.flatten() {
+            if seen.insert((node.id.clone(), node.file_path.clone(), node.line_start)) {
+                nodes.push(node);
+            }
+        }
+    }
+    nodes.sort_by(|a, b| (&a.file_path, a.line_start).cmp(&(&b.file_path, b.line_start)));
+    nodes
+}
+
+// 🆕 修改：使用 canonical_id
+fn prefix_suffix_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
+    let prefix_pattern = format!("{}%", query);
+    let suffix_pattern = format!("%{}", query);
+    let sql = format!(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id
+         WHERE name LIKE ?1 OR name LIKE ?2 {} LIMIT ?3",
+        IMPORTANCE_ORDER
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+
+    let rows = match stmt.query_map(
+        params![prefix_pattern, suffix_pattern, limit as i64],
+        |row| {
+            Ok(Node {
+                id: row.get::<_, String>(0)?, // 🆕 canonical_id
+                name: row.get(1)?,
+                qualified_name: row.get(2)?,
+                file_path: row.get(3)?,
+                line_start: row.get(4)?,
+                line_end: row.get(5)?,
+                node_type: row.get(6)?,
+                signature: None,
+                summary: None,
+                stable_id: None,
+                anchor: row.get(7)?,
+                doc: None,
+                source: None,
+                calls: vec![],
+            })
+        },
+    ) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    rows.filter_map(|r| r.ok()).collect()
+}
+
+// 🆕 修改：使用 canonical_id
+fn substring_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
+    let pattern = format!("%{}%", query);
+    let sql = format!(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id
+         WHERE name LIKE ?1 {} LIMIT ?2",
+        IMPORTANCE_ORDER
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+
+    let rows = match stmt.query_map(params![pattern, limit as i64], |row| {
+        Ok(Node {
+            id: row.get::<_, String>(0)?, // 🆕 canonical_id
+            name: row.get(1)?,
+            qualified_name: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            line_end: row.get(5)?,
+            node_type: row.get(6)?,
+            signature: None,
+            summary: None,
+            stable_id: None,
+            anchor: row.get(7)?,
+            doc: None,
+            source: None,
+            calls: vec![],
+        })
+    }) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    rows.filter_map(|r| r.ok()).collect()
+}
+
+// 🆕 修改：使用 canonical_id
+fn levenshtein_match_multi(
+    conn: &Connection,
+    query: &str,
+    max_distance: usize,
+    limit: usize,
+) -> Vec<(Node, usize)> {
+    // 按重要度预排序，下面的稳定排序只按距离分组
+    let sql = format!(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id {}",
+        IMPORTANCE_ORDER
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+
+    let query_lower = query.to_lowercase();
+    let mut matches: Vec<(Node, usize)> = vec![];
+
+    let rows = match stmt.query_map([], |row| {
+        Ok(Node {
+            id: row.get::<_, String>(0)?, // 🆕 canonical_id
+            name: row.get(1)?,
+            qualified_name: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            line_end: row.get(5)?,
+            node_type: row.get(6)?,
+            signature: None,
+            summary: None,
+            stable_id: None,
+            anchor: row.get(7)?,
+            doc: None,
+            source: None,
+            calls: vec![],
+        })
+    }) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    for node in rows.flatten() {
+        let dist = levenshtein(&query_lower, &node.name.to_lowercase());
+        if dist <= max_distance {
+            matches.push((node, dist));
+        }
+    }
+
+    // 按距离排序
+    matches.sort_by_key(|(_, d)| *d);
+    matches.truncate(limit);
+    matches
+}
+
+// 🆕 修改：使用 canonical_id
+fn stem_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
+    let Some(stem) = char_prefix(query, 4) else {
+        return vec![];
+    };
+    let pattern = format!("{}%", stem);
+    let sql = format!(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id
+         WHERE name LIKE ?1 {} LIMIT ?2",
+        IMPORTANCE_ORDER
+    );
+    let mut stmt = match conn.prepare(&sql) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+
+    let rows = match stmt.query_map(params![pattern, limit as i64], |row| {
+        Ok(Node {
+            id: row.get::<_, String>(0)?, // 🆕 canonical_id
+            name: row.get(1)?,
+            qualified_name: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            line_end: row.get(5)?,
+            node_type: row.get(6)?,
+            signature: None,
+            summary: None,
+            stable_id: None,
+            anchor: row.get(7)?,
+            doc: None,
+            source: None,
+            calls: vec![],
+        })
+    }) {
+        Ok(r) => r,
+        Err(_) => return vec![],
+    };
+
+    rows.filter_map(|r| r.ok()).collect()
+}
+
+// ============================================================================
+// Field Projection (--fields)
+// 只保留符号对象中列出的字段，缩小推给 LLM 上下文的载荷；结果外层结构不变
+// ============================================================================
+
+const NODE_FIELDS: &[&str] = &[
+    "id",
+    "type",
+    "name",
+    "qualified_name",
+    "file_path",
+    "line_start",
+    "line_end",
+    "signature",
+    "summary",
+    "stable_id",
+    "anchor",
+    "doc",
+    "source",
+    "calls",
+];
+
+fn parse_fields(spec: Option<&str>) -> anyhow::Result<Option<Vec<String>>> {
+    let Some(spec) = spec else {
+        return Ok(None);
+    };
+    let fields: Vec<String> = spec
+        .split(',')
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+    if let Some(unknown) = fields.iter().find(|f| !NODE_FIELDS.contains(&f.as_str())) {
+        anyhow::bail!(
+            "unknown field {:?} in --fields (expected: {})",
+            unknown,
+            NODE_FIELDS.join(", ")
+        );
+    }
+    Ok(Some(fields))
+}
+
+fn project_node(node: &mut serde_json::Value, fields: &[String]) {
+    if let Some(obj) = node.as_object_mut() {
+        obj.retain(|k, _| fields.contains(k));
+    }
+}
+
+fn run_query(args: &Args) -> anyhow::Result<()> {
+    let fields = parse_fields(args.fields.as_deref())?;
+    let conn = match &args.snapshot {
+        Some(path) => open_snapshot_db(path)?,
+        None => Connection::open(&args.db)?,
+    };
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+
+    // 策略优先级：
+    // 1. 如果有 file + line，按行号定位符号
+    // 2. 如果有 query，使用模糊匹配
+
+    let found: Option<Node>;
+    let mut candidates: Vec<CandidateMatch> = vec![];
+    let mut match_type_str: Option<String> = None;
+    let mut query_text = args.query.clone().unwrap_or_default();
+    let mut reparsed = false;
+    let annotations = annotation_names(args.annotation.as_deref());
+    let kinds = symbol_type_filter(args.symbol_type.as_deref());
+    let with_attributes = attribute_filter(&conn, args.attribute.as_deref())?;
+    let mut listing = false;
+
+    if let Some(token) = &args.resolve {
+        // === 消歧回调：由 token 复原候选列表，取第 N 个 ===
+        let pick = args
+            .pick
+            .ok_or_else(|| anyhow::anyhow!("--resolve requires --pick <N>"))?;
+        let (token_query, cands) = resolve_candidates(&conn, token)?;
+        let chosen = pick
+            .checked_sub(1)
+            .and_then(|i| cands.get(i))
+            .ok_or_else(|| anyhow::anyhow!("--pick {} out of range (1..={})", pick, cands.len()))?;
+        found = Some(chosen.node.clone());
+        match_type_str = Some("resolved".to_string());
+        query_text = token_query;
+    } else if let (Some(file_path), Some(line_num)) = (&args.file, &args.line) {
+        // === 行号定位模式 ===
+        // 使用 LIKE 模糊匹配文件路径（支持相对路径）
+        let file_pattern = format!("%{}", file_path.replace("\\", "/"));
+        // 磁盘文件与索引不一致时行范围已漂移：现场重解析该文件再定位（快照是历史数据，不校验）
+        let refreshed = match args.snapshot {
+            Some(_) => None,
+            None => refresh_symbol_at_line(args, &conn, &file_pattern, *line_num)?,
+        };
+        if let Some(node) = refreshed {
+            reparsed = true;
+            found = node;
+        } else {
+            // 找到包含该行的符号（line_start <= line <= line_end）
+            let mut stmt = conn.prepare(
+            "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor 
+             FROM symbols JOIN files ON symbols.file_id = files.file_id 
+             WHERE file_path LIKE ?1 AND line_start <= ?2 AND line_end >= ?2
+             ORDER BY (line_end - line_start) ASC
+             LIMIT 1",
+        )?;
+            found = stmt
+                .query_row(params![file_pattern, line_num], |row| {
+                    Ok(Node {
+                        id: row.get::<_, String>(0)?,
+                        name: row.get(1)?,
+                        qualified_name: row.get(2)?,
+                        file_path: row.get(3)?,
+                        line_start: row.get(4)?,
+                        line_end: row.get(5)?,
+                        node_type: row.get(6)?,
+                        signature: None,
+                        summary: None,
+                        stable_id: None,
+                        anchor: row.get(7)?,
+                        doc: None,
+                        source: None,
+                        calls: vec![],
+                    })
+                })
+                .optional()?;
+        }
+    } else if let Some(query_str) = &args.query {
+        // === 渐进式容错匹配（多候选） ===
+        let (best_match, cands, _success) = progressive_search_multi(&conn, query_str);
+        let mut best = best_match.clone().map(|(node, _)| node);
+        candidates = cands;
+        match_type_str = best_match.map(|(_, mt)| mt);
+        // --annotation：只保留带该注解的命中；首选被滤掉时由剩余候选顶上
+        if !annotations.is_empty() {
+            let keep: HashSet<String> = annotated_with(&conn, &annotations)
+                .into_iter()
+                .map(|n| n.id)
+                .collect();
+            candidates.retain(|c| keep.contains(&c.node.id));
+            best = best
+                .filter(|n| keep.contains(&n.id))
+                .or_else(|| candidates.first().map(|c| c.node.clone()));
+            if best.is_none() {
+                match_type_str = None;
+            }
+        }
+        // --symbol-type：同样只留指定类型，首选被滤掉时由剩余候选顶上
+        if !kinds.is_empty() {
+            candidates.retain(|c| kinds.contains(&c.node.node_type));
+            best = best
+                .filter(|n| kinds.contains(&n.node_type))
+                .or_else(|| candidates.first().map(|c| c.node.clone()));
+            if best.is_none() {
+                match_type_str = None;
+            }
+        }
+        // --attribute：只留属性全部满足的候选
+        if let Some(ids) = &with_attributes {
+            candidates.retain(|c| ids.contains(&c.node.id));
+            best = best
+                .filter(|n| ids.contains(&n.id))
+                .or_else(|| candidates.first().map(|c| c.node.clone()));
+            if best.is_none() {
+                match_type_str = None;
+            }
+        }
+        found = best;
+    } else if !annotations.is_empty() {
+        // === 只给 --annotation：列出所有带该注解的符号（如全部 HTTP 端点），不做消歧 ===
+        listing = true;
+        found = None;
+        candidates = annotated_with(&conn, &annotations)
+            .into_iter()
+            .filter(|node| kinds.is_empty() || kinds.contains(&node.node_type))
+            .filter(|node| {
+                with_attributes
+                    .as_ref()
+                    .is_none_or(|ids| ids.contains(&node.id))
+            })
+            .map(|node| CandidateMatch {
+                node,
+                match_type: "annotation".to_string(),
+                score: SCORE_EXACT,
+            })
+            .collect();
+        match_type_str = Some("annotation".to_string());
+    } else {
+        // 无查询条件
+        found = None;
+        candidates = vec![];
+        match_type_str = None;
+    }
+
+    // 命中符号带上索引期落库的 doc，MCP 层可直接喂给上下文
+    let mut found = found;
+    if let Some(sym) = found.as_mut() {
+        sym.doc = conn
+            .query_row(
+                "SELECT doc FROM symbols WHERE canonical_id = ?1 AND doc IS NOT NULL LIMIT 1",
+                [&sym.id],
+                |r| r.get(0),
+            )
+            .optional()?;
+    }
+
+    // 查找调用者（保持原有逻辑），每个调用点附上所在行
+    let mut related = vec![];
+    if let Some(ref sym) = found {
+        let roots = project_roots(args).unwrap_or_default();
+        let mut sources: HashMap<String, Option<Vec<String>>> = HashMap::new();
+        let mut call_stmt = conn.prepare(
+            "SELECT s.canonical_id, s.name, s.qualified_name, f.file_path, s.line_start, s.line_end, s.symbol_type, c.kind, s.anchor, c.receiver_type, c.call_line,
+                    coalesce((SELECT o.file_hash FROM main.overlay_files o WHERE o.file_id = f.file_id AND o.sandbox = ?3), f.file_hash)
+             FROM calls c 
+             JOIN symbols s ON c.caller_id = s.symbol_id 
+             JOIN files f ON s.file_id = f.file_id
+             WHERE c.callee_id = ?1 OR (c.callee_id IS NULL AND c.kind NOT IN ('reference', 'render', 'decorator') AND c.receiver_type IS NULL AND c.callee_name = ?2)
+             ORDER BY f.file_path, s.line_start, c.call_id"
+        )?;
+
+        let rows = call_stmt.query_map(
+            params![sym.id.clone(), sym.name.clone(), overlay_layer(args)],
+            |row| {
+                let site = (row.get::<_, Option<usize>>(10)?, row.get::<_, String>(11)?);
+                let info = CallerInfo {
+                    node: Node {
+                        id: row.get::<_, String>(0)?,
+                        name: row.get(1)?,
+                        qualified_name: row.get(2)?,
+                        file_path: row.get(3)?,
+                        line_start: row.get(4)?,
+                        line_end: row.get(5)?,
+                        node_type: row.get(6)?,
+                        signature: None,
+                        summary: None,
+                        stable_id: None,
+                        anchor: row.get(8)?,
+                        doc: None,
+                        source: None,
+                        calls: vec![],
+                    },
+                    // http 边来自客户端 URL 字面量（跨服务调用）；render 边是 JSX 中渲染该组件的位置
+                    call_type: match row.get::<_, Option<String>>(7)?.as_deref() {
+                        Some("http") => "http".to_string(),
+                        Some("reference") => "reference".to_string(),
+                        Some("render") => "render".to_string(),
+                        Some("decorator") => "decorator".to_string(),
+                        _ => "direct".to_string(),
+                    },
+                    receiver_type: row.get(9)?,
+                    confidence: None,
+                    call_site: None,
+                };
+                Ok((info, site))
+            },
+        )?;
+
+        for (mut info, (call_line, file_hash)) in rows.flatten() {
+            info.call_site = call_line.map(|line| {
+                call_site_snippet(
+                    &mut sources,
+                    &roots,
+                    &info.node.file_path,
+                    &file_hash,
+                    line,
+                    args.context_lines,
+                )
+            });
+            related.push(info);
+        }
+    }
+
+    // 置信度不足：交给调用方澄清，token 用于下一次 --resolve
+    let confidence = query_confidence(found.is_some(), &candidates);
+    let ambiguous = !listing && confidence < args.min_confidence && !candidates.is_empty();
+    let resolution_token = ambiguous.then(|| resolution_token(&query_text, &candidates));
+    for c in &mut candidates {
+        c.score = round_score(c.score, args.score_precision);
+    }
+    if let Some(overlaid) = overlay_paths(&conn, overlay_layer(args)) {
+        let nodes = found
+            .iter_mut()
+            .chain(candidates.iter_mut().map(|c| &mut c.node))
+            .chain(related.iter_mut().map(|r| &mut r.node));
+        tag_source(nodes, &overlaid);
+    }
+
+    // 输出结果
+    if let Some(out_path) = &args.output {
+        let res = QueryResult {
+            status: if ambiguous { "ambiguous" } else { "success" }.to_string(),
+            generation: read_generation(&conn)?,
+            query: query_text,
+            found_symbol: found,
+            match_type: match_type_str,
+            candidates,
+            confidence: round_score(confidence, args.score_precision),
+            score_version: SCORE_VERSION,
+            resolution_token,
+            related_nodes: related,
+            reparsed,
+        };
+        let f = fs::File::create(out_path)?;
+        match &fields {
+            Some(fields) => {
+                let mut value = serde_json::to_value(&res)?;
+                project_node(&mut value["found_symbol"], fields);
+                for key in ["candidates", "related_nodes"] {
+                    for item in value[key].as_array_mut().into_iter().flatten() {
+                        project_node(&mut item["node"], fields);
+                    }
+                }
+                serde_json::to_writer(f, &value)?;
+            }
+            None => serde_json::to_writer(f, &res)?,
+        }
+    }
+
+    Ok(())
+}
+
+// 行号定位前的新鲜度校验：磁盘内容哈希与索引一致返回 None（照常查库）；
+// 不一致则只重解析这一个文件，取包含该行的最内层符号（不写库，下次 index 再落库）。
+// 有 overlay 的文件以编辑器缓冲区为准，同样照常查库
+fn refresh_symbol_at_line(
+    args: &Args,
+    conn: &Connection,
+    file_pattern: &str,
+    line: usize,
+) -> anyhow::Result<Option<Option<Node>>> {
+    let row = conn
+        .query_row(
+            "SELECT file_id, file_path, language, file_hash FROM files
+             WHERE file_path LIKE ?1 ORDER BY length(file_path) LIMIT 1",
+            [file_pattern],
+            |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, String>(3)?,
+                ))
+            },
+        )
+        .optional()?;
+    let (Some((file_id, file_path, language, file_hash)), Ok(roots)) = (row, project_roots(args))
+    else {
+        return Ok(None);
+    };
+    let overlaid = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM main.overlay_files WHERE file_id = ?1 AND sandbox = ?2)",
+            params![file_id, overlay_layer(args)],
+            |r| r.get::<_, bool>(0),
+        )
+        .unwrap_or(false);
+    if overlaid {
+        return Ok(None);
+    }
+    let Some(content) = fs::read(resolve_source_path(&roots, &file_path))
+        .ok()
+        .and_then(|b| decode_source(&b))
+    else {
+        return Ok(None);
+    };
+    if hex::encode(Sha256::digest(content.as_bytes())) == file_hash {
+        return Ok(None);
+    }
+
+    let parsers = get_parser_setup();
+    if !parsers.contains_key(&language)
+        && text_extractor(&language).is_none()
+        && sfc_extractor(&language).is_none()
+        && objc_extractor(&language).is_none()
+    {
+        return Ok(None);
+    }
+    let type_map = load_type_map(args.type_map.as_deref())?;
+    let features = ExtractFeatures {
+        calls: false,
+        signatures: false,
+        docs: false,
+    };
+    let parsed = extract_with_fallback(&parsers, &language, &content, features);
+    let lines: Vec<&str> = content.lines().collect();
+    let node = parsed
+        .symbols
+        .into_iter()
+        .filter(|s| s.line_start <= line && line <= s.line_end)
+        .min_by_key(|s| s.line_end - s.line_start)
+        .map(|s| Node {
+            // 与索引期同一规则，现存符号的 ID 不变，调用者查询照常命中
+            id: format!(
+                "{}:{}::{}",
+                canonical_prefix(&s.symbol_type),
+                file_path,
+                anon_in_file(&s.name, &file_path)
+            ),
+            name: anon_in_file(&s.name, &file_path),
+            qualified_name: anon_in_file(&s.qualified_name, &file_path),
+            file_path: file_path.clone(),
+            line_start: s.line_start,
+            line_end: s.line_end,
+            anchor: Some(edit_anchor(&lines, s.line_start, s.line_end)),
+            node_type: type_map.resolve(&language, &s.symbol_type).to_string(),
+            signature: None,
+            summary: None,
+            stable_id: None,
+            doc: None,
+            source: None,
+            calls: vec![],
+        });
+    Ok(Some(node))
+}
+
+#[derive(Serialize)]
+struct MapResult {
+    generation: i64,
+    statistics: Stats,
+    structure: BTreeMap<String, Vec<Node>>, // 文件路径有序，文件内按行号
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    readmes: BTreeMap<String, String>, // 🆕 目录 -> README 首段（根目录为 ""）
+    elapsed: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completeness: Option<Completeness>, // 🆕 仅 --allow-partial
+}
+
+// 首次索引期间的进度：已抽取符号的文件 / 已知文件（DB 与心跳取大）
+#[derive(Serialize, Default)]
+struct Completeness {
+    ratio: f64,
+    indexed_files: usize,
+    known_files: usize,
+    in_progress: bool,
+}
+
+// 心跳 120s 内更新、未标记 done 且写入进程仍存活，视为索引进行中
+fn index_completeness(conn: &Connection, heartbeat_path: &Path) -> Completeness {
+    let count = |sql: &str| -> usize { conn.query_row(sql, [], |r| r.get(0)).unwrap_or(0) };
+    let indexed_files = count("SELECT count(*) FROM files WHERE index_level = 'symbol'");
+    let mut known_files = count("SELECT count(*) FROM files");
+    let mut in_progress = false;
+
+    let heartbeat = fs::read(heartbeat_path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice::<serde_json::Value>(&raw).ok());
+    if let Some(hb) = heartbeat {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let fresh = hb["timestamp"]
+            .as_u64()
+            .is_some_and(|t| now.saturating_sub(t) < STALE_HEARTBEAT_SECS);
+        let writer_dead =
+            hb["pid"].as_u64().and_then(|pid| process_alive(pid as u32)) == Some(false);
+        in_progress = fresh && !writer_dead && !hb["done"].as_bool().unwrap_or(false);
+        if in_progress {
+            let processed = hb["processed"].as_u64().unwrap_or(0) as usize;
+            // total 含不可解析的扩展名，只用于进行中的估计
+            let total = hb["total"].as_u64().unwrap_or(0) as usize;
+            known_files = known_files.max(total).max(processed);
+        }
+    }
+
+    let ratio = if known_files == 0 {
+        0.0
+    } else {
+        (indexed_files as f64 / known_files as f64).min(1.0)
+    };
+    Completeness {
+        ratio,
+        indexed_files,
+        known_files,
+        in_progress,
+    }
+}
+
+#[derive(Serialize, Default)]
+struct Stats {
+    total_files: usize,
+    total_symbols: usize,
+    licenses: LicenseReport, // 🆕 许可证构成 + 与项目许可证不一致的文件
+}
+
+fn run_map(args: &Args) -> anyhow::Result<()> {
+    let fields = parse_fields(args.fields.as_deref())?;
+    let conn = Connection::open(&args.db)?;
+
+    // --allow-partial：索引可能刚开始（表尚未建立）或正在写入
+    let completeness = if args.allow_partial {
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        Some(index_completeness(&conn, &heartbeat_path(args)?))
+    } else {
+        None
+    };
+    let has_tables: bool = conn
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name IN ('files', 'symbols')",
+            [],
+            |r| r.get::<_, i64>(0),
+        )
+        .map(|n| n == 2)
+        .unwrap_or(false);
+    if args.allow_partial && !has_tables {
+        if let Some(out_path) = &args.output {
+            let res = MapResult {
+                generation: 0,
+                statistics: Stats::default(),
+                structure: BTreeMap::new(),
+                readmes: BTreeMap::new(),
+                elapsed: "0s".to_string(),
+                completeness,
+            };
+            serde_json::to_writer(fs::File::create(out_path)?, &res)?;
+        }
+        return Ok(());
+    }
+
+    apply_read_filter(
+        &conn,
+        args.root.as_deref(),
+        &prune_globs(args.prune_path.as_deref()),
+        Some(overlay_layer(args)),
+    )?;
+
+    // Stats
+    let mut stats = Stats::default();
+
+    // Structure
+    let mut structure: BTreeMap<String, Vec<Node>> = BTreeMap::new();
+
+    // 🆕 修改：添加 canonical_id 和 signature 字段
+    let sql_base = "SELECT file_path, name, qualified_name, symbol_type, line_start, line_end, canonical_id, signature, summary, stable_id, anchor, doc FROM symbols JOIN files ON symbols.file_id = files.file_id";
+    // doc 只在 --detail full 输出，standard / overview 保持紧凑
+    let with_doc = args.detail == "full";
+
+    if let Some(scope) = &args.scope {
+        if !scope.is_empty() {
+            // === 有 Scope 过滤 ===
+            let pattern = format!("{}%", scope.replace("\\", "/"));
+
+            // Stats (Scoped)
+            stats.total_files = conn
+                .query_row(
+                    "SELECT count(*) FROM files WHERE file_path LIKE ?1",
+                    [&pattern],
+                    |r| r.get(0),
+                )
+                .unwrap_or(0);
+            stats.total_symbols = conn.query_row("SELECT count(*) FROM symbols JOIN files ON symbols.file_id = files.file_id WHERE file_path LIKE ?1", [&pattern], |r| r.get(0)).unwrap_or(0);
+
+            let sql = format!("{} WHERE file_path LIKE ?1", sql_base);
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map([&pattern], |row| {
+                Ok((
+                    row.get::<_, String>(0)?, // file_path
+                    Node {
+                        id: row.get::<_, String>(6)?, // 🆕 canonical_id as ID (规范字符串)
+                        name: row.get(1)?,
+                        qualified_name: row.get(2)?,
+                        file_path: row.get(0)?,
+                        line_start: row.get(4)?,
+                        line_end: row.get(5)?,
+                        node_type: row.get(3)?,
+                        signature: row.get(7)?, // 🆕 从数据库读取签名
+                        summary: row.get(8)?,
+                        stable_id: row.get(9)?,
+                        anchor: row.get(10)?,
+                        doc: if with_doc { row.get(11)? } else { None },
+                        source: None,
+                        calls: vec![],
+                    },
+                ))
+            })?;
+
+            for (path, node) in rows.flatten() {
+                structure.entry(path).or_default().push(node);
+            }
+        } else {
+            // === Scope 为空字符串，视为全量 ===
+            stats.total_files = conn
+                .query_row("SELECT count(*) FROM files", [], |r| r.get(0))
+                .unwrap_or(0);
+            stats.total_symbols = conn
+                .query_row("SELECT count(*) FROM symbols", [], |r| r.get(0))
+                .unwrap_or(0);
+
+            let mut stmt = conn.prepare(sql_base)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    Node {
+                        id: row.get::<_, String>(6)?,
+                        name: row.get(1)?,
+                        qualified_name: row.get(2)?,
+                        file_path: row.get(0)?,
+                        line_start: row.get(4)?,
+                        line_end: row.get(5)?,
+                        node_type: row.get(3)?,
+                        signature: row.get(7)?, // 🆕
+                        summary: row.get(8)?,
+                        stable_id: row.get(9)?,
+                        anchor: row.get(10)?,
+                        doc: if with_doc { row.get(11)? } else { None },
+                        source: None,
+                        calls: vec![],
+                    },
+                ))
+            })?;
+            for (path, node) in rows.flatten() {
+                structure.entry(path).or_default().push(node);
+            }
+        }
+    } else {
+        // === 无 Scope 参数，视为全量 ===
+        stats.total_files = conn
+            .query_row("SELECT count(*) FROM files", [], |r| r.get(0))
+            .unwrap_or(0);
+        stats.total_symbols = conn
+            .query_row("SELECT count(*) FROM symbols", [], |r| r.get(0))
+            .unwrap_or(0);
+
+        let mut stmt = conn.prepare(sql_base)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                Node {
+                    id: row.get::<_, String>(6)?,
+                    name: row.get(1)?,
+                    qualified_name: row.get(2)?,
+                    file_path: row.get(0)?,
+                    line_start: row.get(4)?,
+                    line_end: row.get(5)?,
+                    node_type: row.get(3)?,
+                    signature: row.get(7)?, // 🆕
+                    summary: row.get(8)?,
+                    stable_id: row.get(9)?,
+                    anchor: row.get(10)?,
+                    doc: if with_doc { row.get(11)? } else { None },
+                    source: None,
+                    calls: vec![],
+                },
+            ))
+        })?;
+        for (path, node) in rows.flatten() {
+            structure.entry(path).or_default().push(node);
+        }
+    };
+
+    for nodes in structure.values_mut() {
+        nodes.sort_by(|a, b| {
+            (a.line_start, a.line_end, &a.id).cmp(&(b.line_start, b.line_end, &b.id))
+        });
+    }
+
+    let scope_pattern = args
+        .scope
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("{}%", s.replace("\\", "/")));
+    stats.licenses = license_report(&conn, scope_pattern.as_deref())?;
+
+    // 目录 README 首段：人写的模块意图，和符号列表一起给 agent 做总览
+    let mut readmes = BTreeMap::new();
+    if let Ok(roots) = project_roots(args) {
+        let dirs: BTreeSet<String> = structure
+            .keys()
+            .map(|p| p.rsplit_once('/').map(|(d, _)| d).unwrap_or("").to_string())
+            .collect();
+        for dir in dirs {
+            if let Some(summary) = dir_readme(&resolve_source_path(&roots, &dir)) {
+                readmes.insert(dir, summary);
+            }
+        }
+    }
+
+    if let Some(overlaid) = overlay_paths(&conn, overlay_layer(args)) {
+        tag_source(structure.values_mut().flatten(), &overlaid);
+    }
+
+    if let Some(out_path) = &args.output {
+        let res = MapResult {
+            generation: read_generation(&conn)?,
+            statistics: stats,
+            structure,
+            readmes,
+            elapsed: "0s".to_string(),
+            completeness,
+        };
+        let f = fs::File::create(out_path)?;
+        match &fields {
+            Some(fields) => {
+                let mut value = serde_json::to_value(&res)?;
+                for nodes in value["structure"]
+                    .as_object_mut()
+                    .into_iter()
+                    .flat_map(|m| m.values_mut())
+                {
+                    for node in nodes.as_array_mut().into_iter().flatten() {
+                        project_node(node, fields);
+                    }
+                }
+                serde_json::to_writer(f, &value)?;
+            }
+            None => serde_json::to_writer(f, &res)?,
+        }
+    }
+
+    Ok(())
+}
+
+// ext -> (语法, 编译好的 query, 抽取器版本)
+// 语法表：每个扩展名单独编译一份 Query（Query is not Clone），首次遇到该扩展名时才编译；
+// 各语言可在构建时用 cargo feature 裁掉（--no-default-features --features python,go）
+struct GrammarSpec {
+    exts: &'static [&'static str],
+    language: fn() -> Language,
+    query: &'static str,
+    what: &'static str,
+}
+
+// (Language, Query, extractor_version)
+type CompiledGrammar = (Language, Query, String);
+
+struct ParserSetup {
+    grammars: HashMap<&'static str, (&'static GrammarSpec, OnceLock<CompiledGrammar>)>,
+}
+
+impl ParserSetup {
+    fn get(&self, ext: &str) -> Option<&CompiledGrammar> {
+        let (spec, compiled) = self.grammars.get(ext)?;
+        Some(compiled.get_or_init(|| {
+            let lang = (spec.language)();
+            let query = Query::new(&lang, spec.query)
+                .unwrap_or_else(|e| panic!("Invalid {} Query: {:?}", spec.what, e));
+            let version = extractor_version(&lang, spec.query);
+            (lang, query, version)
+        }))
+    }
+
+    fn contains_key(&self, ext: &str) -> bool {
+        self.grammars.contains_key(ext)
+    }
+}
+
+fn get_parser_setup() -> ParserSetup {
+    let grammars = GRAMMARS
+        .iter()
+        .flat_map(|spec| {
+            spec.exts
+                .iter()
+                .map(move |ext| (*ext, (spec, OnceLock::new())))
+        })
+        .collect();
+    ParserSetup { grammars }
+}
+
+// TypeScript (.ts) / TSX (TypeScript + JSX)
+#[cfg(feature = "typescript")]
+const TS_QUERY: &str = r#"
+        (function_declaration name: (identifier) @name) @def.func
+        (class_declaration name: (type_identifier) @name) @def.class
+        (abstract_class_declaration name: (type_identifier) @name) @def.class
+        (method_definition name: (property_identifier) @name) @def.func
+        (method_signature name: (property_identifier) @name) @def.func
+        (abstract_method_signature name: (property_identifier) @name) @def.func
+        (function_signature name: (identifier) @name) @def.func
+        (interface_declaration name: (type_identifier) @name) @def.class
+        (enum_declaration name: (identifier) @name) @def.class
+        (public_field_definition name: (property_identifier) @name) @def.field
+        (property_signature name: (property_identifier) @name) @def.field
+        (enum_body name: (property_identifier) @name @def.const)
+        (enum_assignment name: (property_identifier) @name) @def.const
+        (type_alias_declaration name: (type_identifier) @name) @def.class
+        (internal_module name: (identifier) @name) @def.class
+        (lexical_declaration (variable_declarator name: (identifier) @name value: [(arrow_function) (function_expression)])) @def.func
+        (variable_declaration (variable_declarator name: (identifier) @name value: [(arrow_function) (function_expression)])) @def.func
+        (expression_statement (assignment_expression
+            left: (member_expression property: (property_identifier) @name)
+            right: [(arrow_function) (function_expression)])
+            (#not-eq? @name "exports")) @def.func
+        (lexical_declaration (variable_declarator name: (identifier) @name)) @def.const
+        (call_expression function: (identifier) @callee) @ref.call
+        (call_expression function: (member_expression property: (property_identifier) @callee)) @ref.call
+        (arguments [(arrow_function) (function_expression)] @def.anon)
+        (import_statement) @import
+        (export_statement source: (string)) @import
+        (call_expression function: (identifier) @_req arguments: (arguments . (string))
+            (#eq? @_req "require")) @import
+    "#;
+
+static GRAMMARS: &[GrammarSpec] = &[
+    // Python
+    #[cfg(feature = "python")]
+    GrammarSpec {
+        exts: &["py"],
+        language: || tree_sitter_python::LANGUAGE.into(),
+        query: r#"
+        (function_definition name: (identifier) @name) @def.func
+        (class_definition name: (identifier) @name) @def.class
+        (class_definition body: (block (expression_statement (assignment left: (identifier) @name type: (_)) @def.field)))
+        (module (expression_statement (assignment left: (identifier) @name)) @def.const)
+        (call function: (identifier) @callee) @ref.call
+        (call function: (attribute attribute: (identifier) @callee)) @ref.call
+        (decorator (identifier) @callee)
+        (decorator (attribute attribute: (identifier) @callee))
+        (import_statement) @import
+        (import_from_statement) @import
+    "#,
+        what: "Python",
+    },
+    // JS / Node.js ES Modules (.mjs) / CommonJS (.cjs)
+    #[cfg(feature = "javascript")]
+    GrammarSpec {
+        exts: &["js", "mjs", "cjs", "jsx"],
+        language: || tree_sitter_javascript::LANGUAGE.into(),
+        query: r#"
+        (function_declaration name: (identifier) @name) @def.func
+        (class_declaration name: (identifier) @name) @def.class
+        (method_definition name: (property_identifier) @name) @def.func
+        (lexical_declaration (variable_declarator name: (identifier) @name value: [(arrow_function) (function_expression)])) @def.func
+        (variable_declaration (variable_declarator name: (identifier) @name value: [(arrow_function) (function_expression)])) @def.func
+        (expression_statement (assignment_expression
+            left: (member_expression property: (property_identifier) @name)
+            right: [(arrow_function) (function_expression)])
+            (#not-eq? @name "exports")) @def.func
+        (lexical_declaration (variable_declarator name: (identifier) @name)) @def.const
+        (call_expression function: (identifier) @callee) @ref.call
+        (call_expression function: (member_expression property: (property_identifier) @callee)) @ref.call
+        (arguments [(arrow_function) (function_expression)] @def.anon)
+        (import_statement) @import
+        (export_statement source: (string)) @import
+        (call_expression function: (identifier) @_req arguments: (arguments . (string))
+            (#eq? @_req "require")) @import
+    "#,
+        what: "JS",
+    },
+    #[cfg(feature = "typescript")]
+    GrammarSpec {
+        exts: &["ts"],
+        language: || tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+        query: TS_QUERY,
+        what: "TypeScript",
+    },
+    #[cfg(feature = "typescript")]
+    GrammarSpec {
+        exts: &["tsx"],
+        language: || tree_sitter_typescript::LANGUAGE_TSX.into(),
+        query: TS_QUERY,
+        what: "TSX",
+    },
+    // Go
+    #[cfg(feature = "go")]
+    GrammarSpec {
+        exts: &["go"],
+        language: || tree_sitter_go::LANGUAGE.into(),
+        query: r#"
+        (function_declaration name: (identifier) @name) @def.func
+        (method_declaration name: (field_identifier) @name) @def.func
+        (method_elem name: (field_identifier) @name) @def.func
+        (type_spec name: (type_identifier) @name) @def.class
+        (field_declaration name: (field_identifier) @name) @def.field
+        (const_spec name: (identifier) @name) @def.const
+        (call_expression function: (identifier) @callee) @ref.call
+        (call_expression function: (selector_expression field: (field_identifier) @callee)) @ref.call
+        (argument_list (func_literal) @def.anon)
+        (import_spec) @import
+    "#,
+        what: "Go",
+    },
+    // Rust
+    #[cfg(feature = "rust")]
+    GrammarSpec {
+        exts: &["rs"],
+        language: || tree_sitter_rust::LANGUAGE.into(),
+        query: r#"
+        (function_item name: (identifier) @name) @def.func
+        (trait_item body: (declaration_list (function_signature_item name: (identifier) @name) @def.func))
+        (struct_item name: (type_identifier) @name) @def.class
+        (field_declaration name: (field_identifier) @name) @def.field
+        (enum_item name: (type_identifier) @name) @def.class
+        (enum_variant name: (identifier) @name) @def.const
+        (impl_item type: (type_identifier) @name) @def.class
+        (impl_item type: (generic_type type: (type_identifier) @name)) @def.class
+        (trait_item name: (type_identifier) @name) @def.class
+        (mod_item name: (identifier) @name) @def.class
+        (const_item name: (identifier) @name) @def.const
+        (static_item name: (identifier) @name) @def.const
+        (call_expression function: (identifier) @callee) @ref.call
+        (call_expression function: (scoped_identifier name: (identifier) @callee)) @ref.call
+        (call_expression function: (field_expression field: (field_identifier) @callee)) @ref.call
+        (macro_definition name: (identifier) @name) @def.func
+        (macro_invocation macro: (identifier) @callee) @ref.call
+        (macro_invocation macro: (scoped_identifier name: (identifier) @callee)) @ref.call
+        (macro_invocation (token_tree (identifier) @callee . (token_tree)))
+        (attribute_item (attribute (identifier) @_derive arguments: (token_tree (identifier) @callee))
+            (#eq? @_derive "derive")) @ref.call
+        (attribute_item (attribute (identifier) @callee)
+            (#not-any-of? @callee "derive" "cfg" "cfg_attr" "test" "bench" "ignore" "should_panic"
+                "allow" "warn" "deny" "forbid" "expect" "inline" "cold" "must_use" "deprecated"
+                "doc" "path" "repr" "non_exhaustive" "track_caller" "automatically_derived"
+                "macro_export" "macro_use" "no_mangle" "export_name" "link" "link_name" "link_section"
+                "used" "global_allocator" "proc_macro" "proc_macro_derive" "proc_macro_attribute"
+                "feature" "recursion_limit" "no_std" "no_main" "crate_type" "crate_name")) @ref.call
+        (attribute_item (attribute (scoped_identifier path: (identifier) @_tool name: (identifier) @callee))
+            (#not-any-of? @_tool "rustfmt" "clippy" "diagnostic")) @ref.call
+        (use_declaration) @import
+    "#,
+        what: "Rust",
+    },
+    // Java
+    #[cfg(feature = "java")]
+    GrammarSpec {
+        exts: &["java"],
+        language: || tree_sitter_java::LANGUAGE.into(),
+        query: r#"
+        (class_declaration name: (identifier) @name) @def.class
+        (method_declaration name: (identifier) @name) @def.func
+        (interface_declaration name: (identifier) @name) @def.class
+        (enum_declaration name: (identifier) @name) @def.class
+        (enum_constant name: (identifier) @name) @def.const
+        (field_declaration declarator: (variable_declarator name: (identifier) @name)) @def.field
+        (constructor_declaration name: (identifier) @name) @def.func
+        (method_invocation name: (identifier) @callee) @ref.call
+        (import_declaration) @import
+    "#,
+        what: "Java",
+    },
+    // C (+ headers)
+    #[cfg(feature = "c")]
+    GrammarSpec {
+        exts: &["c", "h"],
+        language: || tree_sitter_c::LANGUAGE.into(),
+        query: r#"
+        (function_definition declarator: (function_declarator declarator: (identifier) @name)) @def.func
+        (struct_specifier name: (type_identifier) @name) @def.class
+        (field_declaration declarator: (field_identifier) @name) @def.field
+        (enum_specifier name: (type_identifier) @name body: (enumerator_list)) @def.class
+        (preproc_def name: (identifier) @name) @def.func
+        (preproc_function_def name: (identifier) @name) @def.func
+        (declaration declarator: (identifier) @name @def.const)
+        (declaration declarator: (init_declarator declarator: (identifier) @name) @def.const)
+        (declaration declarator: (init_declarator declarator: (pointer_declarator declarator: (identifier) @name)) @def.const)
+        (declaration declarator: (init_declarator declarator: (array_declarator declarator: (identifier) @name)) @def.const)
+        (declaration declarator: (pointer_declarator declarator: (identifier) @name) @def.const)
+        (declaration declarator: (array_declarator declarator: (identifier) @name) @def.const)
+        (call_expression function: (identifier) @callee) @ref.call
+        (preproc_include) @import
+        (preproc_call directive: (preproc_directive) @_dir (#match? @_dir "import$")) @import
+    "#,
+        what: "C",
+    },
+    // C++
+    #[cfg(feature = "cpp")]
+    GrammarSpec {
+        exts: &["cpp", "cc", "hpp"],
+        language: || tree_sitter_cpp::LANGUAGE.into(),
+        query: r#"
+        (function_definition declarator: (function_declarator declarator: (identifier) @name)) @def.func
+        (function_definition declarator: (function_declarator declarator: (field_identifier) @name)) @def.func
+        (function_definition declarator: (function_declarator declarator: (qualified_identifier name: (identifier) @name))) @def.func
+        (class_specifier name: (type_identifier) @name) @def.class
+        (struct_specifier name: (type_identifier) @name) @def.class
+        (field_declaration declarator: (field_identifier) @name) @def.field
+        (enum_specifier name: (type_identifier) @name body: (enumerator_list)) @def.class
+        (namespace_definition name: (namespace_identifier) @name) @def.class
+        (preproc_def name: (identifier) @name) @def.func
+        (preproc_function_def name: (identifier) @name) @def.func
+        (declaration declarator: (identifier) @name @def.const)
+        (declaration declarator: (init_declarator declarator: (identifier) @name) @def.const)
+        (declaration declarator: (init_declarator declarator: (pointer_declarator declarator: (identifier) @name)) @def.const)
+        (declaration declarator: (init_declarator declarator: (array_declarator declarator: (identifier) @name)) @def.const)
+        (declaration declarator: (pointer_declarator declarator: (identifier) @name) @def.const)
+        (declaration declarator: (array_declarator declarator: (identifier) @name) @def.const)
+        (call_expression function: (identifier) @callee) @ref.call
+        (call_expression function: (field_expression field: (field_identifier) @callee)) @ref.call
+        (preproc_include) @import
+        (preproc_call directive: (preproc_directive) @_dir (#match? @_dir "import$")) @import
+    "#,
+        what: "C++",
+    },
+    // Swift（extension 成员经 scope_path 归到被扩展的类型下）
+    #[cfg(feature = "swift")]
+    GrammarSpec {
+        exts: &["swift"],
+        language: || tree_sitter_swift::LANGUAGE.into(),
+        query: r#"
+        (class_declaration name: (type_identifier) @name) @def.class
+        (class_declaration name: (user_type (type_identifier) @name)) @def.class
+        (protocol_declaration name: (type_identifier) @name) @def.class
+        (function_declaration name: (simple_identifier) @name) @def.func
+        (protocol_function_declaration name: (simple_identifier) @name) @def.func
+        (init_declaration "init" @name) @def.func
+        (call_expression (simple_identifier) @callee) @ref.call
+        (call_expression (navigation_expression suffix: (navigation_suffix suffix: (simple_identifier) @callee))) @ref.call
+        (import_declaration) @import
+    "#,
+        what: "Swift",
+    },
+    // Ruby（嵌套 module/class 折叠进 scope_path；裸标识符调用与局部变量无法区分，只收带参数/接收者的调用）
+    #[cfg(feature = "ruby")]
+    GrammarSpec {
+        exts: &["rb"],
+        language: || tree_sitter_ruby::LANGUAGE.into(),
+        query: r#"
+        (class name: (constant) @name) @def.class
+        (class name: (scope_resolution name: (constant) @name)) @def.class
+        (module name: (constant) @name) @def.class
+        (module name: (scope_resolution name: (constant) @name)) @def.class
+        (method name: (_) @name) @def.func
+        (singleton_method name: (_) @name) @def.func
+        (assignment left: (constant) @name) @def.const
+        (call method: (identifier) @callee) @ref.call
+        (call method: (identifier) @_req arguments: (argument_list . (string))
+            (#any-of? @_req "require" "require_relative")) @import
+    "#,
+        what: "Ruby",
+    },
+    // PHP（LANGUAGE_PHP 支持 <?php 与 HTML 混排；命名空间多为语句形式，不进 scope_path）
+    #[cfg(feature = "php")]
+    GrammarSpec {
+        exts: &["php"],
+        language: || tree_sitter_php::LANGUAGE_PHP.into(),
+        query: r#"
+        (class_declaration name: (name) @name) @def.class
+        (interface_declaration name: (name) @name) @def.class
+        (trait_declaration name: (name) @name) @def.class
+        (enum_declaration name: (name) @name) @def.class
+        (function_definition name: (name) @name) @def.func
+        (method_declaration name: (name) @name) @def.func
+        (function_call_expression function: (name) @callee) @ref.call
+        (function_call_expression function: (qualified_name (name) @callee)) @ref.call
+        (member_call_expression name: (name) @callee) @ref.call
+        (nullsafe_member_call_expression name: (name) @callee) @ref.call
+        (scoped_call_expression name: (name) @callee) @ref.call
+        (namespace_use_declaration) @import
+        [(require_expression) (require_once_expression) (include_expression) (include_once_expression)] @import
+    "#,
+        what: "PHP",
+    },
+    // Elixir（defmodule/def 都是 call 节点，靠 #any-of? 谓词区分；|> 管道右侧视为调用）
+    #[cfg(feature = "elixir")]
+    GrammarSpec {
+        exts: &["ex", "exs"],
+        language: || tree_sitter_elixir::LANGUAGE.into(),
+        query: r#"
+        (call target: (identifier) @_kw (arguments (alias) @name)
+            (#any-of? @_kw "defmodule" "defprotocol")) @def.class
+        (call target: (identifier) @_kw
+            (arguments [
+                (identifier) @name
+                (call target: (identifier) @name)
+                (binary_operator left: (call target: (identifier) @name) operator: "when")
+            ])
+            (#any-of? @_kw "def" "defp" "defmacro" "defmacrop" "defguard" "defguardp" "defdelegate")) @def.func
+        (call target: (identifier) @callee
+            (#not-any-of? @callee "def" "defp" "defmacro" "defmacrop" "defguard" "defguardp" "defdelegate"
+                "defmodule" "defprotocol" "defimpl" "defstruct" "defexception" "alias" "import" "require"
+                "use" "case" "cond" "if" "unless" "with" "for" "try" "receive" "quote" "unquote" "raise")) @ref.call
+        (call target: (dot right: (identifier) @callee) (arguments)) @ref.call
+        (binary_operator operator: "|>" right: (identifier) @callee) @ref.call
+        (call target: (identifier) @_kw (arguments . (alias))
+            (#any-of? @_kw "alias" "import" "require" "use")) @import
+    "#,
+        what: "Elixir",
+    },
+    // C#（namespace 与 Java package 一样不进 scope_path；属性是 property 符号）
+    #[cfg(feature = "csharp")]
+    GrammarSpec {
+        exts: &["cs"],
+        language: || tree_sitter_c_sharp::LANGUAGE.into(),
+        query: r#"
+        (class_declaration name: (identifier) @name) @def.class
+        (interface_declaration name: (identifier) @name) @def.class
+        (struct_declaration name: (identifier) @name) @def.class
+        (record_declaration name: (identifier) @name) @def.class
+        (enum_declaration name: (identifier) @name) @def.class
+        (enum_member_declaration name: (identifier) @name) @def.const
+        (method_declaration name: (identifier) @name) @def.func
+        (constructor_declaration name: (identifier) @name) @def.func
+        (property_declaration name: (identifier) @name) @def.func
+        (field_declaration (variable_declaration (variable_declarator name: (identifier) @name))) @def.field
+        (invocation_expression function: (identifier) @callee) @ref.call
+        (invocation_expression function: (member_access_expression name: (identifier) @callee)) @ref.call
+        (object_creation_expression type: (identifier) @callee) @ref.call
+        (using_directive) @import
+    "#,
+        what: "C#",
+    },
+    // TODO: Kotlin 暂无适配 tree-sitter 0.26 的 grammar crate
+];
+
+// ============================================================================
+// Impact Analysis & Dice Algorithm (Rust Implementation)
+// ============================================================================
+
+#[derive(Serialize)]
+struct AnalysisResult {
+    status: String,
+    generation: i64,
+    node_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>, // 🆕 目标符号的来源（overlay | disk），仅在存在覆盖层时输出
+    complexity_score: f64,
+    complexity_level: String,
+    affected_nodes: usize,
+    direct_callers: Vec<CallerInfo>,
+    indirect_callers: Vec<CallerInfo>,
+    risk_level: String,
+    risk_score: f64,
+    risk_factors: Vec<RiskFactor>, // 🆕 风险来源明细
+    modification_checklist: Vec<String>,
+    seed: u64,
+    random_walks: usize,
+    checklist_items: Vec<ChecklistItem>, // 🆕 结构化清单（与文本一一对应，供 Go 侧生成任务）
+    impact_groups: Vec<ImpactGroup>,     // 🆕 按模块/目录聚合
+    recursive: bool,                     // 🆕 目标自递归或处在相互递归的环上
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mutual_recursion: Vec<String>, // 🆕 与目标同处一个调用环的其他符号
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldInfo>, // 🆕 目标为类型时的字段及声明类型（数据形状）
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    implementations: Vec<Node>, // 🆕 目标为接口 / 基类方法时，各（传递）子类型里的实现
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    overrides: Vec<Node>, // 🆕 目标为重写方法时，被它（传递）重写的基方法
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_stubs: Option<usize>, // 🆕 折叠进目标及受影响 proto 定义的生成桩符号数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<AnalysisComparison>, // 🆕 与 --compare-with 基线的差异
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct FieldInfo {
+    name: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none", default)]
+    field_type: Option<String>,
+    line: usize,
+}
+
+// 类型符号的直接字段，按声明顺序
+fn load_fields(conn: &Connection, canonical_id: &str) -> Result<Vec<FieldInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, signature, line_start FROM symbols
+         WHERE symbol_type = 'field'
+           AND parent_id IN (SELECT symbol_id FROM symbols WHERE canonical_id = ?1)
+         ORDER BY line_start",
+    )?;
+    let rows = stmt.query_map([canonical_id], |r| {
+        Ok(FieldInfo {
+            name: r.get(0)?,
+            field_type: r.get(1)?,
+            line: r.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+#[derive(Serialize)]
+struct ChecklistItem {
+    kind: String, // target | caller | dependency
+    target: String,
+    file: String,
+    line: usize,
+    reason: String,
+    suggested_action: String,
+}
+
+#[derive(Serialize)]
+struct ImpactGroup {
+    module: String,
+    direct: usize,
+    indirect: usize,
+    risk_level: String,
+    exemplars: Vec<Node>,
+}
+
+// 受影响符号按所在目录（≈ package/module）聚合；组内风险按 直接×2 + 间接 评分，
+// 代表符号取自身被依赖最多的前 3 个
+fn group_impact(
+    direct: &[CallerInfo],
+    indirect: &[CallerInfo],
+    reverse_adjacency: &HashMap<String, Vec<String>>,
+) -> Vec<ImpactGroup> {
+    let mut groups: BTreeMap<String, (usize, usize, Vec<&Node>)> = BTreeMap::new();
+    for (info, is_direct) in direct
+        .iter()
+        .map(|c| (c, true))
+        .chain(indirect.iter().map(|c| (c, false)))
+    {
+        let module = Path::new(&info.node.file_path)
+            .parent()
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| ".".to_string());
+        let entry = groups.entry(module).or_default();
+        if is_direct {
+            entry.0 += 1;
+        } else {
+            entry.1 += 1;
+        }
+        entry.2.push(&info.node);
+    }
+
+    let fan_in = |n: &Node| reverse_adjacency.get(&n.id).map(|v| v.len()).unwrap_or(0);
+    let mut result: Vec<(usize, ImpactGroup)> = groups
+        .into_iter()
+        .map(|(module, (d, i, mut nodes))| {
+            let score = d * 2 + i;
+            let risk_level = if score <= 3 {
+                "low"
+            } else if score <= 10 {
+                "medium"
+            } else {
+                "high"
+            };
+            // 稳定排序：同分时保留 直接 -> 间接 的顺序
+            nodes.sort_by_key(|n| std::cmp::Reverse(fan_in(n)));
+            let exemplars = nodes.into_iter().take(3).cloned().collect();
+            (
+                score,
+                ImpactGroup {
+                    module,
+                    direct: d,
+                    indirect: i,
+                    risk_level: risk_level.to_string(),
+                    exemplars,
+                },
+            )
+        })
+        .collect();
+    result.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    result.into_iter().map(|(_, g)| g).collect()
+}
+
+// ============================================================================
+// Risk Model (可配置风险模型)
+// score = Σ weight × factor；默认权重：affected 1、untested 2（3 跳内没有测试够到目标）、
+// churn 0.25/次提交、centrality 0.1/条边，阈值 3/10
+// ============================================================================
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RiskModel {
+    weights: RiskWeights,
+    thresholds: RiskThresholds,
+    churn_days: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RiskWeights {
+    affected: f64,
+    untested: f64,
+    churn: f64,
+    centrality: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RiskThresholds {
+    medium: f64,
+    high: f64,
+}
+
+impl Default for RiskModel {
+    fn default() -> Self {
+        RiskModel {
+            weights: RiskWeights::default(),
+            thresholds: RiskThresholds::default(),
+            churn_days: 90,
+        }
+    }
+}
+
+impl Default for RiskWeights {
+    fn default() -> Self {
+        RiskWeights {
+            affected: 1.0,
+            untested: 2.0,
+            churn: 0.25,
+            centrality: 0.1,
+        }
+    }
+}
+
+impl Default for RiskThresholds {
+    fn default() -> Self {
+        RiskThresholds {
+            medium: 3.0,
+            high: 10.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RiskFactor {
+    name: String,
+    value: f64,
+    weight: f64,
+    contribution: f64,
+    detail: String,
+}
+
+fn load_risk_model(path: Option<&str>) -> anyhow::Result<RiskModel> {
+    let Some(path) = path else {
+        return Ok(RiskModel::default());
+    };
+    let raw = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read risk model {}: {}", path, e))?;
+    serde_json::from_str(&raw).map_err(|e| anyhow::anyhow!("invalid risk model {}: {}", path, e))
+}
+
+fn is_test_path(path: &str) -> bool {
+    let path = path.replace('\\', "/");
+    let base = path.rsplit('/').next().unwrap_or(&path);
+    base.starts_with("test_")
+        || base.contains("_test.")
+        || base.contains(".test.")
+        || base.contains(".spec.")
+        || path.starts_with("tests/")
+        || path.starts_with("test/")
+        || path.contains("/tests/")
+        || path.contains("/test/")
+}
+
+// 目标文件近 N 天的提交次数；非 git 仓库 / git 不可用时返回 None
+fn file_churn(args: &Args, file_path: &str, days: u32) -> Option<usize> {
+    let roots = project_roots(args).ok()?;
+    let abs = resolve_source_path(&roots, file_path);
+    let dir = abs.parent()?;
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "log",
+            "--format=%H",
+            &format!("--since={}.days", days),
+            "--",
+        ])
+        .arg(&abs)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).lines().count())
+}
+
+// 🆕 动态分发（--expand-dynamic）推测边的置信度：实际执行的是哪个重写只有运行时才知道
+const DYNAMIC_DISPATCH_CONFIDENCE: f32 = 0.5;
+
+// 对每条调用边：取被调方法所属类型（有接收者类型时用接收者类型），沿继承表找出全部（传递）子类型，
+// 子类型里的同名方法都连一条推测边。返回 (caller, callee) canonical_id 对（有序：边按固定顺序并入邻接表，随机游走可复现）
+fn dynamic_dispatch_edges(
+    conn: &Connection,
+    visible: &HashSet<String>,
+) -> anyhow::Result<BTreeSet<(String, String)>> {
+    let subtypes = load_subtypes(conn)?;
+    if subtypes.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    let (methods, owners) = load_method_owners(conn)?;
+
+    // 类型 -> 全部传递子类型（按需计算并缓存）
+    let mut descendants: HashMap<String, Vec<String>> = HashMap::new();
+    let mut descendants_of = |root: &str| -> Vec<String> {
+        descendants
+            .entry(root.to_string())
+            .or_insert_with(|| {
+                let mut seen: HashSet<String> = HashSet::new();
+                let mut queue = vec![root.to_string()];
+                while let Some(t) = queue.pop() {
+                    for sub in subtypes.get(&t).into_iter().flatten() {
+                        if sub != root && seen.insert(sub.clone()) {
+                            queue.push(sub.clone());
+                        }
+                    }
+                }
+                seen.into_iter().collect()
+            })
+            .clone()
+    };
+
+    let mut edges = BTreeSet::new();
+    let mut stmt = conn.prepare(
+        "SELECT s.canonical_id, c.callee_id, c.callee_name, c.receiver_type
+         FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id
+         WHERE c.kind = 'call'",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, Option<String>>(1)?,
+            r.get::<_, String>(2)?,
+            r.get::<_, Option<String>>(3)?,
+        ))
+    })?;
+    for (caller, callee_id, callee_name, receiver_type) in rows.flatten() {
+        let roots = match receiver_type {
+            Some(t) => vec![t],
+            None => callee_id
+                .as_ref()
+                .and_then(|id| owners.get(id))
+                .cloned()
+                .unwrap_or_default(),
+        };
+        for root in roots {
+            for sub in descendants_of(&root) {
+                for id in methods
+                    .get(&(sub, callee_name.clone()))
+                    .into_iter()
+                    .flatten()
+                {
+                    if visible.contains(id) && callee_id.as_ref() != Some(id) {
+                        edges.insert((caller.clone(), id.clone()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(edges)
+}
+
+// 父类型名 -> 直接子类型名
+fn load_subtypes(conn: &Connection) -> Result<HashMap<String, Vec<String>>> {
+    let mut subtypes: HashMap<String, Vec<String>> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "SELECT i.base_name, s.name FROM inherits i JOIN symbols s ON s.symbol_id = i.child_id",
+    )?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+    for (base, child) in rows.flatten() {
+        subtypes.entry(base).or_default().push(child);
+    }
+    Ok(subtypes)
+}
+
+// (所属类型, 方法名) -> canonical_id；canonical_id -> 所属类型（同文件同名方法共用一个 ID）
+// 所属类型取作用域链上的外层类型；Go 方法的作用域链不带接收者，从签名里取
+type MethodOwners = (
+    HashMap<(String, String), Vec<String>>,
+    HashMap<String, Vec<String>>,
+);
+
+fn load_method_owners(conn: &Connection) -> Result<MethodOwners> {
+    let mut methods: HashMap<(String, String), Vec<String>> = HashMap::new();
+    let mut owners: HashMap<String, Vec<String>> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "SELECT canonical_id, name, scope_path, signature FROM symbols WHERE canonical_id LIKE 'func:%'",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, String>(1)?,
+            r.get::<_, Option<String>>(2)?,
+            r.get::<_, Option<String>>(3)?,
+        ))
+    })?;
+    for (id, name, scope_path, signature) in rows.flatten() {
+        let Some(owner) = scope_path
+            .as_deref()
+            .and_then(|p| p.strip_suffix(name.as_str()))
+            .and_then(|p| p.strip_suffix("::"))
+            .and_then(|p| p.rsplit("::").next())
+            .and_then(|p| p.rsplit('.').next())
+            .or_else(|| signature.as_deref().and_then(go_receiver_type))
+        else {
+            continue;
+        };
+        methods
+            .entry((owner.to_string(), name))
+            .or_default()
+            .push(id.clone());
+        owners.entry(id).or_default().push(owner.to_string());
+    }
+    Ok((methods, owners))
+}
+
+// 目标是类型 / 接口上的方法时，全部（传递）子类型里的同名方法：改签名时这些实现都得跟着改
+fn implementations_of(
+    conn: &Connection,
+    target_id: &str,
+    name: &str,
+    visible: &HashSet<String>,
+) -> Result<Vec<String>> {
+    let subtypes = load_subtypes(conn)?;
+    if subtypes.is_empty() {
+        return Ok(vec![]);
+    }
+    let (methods, owners) = load_method_owners(conn)?;
+    let mut found = BTreeSet::new();
+    for owner in owners.get(target_id).into_iter().flatten() {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut queue = vec![owner.as_str()];
+        while let Some(t) = queue.pop() {
+            for sub in subtypes.get(t).into_iter().flatten() {
+                if seen.insert(sub) {
+                    queue.push(sub);
+                    for id in methods
+                        .get(&(sub.clone(), name.to_string()))
+                        .into_iter()
+                        .flatten()
+                    {
+                        if id != target_id && visible.contains(id) {
+                            found.insert(id.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(found.into_iter().collect())
+}
+
+// 🆕 修改：使用 canonical_id
+fn run_analyze(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(
+        &conn,
+        args.root.as_deref(),
+        &prune_globs(args.prune_path.as_deref()),
+        Some(overlay_layer(args)),
+    )?;
+    let query_str = args.query.as_ref().expect("Query required for analysis");
+
+    // 1. Locate Target Node (精确匹配优先，失败后模糊匹配)
+    // 先尝试精确匹配
+    let mut stmt = conn.prepare("SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor FROM symbols JOIN files ON symbols.file_id = files.file_id WHERE name = ?1 LIMIT 1")?;
+
+    let target_node = stmt
+        .query_row([query_str], |row| {
+            Ok(Node {
+                id: row.get::<_, String>(0)?, // 🆕 canonical_id
+                name: row.get(1)?,
+                qualified_name: row.get(2)?,
+                file_path: row.get(3)?,
+                line_start: row.get(4)?,
+                line_end: row.get(5)?,
+                node_type: row.get(6)?,
+                signature: None,
+                summary: None,
+                stable_id: None,
+                anchor: row.get(7)?,
+                doc: None,
+                source: None,
+                calls: vec![],
+            })
+        })
+        .optional()?
+        .or_else(|| {
+            // 精确匹配失败，尝试模糊匹配
+            let fuzzy_pattern = format!("%{}%", query_str);
+            let mut fuzzy_stmt = conn.prepare(
+            "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, anchor
+             FROM symbols JOIN files ON symbols.file_id = files.file_id
+             WHERE name LIKE ?1 OR qualified_name LIKE ?1
+             LIMIT 1"
+        ).ok()?;
+            fuzzy_stmt
+                .query_row([fuzzy_pattern], |row| {
+                    Ok(Node {
+                        id: row.get::<_, String>(0)?, // 🆕 canonical_id
+                        name: row.get(1)?,
+                        qualified_name: row.get(2)?,
+                        file_path: row.get(3)?,
+                        line_start: row.get(4)?,
+                        line_end: row.get(5)?,
+                        node_type: row.get(6)?,
+                        signature: None,
+                        summary: None,
+                        stable_id: None,
+                        anchor: row.get(7)?,
+                        doc: None,
+                        source: None,
+                        calls: vec![],
+                    })
+                })
+                .ok()
+        });
+
+    let target = match target_node {
+        Some(n) => n,
+        None => {
+            // Return empty/error JSON
+            if let Some(out_path) = &args.output {
+                let err = serde_json::json!({"status": "error", "message": "Symbol not found"});
+                let f = fs::File::create(out_path)?;
+                serde_json::to_writer(f, &err)?;
+            }
+            return Ok(());
+        }
+    };
+
+    // 🆕 生成桩 -> proto 定义：图里的桩节点一律折叠到 proto 上（两端都可见才折叠）
+    let mut generated: HashMap<String, String> = HashMap::new();
+    {
+        let mut s = conn.prepare(
+            "SELECT s.canonical_id, s.generated_from FROM symbols s
+             WHERE s.generated_from IS NOT NULL
+               AND EXISTS (SELECT 1 FROM symbols p WHERE p.canonical_id = s.generated_from)",
+        )?;
+        let rows = s.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        generated.extend(rows.flatten());
+    }
+    let fold = |id: String| generated.get(&id).cloned().unwrap_or(id);
+
+    // 目标本身是生成桩时改为分析其 proto 定义
+    let target = match generated.get(&target.id) {
+        Some(proto_id) => get_node_by_id(&conn, proto_id)?,
+        None => target,
+    };
+
+    // 🆕 target.id 现在是 canonical_id (String)，不再需要 parse
+    let target_id: String = target.id;
+
+    // 2. Build In-Memory Graph (Adjacency & Reverse Adjacency)
+    // For Dice: we need Outgoing edges (Calls).
+    // For Impact: we need Incoming edges (Called By).
+
+    // Query all calls: caller_id -> callee_id (优先) / callee_name (回退兼容)
+
+    println!("Building dependency graph...");
+
+    // 🆕 使用 canonical_id (String) 而不是 symbol_id (i64)
+    // Load all symbols into Map: Name -> Vec<canonical_id>
+    let mut name_to_ids: HashMap<String, Vec<String>> = HashMap::new();
+    let mut visible: HashSet<String> = HashSet::new(); // --root / --prune-path 过滤后仍可见的符号
+    {
+        let mut s = conn.prepare("SELECT canonical_id, name FROM symbols ORDER BY symbol_id")?; // 🆕 canonical_id
+        let rows = s.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?; // 🆕 String, String
+        for (id, name) in rows.flatten() {
+            visible.insert(id.clone());
+            name_to_ids.entry(name).or_default().push(id);
+        }
+    }
+
+    // Load all calls
+    // 🆕 使用 String (canonical_id) 而不是 i64 (symbol_id)
+    let mut adjacency: HashMap<String, Vec<String>> = HashMap::new(); // Caller -> Callee(s)
+    let mut reverse_adjacency: HashMap<String, Vec<String>> = HashMap::new(); // Callee -> Caller(s)
+    let mut edge_receivers: HashMap<(String, String), String> = HashMap::new(); // (Caller, Callee) -> 接收者类型
+    let mut reference_edges: HashMap<(String, String), Option<&str>> = HashMap::new(); // 边只由回调实参 / 装饰器构成时的种类
+
+    {
+        // JOIN symbols 获取 caller 的 canonical_id；callee 优先使用 c.callee_id
+        let mut s = conn.prepare("SELECT s.canonical_id, c.callee_id, c.callee_name, c.receiver_type, c.kind FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id ORDER BY c.call_id")?;
+        let rows = s.query_map([], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, Option<String>>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, Option<String>>(3)?,
+                r.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        for (caller_canonical_id, callee_id_opt, callee_name, receiver_type, kind) in rows.flatten()
+        {
+            let reference = match kind.as_deref() {
+                Some("reference") => Some("reference"),
+                Some("decorator") => Some("decorator"),
+                _ => None,
+            };
+            let caller_canonical_id = fold(caller_canonical_id);
+            if let Some(callee_id) = callee_id_opt {
+                // 被过滤掉的 callee 直接断边，不回退到按名匹配
+                if !visible.contains(&callee_id) {
+                    continue;
+                }
+                let callee_id = fold(callee_id);
+                if let Some(t) = receiver_type {
+                    edge_receivers.insert((caller_canonical_id.clone(), callee_id.clone()), t);
+                }
+                reference_edges
+                    .entry((caller_canonical_id.clone(), callee_id.clone()))
+                    .and_modify(|only| {
+                        if *only != reference {
+                            *only = None
+                        }
+                    })
+                    .or_insert(reference);
+                adjacency
+                    .entry(caller_canonical_id.clone())
+                    .or_default()
+                    .push(callee_id.clone());
+                reverse_adjacency
+                    .entry(callee_id)
+                    .or_default()
+                    .push(caller_canonical_id.clone());
+            } else if let Some(callee_ids) = name_to_ids
+                .get(&callee_name)
+                .filter(|_| receiver_type.is_none() && reference.is_none())
+            {
+                // 推断出接收者类型却没链上：类型在索引之外，不按名扩散
+                for callee_id in callee_ids {
+                    let callee_id = fold(callee_id.clone());
+                    adjacency
+                        .entry(caller_canonical_id.clone())
+                        .or_default()
+                        .push(callee_id.clone());
+                    reverse_adjacency
+                        .entry(callee_id)
+                        .or_default()
+                        .push(caller_canonical_id.clone());
+                }
+            }
+        }
+    }
+
+    // 重写：调用基方法的地方实际可能执行重写版本，改动重写时基方法的调用者同样受影响
+    let overrides = load_overrides(&conn);
+    let mut override_edges: HashSet<(String, String)> = HashSet::new();
+    let mut overriding: Vec<(&String, &Vec<String>)> = overrides.iter().collect();
+    overriding.sort();
+    for (method, bases) in overriding {
+        if !visible.contains(method) {
+            continue;
+        }
+        let method = fold(method.clone());
+        for base in bases.iter().filter(|b| visible.contains(*b)) {
+            let callers = reverse_adjacency
+                .get(&fold(base.clone()))
+                .cloned()
+                .unwrap_or_default();
+            for caller in callers {
+                if caller == method || adjacency.get(&caller).is_some_and(|c| c.contains(&method)) {
+                    continue;
+                }
+                adjacency
+                    .entry(caller.clone())
+                    .or_default()
+                    .push(method.clone());
+                reverse_adjacency
+                    .entry(method.clone())
+                    .or_default()
+                    .push(caller.clone());
+                override_edges.insert((caller, method.clone()));
+            }
+        }
+    }
+
+    // 🆕 动态分发展开：调用落在基类型 / 接口的方法上时，子类型的重写也可能被执行
+    let mut dynamic_edges: HashSet<(String, String)> = HashSet::new();
+    if args.expand_dynamic {
+        for (caller, callee) in dynamic_dispatch_edges(&conn, &visible)? {
+            let (caller, callee) = (fold(caller), fold(callee));
+            if caller == callee {
+                continue;
+            }
+            if adjacency.get(&caller).is_some_and(|c| c.contains(&callee)) {
+                continue;
+            }
+            adjacency
+                .entry(caller.clone())
+                .or_default()
+                .push(callee.clone());
+            reverse_adjacency
+                .entry(callee.clone())
+                .or_default()
+                .push(caller.clone());
+            dynamic_edges.insert((caller, callee));
+        }
+    }
+
+    // 递归：自调用边单独记下后从图里去掉（不计入扇入扇出 / 随机游走），同一对符号的重复边只留一条
+    let self_recursive = adjacency
+        .get(&target_id)
+        .is_some_and(|callees| callees.contains(&target_id));
+    for (node, edges) in adjacency.iter_mut().chain(reverse_adjacency.iter_mut()) {
+        let mut seen = HashSet::new();
+        edges.retain(|e| e != node && seen.insert(e.clone()));
+    }
+    // 相互递归：既能从目标到达、又能到达目标的符号（目标所在的强连通分量）
+    let mutual_recursion: Vec<String> = {
+        let downstream = reachable(&adjacency, &target_id);
+        let upstream = reachable(&reverse_adjacency, &target_id);
+        let mut cycle: Vec<String> = downstream
+            .intersection(&upstream)
+            .filter(|id| **id != target_id)
+            .cloned()
+            .collect();
+        cycle.sort();
+        cycle
+    };
+
+    // 3. Impact Analysis (BFS)
+    let mut direct_nodes = Vec::new();
+    let mut indirect_nodes = Vec::new();
+    let mut affected_nodes = HashSet::new();
+
+    let direction = args.direction.to_lowercase();
+
+    // 我们定义“主方向图”
+    // 如果是 backward (影响分析)，我们需要找到“谁在调用我”，即使用 reverse_adjacency
+    // 如果是 forward (依赖分析)，我们需要找到“我在调用谁”，即使用 adjacency
+    let primary_graph = if direction == "forward" {
+        &adjacency
+    } else {
+        &reverse_adjacency // 默认 backward
+    };
+
+    // Direct
+    if let Some(nodes) = primary_graph.get(&target_id) {
+        for cid in nodes {
+            affected_nodes.insert(cid.clone());
+            // Get Node Info
+            let node = get_node_by_id(&conn, cid)?;
+            let edge = if direction == "forward" {
+                (target_id.clone(), cid.clone())
+            } else {
+                (cid.clone(), target_id.clone())
+            };
+            let dynamic = dynamic_edges.contains(&edge);
+            let overriding = override_edges.contains(&edge);
+            direct_nodes.push(CallerInfo {
+                node,
+                call_type: if dynamic {
+                    "dynamic"
+                } else if overriding {
+                    "override"
+                } else if let Some(Some(kind)) = reference_edges.get(&edge) {
+                    kind
+                } else {
+                    "direct"
+                }
+                .to_string(),
+                receiver_type: edge_receivers.get(&edge).cloned(),
+                confidence: (dynamic || overriding).then_some(DYNAMIC_DISPATCH_CONFIDENCE),
+                call_site: None,
+            });
+        }
+    }
+
+    // Indirect (Depth 2-3) - BFS
+    let mut queue: Vec<(String, usize)> = direct_nodes
+        .iter()
+        .map(|c| (c.node.id.clone(), 1))
+        .collect();
+    let mut visited: HashSet<String> = HashSet::new();
+    visited.insert(target_id.clone());
+    for c in &direct_nodes {
+        visited.insert(c.node.id.clone());
+    }
+
+    while let Some((curr, depth)) = queue.pop() {
+        if depth >= 3 {
+            continue;
+        }
+        if let Some(nodes) = primary_graph.get(&curr) {
+            for cid in nodes {
+                if !visited.contains(cid) {
+                    visited.insert(cid.clone());
+                    affected_nodes.insert(cid.clone());
+                    let node = get_node_by_id(&conn, cid)?;
+                    indirect_nodes.push(CallerInfo {
+                        node,
+                        call_type: "indirect".to_string(),
+                        receiver_type: None,
+                        confidence: None,
+                        call_site: None,
+                    });
+                    queue.push((cid.clone(), depth + 1));
+                }
+            }
+        }
+    }
+
+    // 输出顺序与遍历顺序无关：按 文件 / 行号 / ID 排
+    for list in [&mut direct_nodes, &mut indirect_nodes] {
+        list.sort_by(|a, b| {
+            (&a.node.file_path, a.node.line_start, &a.node.id).cmp(&(
+                &b.node.file_path,
+                b.node.line_start,
+                &b.node.id,
+            ))
+        });
+    }
+
+    // 4. Dice Algorithm (Complexity Score via Random Walk)
+    // Run random walk starting from target node on the DIRECT graph (forward).
+    // "If I am complex, I call many things which call many things."
+    use rand::prelude::IndexedRandom; // rand 0.9 fix
+    use rand::{Rng, SeedableRng};
+
+    // 🆕 使用 String (canonical_id) 而不是 i64 (symbol_id)
+    let mut walk_visits: HashMap<String, u32> = HashMap::new();
+    let walk_batch = 1000;
+    let max_walks = 16_000;
+    let walk_length = 10;
+    let damping = 0.85;
+    // 固定种子：同一 DB 上重复分析得到相同分数
+    let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+
+    // 分批游走直到覆盖面稳定（新一批新增节点 <= 1%），大图自动多走几批
+    let mut num_walks = 0;
+    while num_walks < max_walks {
+        let before = walk_visits.len();
+        for _ in 0..walk_batch {
+            let mut curr = target_id.clone();
+            for _ in 0..walk_length {
+                *walk_visits.entry(curr.clone()).or_insert(0) += 1;
+
+                if rng.random::<f64>() > damping {
+                    break;
+                }
+
+                match adjacency.get(&curr) {
+                    Some(neighbors) if !neighbors.is_empty() => {
+                        curr = neighbors.choose(&mut rng).unwrap().clone();
+                    }
+                    _ => break,
+                }
+            }
+        }
+        num_walks += walk_batch;
+        let grown = walk_visits.len() - before;
+        if num_walks > walk_batch && grown as f64 <= walk_visits.len() as f64 * 0.01 {
+            break;
+        }
+    }
+
+    // Calculate Score
+    // Scope (Affected Nodes in dependency chain) - actually Random Walk measures "Effort to understand dependencies".
+    let coverage = walk_visits.len();
+
+    // Density (Fan-out)
+    let out_degree = adjacency.get(&target_id).map(|v| v.len()).unwrap_or(0);
+    let in_degree = reverse_adjacency
+        .get(&target_id)
+        .map(|v| v.len())
+        .unwrap_or(0);
+
+    // Formula from dice.py: (affected * 0.4) + (density * 0.3) + (variance * 0.3)
+    // Simplify for Rust MVP
+    let complexity_score =
+        (coverage as f64 * 0.5) + (out_degree as f64 * 2.0) + (in_degree as f64 * 1.0);
+    let normalized_score = if complexity_score > 100.0 {
+        100.0
+    } else {
+        complexity_score
+    };
+
+    let complexity_level = if normalized_score < 20.0 {
+        "Simple"
+    } else if normalized_score < 50.0 {
+        "Medium"
+    } else if normalized_score < 80.0 {
+        "High"
+    } else {
+        "Extreme"
+    };
+
+    // Risk Level (Only meaningful for backward)
+    let total_affected = direct_nodes.len() + indirect_nodes.len();
+    let risk_model = load_risk_model(args.risk_model.as_deref())?;
+
+    // 测试覆盖：反向 3 跳内是否有测试符号（taxonomy 中的 test，或位于测试文件）
+    let test_ids: HashSet<String> = {
+        let mut s = conn.prepare(
+            "SELECT canonical_id, symbol_type, file_path FROM symbols JOIN files ON symbols.file_id = files.file_id",
+        )?;
+        let rows = s.query_map([], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        })?;
+        rows.flatten()
+            .filter(|(_, ty, path)| ty == "test" || is_test_path(path))
+            .map(|(id, _, _)| id)
+            .collect()
+    };
+    let mut reaching_tests = HashSet::new();
+    let mut frontier = vec![target_id.clone()];
+    let mut seen: HashSet<String> = frontier.iter().cloned().collect();
+    for _ in 0..3 {
+        let mut next = vec![];
+        for id in &frontier {
+            for caller in reverse_adjacency.get(id).into_iter().flatten() {
+                if seen.insert(caller.clone()) {
+                    if test_ids.contains(caller) {
+                        reaching_tests.insert(caller.clone());
+                    }
+                    next.push(caller.clone());
+                }
+            }
+        }
+        frontier = next;
+    }
+    let untested = if reaching_tests.is_empty() { 1.0 } else { 0.0 };
+
+    let churn = file_churn(args, &target.file_path, risk_model.churn_days);
+    let centrality = (in_degree + out_degree) as f64;
+
+    let w = &risk_model.weights;
+    let risk_factors: Vec<RiskFactor> = [
+        (
+            "affected",
+            total_affected as f64,
+            w.affected,
+            format!(
+                "{} direct + {} indirect",
+                direct_nodes.len(),
+                indirect_nodes.len()
+            ),
+        ),
+        (
+            "untested",
+            untested,
+            w.untested,
+            format!(
+                "{} tests reach the target within 3 hops",
+                reaching_tests.len()
+            ),
+        ),
+        (
+            "churn",
+            churn.unwrap_or(0) as f64,
+            w.churn,
+            match churn {
+                Some(n) => format!("{} commits in the last {} days", n, risk_model.churn_days),
+                None => "git history unavailable".to_string(),
+            },
+        ),
+        (
+            "centrality",
+            centrality,
+            w.centrality,
+            format!("fan-in {} + fan-out {}", in_degree, out_degree),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value, weight, detail)| RiskFactor {
+        name: name.to_string(),
+        value,
+        weight,
+        contribution: value * weight,
+        detail,
+    })
+    .collect();
+    let risk_score: f64 = risk_factors.iter().map(|f| f.contribution).sum();
+    let risk_level = if risk_score <= risk_model.thresholds.medium {
+        "low"
+    } else if risk_score <= risk_model.thresholds.high {
+        "medium"
+    } else {
+        "high"
+    };
+
+    // Generate Checklist
+    let mut checklist = vec![format!(
+        "📌 Target Symbol: {} ({})",
+        target.qualified_name, target.file_path
+    )];
+    let mut checklist_items = vec![ChecklistItem {
+        kind: "target".to_string(),
+        target: target_id.clone(),
+        file: target.file_path.clone(),
+        line: target.line_start,
+        reason: format!("modification target ({} risk)", risk_level),
+        suggested_action: "modify".to_string(),
+    }];
+    let (label, kind, reason, action) = if direction == "forward" {
+        (
+            "Dependency",
+            "dependency",
+            "called by the target",
+            "verify the contract the target relies on still holds",
+        )
+    } else {
+        (
+            "Caller",
+            "caller",
+            "directly calls the target",
+            "review call site and update for the new behavior",
+        )
+    };
+    for c in &direct_nodes {
+        checklist.push(format!(
+            "⚠️ Check {}: {}:{} ({})",
+            label, c.node.node_type, c.node.name, c.node.file_path
+        ));
+        checklist_items.push(ChecklistItem {
+            kind: kind.to_string(),
+            target: c.node.id.clone(),
+            file: c.node.file_path.clone(),
+            line: c.node.line_start,
+            reason: if c.call_type == "override" {
+                "calls a base method the target overrides".to_string()
+            } else {
+                reason.to_string()
+            },
+            suggested_action: action.to_string(),
+        });
+    }
+
+    let mut implementations = vec![];
+    for id in implementations_of(&conn, &target_id, &target.name, &visible)? {
+        let node = get_node_by_id(&conn, &id)?;
+        checklist.push(format!(
+            "⚠️ Check Implementation: {}:{} ({})",
+            node.node_type, node.name, node.file_path
+        ));
+        checklist_items.push(ChecklistItem {
+            kind: "implementation".to_string(),
+            target: node.id.clone(),
+            file: node.file_path.clone(),
+            line: node.line_start,
+            reason: "implements the target method".to_string(),
+            suggested_action: "keep the implementation in line with the changed contract"
+                .to_string(),
+        });
+        implementations.push(node);
+    }
+
+    let mut base_methods = vec![];
+    for id in overrides.get(&target_id).into_iter().flatten() {
+        if !visible.contains(id) {
+            continue;
+        }
+        let node = get_node_by_id(&conn, id)?;
+        checklist.push(format!(
+            "⚠️ Check Base Method: {}:{} ({})",
+            node.node_type, node.name, node.file_path
+        ));
+        checklist_items.push(ChecklistItem {
+            kind: "base_method".to_string(),
+            target: node.id.clone(),
+            file: node.file_path.clone(),
+            line: node.line_start,
+            reason: "overridden by the target".to_string(),
+            suggested_action: "keep the override compatible with the base contract".to_string(),
+        });
+        base_methods.push(node);
+    }
+
+    let impact_groups = group_impact(&direct_nodes, &indirect_nodes, &reverse_adjacency);
+    let fields = load_fields(&conn, &target_id)?;
+    let generated_stubs = generated
+        .values()
+        .filter(|proto_id| **proto_id == target_id || affected_nodes.contains(*proto_id))
+        .count();
+
+    let overlaid = overlay_paths(&conn, overlay_layer(args));
+    let mut final_res = AnalysisResult {
+        status: "success".to_string(),
+        generation: read_generation(&conn)?,
+        node_id: target_id,
+        source: overlaid
+            .as_ref()
+            .map(|o| symbol_source(&target.file_path, o)),
+        complexity_score: normalized_score,
+        complexity_level: complexity_level.to_string(),
+        affected_nodes: total_affected,
+        direct_callers: direct_nodes,
+        indirect_callers: indirect_nodes,
+        risk_level: risk_level.to_string(),
+        risk_score,
+        risk_factors,
+        modification_checklist: checklist,
+        seed: args.seed,
+        random_walks: num_walks,
+        checklist_items,
+        impact_groups,
+        recursive: self_recursive || !mutual_recursion.is_empty(),
+        mutual_recursion,
+        fields,
+        implementations,
+        overrides: base_methods,
+        generated_stubs: (generated_stubs > 0).then_some(generated_stubs),
+        comparison: None,
+    };
+    if let Some(overlaid) = &overlaid {
+        let callers = final_res
+            .direct_callers
+            .iter_mut()
+            .chain(final_res.indirect_callers.iter_mut())
+            .map(|c| &mut c.node);
+        let exemplars = final_res
+            .impact_groups
+            .iter_mut()
+            .flat_map(|g| g.exemplars.iter_mut());
+        tag_source(
+            callers
+                .chain(exemplars)
+                .chain(final_res.implementations.iter_mut())
+                .chain(final_res.overrides.iter_mut()),
+            overlaid,
+        );
+    }
+    if let Some(path) = &args.compare_with {
+        let prev = load_previous_analysis(path)?;
+        final_res.comparison = Some(compare_analysis(&prev, &final_res));
+    }
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &final_res)?;
+    }
+
+    Ok(())
+}
+
+// 不限深度的可达集合（不含起点自身，除非经环回到起点）
+fn reachable(graph: &HashMap<String, Vec<String>>, start: &str) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![start.to_string()];
+    while let Some(curr) = stack.pop() {
+        for next in graph.get(&curr).into_iter().flatten() {
+            if seen.insert(next.clone()) {
+                stack.push(next.clone());
+            }
+        }
+    }
+    seen
+}
+
+// ============================================================================
+// Analysis Comparison (跨次运行对比)
+// 读取旧的 analyze 输出，对比受影响集合与复杂度/风险分，判断重构是否真的缩小了影响面
+// ============================================================================
+
+// 只取对比需要的字段；旧版本输出缺少的字段按默认值处理
+#[derive(Deserialize)]
+struct PreviousAnalysis {
+    node_id: String,
+    complexity_score: f64,
+    affected_nodes: usize,
+    #[serde(default)]
+    risk_score: Option<f64>,
+    #[serde(default)]
+    direct_callers: Vec<CallerInfo>,
+    #[serde(default)]
+    indirect_callers: Vec<CallerInfo>,
+    #[serde(default)]
+    fields: Vec<FieldInfo>,
+}
+
+#[derive(Serialize)]
+struct AnalysisComparison {
+    previous_node_id: String,
+    same_symbol: bool,
+    affected_before: usize,
+    affected_after: usize,
+    affected_delta: i64,
+    complexity_delta: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    risk_score_delta: Option<f64>,
+    added: Vec<String>,   // 新增的受影响符号
+    removed: Vec<String>, // 不再受影响的符号
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    field_changes: Vec<String>, // 🆕 同一类型前后两次的字段增删 / 类型变化
+    verdict: String,      // reduced | grew | shifted | unchanged
+}
+
+fn load_previous_analysis(path: &str) -> anyhow::Result<PreviousAnalysis> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read previous analysis {}: {}", path, e))?;
+    serde_json::from_str(&raw)
+        .map_err(|e| anyhow::anyhow!("invalid previous analysis {}: {}", path, e))
+}
+
+fn compare_analysis(prev: &PreviousAnalysis, current: &AnalysisResult) -> AnalysisComparison {
+    let ids = |direct: &[CallerInfo], indirect: &[CallerInfo]| -> HashSet<String> {
+        direct
+            .iter()
+            .chain(indirect)
+            .map(|c| c.node.id.clone())
+            .collect()
+    };
+    let before = ids(&prev.direct_callers, &prev.indirect_callers);
+    let after = ids(&current.direct_callers, &current.indirect_callers);
+    let mut added: Vec<String> = after.difference(&before).cloned().collect();
+    let mut removed: Vec<String> = before.difference(&after).cloned().collect();
+    added.sort();
+    removed.sort();
+
+    let affected_delta = current.affected_nodes as i64 - prev.affected_nodes as i64;
+    let verdict = match affected_delta.cmp(&0) {
+        std::cmp::Ordering::Less => "reduced",
+        std::cmp::Ordering::Greater => "grew",
+        std::cmp::Ordering::Equal if added.is_empty() && removed.is_empty() => "unchanged",
+        std::cmp::Ordering::Equal => "shifted",
+    };
+
+    let same_symbol = prev.node_id == current.node_id;
+    let field_changes = if same_symbol {
+        field_changes(&prev.fields, &current.fields)
+    } else {
+        vec![]
+    };
+
+    AnalysisComparison {
+        previous_node_id: prev.node_id.clone(),
+        same_symbol,
+        affected_before: prev.affected_nodes,
+        affected_after: current.affected_nodes,
+        affected_delta,
+        complexity_delta: current.complexity_score - prev.complexity_score,
+        risk_score_delta: prev.risk_score.map(|r| current.risk_score - r),
+        added,
+        removed,
+        field_changes,
+        verdict: verdict.to_string(),
+    }
+}
+
+fn field_changes(before: &[FieldInfo], after: &[FieldInfo]) -> Vec<String> {
+    let ty = |f: &FieldInfo| f.field_type.clone().unwrap_or_else(|| "?".to_string());
+    let mut changes = vec![];
+    for f in after {
+        match before.iter().find(|b| b.name == f.name) {
+            None => changes.push(format!("added {}: {}", f.name, ty(f))),
+            Some(b) if b.field_type != f.field_type => {
+                changes.push(format!("{}: {} -> {}", f.name, ty(b), ty(f)))
+            }
+            _ => {}
+        }
+    }
+    for b in before {
+        if !after.iter().any(|f| f.name == b.name) {
+            changes.push(format!("removed {}: {}", b.name, ty(b)));
+        }
+    }
+    changes
+}
+
+// 🆕 修改：使用 canonical_id (String) 而不是 symbol_id (i64)
+fn get_node_by_id(conn: &Connection, id: &str) -> Result<Node> {
+    conn.query_row(
+        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type, stable_id, anchor
+         FROM symbols JOIN files ON symbols.file_id = files.file_id
+         WHERE canonical_id = ?1",
+        [id],
+        |row| {
+            Ok(Node {
+                id: row.get::<_, String>(0)?, // 🆕 canonical_id
+                name: row.get(1)?,
+                qualified_name: row.get(2)?,
+                file_path: row.get(3)?,
+                line_start: row.get(4)?,
+                line_end: row.get(5)?,
+                node_type: row.get(6)?,
+                signature: None,
+                summary: None,
+                stable_id: row.get(7)?,
+                anchor: row.get(8)?,
+                doc: None,
+                source: None,
+                calls: vec![],
+            })
+        },
+    )
+}
+
+// ============================================================================
+// Snapshot & Diff
+// ============================================================================
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    timestamp: u64,
+    symbols: BTreeMap<String, SnapshotSymbol>, // key: qualified_name (or id if stable)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scopes: Vec<String>, // 🆕 快照覆盖的路径前缀（空 = 全仓库）
+}
+
+// 🆕 逗号分隔的路径前缀，与 map --scope 一样按前缀匹配
+fn parse_scopes(spec: Option<&str>) -> Vec<String> {
+    spec.unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().replace('\\', "/"))
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn in_scopes(file_path: &str, scopes: &[String]) -> bool {
+    scopes.is_empty() || scopes.iter().any(|s| file_path.starts_with(s.as_str()))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)] // Added PartialEq for easy diff
+struct SnapshotSymbol {
+    name: String,
+    qualified_name: String,
+    file_path: String,
+    symbol_type: String,
+    line_start: usize,
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature_shape: Option<SignatureShape>, // 🆕 结构化签名（旧快照没有）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    visibility: Option<String>, // 🆕 public / private 等（旧快照没有）
+    calls: Vec<String>, // List of callee qualified_names
+}
+
+// 🆕 把快照载入内存库（与正式库同一 schema），query 的检索/排序/调用者逻辑因此原样复用
+fn open_snapshot_db(path: &str) -> anyhow::Result<Connection> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read snapshot {}: {}", path, e))?;
+    let snapshot: Snapshot = serde_json::from_str(&raw)
+        .map_err(|e| anyhow::anyhow!("invalid snapshot {}: {}", path, e))?;
+
+    let mut conn = Connection::open_in_memory()?;
+    init_db(&conn)?;
+    let tx = conn.transaction()?;
+    {
+        let mut file_ids: HashMap<&str, i64> = HashMap::new();
+        let mut insert_file =
+            tx.prepare("INSERT INTO files (file_path, file_hash, updated_at) VALUES (?1, '', ?2)")?;
+        let mut insert_symbol = tx.prepare(
+            "INSERT INTO symbols (file_id, name, qualified_name, canonical_id, scope_path, symbol_type, line_start, line_end, signature, visibility)
+             VALUES (?1, ?2, ?3, ?4, ?3, ?5, ?6, ?6, ?7, ?8)",
+        )?;
+        let mut insert_call =
+            tx.prepare("INSERT INTO calls (caller_id, callee_name) VALUES (?1, ?2)")?;
+        for (id, sym) in &snapshot.symbols {
+            let file_id = match file_ids.get(sym.file_path.as_str()) {
+                Some(fid) => *fid,
+                None => {
+                    insert_file.execute(params![sym.file_path, snapshot.timestamp as i64])?;
+                    let fid = tx.last_insert_rowid();
+                    file_ids.insert(&sym.file_path, fid);
+                    fid
+                }
+            };
+            insert_symbol.execute(params![
+                file_id,
+                sym.name,
+                sym.qualified_name,
+                id,
+                sym.symbol_type,
+                sym.line_start,
+                sym.signature,
+                sym.visibility
+            ])?;
+            let symbol_id = tx.last_insert_rowid();
+            for callee in &sym.calls {
+                insert_call.execute(params![symbol_id, callee])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(conn)
+}
+
+// 🆕 修改：使用 canonical_id
+fn run_snapshot(args: &Args) -> anyhow::Result<()> {
+    // Export current DB state to a JSON file
+    let conn = Connection::open(&args.db)?;
+    let scopes = parse_scopes(args.scope.as_deref());
+
+    // 1. Load Symbols
+    let mut symbols_map: BTreeMap<String, SnapshotSymbol> = BTreeMap::new();
+    let mut id_to_qname: HashMap<String, String> = HashMap::new(); // 🆕 canonical_id -> qualified_name
+
+    {
+        // 🆕 查询包含 canonical_id
+        let mut stmt = conn.prepare("SELECT canonical_id, name, qualified_name, file_path, line_start, symbol_type, signature, signature_shape, visibility FROM symbols JOIN files ON symbols.file_id = files.file_id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?, // 🆕 canonical_id
+                SnapshotSymbol {
+                    name: row.get(1)?,
+                    qualified_name: row.get(2)?,
+                    file_path: row.get(3)?,
+                    symbol_type: row.get(5)?,
+                    line_start: row.get(4)?,
+                    signature: row.get(6)?, // 🆕 SQL 表签名即列定义：schema 漂移靠它体现
+                    signature_shape: row
+                        .get::<_, Option<String>>(7)?
+                        .and_then(|json| serde_json::from_str(&json).ok()),
+                    visibility: row.get(8)?,
+                    calls: vec![],
+                },
+            ))
+        })?;
+
+        for (id, sym) in rows.flatten() {
+            if !in_scopes(&sym.file_path, &scopes) {
+                continue;
+            }
+            id_to_qname.insert(id.clone(), sym.qualified_name.clone());
+            // Use canonical_id as stable key
+            symbols_map.insert(id, sym);
+        }
+    }
+
+    // 2. Load Calls (hydrate symbols)
+    {
+        // 🆕 JOIN symbols 表获取 canonical_id
+        let mut stmt = conn.prepare("SELECT s.canonical_id, c.callee_name FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id WHERE c.kind NOT IN ('reference', 'decorator')")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        for (caller_canonical_id, callee_name) in rows.flatten() {
+            if let Some(sym) = symbols_map.get_mut(&caller_canonical_id) {
+                sym.calls.push(callee_name);
+            }
+        }
+    }
+
+    for sym in symbols_map.values_mut() {
+        sym.calls.sort();
+    }
+
+    let snapshot = Snapshot {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        symbols: symbols_map,
+        scopes,
+    };
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &snapshot)?;
+    } else {
+        // Print to stdout? No, binary output usually silent unless error.
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct DiffResult {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+    out_of_scope: Vec<String>, // 🆕 只在一侧出现、且落在另一侧快照范围之外的符号
+    details: BTreeMap<String, DiffDetail>,
+}
+
+#[derive(Serialize)]
+struct DiffDetail {
+    change_type: String, // "signature_changed", "calls_changed", "moved"
+    diff_msg: String,
+}
+
+fn run_diff(args: &Args) -> anyhow::Result<()> {
+    let base_path = args.base.as_ref().expect("Base snapshot required for diff");
+    let target_path = args
+        .target
+        .as_ref()
+        .expect("Target snapshot required for diff");
+
+    let base: Snapshot = serde_json::from_reader(fs::File::open(base_path)?)?;
+    let target: Snapshot = serde_json::from_reader(fs::File::open(target_path)?)?;
+
+    let res = diff_snapshots(&base, &target);
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+fn diff_snapshots(base: &Snapshot, target: &Snapshot) -> DiffResult {
+    let mut added = vec![];
+    let mut removed = vec![];
+    let mut modified = vec![];
+    let mut out_of_scope = vec![];
+    let mut details = BTreeMap::new();
+
+    // Check Removed（对方快照没覆盖到的路径不能算删除）
+    for (k, base_sym) in &base.symbols {
+        if !target.symbols.contains_key(k) {
+            if in_scopes(&base_sym.file_path, &target.scopes) {
+                removed.push(k.clone());
+            } else {
+                out_of_scope.push(k.clone());
+            }
+        }
+    }
+
+    // Check Added & Modified
+    for (k, target_sym) in &target.symbols {
+        if !base.symbols.contains_key(k) {
+            if in_scopes(&target_sym.file_path, &base.scopes) {
+                added.push(k.clone());
+            } else {
+                out_of_scope.push(k.clone());
+            }
+        } else {
+            let base_sym = base.symbols.get(k).unwrap();
+
+            // Compare
+            let mut diffs = vec![];
+
+            if base_sym.file_path != target_sym.file_path {
+                diffs.push(format!(
+                    "Moved from {} to {}",
+                    base_sym.file_path, target_sym.file_path
+                ));
+            }
+
+            if base_sym.symbol_type != target_sym.symbol_type {
+                diffs.push(format!(
+                    "Type changed: {} -> {}",
+                    base_sym.symbol_type, target_sym.symbol_type
+                ));
+            }
+
+            // 收窄可见性（public -> internal / private）对调用方是破坏性变更
+            if let (Some(old), Some(new)) = (&base_sym.visibility, &target_sym.visibility) {
+                if old != new {
+                    diffs.push(format!("Visibility changed: {} -> {}", old, new));
+                }
+            }
+
+            // 两侧都有结构化签名时逐项报告参数 / 返回类型变化；否则退回比较原文（旧快照没有签名时不比较）
+            let shape_changes = match (&base_sym.signature_shape, &target_sym.signature_shape) {
+                (Some(old), Some(new)) => signature_shape_changes(old, new),
+                _ => vec![],
+            };
+            if !shape_changes.is_empty() {
+                diffs.extend(shape_changes);
+            } else if let (Some(old), Some(new)) = (&base_sym.signature, &target_sym.signature) {
+                if old != new {
+                    diffs.push(format!("Signature changed: {} -> {}", old, new));
+                }
+            }
+
+            // Check Calls
+            let base_calls: BTreeSet<_> = base_sym.calls.iter().collect();
+            let target_calls: BTreeSet<_> = target_sym.calls.iter().collect();
+
+            let new_calls: Vec<_> = target_calls.difference(&base_calls).collect();
+            let lost_calls: Vec<_> = base_calls.difference(&target_calls).collect();
+
+            if !new_calls.is_empty() {
+                diffs.push(format!("Added calls: {:?}", new_calls));
+            }
+            if !lost_calls.is_empty() {
+                diffs.push(format!("Removed calls: {:?}", lost_calls));
+            }
+
+            if !diffs.is_empty() {
+                modified.push(k.clone());
+                details.insert(
+                    k.clone(),
+                    DiffDetail {
+                        change_type: "modified".into(),
+                        diff_msg: diffs.join("; "),
+                    },
+                );
+            }
+        }
+    }
+
+    // 两侧各自有序，合并后再排一次
+    out_of_scope.sort();
+
+    DiffResult {
+        added,
+        removed,
+        modified,
+        out_of_scope,
+        details,
+    }
+}
+
+// 参数按名字对齐：新增 / 删除 / 类型变化 / 仅顺序变化，再比返回类型
+fn signature_shape_changes(old: &SignatureShape, new: &SignatureShape) -> Vec<String> {
+    let mut changes = vec![];
+    let type_of = |shape: &SignatureShape, name: &str| {
+        shape
+            .params
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.param_type.clone())
+    };
+    let added: Vec<&str> = new
+        .params
+        .iter()
+        .filter(|p| type_of(old, &p.name).is_none())
+        .map(|p| p.name.as_str())
+        .collect();
+    let removed: Vec<&str> = old
+        .params
+        .iter()
+        .filter(|p| type_of(new, &p.name).is_none())
+        .map(|p| p.name.as_str())
+        .collect();
+    if !added.is_empty() {
+        changes.push(format!("Parameters added: {}", added.join(", ")));
+    }
+    if !removed.is_empty() {
+        changes.push(format!("Parameters removed: {}", removed.join(", ")));
+    }
+    let show = |t: &Option<String>| t.clone().unwrap_or_else(|| "?".to_string());
+    for p in &new.params {
+        if let Some(old_type) = type_of(old, &p.name) {
+            if old_type != p.param_type {
+                changes.push(format!(
+                    "Parameter type changed: {}: {} -> {}",
+                    p.name,
+                    show(&old_type),
+                    show(&p.param_type)
+                ));
+            }
+        }
+    }
+    let order = |shape: &SignatureShape, other: &SignatureShape| -> Vec<String> {
+        shape
+            .params
+            .iter()
+            .filter(|p| other.params.iter().any(|o| o.name == p.name))
+            .map(|p| p.name.clone())
+            .collect()
+    };
+    if order(old, new) != order(new, old) {
+        changes.push(format!(
+            "Parameters reordered: ({}) -> ({})",
+            order(old, new).join(", "),
+            order(new, old).join(", ")
+        ));
+    }
+    if old.returns != new.returns {
+        changes.push(format!(
+            "Return type changed: {} -> {}",
+            show(&old.returns),
+            show(&new.returns)
+        ));
+    }
+    // 泛型参数：增删与约束变化（收紧约束会让原本合法的调用方编译失败）
+    let bounds_of = |shape: &SignatureShape, name: &str| {
+        shape
+            .type_params
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.bounds.clone())
+    };
+    let names = |from: &SignatureShape, other: &SignatureShape| -> Vec<String> {
+        from.type_params
+            .iter()
+            .filter(|p| bounds_of(other, &p.name).is_none())
+            .map(|p| p.name.clone())
+            .collect()
+    };
+    let (added, removed) = (names(new, old), names(old, new));
+    if !added.is_empty() {
+        changes.push(format!("Type parameters added: {}", added.join(", ")));
+    }
+    if !removed.is_empty() {
+        changes.push(format!("Type parameters removed: {}", removed.join(", ")));
+    }
+    for p in &new.type_params {
+        if let Some(old_bounds) = bounds_of(old, &p.name) {
+            if old_bounds != p.bounds {
+                changes.push(format!(
+                    "Type parameter bounds changed: {}: {} -> {}",
+                    p.name,
+                    old_bounds.as_deref().unwrap_or("(none)"),
+                    p.bounds.as_deref().unwrap_or("(none)")
+                ));
+            }
+        }
+    }
+    changes
+}
+
+// ============================================================================
+// Structure Mode - 快速目录结构扫描 (No AST)
+// ============================================================================
+
+#[derive(Serialize)]
+struct DirInfo {
+    file_count: usize,
+    files: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    readme: Option<String>, // 🆕 目录 README 的首段
+}
+
+#[derive(Serialize)]
+struct StructureResult {
+    status: String,
+    total_files: usize,
+    structure: BTreeMap<String, DirInfo>,
+}
+
+fn run_structure(args: &Args) -> anyhow::Result<()> {
+    // 快速目录扫描，不做任何 AST 解析
+    let roots = project_roots(args)?;
+    let multi_root = roots.len() > 1;
+
+    // 应用忽略目录过滤（包含默认忽略）
+    let default_ignores: HashSet<String> = [
+        ".git",
+        "node_modules",
+        "vendor",
+        "dist",
+        "build",
+        "out",
+        "target",
+        "__pycache__",
+        ".venv",
+        "venv",
+        "site-packages",
+        ".m2",
+        ".gradle",
+        ".idea",
+        ".vscode",
+        "coverage",
+        "_build",
+        ".next",
+        ".nuxt",
+        ".svelte-kit",
+    ]
+    .into_iter()
+    .map(|s| s.to_string())
+    .collect();
+
+    let mut ignore_set = default_ignores;
+    if let Some(ignores) = &args.ignore_dirs {
+        for s in ignores
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+        {
+            ignore_set.insert(s.to_string());
+        }
+    }
+
+    // 应用扩展名过滤
+    let allowed_exts: HashSet<String> = args
+        .extensions
+        .as_ref()
+        .map(|s| {
+            s.split(',')
+                .map(|ext| ext.trim().trim_start_matches('.').to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // 收集文件，按目录分组
+    let include_files = args.detail == "full";
+    let file_list_limit: usize = 50;
+    let mut structure: BTreeMap<String, DirInfo> = BTreeMap::new();
+    let mut readmes: HashMap<String, String> = HashMap::new();
+    let mut total_files = 0;
+
+    for root in &roots {
+        let scan_root = match root_scan_path(root, args.scope.as_deref(), multi_root) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        // 构建目录遍历器
+        let mut builder = WalkBuilder::new(&scan_root);
+        builder.hidden(false);
+        builder.git_ignore(true);
+        // 按文件名遍历：files 截断到前 50 个、同目录多个 README 取哪个都不随文件系统顺序变化
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+        let ignore_set = ignore_set.clone();
+        builder.filter_entry(move |entry| {
+            if !entry.file_type().map(|f| f.is_dir()).unwrap_or(false) {
+                return true;
+            }
+            !ignore_set.contains(entry.file_name().to_str().unwrap_or(""))
+        });
+
+        for entry in builder.build().flatten() {
+            if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                let path = entry.path();
+
+                // README 不受扩展名过滤影响，只用来给所在目录附上说明
+                if is_readme_file(path) {
+                    let rel_dir = path
+                        .parent()
+                        .and_then(|p| p.strip_prefix(&root.path).ok())
+                        .map(|p| p.to_string_lossy().replace("\\", "/"))
+                        .unwrap_or_default();
+                    let dir = format!("{}{}", root.prefix(), rel_dir)
+                        .trim_end_matches('/')
+                        .to_string();
+                    if let Some(summary) = readme_summary(path) {
+                        readmes.entry(dir).or_insert(summary);
+                    }
+                }
+
+                // 扩展名过滤
+                if !allowed_exts.is_empty() {
+                    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                    if !allowed_exts.contains(ext) {
+                        continue;
+                    }
+                }
+
+                // 计算相对路径
+                let rel_path = path.strip_prefix(&root.path).unwrap_or(path);
+                let rel_str = rel_path.to_string_lossy().replace("\\", "/");
+
+                // 提取目录和文件名
+                let (dir, file_name) = if let Some(parent) = rel_path.parent() {
+                    let parent_str = parent.to_string_lossy().replace("\\", "/");
+                    let fname = rel_path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    (parent_str, fname)
+                } else {
+                    ("".to_string(), rel_str.to_string())
+                };
+                // 多根：目录键带 root 名前缀
+                let dir = format!("{}{}", root.prefix(), dir)
+                    .trim_end_matches('/')
+                    .to_string();
+
+                // 添加到结构
+                let dir_info = structure.entry(dir).or_insert(DirInfo {
+                    file_count: 0,
+                    files: vec![],
+                    readme: None,
+                });
+                dir_info.file_count += 1;
+                if include_files && dir_info.files.len() < file_list_limit {
+                    dir_info.files.push(file_name);
+                }
+                total_files += 1;
+            }
+        }
+    }
+
+    for (dir, info) in structure.iter_mut() {
+        info.readme = readmes.remove(dir);
+    }
+
+    // 输出结果
+    let result = StructureResult {
+        status: "success".to_string(),
+        total_files,
+        structure,
+    };
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &result)?;
+    }
+
+    Ok(())
+}
+
+// README.md / README.rst / README.txt / README（大小写不敏感）
+fn is_readme_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    matches!(
+        name.as_str(),
+        "readme" | "readme.md" | "readme.markdown" | "readme.rst" | "readme.txt"
+    )
+}
+
+fn dir_readme(dir: &Path) -> Option<String> {
+    let mut candidates: Vec<PathBuf> = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_readme_file(p))
+        .collect();
+    candidates.sort();
+    candidates.iter().find_map(|p| readme_summary(p))
+}
+
+// README 的首个正文段落：跳过标题、徽章、HTML 与 front matter，截断到 300 字符
+fn readme_summary(path: &Path) -> Option<String> {
+    const MAX_CHARS: usize = 300;
+    let content = fs::read(path).ok().and_then(|b| decode_source(&b))?;
+    let mut lines = content.lines().map(str::trim).peekable();
+    if lines.peek() == Some(&"---") {
+        lines.next();
+        for l in lines.by_ref() {
+            if l == "---" {
+                break;
+            }
+        }
+    }
+
+    let mut paragraph: Vec<&str> = vec![];
+    for l in lines {
+        // setext 标题：下划线紧跟在标题文字之后，已收集的那行其实是标题
+        if !l.is_empty() && l.chars().all(|c| matches!(c, '=' | '-' | '~' | '*')) {
+            paragraph.clear();
+            continue;
+        }
+        let is_heading = l.starts_with('#');
+        let is_noise = l.starts_with("[![") || l.starts_with("![") || l.starts_with('<');
+        if l.is_empty() || is_heading || is_noise {
+            if !paragraph.is_empty() {
+                break;
+            }
+            continue;
+        }
+        paragraph.push(l);
+    }
+    if paragraph.is_empty() {
+        return None;
+    }
+
+    let text = paragraph.join(" ");
+    if text.chars().count() <= MAX_CHARS {
+        return Some(text);
+    }
+    let cut: String = text.chars().take(MAX_CHARS).collect();
+    Some(format!("{}…", cut.trim_end()))
+}
+
+// ============================================================================
+// Docs Mode - 包级公开 API 文档导出 (wiki_writer)
+// ============================================================================
+
+#[derive(Serialize)]
+struct DocsResult {
+    status: String,
+    generation: i64,
+    scope: String,
+    modules: Vec<ModuleDoc>,
+}
+
+#[derive(Serialize)]
+struct ModuleDoc {
+    module: String,
+    files: Vec<String>,
+    symbols: Vec<SymbolDoc>,
+}
+
+#[derive(Serialize)]
+struct SymbolDoc {
+    id: String,
+    name: String,
+    qualified_name: String,
+    #[serde(rename = "type")]
+    symbol_type: String,
+    file_path: String,
+    line_start: usize,
+    line_end: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    doc: Option<String>,
+    examples: Vec<CallExample>,
+}
+
+#[derive(Serialize)]
+struct CallExample {
+    caller: String,
+    file_path: String,
+    line: usize,
+    code: String,
+}
+
+fn run_docs(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+    let roots = project_roots(args)?;
+    let max_examples: i64 = 3;
+
+    let scope = args
+        .scope
+        .as_deref()
+        .map(|s| s.trim().trim_start_matches("./").replace("\\", "/"))
+        .unwrap_or_default();
+    let pattern = format!("{}%", scope);
+
+    let mut stmt = conn.prepare(
+        "SELECT s.canonical_id, s.name, s.qualified_name, s.symbol_type, f.file_path, s.line_start, s.line_end, s.signature, f.language, s.scope_path, s.doc, s.visibility
+         FROM symbols s JOIN files f ON s.file_id = f.file_id
+         WHERE f.file_path LIKE ?1
+         ORDER BY f.file_path, s.line_start",
+    )?;
+    let rows = stmt.query_map([&pattern], |row| {
+        Ok((
+            SymbolDoc {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                qualified_name: row.get(2)?,
+                symbol_type: row.get(3)?,
+                file_path: row.get(4)?,
+                line_start: row.get(5)?,
+                line_end: row.get(6)?,
+                signature: row.get(7)?,
+                doc: row.get(10)?,
+                examples: vec![],
+            },
+            row.get::<_, String>(8)?,
+            row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+            row.get::<_, Option<String>>(11)?,
+        ))
+    })?;
+
+    let mut example_stmt = conn.prepare(
+        "SELECT s.qualified_name, f.file_path, c.call_line
+         FROM calls c
+         JOIN symbols s ON c.caller_id = s.symbol_id
+         JOIN files f ON s.file_id = f.file_id
+         WHERE c.callee_id = ?1
+         ORDER BY f.file_path, c.call_line
+         LIMIT ?2",
+    )?;
+
+    // 源文件行缓存：doc 注释与调用示例都需要回读源码
+    let mut source_cache: HashMap<String, Vec<String>> = HashMap::new();
+    let mut modules: BTreeMap<String, ModuleDoc> = BTreeMap::new();
+
+    for (mut sym, language, scope_path, visibility) in rows.flatten() {
+        let module = Path::new(&sym.file_path)
+            .parent()
+            .map(|p| p.to_string_lossy().replace("\\", "/"))
+            .unwrap_or_default();
+
+        let lines = source_cache
+            .entry(sym.file_path.clone())
+            .or_insert_with(|| read_source_lines(&resolve_source_path(&roots, &sym.file_path)));
+        let decl_line = lines
+            .get(sym.line_start.saturating_sub(1))
+            .map(|l| l.trim())
+            .unwrap_or("");
+        if !is_public_symbol(
+            visibility.as_deref(),
+            &sym.name,
+            &scope_path,
+            decl_line,
+            &language,
+        ) {
+            continue;
+        }
+        // 索引期已落库的 doc 优先，否则回读源码抽取
+        if sym.doc.is_none() {
+            sym.doc = extract_doc_comment(lines, sym.line_start, &language);
+        }
+
+        let examples: Vec<(String, String, usize)> = example_stmt
+            .query_map(params![sym.id, max_examples], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })?
+            .flatten()
+            .collect();
+        for (caller, file_path, line) in examples {
+            let code = source_cache
+                .entry(file_path.clone())
+                .or_insert_with(|| read_source_lines(&resolve_source_path(&roots, &file_path)))
+                .get(line.saturating_sub(1))
+                .map(|l| l.trim().to_string())
+                .unwrap_or_default();
+            sym.examples.push(CallExample {
+                caller,
+                file_path,
+                line,
+                code,
+            });
+        }
+
+        let entry = modules.entry(module.clone()).or_insert_with(|| ModuleDoc {
+            module,
+            files: vec![],
+            symbols: vec![],
+        });
+        if !entry.files.contains(&sym.file_path) {
+            entry.files.push(sym.file_path.clone());
+        }
+        entry.symbols.push(sym);
+    }
+
+    let modules: Vec<ModuleDoc> = modules.into_values().collect();
+
+    let out_path = match &args.output {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+
+    if args.format == "markdown" {
+        // 每个模块一个 .md 文件 + index.md；文件按模块目录结构存放（a/b -> a/b.md），
+        // 避免 a/b 与 a_b 展平后同名互相覆盖
+        let out_dir = Path::new(out_path);
+        fs::create_dir_all(out_dir)?;
+        let mut index = String::from("# API Reference\n\n");
+        for m in &modules {
+            let file_name = if m.module.is_empty() {
+                "root.md".to_string()
+            } else {
+                format!("{}.md", m.module)
+            };
+            let file_path = out_dir.join(&file_name);
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&file_path, render_module_markdown(m))?;
+            let title = if m.module.is_empty() { "/" } else { &m.module };
+            index.push_str(&format!(
+                "- [{}]({}) ({} symbols)\n",
+                title,
+                file_name,
+                m.symbols.len()
+            ));
+        }
+        fs::write(out_dir.join("index.md"), index)?;
+    } else {
+        let res = DocsResult {
+            status: "success".to_string(),
+            generation: read_generation(&conn)?,
+            scope,
+            modules,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+fn read_source_lines(path: &Path) -> Vec<String> {
+    fs::read(path)
+        .ok()
+        .and_then(|b| decode_source(&b))
+        .map(|c| c.lines().map(|l| l.to_string()).collect())
+        .unwrap_or_default()
+}
+
+// 索引期记录的可见性优先；旧库或不判断可见性的语言退回按声明行推断
+fn is_public_symbol(
+    visibility: Option<&str>,
+    name: &str,
+    scope_path: &str,
+    decl_line: &str,
+    language: &str,
+) -> bool {
+    match visibility {
+        Some(v) => v == "public",
+        None => is_exported_symbol(name, scope_path, decl_line, language),
+    }
+}
+
+// 公开性启发式：Go 首字母大写、Python 非下划线、Rust pub、JS/TS export、Java public、C/C++ 非 static
+fn is_exported_symbol(name: &str, scope_path: &str, decl_line: &str, language: &str) -> bool {
+    let nested = scope_path.contains("::");
+    match language {
+        "go" => name
+            .chars()
+            .next()
+            .map(|c| c.is_uppercase())
+            .unwrap_or(false),
+        "py" => !name.starts_with('_') || (name.starts_with("__") && name.ends_with("__")),
+        "rs" => decl_line.starts_with("pub"),
+        "js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx" => {
+            if nested {
+                !name.starts_with('_')
+                    && !name.starts_with('#')
+                    && !decl_line.starts_with("private")
+            } else {
+                decl_line.starts_with("export") || decl_line.starts_with("module.exports")
+            }
+        }
+        "java" => decl_line.contains("public"),
+        "c" | "h" | "cpp" | "cc" | "hpp" => !decl_line.starts_with("static"),
+        _ => true,
+    }
+}
+
+// 紧邻声明上方的注释块（///, //, #, /** */）；Python 取 def 下方的 docstring
+fn extract_doc_comment(lines: &[String], line_start: usize, language: &str) -> Option<String> {
+    if line_start == 0 || line_start > lines.len() {
+        return None;
+    }
+
+    if language == "py" {
+        let body = lines.get(line_start)?.trim();
+        let quote = ["\"\"\"", "'''"]
+            .into_iter()
+            .find(|q| body.starts_with(q))?;
+        let first = &body[3..];
+        if let Some(end) = first.find(quote) {
+            return Some(first[..end].trim().to_string()).filter(|d| !d.is_empty());
+        }
+        let mut parts = vec![first.trim().to_string()];
+        for l in lines.iter().skip(line_start + 1) {
+            if let Some(end) = l.find(quote) {
+                parts.push(l[..end].trim().to_string());
+                break;
+            }
+            parts.push(l.trim().to_string());
+        }
+        let doc = parts.join("\n").trim().to_string();
+        return Some(doc).filter(|d| !d.is_empty());
+    }
+
+    let mut collected: Vec<String> = vec![];
+    let mut idx = line_start - 1;
+    while idx > 0 {
+        let l = lines[idx - 1].trim();
+        let is_comment = l.starts_with("//")
+            || l.starts_with("/*")
+            || l.starts_with('*')
+            || l.starts_with("--")
+            || (l.starts_with('#') && !l.starts_with("#[") && !l.starts_with("#include"));
+        // 跳过注解/属性行（@Override, #[derive]）
+        let is_attr = l.starts_with('@') || l.starts_with("#[");
+        if is_comment {
+            let text = l
+                .trim_start_matches('/')
+                .trim_start_matches('*')
+                .trim_start_matches('!')
+                .trim_start_matches('#')
+                .trim_start_matches('-')
+                .trim_end_matches("*/")
+                .trim();
+            // Haddock：`-- |` 描述后一项，`-- ^` 描述前一项
+            let text = if language == "hs" {
+                text.trim_start_matches(['|', '^']).trim()
+            } else {
+                text
+            };
+            collected.push(text.to_string());
+        } else if !is_attr {
+            break;
+        }
+        idx -= 1;
+    }
+    collected.reverse();
+    let doc = collected.join("\n").trim().to_string();
+    Some(doc).filter(|d| !d.is_empty())
+}
+
+fn render_module_markdown(m: &ModuleDoc) -> String {
+    let title = if m.module.is_empty() { "/" } else { &m.module };
+    let mut md = format!("# {}\n\nFiles: {}\n", title, m.files.len());
+    for sym in &m.symbols {
+        md.push_str(&format!(
+            "\n## `{}` ({})\n\nDefined in `{}:{}`\n\n",
+            sym.qualified_name, sym.symbol_type, sym.file_path, sym.line_start
+        ));
+        if let Some(sig) = &sym.signature {
+            md.push_str(&format!("```\n{}\n```\n\n", sig));
+        }
+        if let Some(doc) = &sym.doc {
+            md.push_str(doc);
+            md.push_str("\n\n");
+        }
+        if !sym.examples.is_empty() {
+            md.push_str("**Examples**\n\n");
+            for ex in &sym.examples {
+                md.push_str(&format!(
+                    "- `{}:{}` in `{}`: `{}`\n",
+                    ex.file_path, ex.line, ex.caller, ex.code
+                ));
+            }
+        }
+    }
+    md
+}
+
+// ============================================================================
+// Deprecated Mode - 带弃用标记的符号及其剩余调用方（清理工作的清单）
+// ============================================================================
+
+#[derive(Serialize)]
+struct DeprecatedResult {
+    status: String,
+    generation: i64,
+    scope: String,
+    symbols: Vec<DeprecatedSymbol>,
+    callers: usize, // 全部剩余调用点数
+}
+
+#[derive(Serialize)]
+struct DeprecatedSymbol {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    symbol_type: String,
+    file_path: String,
+    line_start: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>, // 弃用说明（替代方案 / 版本）
+    callers: Vec<DeprecatedCaller>,
+}
+
+#[derive(Serialize)]
+struct DeprecatedCaller {
+    id: String,
+    name: String,
+    file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    kind: String,
+    deprecated: bool, // 调用方自己也已弃用：可以一并删除
+}
+
+fn run_deprecated(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    // 属性挂在落库符号上，不叠加 overlay
+    apply_read_filter(&conn, args.root.as_deref(), &[], None)?;
+
+    let scope = args
+        .scope
+        .as_deref()
+        .map(|s| s.trim().trim_start_matches("./").replace("\\", "/"))
+        .unwrap_or_default();
+    let pattern = format!("{}%", scope);
+
+    let mut stmt = conn.prepare(
+        "SELECT s.canonical_id, s.name, s.symbol_type, f.file_path, s.line_start, a.value
+         FROM main.attributes a
+         JOIN symbols s ON a.symbol_id = s.symbol_id
+         JOIN files f ON s.file_id = f.file_id
+         WHERE a.key = 'deprecated' AND f.file_path LIKE ?1
+         ORDER BY f.file_path, s.line_start",
+    )?;
+    let rows = stmt.query_map([&pattern], |row| {
+        let note: String = row.get(5)?;
+        Ok(DeprecatedSymbol {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            symbol_type: row.get(2)?,
+            file_path: row.get(3)?,
+            line_start: row.get(4)?,
+            note: (note != "true").then_some(note),
+            callers: vec![],
+        })
+    })?;
+    let mut symbols: Vec<DeprecatedSymbol> = rows.flatten().collect();
+
+    // 调用方不受 --root / --scope 限制：别处的调用同样要在清理前改掉
+    let mut callers_stmt = conn.prepare(
+        "SELECT cs.canonical_id, cs.name, cf.file_path, c.call_line, COALESCE(c.kind, 'call'),
+                EXISTS (SELECT 1 FROM main.attributes d WHERE d.symbol_id = cs.symbol_id AND d.key = 'deprecated')
+         FROM main.calls c
+         JOIN main.symbols cs ON c.caller_id = cs.symbol_id
+         JOIN main.files cf ON cs.file_id = cf.file_id
+         WHERE c.callee_id = ?1
+         ORDER BY cf.file_path, c.call_line",
+    )?;
+    for sym in &mut symbols {
+        let rows = callers_stmt.query_map([&sym.id], |row| {
+            Ok(DeprecatedCaller {
+                id: row.get(0)?,
                 name: row.get(1)?,
-                qualified_name: row.get(2)?,
+                file_path: row.get(2)?,
+                line: row.get(3)?,
+                kind: row.get(4)?,
+                deprecated: row.get(5)?,
+            })
+        })?;
+        sym.callers = rows.flatten().collect();
+    }
+    let callers = symbols.iter().map(|s| s.callers.len()).sum();
+
+    println!(
+        "[Deprecated] {} deprecated symbols, {} remaining call sites",
+        symbols.len(),
+        callers
+    );
+
+    let res = DeprecatedResult {
+        status: "success".to_string(),
+        generation: read_generation(&conn)?,
+        scope,
+        symbols,
+        callers,
+    };
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Unused Mode - 无人引用的导出符号
+// ============================================================================
+
+#[derive(Serialize)]
+struct UnusedResult {
+    status: String,
+    generation: i64,
+    scope: String,
+    unused_exports: Vec<UnusedSymbol>,
+    unused_imports: Vec<UnusedImport>, // 🆕 绑定名在文件里再没出现过的导入
+}
+
+#[derive(Serialize)]
+struct UnusedImport {
+    file_path: String,
+    line: usize,
+    imported_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imported_symbol: Option<String>,
+    name: String, // 本地绑定名
+}
+
+#[derive(Serialize)]
+struct UnusedSymbol {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    symbol_type: String,
+    file_path: String,
+    line_start: usize,
+    // 同文件内的引用数：> 0 说明只是不必导出，= 0 才是死代码
+    local_refs: usize,
+}
+
+// 入口函数由运行时调用，不会出现在调用图里
+const ENTRY_POINT_NAMES: &[&str] = &["main", "init", "__init__", "__main__"];
+
+fn run_unused(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+    let roots = project_roots(args)?;
+
+    let scope = args
+        .scope
+        .as_deref()
+        .map(|s| s.trim().trim_start_matches("./").replace("\\", "/"))
+        .unwrap_or_default();
+    let pattern = format!("{}%", scope);
+
+    // 只看顶层函数/类型：方法受动态分发影响，调用图看不全。
+    // 跨文件引用统计走 main.*，--root 只收窄报告范围，不屏蔽其他 root 的调用方
+    let mut stmt = conn.prepare(
+        "SELECT s.canonical_id, s.name, s.symbol_type, f.file_path, s.line_start, f.language, s.scope_path,
+                (SELECT COUNT(*) FROM main.calls c JOIN main.symbols cs ON c.caller_id = cs.symbol_id
+                 WHERE c.callee_id = s.canonical_id AND cs.file_id = s.file_id),
+                s.visibility
+         FROM symbols s JOIN files f ON s.file_id = f.file_id
+         WHERE f.file_path LIKE ?1
+           AND (s.canonical_id LIKE 'func:%' OR s.canonical_id LIKE 'class:%')
+           AND s.symbol_type != 'test'
+           AND COALESCE(s.scope_path, s.name) NOT LIKE '%::%'
+           AND NOT EXISTS (
+               SELECT 1 FROM main.calls c JOIN main.symbols cs ON c.caller_id = cs.symbol_id
+               WHERE c.callee_id = s.canonical_id AND cs.file_id != s.file_id)
+           AND NOT EXISTS (
+               SELECT 1 FROM main.inherits i JOIN main.symbols cs ON i.child_id = cs.symbol_id
+               WHERE i.base_id = s.canonical_id AND cs.file_id != s.file_id)
+           AND NOT EXISTS (SELECT 1 FROM main.routes r WHERE r.handler_id = s.canonical_id)
+         ORDER BY f.file_path, s.line_start",
+    )?;
+    let rows = stmt.query_map([&pattern], |row| {
+        Ok((
+            UnusedSymbol {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                symbol_type: row.get(2)?,
                 file_path: row.get(3)?,
                 line_start: row.get(4)?,
-                line_end: row.get(5)?,
-                node_type: row.get(6)?,
-                signature: None,
-                calls: vec![],
+                local_refs: row.get::<_, i64>(7)? as usize,
+            },
+            row.get::<_, String>(5)?,
+            row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            row.get::<_, Option<String>>(8)?,
+        ))
+    })?;
+
+    let mut source_cache: HashMap<String, Vec<String>> = HashMap::new();
+    let mut unused_exports = vec![];
+    for (sym, language, scope_path, visibility) in rows.flatten() {
+        if ENTRY_POINT_NAMES.contains(&sym.name.as_str()) || is_test_path(&sym.file_path) {
+            continue;
+        }
+        let lines = source_cache
+            .entry(sym.file_path.clone())
+            .or_insert_with(|| read_source_lines(&resolve_source_path(&roots, &sym.file_path)));
+        let decl_line = lines
+            .get(sym.line_start.saturating_sub(1))
+            .map(|l| l.trim())
+            .unwrap_or("");
+        if is_public_symbol(
+            visibility.as_deref(),
+            &sym.name,
+            &scope_path,
+            decl_line,
+            &language,
+        ) {
+            unused_exports.push(sym);
+        }
+    }
+
+    // 未使用的导入：绑定名在文件中只出现在导入语句本身。
+    // Go / Rust 由编译器把关，跳过；__init__.py 的导入多为转出，也跳过
+    let mut stmt = conn.prepare(
+        "SELECT f.file_path, f.language, i.imported_path, i.imported_symbol, i.alias, i.line
+         FROM imports i JOIN files f ON i.file_id = f.file_id
+         WHERE f.file_path LIKE ?1 AND i.kind = 'import' AND f.language NOT IN ('go', 'rs')
+         ORDER BY f.file_path, i.line",
+    )?;
+    let rows = stmt.query_map([&pattern], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, usize>(5)?,
+        ))
+    })?;
+    let mut unused_imports = vec![];
+    let mut text_cache: HashMap<String, String> = HashMap::new();
+    for (file_path, language, imported_path, imported_symbol, alias, line) in rows.flatten() {
+        if file_path.ends_with("__init__.py") {
+            continue;
+        }
+        let Some(name) = import_binding(&language, &imported_path, &imported_symbol, &alias) else {
+            continue;
+        };
+        let text = text_cache.entry(file_path.clone()).or_insert_with(|| {
+            fs::read(resolve_source_path(&roots, &file_path))
+                .ok()
+                .and_then(|b| decode_source(&b))
+                .unwrap_or_default()
+        });
+        // 读不到源码时不下结论
+        if !text.is_empty() && identifier_occurrences(text, &name) <= 1 {
+            unused_imports.push(UnusedImport {
+                file_path,
+                line,
+                imported_path,
+                imported_symbol,
+                name,
+            });
+        }
+    }
+
+    let res = UnusedResult {
+        status: "success".to_string(),
+        generation: read_generation(&conn)?,
+        scope,
+        unused_exports,
+        unused_imports,
+    };
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+// 导入在文件内引入的本地名；None 表示不绑定名字（副作用导入 / 通配 / #include / require）
+fn import_binding(
+    language: &str,
+    path: &str,
+    symbol: &Option<String>,
+    alias: &Option<String>,
+) -> Option<String> {
+    if let Some(alias) = alias {
+        return Some(alias.clone()).filter(|a| a != "_" && a != ".");
+    }
+    match symbol.as_deref() {
+        Some("*") => None,
+        Some(s) => Some(s.to_string()),
+        // Python：import a.b 绑定的是 a
+        None if language == "py" && !path.starts_with('.') => {
+            path.split('.').next().map(|s| s.to_string())
+        }
+        None => None,
+    }
+}
+
+// 整词出现次数（标识符边界）
+fn identifier_occurrences(text: &str, name: &str) -> usize {
+    if name.is_empty() {
+        return 0;
+    }
+    text.match_indices(name)
+        .filter(|(i, _)| {
+            let before = text[..*i].chars().next_back();
+            let after = text[i + name.len()..].chars().next();
+            !before.is_some_and(|c| is_ident_char(c) || c == '$')
+                && !after.is_some_and(|c| is_ident_char(c) || c == '$')
+        })
+        .count()
+}
+
+// ============================================================================
+// LSP Mode - 基于 symbols.db 的最小 LSP 子集（stdio JSON-RPC）
+// workspace/symbol / documentSymbol / definition / references；编辑器直接复用索引，不经 MCP
+// ============================================================================
+
+type LspReply = std::result::Result<serde_json::Value, (i64, String)>;
+
+const LSP_PARSE_ERROR: i64 = -32700;
+const LSP_INVALID_REQUEST: i64 = -32600;
+const LSP_SERVER_NOT_INITIALIZED: i64 = -32002;
+const LSP_METHOD_NOT_FOUND: i64 = -32601;
+const LSP_INTERNAL_ERROR: i64 = -32603;
+const LSP_WORKSPACE_SYMBOL_LIMIT: i64 = 200;
+// 单条消息体上限：超过的直接丢弃，不按客户端声明的长度分配内存
+const LSP_MAX_MESSAGE_BYTES: usize = 8 << 20;
+
+fn run_lsp(args: &Args) -> anyhow::Result<()> {
+    use std::io::BufReader;
+
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+    let roots: Vec<ProjectRoot> = project_roots(args)?
+        .into_iter()
+        .map(|r| ProjectRoot {
+            path: fs::canonicalize(&r.path).unwrap_or(r.path),
+            name: r.name,
+        })
+        .collect();
+
+    // 配置了 token 时，initialize 校验通过前拒绝其余请求
+    let token = auth_token(args);
+    let mut authorized = token.is_none();
+
+    let warm = Arc::new(Mutex::new(WarmState::new(args.warm_up)));
+    if args.warm_up {
+        let warm = Arc::clone(&warm);
+        let db = args.db.clone();
+        std::thread::spawn(move || {
+            let started = Instant::now();
+            let result = warm_up_index(&db);
+            let mut state = warm.lock().unwrap_or_else(|e| e.into_inner());
+            state.finish(result, started.elapsed().as_millis());
+        });
+    }
+
+    let mut reader = BufReader::new(std::io::stdin().lock());
+    while let Some(frame) = read_lsp_message(&mut reader)? {
+        // 坏帧读不出 id，按 JSON-RPC 约定以 id: null 回错误后继续读下一条
+        let msg = match frame {
+            Ok(msg) => msg,
+            Err((code, message)) => {
+                write_lsp_message(&serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": code, "message": message},
+                }))?;
+                continue;
+            }
+        };
+        let method = msg["method"].as_str().unwrap_or("");
+        if method == "exit" {
+            break;
+        }
+        if method == "initialized" && authorized {
+            let mut state = warm.lock().unwrap_or_else(|e| e.into_inner());
+            state.initialized = true;
+            state.announce();
+        }
+        let params = &msg["params"];
+        let internal = |e: anyhow::Error| (LSP_INTERNAL_ERROR, e.to_string());
+        let reply: LspReply = match method {
+            "initialize" if !authorized => {
+                let given = params["initializationOptions"]["token"]
+                    .as_str()
+                    .unwrap_or("");
+                if token.as_deref().is_some_and(|t| token_matches(t, given)) {
+                    authorized = true;
+                    Ok(lsp_capabilities())
+                } else {
+                    Err((
+                        LSP_SERVER_NOT_INITIALIZED,
+                        "invalid or missing initializationOptions.token".to_string(),
+                    ))
+                }
+            }
+            "initialize" => Ok(lsp_capabilities()),
+            "shutdown" => Ok(serde_json::Value::Null),
+            _ if !authorized => Err((
+                LSP_SERVER_NOT_INITIALIZED,
+                "not authorized: initialize with initializationOptions.token first".to_string(),
+            )),
+            "workspace/symbol" => lsp_workspace_symbols(&conn, &roots, params).map_err(internal),
+            "textDocument/documentSymbol" => {
+                lsp_document_symbols(&conn, &roots, params).map_err(internal)
+            }
+            "textDocument/definition" => lsp_definition(&conn, &roots, params).map_err(internal),
+            "textDocument/references" => lsp_references(&conn, &roots, params).map_err(internal),
+            "mpm/health" => {
+                let state = warm.lock().unwrap_or_else(|e| e.into_inner());
+                serde_json::to_value(&state.status).map_err(|e| internal(e.into()))
+            }
+            _ => Err((
+                LSP_METHOD_NOT_FOUND,
+                format!("method not supported: {}", method),
+            )),
+        };
+        // 通知（initialized / didOpen …）没有 id，不回包
+        let Some(id) = msg.get("id").cloned() else {
+            continue;
+        };
+        let response = match reply {
+            Ok(result) => serde_json::json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        write_lsp_message(&response)?;
+    }
+    Ok(())
+}
+
+// 每条消息单独锁 stdout：预热线程的状态通知与请求的回包不会交错
+fn write_lsp_message(message: &serde_json::Value) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let body = serde_json::to_string(message)?;
+    let mut stdout = std::io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+// --warm-up：启动时后台用独立的只读连接把名字 -> id、canonical_id 与两个方向的调用边
+// （索引与表）顺序读一遍，页面进入操作系统缓存，首个请求不再冷读磁盘。
+// 进度经 mpm/health 请求随时可查；完成后（客户端已 initialized）推一条 $/mpm/status 通知
+#[derive(Serialize)]
+struct WarmStatus {
+    state: &'static str, // off | warming | ready | failed
+    names: usize,
+    symbols: usize,
+    edges: usize,
+    elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct WarmState {
+    status: WarmStatus,
+    initialized: bool,
+    announced: bool,
+}
+
+impl WarmState {
+    fn new(enabled: bool) -> Self {
+        WarmState {
+            status: WarmStatus {
+                state: if enabled { "warming" } else { "off" },
+                names: 0,
+                symbols: 0,
+                edges: 0,
+                elapsed_ms: 0,
+                error: None,
+            },
+            initialized: false,
+            announced: false,
+        }
+    }
+
+    fn finish(&mut self, result: Result<(usize, usize, usize)>, elapsed_ms: u128) {
+        self.status.elapsed_ms = elapsed_ms;
+        match result {
+            Ok((names, symbols, edges)) => {
+                self.status.state = "ready";
+                self.status.names = names;
+                self.status.symbols = symbols;
+                self.status.edges = edges;
+            }
+            Err(e) => {
+                self.status.state = "failed";
+                self.status.error = Some(e.to_string());
+            }
+        }
+        self.announce();
+    }
+
+    // 预热结束且客户端已 initialized 才通知，且只通知一次
+    fn announce(&mut self) {
+        let finished = matches!(self.status.state, "ready" | "failed");
+        if self.announced || !self.initialized || !finished {
+            return;
+        }
+        self.announced = true;
+        let _ = write_lsp_message(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "$/mpm/status",
+            "params": &self.status,
+        }));
+    }
+}
+
+// 返回 (不同名字数, 符号数, 已链接的调用边数)
+fn warm_up_index(db: &str) -> Result<(usize, usize, usize)> {
+    let conn = Connection::open_with_flags(db, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // 名字 -> id：workspace/symbol、definition 按名字查
+    let mut names = 0;
+    let mut symbols = 0;
+    let mut last: Option<String> = None;
+    let mut stmt = conn.prepare("SELECT name, canonical_id FROM symbols ORDER BY name")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        symbols += 1;
+        if last.as_deref() != Some(name.as_str()) {
+            names += 1;
+            last = Some(name);
+        }
+    }
+    // id -> 符号：references 与调用者回查
+    conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT canonical_id FROM symbols ORDER BY canonical_id)",
+        [],
+        |r| r.get::<_, i64>(0),
+    )?;
+    // 邻接表：callee_id（谁调用了它）与 caller_id（它调用了谁）两个方向
+    let edges: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT callee_id, call_line FROM calls WHERE callee_id IS NOT NULL ORDER BY callee_id)",
+        [],
+        |r| r.get(0),
+    )?;
+    conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT caller_id FROM calls ORDER BY caller_id)",
+        [],
+        |r| r.get::<_, i64>(0),
+    )?;
+    conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT file_path FROM files ORDER BY file_path)",
+        [],
+        |r| r.get::<_, i64>(0),
+    )?;
+    Ok((names, symbols, edges as usize))
+}
+
+fn lsp_capabilities() -> serde_json::Value {
+    serde_json::json!({
+        "capabilities": {
+            "workspaceSymbolProvider": true,
+            "documentSymbolProvider": true,
+            "definitionProvider": true,
+            "referencesProvider": true,
+        },
+        "serverInfo": {"name": "ast_indexer_rust", "version": env!("CARGO_PKG_VERSION")},
+    })
+}
+
+// Content-Length 分帧；EOF 返回 None，超长或不是合法 JSON 的消息体返回 Some(Err)，流本身仍可继续读
+fn read_lsp_message(reader: &mut impl std::io::BufRead) -> anyhow::Result<Option<LspReply>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.unwrap_or(0);
+    if length > LSP_MAX_MESSAGE_BYTES {
+        std::io::copy(
+            &mut std::io::Read::take(&mut *reader, length as u64),
+            &mut std::io::sink(),
+        )?;
+        return Ok(Some(Err((
+            LSP_INVALID_REQUEST,
+            format!(
+                "message of {} bytes exceeds the {} byte limit",
+                length, LSP_MAX_MESSAGE_BYTES
+            ),
+        ))));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(
+        serde_json::from_slice(&body).map_err(|e| (LSP_PARSE_ERROR, e.to_string())),
+    ))
+}
+
+// symbol_type -> LSP SymbolKind
+fn lsp_symbol_kind(symbol_type: &str) -> u8 {
+    match symbol_type {
+        "module" | "package" => 2,
+        "namespace" => 3,
+        "class" | "impl" | "resource" | "data" => 5,
+        "method" => 6,
+        "property" => 7,
+        "field" | "column" => 8,
+        "constructor" => 9,
+        "enum" => 10,
+        "interface" | "trait" | "protocol" => 11,
+        "variable" | "output" => 13,
+        "constant" => 14,
+        "variant" => 22,
+        "struct" | "table" | "view" | "message" => 23,
+        "type" => 26,
+        _ => match canonical_prefix(symbol_type) {
+            "class" => 5,
+            "const" => 14,
+            _ => 12, // function
+        },
+    }
+}
+
+fn lsp_uri(roots: &[ProjectRoot], file_path: &str) -> String {
+    let abs = resolve_source_path(roots, file_path);
+    let path = abs.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    // Windows 盘符路径 C:/x -> file:///C:/x
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~:".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+// file:// URI -> DB 中的 file_path（多根带 root 名前缀）；不在任何 root 下返回 None
+fn lsp_file_path(roots: &[ProjectRoot], uri: &str) -> Option<String> {
+    let raw = uri.strip_prefix("file://")?;
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    let mut path = String::from_utf8(decoded).ok()?;
+    // /C:/x -> C:/x
+    if path.as_bytes().get(2) == Some(&b':') {
+        path.remove(0);
+    }
+    let abs = fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(&path));
+    roots.iter().find_map(|root| {
+        let rel = abs.strip_prefix(&root.path).ok()?;
+        Some(format!(
+            "{}{}",
+            root.prefix(),
+            rel.to_string_lossy().replace('\\', "/")
+        ))
+    })
+}
+
+// 符号名在声明行里的位置；找不到时退回整行开头
+fn lsp_range(lines: &[String], line: usize, name: &str) -> serde_json::Value {
+    let row = line.saturating_sub(1);
+    let col = lines
+        .get(row)
+        .and_then(|l| l.find(name).map(|b| l[..b].encode_utf16().count()))
+        .unwrap_or(0);
+    let end = col + name.encode_utf16().count();
+    serde_json::json!({
+        "start": {"line": row, "character": col},
+        "end": {"line": row, "character": end},
+    })
+}
+
+struct LspSymbolRow {
+    name: String,
+    symbol_type: String,
+    file_path: String,
+    line_start: usize,
+    canonical_id: String,
+    container: Option<String>,
+}
+
+fn lsp_symbol_rows(
+    conn: &Connection,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> anyhow::Result<Vec<LspSymbolRow>> {
+    let sql = format!(
+        "SELECT s.name, s.symbol_type, f.file_path, s.line_start, s.canonical_id, p.name
+         FROM symbols s
+         JOIN files f ON s.file_id = f.file_id
+         LEFT JOIN symbols p ON s.parent_id = p.symbol_id
+         {}",
+        filter
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params, |row| {
+        Ok(LspSymbolRow {
+            name: row.get(0)?,
+            symbol_type: row.get(1)?,
+            file_path: row.get(2)?,
+            line_start: row.get(3)?,
+            canonical_id: row.get(4)?,
+            container: row.get(5)?,
+        })
+    })?;
+    Ok(rows.flatten().collect())
+}
+
+fn lsp_symbol_information(
+    sym: &LspSymbolRow,
+    roots: &[ProjectRoot],
+    lines: &[String],
+) -> serde_json::Value {
+    let mut info = serde_json::json!({
+        "name": sym.name,
+        "kind": lsp_symbol_kind(&sym.symbol_type),
+        "location": {
+            "uri": lsp_uri(roots, &sym.file_path),
+            "range": lsp_range(lines, sym.line_start, &sym.name),
+        },
+    });
+    if let Some(container) = &sym.container {
+        info["containerName"] = serde_json::json!(container);
+    }
+    info
+}
+
+fn lsp_workspace_symbols(
+    conn: &Connection,
+    roots: &[ProjectRoot],
+    params: &serde_json::Value,
+) -> anyhow::Result<serde_json::Value> {
+    let query = nfc(params["query"].as_str().unwrap_or(""));
+    let pattern = format!("%{}%", query.replace('%', "\\%").replace('_', "\\_"));
+    // 精确匹配在前，其次名字短的
+    let rows = lsp_symbol_rows(
+        conn,
+        "WHERE s.name LIKE ?1 ESCAPE '\\'
+         ORDER BY s.name != ?2, length(s.name), f.file_path, s.line_start
+         LIMIT ?3",
+        &[&pattern, &query, &LSP_WORKSPACE_SYMBOL_LIMIT],
+    )?;
+    // 只有声明行需要回读源码，按文件缓存
+    let mut source_cache: HashMap<String, Vec<String>> = HashMap::new();
+    let symbols: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|sym| {
+            let lines = source_cache
+                .entry(sym.file_path.clone())
+                .or_insert_with(|| read_source_lines(&resolve_source_path(roots, &sym.file_path)));
+            lsp_symbol_information(sym, roots, lines)
+        })
+        .collect();
+    Ok(serde_json::Value::Array(symbols))
+}
+
+fn lsp_document_symbols(
+    conn: &Connection,
+    roots: &[ProjectRoot],
+    params: &serde_json::Value,
+) -> anyhow::Result<serde_json::Value> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+    let Some(file_path) = lsp_file_path(roots, uri) else {
+        return Ok(serde_json::Value::Array(vec![]));
+    };
+    let rows = lsp_symbol_rows(
+        conn,
+        "WHERE f.file_path = ?1 ORDER BY s.line_start",
+        &[&file_path],
+    )?;
+    let lines = read_source_lines(&resolve_source_path(roots, &file_path));
+    Ok(serde_json::Value::Array(
+        rows.iter()
+            .map(|sym| lsp_symbol_information(sym, roots, &lines))
+            .collect(),
+    ))
+}
+
+// 光标处的标识符 + 所在文件；再按名字查定义（同文件优先）
+fn lsp_definitions_at(
+    conn: &Connection,
+    roots: &[ProjectRoot],
+    params: &serde_json::Value,
+) -> anyhow::Result<Vec<LspSymbolRow>> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+    let Some(file_path) = lsp_file_path(roots, uri) else {
+        return Ok(vec![]);
+    };
+    let row = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+    let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+    let lines = read_source_lines(&resolve_source_path(roots, &file_path));
+    let Some(word) = lines.get(row).and_then(|l| identifier_at(l, character)) else {
+        return Ok(vec![]);
+    };
+    lsp_symbol_rows(
+        conn,
+        "WHERE s.name = ?1 ORDER BY f.file_path != ?2, f.file_path, s.line_start",
+        &[&word, &file_path],
+    )
+}
+
+// LSP 的 character 以 UTF-16 码元计
+fn identifier_at(line: &str, character: usize) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut units = 0;
+    let mut idx = chars.len();
+    for (i, c) in chars.iter().enumerate() {
+        if units >= character {
+            idx = i;
+            break;
+        }
+        units += c.len_utf16();
+    }
+    let is_word = |c: &char| is_ident_char(*c) || *c == '$';
+    // 光标停在标识符末尾之后（`foo|(`）时向左取
+    if !chars.get(idx).is_some_and(is_word) && idx > 0 && chars.get(idx - 1).is_some_and(is_word) {
+        idx -= 1;
+    }
+    if !chars.get(idx).is_some_and(is_word) {
+        return None;
+    }
+    let start = (0..=idx).rev().take_while(|&i| is_word(&chars[i])).last()?;
+    let end = (idx..chars.len())
+        .take_while(|&i| is_word(&chars[i]))
+        .last()?;
+    Some(chars[start..=end].iter().collect())
+}
+
+fn lsp_definition(
+    conn: &Connection,
+    roots: &[ProjectRoot],
+    params: &serde_json::Value,
+) -> anyhow::Result<serde_json::Value> {
+    let mut source_cache: HashMap<String, Vec<String>> = HashMap::new();
+    let locations: Vec<serde_json::Value> = lsp_definitions_at(conn, roots, params)?
+        .iter()
+        .map(|sym| {
+            let lines = source_cache
+                .entry(sym.file_path.clone())
+                .or_insert_with(|| read_source_lines(&resolve_source_path(roots, &sym.file_path)));
+            serde_json::json!({
+                "uri": lsp_uri(roots, &sym.file_path),
+                "range": lsp_range(lines, sym.line_start, &sym.name),
             })
         })
-        .ok()?;
+        .collect();
+    Ok(serde_json::Value::Array(locations))
+}
 
-    for r in rows {
-        if let Ok(node) = r {
-            let dist = levenshtein(&query_lower, &node.name.to_lowercase());
-            if dist <= max_distance {
-                if best.is_none() || dist < best.as_ref().unwrap().1 {
-                    best = Some((node, dist));
-                }
+// 引用 = 已链接到这些定义的调用边（calls.callee_id），外加可选的声明本身
+fn lsp_references(
+    conn: &Connection,
+    roots: &[ProjectRoot],
+    params: &serde_json::Value,
+) -> anyhow::Result<serde_json::Value> {
+    let definitions = lsp_definitions_at(conn, roots, params)?;
+    let include_declaration = params["context"]["includeDeclaration"]
+        .as_bool()
+        .unwrap_or(false);
+
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT f.file_path, c.call_line
+         FROM main.calls c
+         JOIN symbols s ON c.caller_id = s.symbol_id
+         JOIN files f ON s.file_id = f.file_id
+         WHERE c.callee_id = ?1
+         ORDER BY f.file_path, c.call_line",
+    )?;
+    let mut source_cache: HashMap<String, Vec<String>> = HashMap::new();
+    let mut locations = vec![];
+    for def in &definitions {
+        let mut sites: Vec<(String, usize)> = stmt
+            .query_map([&def.canonical_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .flatten()
+            .collect();
+        if include_declaration {
+            sites.insert(0, (def.file_path.clone(), def.line_start));
+        }
+        for (file_path, line) in sites {
+            let lines = source_cache
+                .entry(file_path.clone())
+                .or_insert_with(|| read_source_lines(&resolve_source_path(roots, &file_path)));
+            locations.push(serde_json::json!({
+                "uri": lsp_uri(roots, &file_path),
+                "range": lsp_range(lines, line, &def.name),
+            }));
+        }
+    }
+    Ok(serde_json::Value::Array(locations))
+}
+
+// ============================================================================
+// Symbols Mode - 工作区符号导出（VS Code workspace symbol provider 格式）
+// ============================================================================
+
+fn run_symbols(args: &Args) -> anyhow::Result<()> {
+    if args.format != "vscode" {
+        anyhow::bail!(
+            "unsupported format for symbols mode: {} (expected vscode)",
+            args.format
+        );
+    }
+    let conn = Connection::open(&args.db)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], Some(overlay_layer(args)))?;
+    let roots = project_roots(args)?;
+
+    let scope = args
+        .scope
+        .as_deref()
+        .map(|s| s.trim().trim_start_matches("./").replace("\\", "/"))
+        .unwrap_or_default();
+    let query = args.query.as_deref().unwrap_or("");
+    let kinds = symbol_type_filter(args.symbol_type.as_deref());
+    let with_attributes = attribute_filter(&conn, args.attribute.as_deref())?;
+    let mut rows = lsp_symbol_rows(
+        &conn,
+        "WHERE f.file_path LIKE ?1 AND s.name LIKE ?2 ESCAPE '\\'
+         ORDER BY f.file_path, s.line_start",
+        &[
+            &format!("{}%", scope),
+            &format!("%{}%", query.replace('%', "\\%").replace('_', "\\_")),
+        ],
+    )?;
+    if !kinds.is_empty() {
+        rows.retain(|r| kinds.contains(&r.symbol_type));
+    }
+    if let Some(ids) = &with_attributes {
+        rows.retain(|r| ids.contains(&r.canonical_id));
+    }
+
+    // 行已按文件排序：逐文件读一次源码，算出名字所在列
+    let mut symbols = Vec::with_capacity(rows.len());
+    let mut current: Option<(String, Vec<String>)> = None;
+    for sym in &rows {
+        if current.as_ref().map(|(p, _)| p) != Some(&sym.file_path) {
+            let lines = read_source_lines(&resolve_source_path(&roots, &sym.file_path));
+            current = Some((sym.file_path.clone(), lines));
+        }
+        let lines = current.as_ref().map(|(_, l)| l.as_slice()).unwrap_or(&[]);
+        symbols.push(lsp_symbol_information(sym, &roots, lines));
+    }
+
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &symbols)?;
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Set-Summary Mode - Agent 摘要回写 (单条 / JSONL 批量)
+// ============================================================================
+
+#[derive(Deserialize)]
+struct SummaryRecord {
+    id: String,
+    summary: String,
+}
+
+#[derive(Serialize)]
+struct SetSummaryResult {
+    status: String,
+    generation: i64,
+    updated: usize,
+    missing: Vec<String>,
+}
+
+fn run_set_summary(args: &Args) -> anyhow::Result<()> {
+    let mut conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+
+    // 单条：--id X --text "..."；批量：--input summaries.jsonl（每行 {"id": ..., "summary": ...}）
+    let mut records: Vec<SummaryRecord> = vec![];
+    if let (Some(id), Some(text)) = (&args.id, &args.text) {
+        records.push(SummaryRecord {
+            id: id.clone(),
+            summary: text.clone(),
+        });
+    }
+    if let Some(input) = &args.input {
+        let content = fs::read_to_string(input)?;
+        for (idx, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let rec: SummaryRecord = serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("{}:{}: {}", input, idx + 1, e))?;
+            records.push(rec);
+        }
+    }
+    if records.is_empty() {
+        anyhow::bail!("set-summary requires --id and --text, or --input <jsonl>");
+    }
+
+    let mut updated = 0;
+    let mut missing = vec![];
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE symbols SET summary = ?1 WHERE canonical_id = ?2")?;
+        for rec in &records {
+            // 空字符串视为清除摘要
+            let summary = Some(rec.summary.trim()).filter(|s| !s.is_empty());
+            // 旧路径的 canonical_id / stable_id 也可解析到当前符号
+            let target = resolve_canonical_id(&tx, &rec.id)?.unwrap_or_else(|| rec.id.clone());
+            let n = stmt.execute(params![summary, target])?;
+            if n > 0 {
+                updated += n;
+            } else {
+                missing.push(rec.id.clone());
             }
         }
     }
+    let generation = if updated > 0 {
+        bump_generation(&tx)?
+    } else {
+        read_generation(&tx)?
+    };
+    tx.commit()?;
+    if updated > 0 {
+        notify_generation(args, generation);
+    }
+
+    if let Some(out_path) = &args.output {
+        let res = SetSummaryResult {
+            status: "success".to_string(),
+            generation,
+            updated,
+            missing,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Invalidate Mode - 标记文件为 dirty，下次 index 强制重解析
+// 自定义 query / 修复语法 bug 后内容哈希不变，正常增量索引会跳过
+// --file 接受文件或目录路径（可为后缀），--id 接受符号 ID（解析到所在文件）
+// ============================================================================
 
-    best.map(|(n, _)| n)
+#[derive(Serialize)]
+struct InvalidateResult {
+    status: String,
+    generation: i64,
+    invalidated: usize,
+    files: Vec<String>,
 }
 
-// 🆕 修改：使用 canonical_id
-fn stem_match(conn: &Connection, query: &str) -> Option<Node> {
-    // 简单词根：取前 4 个字符
-    if query.len() < 4 {
-        return None;
+fn run_invalidate(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+
+    let mut file_ids: Vec<i64> = vec![];
+    if let Some(file) = &args.file {
+        let path = file.replace('\\', "/").trim_end_matches('/').to_string();
+        let mut stmt = conn.prepare(
+            "SELECT file_id FROM files
+             WHERE file_path = ?1 OR file_path LIKE ?1 || '/%' OR file_path LIKE '%/' || ?1",
+        )?;
+        let ids = stmt.query_map([&path], |r| r.get::<_, i64>(0))?;
+        file_ids.extend(ids.flatten());
     }
-    let stem = &query[..4];
-    let pattern = format!("{}%", stem);
-    let mut stmt = conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 LIMIT 5"
-    ).ok()?;
-    stmt.query_row([pattern], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    })
-    .ok()
+    if let Some(id) = &args.id {
+        let canonical = resolve_canonical_id(&conn, id)?
+            .ok_or_else(|| anyhow::anyhow!("unknown symbol id '{}'", id))?;
+        file_ids.extend(
+            conn.query_row(
+                "SELECT file_id FROM symbols WHERE canonical_id = ?1",
+                [&canonical],
+                |r| r.get::<_, i64>(0),
+            )
+            .optional()?,
+        );
+    }
+    if args.file.is_none() && args.id.is_none() {
+        anyhow::bail!("invalidate requires --file <path> or --id <symbol id>");
+    }
+    file_ids.sort_unstable();
+    file_ids.dedup();
+
+    let mut files = vec![];
+    {
+        let mut mark = conn.prepare("UPDATE files SET dirty = 1 WHERE file_id = ?1")?;
+        let mut path_of = conn.prepare("SELECT file_path FROM files WHERE file_id = ?1")?;
+        for fid in &file_ids {
+            mark.execute([fid])?;
+            files.push(path_of.query_row([fid], |r| r.get::<_, String>(0))?);
+        }
+    }
+    println!("[Invalidate] Marked {} files dirty", files.len());
+
+    if let Some(out_path) = &args.output {
+        let res = InvalidateResult {
+            status: "success".to_string(),
+            generation: read_generation(&conn)?,
+            invalidated: files.len(),
+            files,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
 }
 
 // ============================================================================
-// Multi-Candidate Match Functions (多候选匹配函数)
+// Reindex-File Mode - 编辑器未保存缓冲区的符号覆盖层
+// --content - 从 stdin 读缓冲区（也可给文件路径），省略时取磁盘内容；
+// 抽取结果写入 overlay_symbols，query/map 等读模式用它遮蔽该文件的落库符号。
+// 内容与已索引版本一致（已保存并索引 / 撤销了修改）时清除 overlay
 // ============================================================================
 
-// 🆕 修改：使用 canonical_id
-fn prefix_suffix_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
-    let prefix_pattern = format!("{}%", query);
-    let suffix_pattern = format!("%{}", query);
-    let mut stmt = match conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 OR name LIKE ?2 LIMIT ?3",
-    ) {
-        Ok(s) => s,
-        Err(_) => return vec![],
+#[derive(Serialize)]
+struct ReindexFileResult {
+    status: String, // overlay | cleared
+    generation: i64,
+    file_path: String,
+    symbols: usize,
+}
+
+fn run_reindex_file(args: &Args) -> anyhow::Result<()> {
+    use std::io::Read;
+
+    let mut conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    let roots = project_roots(args)?;
+
+    let file = args
+        .file
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("reindex-file requires --file <path>"))?;
+    // 编辑器给的通常是绝对路径：落在某个 root 下时换成索引里的相对路径
+    let mut path = file.replace('\\', "/");
+    for root in &roots {
+        let abs = fs::canonicalize(&root.path).unwrap_or_else(|_| root.path.clone());
+        let rel = Path::new(file)
+            .strip_prefix(&abs)
+            .or_else(|_| Path::new(file).strip_prefix(&root.path));
+        if let Ok(rel) = rel {
+            path = format!(
+                "{}{}",
+                root.prefix(),
+                rel.to_string_lossy().replace('\\', "/")
+            );
+            break;
+        }
+    }
+    let (file_id, file_path, language, file_hash) = conn
+        .query_row(
+            "SELECT file_id, file_path, language, file_hash FROM files
+             WHERE file_path = ?1 OR file_path LIKE '%/' || ?1 ORDER BY length(file_path) LIMIT 1",
+            [&path],
+            |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, String>(3)?,
+                ))
+            },
+        )
+        .optional()?
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not in the index; run index mode first", file))?;
+
+    let bytes = match args.content.as_deref() {
+        Some("-") => {
+            let mut buf = vec![];
+            std::io::stdin().read_to_end(&mut buf)?;
+            buf
+        }
+        Some(input) => fs::read(input)?,
+        None => fs::read(resolve_source_path(&roots, &file_path))?,
     };
+    let content = decode_source(&bytes)
+        .ok_or_else(|| anyhow::anyhow!("{}: content is not valid UTF-8 / UTF-16", file_path))?;
+    let content_hash = hex::encode(Sha256::digest(content.as_bytes()));
+
+    let parsers = get_parser_setup();
+    if !parsers.contains_key(&language)
+        && text_extractor(&language).is_none()
+        && sfc_extractor(&language).is_none()
+        && objc_extractor(&language).is_none()
+    {
+        anyhow::bail!("{}: no extractor for language '{}'", file_path, language);
+    }
+    let type_map = load_type_map(args.type_map.as_deref())?;
+    let features = parse_feature_flags(args.features.as_deref())?.for_ext(&language);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let tx = conn.transaction()?;
+    let layer = overlay_layer(args);
+    clear_overlay(&tx, file_id, Some(layer), None)?;
+    let mut symbols = 0;
+    if content_hash != file_hash {
+        let parsed = extract_with_fallback(&parsers, &language, &content, features);
+        tx.execute(
+            "INSERT INTO overlay_files (sandbox, file_id, file_hash, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            params![layer, file_id, content_hash, now],
+        )?;
+        // 摘要按 canonical_id 带过来，与索引期一致
+        let kept_summaries: HashMap<String, String> = tx
+            .prepare(
+                "SELECT canonical_id, summary FROM symbols WHERE file_id = ?1 AND summary IS NOT NULL",
+            )?
+            .query_map(params![file_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .flatten()
+            .collect();
+        // overlay 行取负 ID，不会与落库符号（及其 calls / decorators）撞号
+        let mut next_id: i64 = tx.query_row(
+            "SELECT min(coalesce(min(symbol_id), 0), 0) - 1 FROM overlay_symbols",
+            [],
+            |r| r.get(0),
+        )?;
+        let mut temp_to_db_id: HashMap<usize, i64> = HashMap::new();
+        let mut stmt = tx.prepare(
+            "INSERT INTO overlay_symbols (symbol_id, file_id, name, qualified_name, canonical_id, scope_path, symbol_type, line_start, line_end, signature, parent_id, summary, stable_id, doc, anchor, signature_shape, visibility, sandbox)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+        )?;
+        for sym in &parsed.symbols {
+            let name = anon_in_file(&sym.name, &file_path);
+            let scope_path = anon_in_file(&sym.scope_path, &file_path);
+            let canonical_id = format!(
+                "{}:{}::{}",
+                canonical_prefix(&sym.symbol_type),
+                file_path,
+                name
+            );
+            stmt.execute(params![
+                next_id,
+                file_id,
+                name,
+                anon_in_file(&sym.qualified_name, &file_path),
+                canonical_id,
+                scope_path,
+                type_map.resolve(&language, &sym.symbol_type),
+                sym.line_start,
+                sym.line_end,
+                sym.signature,
+                sym.parent_temp_id
+                    .and_then(|pid| temp_to_db_id.get(&pid).copied()),
+                kept_summaries.get(&canonical_id),
+                stable_symbol_id(&sym.symbol_type, &scope_path, sym.signature.as_deref()),
+                sym.doc,
+                sym.anchor,
+                sym.signature_shape
+                    .as_ref()
+                    .and_then(|shape| serde_json::to_string(shape).ok()),
+                sym.visibility,
+                layer
+            ])?;
+            temp_to_db_id.insert(sym.temp_id, next_id);
+            next_id -= 1;
+        }
+        drop(stmt);
+        symbols = parsed.symbols.len();
+
+        // 调用边同样落到 overlay；callee_id 沿用落库边里同一调用者、同名被调者已解析的目标，
+        // 缺了就留空，由 analyze / query 退回名字匹配
+        let canonical_of: HashMap<i64, String> = tx
+            .prepare("SELECT symbol_id, canonical_id FROM overlay_symbols WHERE file_id = ?1 AND sandbox = ?2")?
+            .query_map(params![file_id, layer], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .flatten()
+            .collect();
+        let resolved: HashMap<(String, String, Option<String>), String> = tx
+            .prepare(
+                "SELECT s.canonical_id, c.callee_name, c.receiver_type, c.callee_id
+                 FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id
+                 WHERE s.file_id = ?1 AND c.callee_id IS NOT NULL",
+            )?
+            .query_map(params![file_id], |r| {
+                Ok(((r.get(0)?, r.get(1)?, r.get(2)?), r.get(3)?))
+            })?
+            .flatten()
+            .collect();
+        let mut next_call_id: i64 = tx.query_row(
+            "SELECT min(coalesce(min(call_id), 0), 0) - 1 FROM overlay_calls",
+            [],
+            |r| r.get(0),
+        )?;
+        let mut stmt = tx.prepare(
+            "INSERT INTO overlay_calls (call_id, sandbox, file_id, caller_id, callee_name, call_line, callee_id, kind, receiver_type, arg_count, arg_keywords)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for call in &parsed.calls {
+            let Some(caller_id) = temp_to_db_id.get(&call.caller_temp_id) else {
+                continue;
+            };
+            let callee_name = anon_in_file(&call.callee_name, &file_path);
+            let callee_id = canonical_of.get(caller_id).and_then(|caller| {
+                resolved.get(&(
+                    caller.clone(),
+                    callee_name.clone(),
+                    call.receiver_type.clone(),
+                ))
+            });
+            // 与落库一致：连不上符号的引用边不要
+            if matches!(call.kind.as_str(), "reference" | "decorator") && callee_id.is_none() {
+                continue;
+            }
+            stmt.execute(params![
+                next_call_id,
+                layer,
+                file_id,
+                *caller_id,
+                callee_name,
+                call.line,
+                callee_id,
+                call.kind,
+                call.receiver_type,
+                call.args.as_ref().map(|a| a.positional),
+                call.args
+                    .as_ref()
+                    .filter(|a| !a.keywords.is_empty())
+                    .map(|a| a.keywords.join(","))
+            ])?;
+            next_call_id -= 1;
+        }
+    }
+    let generation = bump_generation(&tx)?;
+    tx.commit()?;
+    notify_generation(args, generation);
 
-    let rows = match stmt.query_map(
-        params![prefix_pattern, suffix_pattern, limit as i64],
-        |row| {
-            Ok(Node {
-                id: row.get::<_, String>(0)?, // 🆕 canonical_id
-                name: row.get(1)?,
-                qualified_name: row.get(2)?,
-                file_path: row.get(3)?,
-                line_start: row.get(4)?,
-                line_end: row.get(5)?,
-                node_type: row.get(6)?,
-                signature: None,
-                calls: vec![],
-            })
-        },
-    ) {
-        Ok(r) => r,
-        Err(_) => return vec![],
+    let status = if content_hash == file_hash {
+        "cleared"
+    } else {
+        "overlay"
     };
+    println!(
+        "[Reindex-File] {} {} ({} symbols)",
+        file_path, status, symbols
+    );
 
-    rows.filter_map(|r| r.ok()).collect()
+    if let Some(out_path) = &args.output {
+        let res = ReindexFileResult {
+            status: status.to_string(),
+            generation,
+            file_path,
+            symbols,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Sandbox Modes - 命名沙箱（reindex-file --sandbox 写入）的差异与丢弃
+// 沙箱是叠在基础索引上的写时复制层：只保存改过的文件，其余文件照常读基础索引
+// ============================================================================
+
+#[derive(Serialize)]
+struct SandboxDiffResult {
+    status: String,
+    generation: i64,
+    sandbox: String,
+    files: Vec<String>, // 沙箱里改过的文件
+    #[serde(flatten)]
+    diff: DiffResult,
+}
+
+fn run_sandbox_diff(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    let sandbox = args
+        .sandbox
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("sandbox-diff requires --sandbox <name>"))?;
+
+    let files: Vec<String> = conn
+        .prepare(
+            "SELECT f.file_path FROM overlay_files o JOIN files f ON f.file_id = o.file_id
+             WHERE o.sandbox = ?1 ORDER BY f.file_path",
+        )?
+        .query_map([sandbox], |r| r.get(0))?
+        .flatten()
+        .collect();
+
+    // 两侧只取沙箱改过的文件；覆盖层不存调用边，比较的是符号本身（增删、类型、可见性、签名）
+    let layer_symbols = |sql: &str| -> Result<BTreeMap<String, SnapshotSymbol>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([sandbox], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                SnapshotSymbol {
+                    name: row.get(1)?,
+                    qualified_name: row.get(2)?,
+                    file_path: row.get(3)?,
+                    symbol_type: row.get(5)?,
+                    line_start: row.get(4)?,
+                    signature: row.get(6)?,
+                    signature_shape: row
+                        .get::<_, Option<String>>(7)?
+                        .and_then(|json| serde_json::from_str(&json).ok()),
+                    visibility: row.get(8)?,
+                    calls: vec![],
+                },
+            ))
+        })?;
+        Ok(rows.flatten().collect())
+    };
+    let base = layer_symbols(
+        "SELECT s.canonical_id, s.name, s.qualified_name, f.file_path, s.line_start, s.symbol_type, s.signature, s.signature_shape, s.visibility
+         FROM symbols s JOIN files f ON s.file_id = f.file_id
+         WHERE s.file_id IN (SELECT file_id FROM overlay_files WHERE sandbox = ?1)
+         ORDER BY s.symbol_id",
+    )?;
+    let target = layer_symbols(
+        "SELECT s.canonical_id, s.name, s.qualified_name, f.file_path, s.line_start, s.symbol_type, s.signature, s.signature_shape, s.visibility
+         FROM overlay_symbols s JOIN files f ON s.file_id = f.file_id
+         WHERE s.sandbox = ?1
+         ORDER BY s.symbol_id DESC",
+    )?;
+    let snapshot = |symbols| Snapshot {
+        timestamp: 0,
+        symbols,
+        scopes: vec![],
+    };
+    let diff = diff_snapshots(&snapshot(base), &snapshot(target));
+
+    println!(
+        "[Sandbox] {}: {} files, {} added, {} removed, {} modified",
+        sandbox,
+        files.len(),
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    );
+
+    if let Some(out_path) = &args.output {
+        let res = SandboxDiffResult {
+            status: "success".to_string(),
+            generation: read_generation(&conn)?,
+            sandbox: sandbox.to_string(),
+            files,
+            diff,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SandboxDiscardResult {
+    status: String,
+    generation: i64,
+    sandbox: String,
+    files: usize,
 }
 
-// 🆕 修改：使用 canonical_id
-fn substring_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
-    let pattern = format!("%{}%", query);
-    let mut stmt = match conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 LIMIT ?2",
-    ) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
+fn run_sandbox_discard(args: &Args) -> anyhow::Result<()> {
+    let mut conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    let sandbox = args
+        .sandbox
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("sandbox-discard requires --sandbox <name>"))?;
+
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM overlay_symbols WHERE sandbox = ?1", [sandbox])?;
+    tx.execute("DELETE FROM overlay_calls WHERE sandbox = ?1", [sandbox])?;
+    let files = tx.execute("DELETE FROM overlay_files WHERE sandbox = ?1", [sandbox])?;
+    let generation = bump_generation(&tx)?;
+    tx.commit()?;
+    notify_generation(args, generation);
 
-    let rows = match stmt.query_map(params![pattern, limit as i64], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    }) {
-        Ok(r) => r,
-        Err(_) => return vec![],
-    };
+    println!("[Sandbox] {}: discarded {} files", sandbox, files);
 
-    rows.filter_map(|r| r.ok()).collect()
+    if let Some(out_path) = &args.output {
+        let res = SandboxDiscardResult {
+            status: "discarded".to_string(),
+            generation,
+            sandbox: sandbox.to_string(),
+            files,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
+    }
+
+    Ok(())
 }
 
-// 🆕 修改：使用 canonical_id
-fn levenshtein_match_multi(
-    conn: &Connection,
-    query: &str,
-    max_distance: usize,
-    limit: usize,
-) -> Vec<(Node, usize)> {
-    let mut stmt = match conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id",
-    ) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
+// ============================================================================
+// Source Mode - 按符号 ID 回读当前源码片段
+// 文件哈希与索引一致时直接按落库的行范围切片；不一致时就地重解析该文件、
+// 按 qualified_name 重新定位，避免调用方拿过期行范围切出错位的代码
+// ============================================================================
 
-    let query_lower = query.to_lowercase();
-    let mut matches: Vec<(Node, usize)> = vec![];
+#[derive(Serialize)]
+struct SourceResult {
+    status: String, // success | stale（文件已变且符号已不存在）
+    generation: i64,
+    id: String,
+    file_path: String,
+    line_start: usize,
+    line_end: usize,
+    fresh: bool,     // 磁盘文件哈希与索引一致
+    relocated: bool, // 文件已变，行范围来自重解析
+    text: Option<String>,
+}
 
-    let rows = match stmt.query_map([], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    }) {
-        Ok(r) => r,
-        Err(_) => return vec![],
-    };
+fn run_source(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    let roots = project_roots(args)?;
+
+    let id = args
+        .id
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("source requires --id <symbol id>"))?;
+    let canonical = resolve_canonical_id(&conn, id)?
+        .ok_or_else(|| anyhow::anyhow!("unknown symbol id '{}'", id))?;
+    let (file_path, language, file_hash, qualified_name, mut line_start, mut line_end) = conn
+        .query_row(
+        "SELECT f.file_path, f.language, f.file_hash, s.qualified_name, s.line_start, s.line_end
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE s.canonical_id = ?1",
+        [&canonical],
+        |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, usize>(4)?,
+                r.get::<_, usize>(5)?,
+            ))
+        },
+    )?;
 
-    for r in rows {
-        if let Ok(node) = r {
-            let dist = levenshtein(&query_lower, &node.name.to_lowercase());
-            if dist <= max_distance {
-                matches.push((node, dist));
-            }
+    let disk_path = resolve_source_path(&roots, &file_path);
+    let content = fs::read(&disk_path)
+        .ok()
+        .and_then(|b| decode_source(&b))
+        .ok_or_else(|| anyhow::anyhow!("cannot read {}", disk_path.display()))?;
+    // 与索引同口径：对解码后的内容取哈希
+    let fresh = hex::encode(Sha256::digest(content.as_bytes())) == file_hash;
+
+    let mut located = true;
+    if !fresh {
+        // 同名（重载 / 多个 impl）时取离原位置最近的一个
+        let parsers = get_parser_setup();
+        let features = ExtractFeatures {
+            calls: false,
+            signatures: false,
+            docs: false,
+        };
+        let parsed = extract_with_fallback(&parsers, &language, &content, features);
+        let found = parsed
+            .symbols
+            .iter()
+            .filter(|s| s.qualified_name == qualified_name)
+            .min_by_key(|s| s.line_start.abs_diff(line_start));
+        located = found.is_some();
+        if let Some(sym) = found {
+            line_start = sym.line_start;
+            line_end = sym.line_end;
         }
     }
 
-    // 按距离排序
-    matches.sort_by_key(|(_, d)| *d);
-    matches.truncate(limit);
-    matches
-}
+    // 保留原换行符，只去掉最后一行的行尾
+    let text = located.then(|| {
+        let body: String = content
+            .split_inclusive('\n')
+            .skip(line_start.saturating_sub(1))
+            .take(line_end + 1 - line_start.max(1))
+            .collect();
+        body.trim_end_matches(['\r', '\n']).to_string()
+    });
 
-// 🆕 修改：使用 canonical_id
-fn stem_match_multi(conn: &Connection, query: &str, limit: usize) -> Vec<Node> {
-    if query.len() < 4 {
-        return vec![];
+    if let Some(out_path) = &args.output {
+        let res = SourceResult {
+            status: if located { "success" } else { "stale" }.to_string(),
+            generation: read_generation(&conn)?,
+            id: canonical,
+            file_path,
+            line_start,
+            line_end,
+            fresh,
+            relocated: !fresh && located,
+            text,
+        };
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
     }
-    let stem = &query[..4];
-    let pattern = format!("{}%", stem);
-    let mut stmt = match conn.prepare(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE name LIKE ?1 LIMIT ?2",
-    ) {
-        Ok(s) => s,
-        Err(_) => return vec![],
-    };
 
-    let rows = match stmt.query_map(params![pattern, limit as i64], |row| {
-        Ok(Node {
-            id: row.get::<_, String>(0)?, // 🆕 canonical_id
-            name: row.get(1)?,
-            qualified_name: row.get(2)?,
-            file_path: row.get(3)?,
-            line_start: row.get(4)?,
-            line_end: row.get(5)?,
-            node_type: row.get(6)?,
-            signature: None,
-            calls: vec![],
-        })
-    }) {
-        Ok(r) => r,
-        Err(_) => return vec![],
-    };
+    Ok(())
+}
 
-    rows.filter_map(|r| r.ok()).collect()
+// ============================================================================
+// Rename Plan Mode - 重命名影响面（只列出位置，不改文件）
+// definitions / references 来自已链接的边，可以放心改；dynamic 是推测出的位置
+// （未链接的同名调用、继承链上的同名重写、来源不明的导入），需要逐个确认
+// ============================================================================
+
+#[derive(Serialize)]
+struct RenamePlan {
+    status: String,
+    generation: i64,
+    id: String,
+    name: String,
+    to: String,
+    definitions: Vec<RenameSite>,
+    references: Vec<RenameSite>,
+    dynamic: Vec<RenameSite>,
+    conflicts: Vec<String>, // 已存在的 --to 同名符号（同文件或同一作用域），改名后会撞名
 }
 
-fn run_query(args: &Args) -> anyhow::Result<()> {
-    let conn = Connection::open(&args.db)?;
+#[derive(Serialize)]
+struct RenameSite {
+    file_path: String,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col: Option<usize>, // 名字在该行的列（1 起，按字符计）；跨行调用等找不到时省略
+    context: String,
+    kind: String, // definition | call | reference | inherit | import | override | unresolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
 
-    // 策略优先级：
-    // 1. 如果有 file + line，按行号定位符号
-    // 2. 如果有 query，使用模糊匹配
+// 成员所属类型：scope_path `a::Base::save` -> Base；顶层符号没有
+fn scope_owner<'a>(scope_path: &'a str, name: &str) -> Option<&'a str> {
+    scope_path
+        .strip_suffix(name)?
+        .strip_suffix("::")?
+        .rsplit("::")
+        .next()
+}
 
-    let found: Option<Node>;
-    let mut candidates: Vec<CandidateMatch> = vec![];
-    let mut match_type_str: Option<String> = None;
+// 整词匹配的所有列号（1 起，按字符计）
+fn word_columns(line: &str, word: &str) -> Vec<usize> {
+    let mut cols = vec![];
+    let mut from = 0;
+    while let Some(i) = line[from..].find(word).map(|i| from + i) {
+        let before = line[..i].chars().next_back();
+        let after = line[i + word.len()..].chars().next();
+        if !before.is_some_and(is_ident_char) && !after.is_some_and(is_ident_char) {
+            cols.push(line[..i].chars().count() + 1);
+        }
+        from = i + word.len();
+    }
+    cols
+}
 
-    if let (Some(file_path), Some(line_num)) = (&args.file, &args.line) {
-        // === 行号定位模式 ===
-        // 找到包含该行的符号（line_start <= line <= line_end）
-        let mut stmt = conn.prepare(
-            "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type 
-             FROM symbols JOIN files ON symbols.file_id = files.file_id 
-             WHERE file_path LIKE ?1 AND line_start <= ?2 AND line_end >= ?2
-             ORDER BY (line_end - line_start) ASC
-             LIMIT 1",
-        )?;
-        // 使用 LIKE 模糊匹配文件路径（支持相对路径）
-        let file_pattern = format!("%{}", file_path.replace("\\", "/"));
-        found = stmt
-            .query_row(params![file_pattern, line_num], |row| {
-                Ok(Node {
-                    id: row.get::<_, String>(0)?,
-                    name: row.get(1)?,
-                    qualified_name: row.get(2)?,
-                    file_path: row.get(3)?,
-                    line_start: row.get(4)?,
-                    line_end: row.get(5)?,
-                    node_type: row.get(6)?,
-                    signature: None,
-                    calls: vec![],
-                })
-            })
-            .optional()?;
-    } else if let Some(query_str) = &args.query {
-        // === 渐进式容错匹配（多候选） ===
-        let (best_match, cands, _success) = progressive_search_multi(&conn, query_str);
-        found = best_match.clone().map(|(node, _)| node);
-        candidates = cands;
-        match_type_str = best_match.map(|(_, mt)| mt);
-    } else {
-        // 无查询条件
-        found = None;
-        candidates = vec![];
-        match_type_str = None;
+fn run_rename_plan(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], None)?;
+    let roots = project_roots(args)?;
+
+    let id = args
+        .id
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("rename-plan requires --id <symbol id>"))?;
+    let to = args
+        .to
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("rename-plan requires --to <new name>"))?;
+    if to.is_empty()
+        || to.starts_with(|c: char| c.is_ascii_digit())
+        || !to.chars().all(|c| is_ident_char(c) || c == '$')
+    {
+        anyhow::bail!("--to '{}' is not a valid identifier", to);
     }
+    let canonical = resolve_canonical_id(&conn, id)?
+        .ok_or_else(|| anyhow::anyhow!("unknown symbol id '{}'", id))?;
 
-    // 查找调用者（保持原有逻辑）
-    let mut related = vec![];
-    if let Some(ref sym) = found {
-        let mut call_stmt = conn.prepare(
-            "SELECT s.canonical_id, s.name, s.qualified_name, f.file_path, s.line_start, s.line_end, s.symbol_type 
-             FROM calls c 
-             JOIN symbols s ON c.caller_id = s.symbol_id 
-             JOIN files f ON s.file_id = f.file_id
-             WHERE c.callee_id = ?1 OR (c.callee_id IS NULL AND c.callee_name = ?2)"
+    // 同一 canonical_id 可能有多处定义（重载、同文件同名方法）
+    let mut definitions_rows: Vec<(String, usize, String, Option<String>)> = vec![];
+    {
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, s.line_start, s.name, s.scope_path
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE s.canonical_id = ?1 ORDER BY f.file_path, s.line_start",
         )?;
+        let rows = stmt.query_map([&canonical], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+        })?;
+        definitions_rows.extend(rows.flatten());
+    }
+    let Some((def_file, _, name, scope_path)) = definitions_rows.first().cloned() else {
+        anyhow::bail!("unknown symbol id '{}'", id);
+    };
 
-        let rows = call_stmt.query_map(params![sym.id.clone(), sym.name.clone()], |row| {
-            Ok(CallerInfo {
-                node: Node {
-                    id: row.get::<_, String>(0)?,
-                    name: row.get(1)?,
-                    qualified_name: row.get(2)?,
-                    file_path: row.get(3)?,
-                    line_start: row.get(4)?,
-                    line_end: row.get(5)?,
-                    node_type: row.get(6)?,
-                    signature: None,
-                    calls: vec![],
-                },
-                call_type: "direct".to_string(),
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    let mut seen: HashSet<(String, usize, Option<usize>)> = HashSet::new();
+    let mut site = |file_path: &str, line: usize, kind: &str, reason: Option<String>| {
+        let lines = sources
+            .entry(file_path.to_string())
+            .or_insert_with(|| read_source_lines(&resolve_source_path(&roots, file_path)));
+        let context = lines
+            .get(line.saturating_sub(1))
+            .cloned()
+            .unwrap_or_default();
+        // 一行里出现几次就列几处（`f(x); f(y)`）；一次都找不到时保留整行
+        let mut cols: Vec<Option<usize>> = word_columns(&context, &name)
+            .into_iter()
+            .map(Some)
+            .collect();
+        if cols.is_empty() {
+            cols.push(None);
+        }
+        cols.into_iter()
+            .filter(|col| seen.insert((file_path.to_string(), line, *col)))
+            .map(|col| RenameSite {
+                file_path: file_path.to_string(),
+                line,
+                col,
+                context: context.trim().to_string(),
+                kind: kind.to_string(),
+                reason: reason.clone(),
             })
+            .collect::<Vec<_>>()
+    };
+
+    let definitions: Vec<RenameSite> = definitions_rows
+        .iter()
+        .flat_map(|(file, line, _, _)| site(file, *line, "definition", None))
+        .collect();
+
+    let mut references = vec![];
+    let mut dynamic = vec![];
+
+    // 已链接的调用 / 引用边
+    {
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, c.call_line, c.kind FROM calls c
+             JOIN symbols s ON c.caller_id = s.symbol_id JOIN files f ON s.file_id = f.file_id
+             WHERE c.callee_id = ?1 ORDER BY f.file_path, c.call_line",
+        )?;
+        let rows = stmt.query_map([&canonical], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, usize>(1)?,
+                r.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        for (file, line, kind) in rows.flatten() {
+            let kind = match kind.as_deref() {
+                Some("reference" | "decorator") => "reference",
+                _ => "call",
+            };
+            references.extend(site(&file, line, kind, None));
+        }
+    }
+    // 继承子句：extends / implements 写的是类型名
+    {
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, s.line_start FROM inherits i
+             JOIN symbols s ON i.child_id = s.symbol_id JOIN files f ON s.file_id = f.file_id
+             WHERE i.base_id = ?1 ORDER BY f.file_path, s.line_start",
+        )?;
+        let rows = stmt.query_map([&canonical], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, usize>(1)?))
+        })?;
+        for (file, line) in rows.flatten() {
+            references.extend(site(&file, line, "inherit", None));
+        }
+    }
+    // 导入：路径末段与定义文件同名时算确定引用，否则只是同名导入
+    {
+        let def_stem = Path::new(&def_file)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, i.line, i.imported_path FROM imports i
+             JOIN files f ON i.file_id = f.file_id
+             WHERE i.imported_symbol = ?1 ORDER BY f.file_path, i.line",
+        )?;
+        let rows = stmt.query_map([&name], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, Option<usize>>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        })?;
+        for (file, line, imported_path) in rows.flatten() {
+            let Some(line) = line else {
+                continue;
+            };
+            let last = imported_path
+                .rsplit(['/', '.', ':', '\\'])
+                .find(|seg| !seg.is_empty())
+                .unwrap_or("");
+            if last == def_stem {
+                references.extend(site(&file, line, "import", None));
+            } else {
+                dynamic.extend(site(
+                    &file,
+                    line,
+                    "import",
+                    Some(format!(
+                        "imported from '{}', not resolved to {}",
+                        imported_path, def_file
+                    )),
+                ));
+            }
+        }
+    }
+    // 未链接的同名调用：接收者类型未知，可能落在这个符号上
+    {
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, c.call_line FROM calls c
+             JOIN symbols s ON c.caller_id = s.symbol_id JOIN files f ON s.file_id = f.file_id
+             WHERE c.callee_id IS NULL AND c.callee_name = ?1 ORDER BY f.file_path, c.call_line",
+        )?;
+        let rows = stmt.query_map([&name], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, usize>(1)?))
+        })?;
+        for (file, line) in rows.flatten() {
+            dynamic.extend(site(
+                &file,
+                line,
+                "unresolved",
+                Some("call with the same name was not linked to any definition".to_string()),
+            ));
+        }
+    }
+    // 方法：父 / 子类型里的同名方法是重写关系，改名要一起改
+    let owner = scope_path
+        .as_deref()
+        .and_then(|p| scope_owner(p, &name))
+        .map(str::to_string);
+    if let (Some(owner), true) = (owner, canonical.starts_with("func:")) {
+        let mut edges: Vec<(String, String)> = vec![];
+        {
+            let mut stmt = conn.prepare(
+                "SELECT s.name, i.base_name FROM inherits i JOIN symbols s ON s.symbol_id = i.child_id",
+            )?;
+            let rows =
+                stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+            edges.extend(rows.flatten());
+        }
+        let mut related: BTreeSet<String> = BTreeSet::new();
+        let mut frontier = vec![owner.clone()];
+        while let Some(t) = frontier.pop() {
+            for (child, base) in &edges {
+                for next in [(base == &t).then_some(child), (child == &t).then_some(base)]
+                    .into_iter()
+                    .flatten()
+                {
+                    if next != &owner && related.insert(next.clone()) {
+                        frontier.push(next.clone());
+                    }
+                }
+            }
+        }
+        let mut stmt = conn.prepare(
+            "SELECT f.file_path, s.line_start, s.scope_path, s.canonical_id
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE s.name = ?1 AND s.canonical_id LIKE 'func:%' AND s.canonical_id != ?2
+             ORDER BY f.file_path, s.line_start",
+        )?;
+        let rows = stmt.query_map(params![name, canonical], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, usize>(1)?,
+                r.get::<_, Option<String>>(2)?,
+                r.get::<_, String>(3)?,
+            ))
         })?;
-
-        for r in rows {
-            if let Ok(info) = r {
-                related.push(info);
+        for (file, line, other_scope, other_id) in rows.flatten() {
+            let Some(other_owner) = other_scope.as_deref().and_then(|p| scope_owner(p, &name))
+            else {
+                continue;
+            };
+            if related.contains(other_owner) {
+                dynamic.extend(site(
+                    &file,
+                    line,
+                    "override",
+                    Some(format!(
+                        "{} overrides or is overridden by {}",
+                        other_id, canonical
+                    )),
+                ));
             }
         }
     }
 
-    // 输出结果
+    // 撞名：同文件里已有 --to，或同一作用域下已有同名成员
+    let parent_scope = scope_path
+        .as_deref()
+        .and_then(|p| p.strip_suffix(name.as_str()))
+        .unwrap_or("")
+        .to_string();
+    let mut conflicts: Vec<String> = {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT s.canonical_id FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE s.name = ?1 AND (f.file_path = ?2 OR s.scope_path = ?3)",
+        )?;
+        let rows = stmt.query_map(
+            params![to, def_file, format!("{}{}", parent_scope, to)],
+            |r| r.get::<_, String>(0),
+        )?;
+        rows.flatten().collect()
+    };
+    conflicts.sort();
+    for sites in [&mut references, &mut dynamic] {
+        sites.sort_by(|a, b| (&a.file_path, a.line, a.col).cmp(&(&b.file_path, b.line, b.col)));
+    }
+
     if let Some(out_path) = &args.output {
-        let res = QueryResult {
+        let res = RenamePlan {
             status: "success".to_string(),
-            query: args.query.clone().unwrap_or_default(),
-            found_symbol: found,
-            match_type: match_type_str,
-            candidates: candidates,
-            related_nodes: related,
+            generation: read_generation(&conn)?,
+            id: canonical,
+            name,
+            to: to.to_string(),
+            definitions,
+            references,
+            dynamic,
+            conflicts,
         };
         let f = fs::File::create(out_path)?;
         serde_json::to_writer(f, &res)?;
@@ -1636,140 +16420,267 @@ fn run_query(args: &Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+// ============================================================================
+// Call-site Compatibility (调用点兼容性检查)
+// 签名改动后，用索引里记下的实参个数 / 关键字名 / 接收者类型逐个核对调用点，
+// 列出按新参数表会出错的调用。重载（同一 canonical_id 多处定义）任一匹配即算兼容
+// ============================================================================
+
 #[derive(Serialize)]
-struct MapResult {
-    statistics: Stats,
-    structure: HashMap<String, Vec<Node>>,
-    elapsed: String,
+struct CallsiteCheck {
+    status: String,
+    generation: i64,
+    id: String,
+    name: String,
+    signatures: Vec<String>,
+    checked: usize,
+    unchecked: usize, // 展开实参、文本抽取器语言、JS（实参个数不受约束）等无法核对的调用
+    broken: Vec<BrokenCallsite>,
 }
 
-#[derive(Serialize, Default)]
-struct Stats {
-    total_files: usize,
-    total_symbols: usize,
+#[derive(Serialize)]
+struct BrokenCallsite {
+    file_path: String,
+    line: usize,
+    caller_id: String,
+    context: String,
+    problems: Vec<String>,
 }
 
-fn run_map(args: &Args) -> anyhow::Result<()> {
-    let conn = Connection::open(&args.db)?;
-
-    // Stats
-    let mut stats = Stats::default();
-
-    // Structure
-    let mut structure: HashMap<String, Vec<Node>> = HashMap::new();
-
-    // 🆕 修改：添加 canonical_id 和 signature 字段
-    let sql_base = "SELECT file_path, name, qualified_name, symbol_type, line_start, line_end, canonical_id, signature FROM symbols JOIN files ON symbols.file_id = files.file_id";
-
-    if let Some(scope) = &args.scope {
-        if !scope.is_empty() {
-            // === 有 Scope 过滤 ===
-            let pattern = format!("{}%", scope.replace("\\", "/"));
-
-            // Stats (Scoped)
-            stats.total_files = conn
-                .query_row(
-                    "SELECT count(*) FROM files WHERE file_path LIKE ?1",
-                    [&pattern],
-                    |r| r.get(0),
-                )
-                .unwrap_or(0);
-            stats.total_symbols = conn.query_row("SELECT count(*) FROM symbols JOIN files ON symbols.file_id = files.file_id WHERE file_path LIKE ?1", [&pattern], |r| r.get(0)).unwrap_or(0);
+// 不由调用方显式传入的首参：Python self / cls、Rust self、TS this 形参
+fn implicit_receiver_param(p: &SignatureParam) -> bool {
+    matches!(
+        p.name.as_str(),
+        "self" | "cls" | "&self" | "&mut self" | "mut self" | "this"
+    )
+}
 
-            let sql = format!("{} WHERE file_path LIKE ?1", sql_base);
-            let mut stmt = conn.prepare(&sql)?;
-            let rows = stmt.query_map([&pattern], |row| {
-                Ok((
-                    row.get::<_, String>(0)?, // file_path
-                    Node {
-                        id: row.get::<_, String>(6)?, // 🆕 canonical_id as ID (规范字符串)
-                        name: row.get(1)?,
-                        qualified_name: row.get(2)?,
-                        file_path: row.get(0)?,
-                        line_start: row.get(4)?,
-                        line_end: row.get(5)?,
-                        node_type: row.get(3)?,
-                        signature: row.get(7)?, // 🆕 从数据库读取签名
-                        calls: vec![],
-                    },
-                ))
-            })?;
+// 按一份参数表核对一次调用；返回问题列表，空表示兼容
+fn arity_problems(shape: &SignatureShape, args: &CallArgs) -> Vec<String> {
+    let mut params: Vec<&SignatureParam> = shape
+        .params
+        .iter()
+        .skip_while(|p| implicit_receiver_param(p))
+        // Python 仅位置参数分隔符 `/`；C `f(void)`
+        .filter(|p| p.name != "/" && !(p.name == "void" && p.param_type.is_none()))
+        .collect();
+    if params.len() == 1 && params[0].param_type.as_deref() == Some("void") {
+        params.clear();
+    }
 
-            for r in rows {
-                if let Ok((path, node)) = r {
-                    structure.entry(path).or_default().push(node);
-                }
+    let mut positional: Vec<(&str, bool)> = vec![]; // (名字, 可省略)
+    let mut keyword_only: Vec<(&str, bool)> = vec![];
+    let mut max_positional = Some(0usize);
+    let mut accepts_kwargs = false;
+    let mut after_star = false;
+    for p in params {
+        match p.kind.as_deref() {
+            Some("variadic") => {
+                max_positional = None;
+                after_star = true;
             }
-        } else {
-            // === Scope 为空字符串，视为全量 ===
-            stats.total_files = conn
-                .query_row("SELECT count(*) FROM files", [], |r| r.get(0))
-                .unwrap_or(0);
-            stats.total_symbols = conn
-                .query_row("SELECT count(*) FROM symbols", [], |r| r.get(0))
-                .unwrap_or(0);
-
-            let mut stmt = conn.prepare(sql_base)?;
-            let rows = stmt.query_map([], |row| {
-                Ok((
-                    row.get::<_, String>(0)?,
-                    Node {
-                        id: row.get::<_, String>(6)?,
-                        name: row.get(1)?,
-                        qualified_name: row.get(2)?,
-                        file_path: row.get(0)?,
-                        line_start: row.get(4)?,
-                        line_end: row.get(5)?,
-                        node_type: row.get(3)?,
-                        signature: row.get(7)?, // 🆕
-                        calls: vec![],
-                    },
-                ))
-            })?;
-            for r in rows {
-                if let Ok((path, node)) = r {
-                    structure.entry(path).or_default().push(node);
+            Some("kwargs") => accepts_kwargs = true,
+            // Python 裸 `*`：之后的参数只能按关键字传
+            _ if p.name == "*" => after_star = true,
+            kind => {
+                let optional = kind == Some("optional");
+                if after_star {
+                    keyword_only.push((&p.name, optional));
+                } else {
+                    positional.push((&p.name, optional));
+                    max_positional = max_positional.map(|m| m + 1);
                 }
             }
         }
-    } else {
-        // === 无 Scope 参数，视为全量 ===
-        stats.total_files = conn
-            .query_row("SELECT count(*) FROM files", [], |r| r.get(0))
-            .unwrap_or(0);
-        stats.total_symbols = conn
-            .query_row("SELECT count(*) FROM symbols", [], |r| r.get(0))
-            .unwrap_or(0);
+    }
 
-        let mut stmt = conn.prepare(sql_base)?;
-        let rows = stmt.query_map([], |row| {
+    let mut problems = vec![];
+    if let Some(max) = max_positional.filter(|m| args.positional > *m) {
+        problems.push(format!(
+            "too many arguments: {} given, at most {}",
+            args.positional, max
+        ));
+    }
+    for kw in &args.keywords {
+        let slot = positional.iter().position(|(n, _)| n == kw);
+        if slot.is_some_and(|i| i < args.positional) {
+            problems.push(format!("multiple values for argument '{}'", kw));
+        } else if slot.is_none() && !keyword_only.iter().any(|(n, _)| n == kw) && !accepts_kwargs {
+            problems.push(format!("unknown keyword argument '{}'", kw));
+        }
+    }
+    let missing: Vec<&str> = positional
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i >= args.positional)
+        .map(|(_, p)| *p)
+        .chain(keyword_only.iter().copied())
+        .filter(|(n, optional)| !optional && !args.keywords.iter().any(|k| k == n))
+        .map(|(n, _)| n)
+        .collect();
+    if !missing.is_empty() {
+        problems.push(format!("missing arguments: {}", missing.join(", ")));
+    }
+    problems
+}
+
+fn run_check_callsites(args: &Args) -> anyhow::Result<()> {
+    let conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], None)?;
+    let roots = project_roots(args)?;
+
+    let id = args
+        .id
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("check-callsites requires --id <symbol id>"))?;
+    let canonical = resolve_canonical_id(&conn, id)?
+        .ok_or_else(|| anyhow::anyhow!("unknown symbol id '{}'", id))?;
+
+    // 所有定义（重载）的签名与所属类型
+    let mut name = String::new();
+    let mut signatures = vec![];
+    let mut shapes: Vec<SignatureShape> = vec![];
+    let mut owners: BTreeSet<String> = BTreeSet::new();
+    let mut loose_arity = false;
+    {
+        let mut stmt = conn.prepare(
+            "SELECT s.name, s.signature, s.signature_shape, s.scope_path, f.language
+             FROM symbols s JOIN files f ON s.file_id = f.file_id
+             WHERE s.canonical_id = ?1 ORDER BY f.file_path, s.line_start",
+        )?;
+        let rows = stmt.query_map([&canonical], |r| {
             Ok((
-                row.get::<_, String>(0)?,
-                Node {
-                    id: row.get::<_, String>(6)?,
-                    name: row.get(1)?,
-                    qualified_name: row.get(2)?,
-                    file_path: row.get(0)?,
-                    line_start: row.get(4)?,
-                    line_end: row.get(5)?,
-                    node_type: row.get(3)?,
-                    signature: row.get(7)?, // 🆕
-                    calls: vec![],
-                },
+                r.get::<_, String>(0)?,
+                r.get::<_, Option<String>>(1)?,
+                r.get::<_, Option<String>>(2)?,
+                r.get::<_, Option<String>>(3)?,
+                r.get::<_, String>(4)?,
             ))
         })?;
-        for r in rows {
-            if let Ok((path, node)) = r {
-                structure.entry(path).or_default().push(node);
+        for (n, signature, shape, scope_path, language) in rows.flatten() {
+            signatures.extend(signature);
+            shapes.extend(shape.and_then(|s| serde_json::from_str(&s).ok()));
+            owners.extend(
+                scope_path
+                    .as_deref()
+                    .and_then(|p| scope_owner(p, &n))
+                    .map(str::to_string),
+            );
+            // JS 多传少传都不报错，只核对接收者（files.language 存的是扩展名）
+            loose_arity |= matches!(language.as_str(), "js" | "mjs" | "cjs" | "jsx");
+            name = n;
+        }
+    }
+    if name.is_empty() {
+        anyhow::bail!("unknown symbol id '{}'", id);
+    }
+
+    // 接收者可以是所属类型的任意子类型
+    if !owners.is_empty() {
+        let mut stmt = conn.prepare(
+            "SELECT s.name, i.base_name FROM inherits i JOIN symbols s ON s.symbol_id = i.child_id",
+        )?;
+        let edges: Vec<(String, String)> = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .flatten()
+            .collect();
+        let mut frontier: Vec<String> = owners.iter().cloned().collect();
+        while let Some(t) = frontier.pop() {
+            for (child, base) in &edges {
+                if base == &t && owners.insert(child.clone()) {
+                    frontier.push(child.clone());
+                }
             }
         }
-    };
+    }
+
+    let mut checked = 0;
+    let mut unchecked = 0;
+    let mut broken = vec![];
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "SELECT f.file_path, c.call_line, s.canonical_id, c.receiver_type, c.arg_count, c.arg_keywords
+         FROM calls c
+         JOIN symbols s ON c.caller_id = s.symbol_id JOIN files f ON s.file_id = f.file_id
+         WHERE c.callee_id = ?1 AND c.kind = 'call'
+         ORDER BY f.file_path, c.call_line, c.call_id",
+    )?;
+    let rows = stmt.query_map([&canonical], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, usize>(1)?,
+            r.get::<_, String>(2)?,
+            r.get::<_, Option<String>>(3)?,
+            r.get::<_, Option<usize>>(4)?,
+            r.get::<_, Option<String>>(5)?,
+        ))
+    })?;
+    for (file_path, line, caller_id, receiver_type, arg_count, arg_keywords) in rows.flatten() {
+        let mut problems = vec![];
+        if let Some(recv) = receiver_type.filter(|r| !owners.is_empty() && !owners.contains(r)) {
+            problems.push(format!(
+                "receiver type {} is not {}",
+                recv,
+                owners.iter().cloned().collect::<Vec<_>>().join(" / ")
+            ));
+        }
+        match arg_count.filter(|_| !shapes.is_empty() && !loose_arity) {
+            Some(positional) => {
+                checked += 1;
+                let call_args = CallArgs {
+                    positional,
+                    keywords: arg_keywords
+                        .map(|k| k.split(',').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                };
+                let per_shape: Vec<Vec<String>> = shapes
+                    .iter()
+                    .map(|s| arity_problems(s, &call_args))
+                    .collect();
+                if per_shape.len() > 1 && !per_shape.iter().any(Vec::is_empty) {
+                    problems.push(format!(
+                        "no overload accepts {} positional argument(s){}",
+                        positional,
+                        match call_args.keywords.is_empty() {
+                            true => String::new(),
+                            false => format!(" and keywords {}", call_args.keywords.join(", ")),
+                        }
+                    ));
+                } else if !per_shape.iter().any(Vec::is_empty) {
+                    problems.extend(per_shape.into_iter().next().unwrap_or_default());
+                }
+            }
+            None => unchecked += 1,
+        }
+        if problems.is_empty() {
+            continue;
+        }
+        let lines = sources
+            .entry(file_path.clone())
+            .or_insert_with(|| read_source_lines(&resolve_source_path(&roots, &file_path)));
+        broken.push(BrokenCallsite {
+            context: lines
+                .get(line.saturating_sub(1))
+                .map(|l| l.trim().to_string())
+                .unwrap_or_default(),
+            file_path,
+            line,
+            caller_id,
+            problems,
+        });
+    }
 
     if let Some(out_path) = &args.output {
-        let res = MapResult {
-            statistics: stats,
-            structure,
-            elapsed: "0s".to_string(),
+        let res = CallsiteCheck {
+            status: "success".to_string(),
+            generation: read_generation(&conn)?,
+            id: canonical,
+            name,
+            signatures,
+            checked,
+            unchecked,
+            broken,
         };
         let f = fs::File::create(out_path)?;
         serde_json::to_writer(f, &res)?;
@@ -1778,831 +16689,1971 @@ fn run_map(args: &Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_parser_setup() -> HashMap<String, (Language, Query)> {
-    let mut map = HashMap::new();
-
-    // Python
-    let py_lang = tree_sitter_python::language();
-    let py_query = Query::new(
-        py_lang,
-        r#"
-        (function_definition name: (identifier) @name) @def.func
-        (class_definition name: (identifier) @name) @def.class
-        (call function: (identifier) @callee) @ref.call
-        (call function: (attribute attribute: (identifier) @callee)) @ref.call
-    "#,
-    )
-    .expect("Invalid Python Query");
-    map.insert("py".to_string(), (py_lang, py_query));
-
-    // JS
-    let js_lang = tree_sitter_javascript::language();
-    let js_query_str = r#"
-        (function_declaration name: (identifier) @name) @def.func
-        (class_declaration name: (identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-        (call_expression function: (member_expression property: (property_identifier) @callee)) @ref.call
-    "#;
-    let js_query = Query::new(js_lang, js_query_str).expect("Invalid JS Query");
-    map.insert("js".to_string(), (js_lang, js_query));
-
-    // Node.js ES Modules (.mjs)
-    let mjs_query = Query::new(js_lang, js_query_str).expect("Invalid JS Query");
-    map.insert("mjs".to_string(), (js_lang, mjs_query));
-
-    // Node.js CommonJS (.cjs)
-    let cjs_query = Query::new(js_lang, js_query_str).expect("Invalid JS Query");
-    map.insert("cjs".to_string(), (js_lang, cjs_query));
-
-    // TypeScript (.ts, .tsx)
-    let ts_lang = tree_sitter_typescript::language_typescript();
-    let ts_query_str = r#"
-        (function_declaration name: (identifier) @name) @def.func
-        (class_declaration name: (type_identifier) @name) @def.class
-        (method_definition name: (property_identifier) @name) @def.func
-        (call_expression function: (identifier) @callee) @ref.call
-        (call_expression function: (member_expression property: (property_identifier) @callee)) @ref.call
-    "#;
-    let ts_query = Query::new(ts_lang, ts_query_str).expect("Invalid TypeScript Query");
-    map.insert("ts".to_string(), (ts_lang, ts_query));
-
-    // TSX (TypeScript + JSX)
-    let tsx_lang = tree_sitter_typescript::language_tsx();
-    let tsx_query = Query::new(tsx_lang, ts_query_str).expect("Invalid TSX Query");
-    map.insert("tsx".to_string(), (tsx_lang, tsx_query));
-
-    // Go
-    let go_lang = tree_sitter_go::language();
-    let go_query = Query::new(go_lang, r#"
-        (function_declaration name: (identifier) @name) @def.func
-        (method_declaration name: (field_identifier) @name) @def.func
-        (type_spec name: (type_identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-        (call_expression function: (selector_expression field: (field_identifier) @callee)) @ref.call
-    "#).expect("Invalid Go Query");
-    map.insert("go".to_string(), (go_lang, go_query));
-
-    // Rust
-    let rs_lang = tree_sitter_rust::language();
-    let rs_query = Query::new(
-        rs_lang,
-        r#"
-        (function_item name: (identifier) @name) @def.func
-        (struct_item name: (type_identifier) @name) @def.class
-        (enum_item name: (type_identifier) @name) @def.class
-        (impl_item type: (type_identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-        (call_expression function: (scoped_identifier name: (identifier) @callee)) @ref.call
-        (call_expression function: (field_expression field: (field_identifier) @callee)) @ref.call
-    "#,
-    )
-    .expect("Invalid Rust Query");
-    map.insert("rs".to_string(), (rs_lang, rs_query));
+// ============================================================================
+// License Provenance (许可证来源)
+// 文件头的 SPDX 标识 / 常见许可证正文 + 版权行；根目录 LICENSE 作为项目许可证对照
+// ============================================================================
 
-    // Java
-    let java_lang = tree_sitter_java::language();
-    let java_query = Query::new(
-        java_lang,
-        r#"
-        (class_declaration name: (identifier) @name) @def.class
-        (method_declaration name: (identifier) @name) @def.func
-        (interface_declaration name: (identifier) @name) @def.class
-        (method_invocation name: (identifier) @callee) @ref.call
-    "#,
-    )
-    .expect("Invalid Java Query");
-    map.insert("java".to_string(), (java_lang, java_query));
+// 只看文件头：许可证声明几乎总在前几十行
+const LICENSE_HEADER_LINES: usize = 30;
 
-    // C
-    let c_lang = tree_sitter_c::language();
-    let c_query = Query::new(c_lang, r#"
-        (function_definition declarator: (function_declarator declarator: (identifier) @name)) @def.func
-        (struct_specifier name: (type_identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-    "#).expect("Invalid C Query");
-    map.insert("c".to_string(), (c_lang, c_query));
+// 小写并去掉注释符号，使跨行 / 带 `//` `#` `*` 前缀的正文可以按短语匹配
+fn normalize_license_text(lines: &[&str]) -> String {
+    lines
+        .iter()
+        .flat_map(|l| l.split_whitespace())
+        .filter(|w| !w.chars().all(|c| "/*#-;!<>".contains(c)))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
 
-    // Re-create query for headers (Query is not Clone)
-    let c_query_h = Query::new(c_lang, r#"
-        (function_definition declarator: (function_declarator declarator: (identifier) @name)) @def.func
-        (struct_specifier name: (type_identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-    "#).expect("Invalid C Query");
-    map.insert("h".to_string(), (c_lang, c_query_h));
+// 返回 (SPDX 标识, 版权人)；SPDX-License-Identifier 行优先，其次按正文特征识别
+fn detect_license(lines: &[&str]) -> (String, String) {
+    let spdx = lines.iter().find_map(|l| {
+        let at = l.find("SPDX-License-Identifier:")?;
+        let id = l[at + "SPDX-License-Identifier:".len()..]
+            .trim()
+            .trim_end_matches("*/")
+            .trim_end_matches("-->")
+            .trim();
+        (!id.is_empty()).then(|| id.to_string())
+    });
+    let license = spdx.unwrap_or_else(|| match_license_text(&normalize_license_text(lines)));
+    let copyright = lines
+        .iter()
+        .find_map(|l| copyright_owner(l))
+        .unwrap_or_default();
+    (license, copyright)
+}
 
-    // C++
-    let cpp_lang = tree_sitter_cpp::language();
-    let cpp_query_str = r#"
-        (function_definition declarator: (function_declarator declarator: (identifier) @name)) @def.func
-        (class_specifier name: (type_identifier) @name) @def.class
-        (struct_specifier name: (type_identifier) @name) @def.class
-        (call_expression function: (identifier) @callee) @ref.call
-        (call_expression function: (field_expression field: (field_identifier) @callee)) @ref.call
-    "#;
+fn match_license_text(text: &str) -> String {
+    let version = |v2: &'static str, v3: &'static str| {
+        if text.contains("version 3") {
+            v3
+        } else {
+            v2
+        }
+    };
+    let id = if text.contains("apache license") && text.contains("version 2.0") {
+        "Apache-2.0"
+    } else if text.contains("gnu affero general public license") {
+        "AGPL-3.0"
+    } else if text.contains("gnu lesser general public license")
+        || text.contains("gnu library general public license")
+    {
+        version("LGPL-2.1", "LGPL-3.0")
+    } else if text.contains("gnu general public license") {
+        version("GPL-2.0", "GPL-3.0")
+    } else if text.contains("mozilla public license") && text.contains("2.0") {
+        "MPL-2.0"
+    } else if text.contains("permission is hereby granted, free of charge") {
+        "MIT"
+    } else if text.contains("redistribution and use in source and binary forms") {
+        if text.contains("neither the name") {
+            "BSD-3-Clause"
+        } else {
+            "BSD-2-Clause"
+        }
+    } else if text.contains("permission to use, copy, modify, and/or distribute") {
+        "ISC"
+    } else if text.contains("this is free and unencumbered software") {
+        "Unlicense"
+    } else {
+        ""
+    };
+    id.to_string()
+}
+
+// "Copyright (c) 2019-2024 Jane Doe. All rights reserved." -> "Jane Doe"；
+// 正文里的 "the above copyright notice" 不算（必须跟 (c) / © / 年份）
+fn copyright_owner(line: &str) -> Option<String> {
+    let lower = line.to_lowercase();
+    let rest = match lower.find("copyright") {
+        Some(at) => &line[at + "copyright".len()..],
+        None => &line[line.find('©')?..],
+    };
+    let rest = rest.trim_start();
+    let marked = rest.starts_with("(c)") || rest.starts_with("(C)") || rest.starts_with('©');
+    let rest = rest
+        .trim_start_matches("(c)")
+        .trim_start_matches("(C)")
+        .trim_start_matches('©')
+        .trim_start();
+    if !marked && !rest.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit() || " ,-–".contains(c));
+    let end = rest
+        .to_lowercase()
+        .find("all rights reserved")
+        .unwrap_or(rest.len());
+    let owner = rest[..end]
+        .trim()
+        .trim_end_matches("*/")
+        .trim_end_matches("-->")
+        .trim_end_matches(['.', ',', ' '])
+        .trim();
+    (!owner.is_empty()).then(|| owner.to_string())
+}
 
-    let cpp_query = Query::new(cpp_lang, cpp_query_str).expect("Invalid C++ Query");
-    map.insert("cpp".to_string(), (cpp_lang, cpp_query));
+// 根目录下的 LICENSE* / LICENCE* / COPYING*；多个时取第一个识别成功的
+fn project_license(root: &Path) -> String {
+    let Ok(entries) = fs::read_dir(root) else {
+        return String::new();
+    };
+    let mut candidates: Vec<PathBuf> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            let name = p
+                .file_name()
+                .map(|n| n.to_string_lossy().to_uppercase())
+                .unwrap_or_default();
+            p.is_file()
+                && ["LICENSE", "LICENCE", "COPYING"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+        .iter()
+        .filter_map(|p| decode_source(&fs::read(p).ok()?))
+        .map(|text| {
+            let lines: Vec<&str> = text.lines().take(LICENSE_HEADER_LINES).collect();
+            detect_license(&lines).0
+        })
+        .find(|id| !id.is_empty())
+        .unwrap_or_default()
+}
 
-    let cpp_query_cc = Query::new(cpp_lang, cpp_query_str).expect("Invalid C++ Query");
-    map.insert("cc".to_string(), (cpp_lang, cpp_query_cc));
+// SPDX 表达式 "MIT OR Apache-2.0" 的任一分支与项目许可证相同即视为一致
+fn license_matches(file: &str, project: &str) -> bool {
+    let split = |s: &str| -> Vec<String> {
+        s.trim_matches(['(', ')'])
+            .split(" OR ")
+            .map(|p| p.trim().to_string())
+            .collect()
+    };
+    let project = split(project);
+    split(file).iter().any(|f| project.contains(f))
+}
 
-    let cpp_query_hpp = Query::new(cpp_lang, cpp_query_str).expect("Invalid C++ Query");
-    map.insert("hpp".to_string(), (cpp_lang, cpp_query_hpp));
+#[derive(Serialize, Default)]
+struct LicenseReport {
+    composition: BTreeMap<String, usize>, // 许可证 -> 文件数（空串为未声明）
+    project: BTreeMap<String, String>,    // root 名 -> 项目许可证
+    mismatched: Vec<LicenseMismatch>,
+}
 
-    // TODO: Kotlin, Swift, Ruby need tree-sitter version alignment
-    // Blocked by: tree-sitter-kotlin/swift/ruby require ts 0.22+ but other grammars are on 0.20
-    // Solution: Wait for all grammars to align, or fork/patch individual crates
+#[derive(Serialize)]
+struct LicenseMismatch {
+    file_path: String,
+    license: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    copyright: String,
+    project_license: String,
+}
 
-    map
+// map / stats 共用；pattern 为 file_path LIKE 前缀（map --scope），files 视图已按 --root / --prune-path 过滤
+fn license_report(conn: &Connection, pattern: Option<&str>) -> Result<LicenseReport> {
+    let pattern = pattern.unwrap_or("%");
+    let mut report = LicenseReport::default();
+    {
+        let mut stmt = conn.prepare("SELECT name, license FROM roots")?;
+        let rows = stmt.query_map([], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            ))
+        })?;
+        report.project.extend(rows.flatten());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT file_path, root, license, copyright FROM files
+         WHERE file_path LIKE ?1 AND index_level = 'symbol' ORDER BY file_path",
+    )?;
+    let rows = stmt.query_map([pattern], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        ))
+    })?;
+    for (file_path, root, license, copyright) in rows.flatten() {
+        *report.composition.entry(license.clone()).or_default() += 1;
+        let project_license = report.project.get(&root).cloned().unwrap_or_default();
+        if !license.is_empty()
+            && !project_license.is_empty()
+            && !license_matches(&license, &project_license)
+        {
+            report.mismatched.push(LicenseMismatch {
+                file_path,
+                license,
+                copyright,
+                project_license,
+            });
+        }
+    }
+    Ok(report)
 }
 
 // ============================================================================
-// Impact Analysis & Dice Algorithm (Rust Implementation)
+// Stats Mode - 索引概况 + generation（供 Go 侧缓存判定）
 // ============================================================================
 
 #[derive(Serialize)]
-struct AnalysisResult {
+struct StatsResult {
     status: String,
-    node_id: String,
-    complexity_score: f64,
-    complexity_level: String,
-    affected_nodes: usize,
-    direct_callers: Vec<CallerInfo>,
-    indirect_callers: Vec<CallerInfo>,
-    risk_level: String,
-    modification_checklist: Vec<String>,
+    generation: i64,
+    total_files: usize,
+    total_symbols: usize,
+    total_calls: usize,
+    total_routes: usize,
+    last_indexed_at: i64,
+    languages: BTreeMap<String, usize>,
+    index_levels: BTreeMap<String, usize>,
+    roots: BTreeMap<String, String>,
+    licenses: LicenseReport,
 }
 
-// 🆕 修改：使用 canonical_id
-fn run_analyze(args: &Args) -> anyhow::Result<()> {
+fn run_stats(args: &Args) -> anyhow::Result<()> {
     let conn = Connection::open(&args.db)?;
-    let query_str = args.query.as_ref().expect("Query required for analysis");
-
-    // 1. Locate Target Node (精确匹配优先，失败后模糊匹配)
-    // 先尝试精确匹配
-    let mut stmt = conn.prepare("SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type FROM symbols JOIN files ON symbols.file_id = files.file_id WHERE name = ?1 LIMIT 1")?;
-
-    let target_node = stmt
-        .query_row([query_str], |row| {
-            Ok(Node {
-                id: row.get::<_, String>(0)?, // 🆕 canonical_id
-                name: row.get(1)?,
-                qualified_name: row.get(2)?,
-                file_path: row.get(3)?,
-                line_start: row.get(4)?,
-                line_end: row.get(5)?,
-                node_type: row.get(6)?,
-                signature: None,
-                calls: vec![],
-            })
-        })
-        .optional()?
-        .or_else(|| {
-            // 精确匹配失败，尝试模糊匹配
-            let fuzzy_pattern = format!("%{}%", query_str);
-            let mut fuzzy_stmt = conn.prepare(
-            "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-             FROM symbols JOIN files ON symbols.file_id = files.file_id
-             WHERE name LIKE ?1 OR qualified_name LIKE ?1
-             LIMIT 1"
-        ).ok()?;
-            fuzzy_stmt
-                .query_row([fuzzy_pattern], |row| {
-                    Ok(Node {
-                        id: row.get::<_, String>(0)?, // 🆕 canonical_id
-                        name: row.get(1)?,
-                        qualified_name: row.get(2)?,
-                        file_path: row.get(3)?,
-                        line_start: row.get(4)?,
-                        line_end: row.get(5)?,
-                        node_type: row.get(6)?,
-                        signature: None,
-                        calls: vec![],
-                    })
-                })
-                .ok()
-        });
+    init_db(&conn)?;
 
-    let target = match target_node {
-        Some(n) => n,
-        None => {
-            // Return empty/error JSON
-            if let Some(out_path) = &args.output {
-                let err = serde_json::json!({"status": "error", "message": "Symbol not found"});
-                let f = fs::File::create(out_path)?;
-                serde_json::to_writer(f, &err)?;
-            }
-            return Ok(());
-        }
+    let count = |sql: &str| -> usize {
+        conn.query_row(sql, [], |r| r.get::<_, i64>(0))
+            .map(|n| n as usize)
+            .unwrap_or(0)
+    };
+    let group = |sql: &str| -> anyhow::Result<BTreeMap<String, usize>> {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([], |r| {
+            Ok((
+                r.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                r.get::<_, i64>(1)? as usize,
+            ))
+        })?;
+        Ok(rows.flatten().collect())
     };
 
-    // 🆕 target.id 现在是 canonical_id (String)，不再需要 parse
-    let target_id: String = target.id;
-
-    // 2. Build In-Memory Graph (Adjacency & Reverse Adjacency)
-    // For Dice: we need Outgoing edges (Calls).
-    // For Impact: we need Incoming edges (Called By).
-
-    // Query all calls: caller_id -> callee_id (优先) / callee_name (回退兼容)
-
-    println!("Building dependency graph...");
-
-    // 🆕 使用 canonical_id (String) 而不是 symbol_id (i64)
-    // Load all symbols into Map: Name -> Vec<canonical_id>
-    let mut name_to_ids: HashMap<String, Vec<String>> = HashMap::new();
+    let mut roots = BTreeMap::new();
     {
-        let mut s = conn.prepare("SELECT canonical_id, name FROM symbols")?; // 🆕 canonical_id
-        let rows = s.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?; // 🆕 String, String
-        for r in rows {
-            if let Ok((id, name)) = r {
-                name_to_ids.entry(name).or_default().push(id);
-            }
-        }
+        let mut stmt = conn.prepare("SELECT name, path FROM roots")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        roots.extend(rows.flatten());
     }
 
-    // Load all calls
-    // 🆕 使用 String (canonical_id) 而不是 i64 (symbol_id)
-    let mut adjacency: HashMap<String, Vec<String>> = HashMap::new(); // Caller -> Callee(s)
-    let mut reverse_adjacency: HashMap<String, Vec<String>> = HashMap::new(); // Callee -> Caller(s)
+    let res = StatsResult {
+        status: "success".to_string(),
+        generation: read_generation(&conn)?,
+        total_files: count("SELECT COUNT(*) FROM files"),
+        total_symbols: count("SELECT COUNT(*) FROM symbols"),
+        total_calls: count(
+            "SELECT COUNT(*) FROM calls WHERE kind NOT IN ('reference', 'render', 'decorator') OR callee_id IS NOT NULL",
+        ),
+        total_routes: count("SELECT COUNT(*) FROM routes"),
+        last_indexed_at: conn
+            .query_row("SELECT COALESCE(MAX(indexed_at), 0) FROM files", [], |r| {
+                r.get(0)
+            })
+            .unwrap_or(0),
+        languages: group("SELECT language, COUNT(*) FROM files GROUP BY language")?,
+        index_levels: group("SELECT index_level, COUNT(*) FROM files GROUP BY index_level")?,
+        roots,
+        licenses: license_report(&conn, None)?,
+    };
 
-    {
-        // JOIN symbols 获取 caller 的 canonical_id；callee 优先使用 c.callee_id
-        let mut s = conn.prepare("SELECT s.canonical_id, c.callee_id, c.callee_name FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id")?;
-        let rows = s.query_map([], |r| {
-            Ok((
-                r.get::<_, String>(0)?,
-                r.get::<_, Option<String>>(1)?,
-                r.get::<_, String>(2)?,
-            ))
-        })?;
-        for r in rows {
-            if let Ok((caller_canonical_id, callee_id_opt, callee_name)) = r {
-                if let Some(callee_id) = callee_id_opt {
-                    adjacency
-                        .entry(caller_canonical_id.clone())
-                        .or_default()
-                        .push(callee_id.clone());
-                    reverse_adjacency
-                        .entry(callee_id)
-                        .or_default()
-                        .push(caller_canonical_id.clone());
-                } else if let Some(callee_ids) = name_to_ids.get(&callee_name) {
-                    for callee_id in callee_ids {
-                        adjacency
-                            .entry(caller_canonical_id.clone())
-                            .or_default()
-                            .push(callee_id.clone());
-                        reverse_adjacency
-                            .entry(callee_id.clone())
-                            .or_default()
-                            .push(caller_canonical_id.clone());
-                    }
-                }
-            }
-        }
+    if let Some(out_path) = &args.output {
+        let f = fs::File::create(out_path)?;
+        serde_json::to_writer(f, &res)?;
     }
 
-    // 3. Impact Analysis (BFS)
-    let mut direct_nodes = Vec::new();
-    let mut indirect_nodes = Vec::new();
-    let mut affected_nodes = HashSet::new();
+    Ok(())
+}
 
-    let direction = args.direction.to_lowercase();
+// ============================================================================
+// SBOM Mode - 依赖清单（CycloneDX 1.5 / SPDX 2.3 JSON）
+// 直接读各项目根下的包管理清单（不依赖索引）；版本取清单里声明的写法，
+// 只有精确版本（1.2.3 / ==1.2.3）才写进 purl，范围约束（^1.2 / >=1）只出现在 version 字段
+// ============================================================================
 
-    // 我们定义“主方向图”
-    // 如果是 backward (影响分析)，我们需要找到“谁在调用我”，即使用 reverse_adjacency
-    // 如果是 forward (依赖分析)，我们需要找到“我在调用谁”，即使用 adjacency
-    let primary_graph = if direction == "forward" {
-        &adjacency
-    } else {
-        &reverse_adjacency // 默认 backward
-    };
+#[derive(Clone)]
+struct ManifestDependency {
+    ecosystem: &'static str, // purl type：npm / cargo / golang / pypi / composer / gem / maven / nuget
+    name: String,
+    version: Option<String>,
+    dev: bool, // 仅开发 / 测试期依赖（CycloneDX scope=optional，SPDX DEV_DEPENDENCY_OF）
+    manifest: String, // 声明它的清单（相对路径，多根时带根名前缀）
+}
 
-    // Direct
-    if let Some(nodes) = primary_graph.get(&target_id) {
-        for cid in nodes {
-            affected_nodes.insert(cid.clone());
-            // Get Node Info
-            let node = get_node_by_id(&conn, cid)?;
-            direct_nodes.push(CallerInfo {
-                node,
-                call_type: "direct".to_string(),
-            });
+impl ManifestDependency {
+    // 精确版本：去掉 ==、=、前导 v 后只剩数字、点与预发布后缀
+    // Cargo 的裸版本 "1.0" 等价于 ^1.0，只有 "=1.0" 才算锁定
+    fn pinned_version(&self) -> Option<&str> {
+        let v = self.version.as_deref()?.trim();
+        if self.ecosystem == "cargo" && !v.starts_with('=') {
+            return None;
         }
+        let v = v.trim_start_matches("==").trim_start_matches('=').trim();
+        let bare = v.trim_start_matches('v');
+        (bare.starts_with(|c: char| c.is_ascii_digit())
+            && bare
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+')))
+        .then_some(v)
     }
 
-    // Indirect (Depth 2-3) - BFS
-    let mut queue: Vec<(String, usize)> = direct_nodes
-        .iter()
-        .map(|c| (c.node.id.clone(), 1))
-        .collect();
-    let mut visited: HashSet<String> = HashSet::new();
-    visited.insert(target_id.clone());
-    for c in &direct_nodes {
-        visited.insert(c.node.id.clone());
+    fn purl(&self) -> String {
+        let name = match self.ecosystem {
+            // @scope/pkg -> %40scope/pkg
+            "npm" => self.name.replacen('@', "%40", 1),
+            "pypi" => self.name.to_lowercase().replace('_', "-"),
+            "maven" => self.name.replacen(':', "/", 1),
+            _ => self.name.clone(),
+        };
+        match self.pinned_version() {
+            Some(v) => format!("pkg:{}/{}@{}", self.ecosystem, name, v),
+            None => format!("pkg:{}/{}", self.ecosystem, name),
+        }
     }
+}
 
-    while let Some((curr, depth)) = queue.pop() {
-        if depth >= 3 {
-            continue;
+const MANIFEST_FILES: &[&str] = &[
+    "package.json",
+    "Cargo.toml",
+    "go.mod",
+    "pyproject.toml",
+    "composer.json",
+    "Gemfile",
+    "pom.xml",
+];
+
+fn is_manifest_file(name: &str) -> bool {
+    MANIFEST_FILES.contains(&name)
+        || (name.starts_with("requirements") && name.ends_with(".txt"))
+        || name.ends_with(".csproj")
+}
+
+fn parse_manifest(
+    file_name: &str,
+    text: &str,
+) -> Vec<(&'static str, String, Option<String>, bool)> {
+    let mut deps = vec![];
+    match file_name {
+        "package.json" | "composer.json" => {
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(text) else {
+                return deps;
+            };
+            let (ecosystem, sections): (&str, &[(&str, bool)]) = match file_name {
+                "package.json" => (
+                    "npm",
+                    &[
+                        ("dependencies", false),
+                        ("optionalDependencies", false),
+                        ("peerDependencies", false),
+                        ("devDependencies", true),
+                    ],
+                ),
+                _ => ("composer", &[("require", false), ("require-dev", true)]),
+            };
+            for (section, dev) in sections {
+                let Some(map) = json[*section].as_object() else {
+                    continue;
+                };
+                for (name, version) in map {
+                    // composer 的 php / ext-* 是运行时约束，不是包
+                    if ecosystem == "composer" && (name == "php" || name.starts_with("ext-")) {
+                        continue;
+                    }
+                    deps.push((
+                        ecosystem,
+                        name.clone(),
+                        version.as_str().map(str::to_string),
+                        *dev,
+                    ));
+                }
+            }
         }
-        if let Some(nodes) = primary_graph.get(&curr) {
-            for cid in nodes {
-                if !visited.contains(cid) {
-                    visited.insert(cid.clone());
-                    affected_nodes.insert(cid.clone());
-                    let node = get_node_by_id(&conn, cid)?;
-                    indirect_nodes.push(CallerInfo {
-                        node,
-                        call_type: "indirect".to_string(),
+        "Cargo.toml" => {
+            // [dependencies] / [dev-dependencies] / [target.'cfg(..)'.dependencies] / [dependencies.foo]
+            let mut section: Option<(bool, Option<String>)> = None;
+            for line in text.lines().map(str::trim) {
+                if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                    let header = header.trim_matches(['[', ']']);
+                    let (table, inline) = match header.rsplit_once(".dependencies.") {
+                        Some((t, name)) => (format!("{}.dependencies", t), Some(name)),
+                        None => match header.strip_prefix("dependencies.") {
+                            Some(name) => ("dependencies".to_string(), Some(name)),
+                            None => (header.to_string(), None),
+                        },
+                    };
+                    let kind = table.rsplit('.').next().unwrap_or("");
+                    section = matches!(
+                        kind,
+                        "dependencies" | "dev-dependencies" | "build-dependencies"
+                    )
+                    .then(|| {
+                        (
+                            kind == "dev-dependencies",
+                            inline.map(|n| n.trim_matches('"').to_string()),
+                        )
                     });
-                    queue.push((cid.clone(), depth + 1));
+                    if let Some((dev, Some(name))) = &section {
+                        deps.push(("cargo", name.clone(), None, *dev));
+                    }
+                    continue;
+                }
+                let Some((dev, inline)) = &section else {
+                    continue;
+                };
+                let Some((key, value)) = line.split_once('=') else {
+                    continue;
+                };
+                let (key, value) = (key.trim(), value.trim());
+                match inline {
+                    // [dependencies.foo] 下的 version = "1"
+                    Some(_) if key == "version" => {
+                        if let Some(last) = deps.last_mut() {
+                            last.2 = toml_string(value);
+                        }
+                    }
+                    None if !key.starts_with('#') => {
+                        let key = key.trim_matches('"').to_string();
+                        if value.starts_with('{') {
+                            // foo = { package = "real-name", version = "1" }
+                            let name = inline_table_value(value, "package").unwrap_or(key);
+                            deps.push(("cargo", name, inline_table_value(value, "version"), *dev));
+                        } else {
+                            deps.push(("cargo", key, toml_string(value), *dev));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        "go.mod" => {
+            let mut in_block = false;
+            for line in text.lines().map(str::trim) {
+                let spec = if in_block {
+                    if line == ")" {
+                        in_block = false;
+                        continue;
+                    }
+                    line
+                } else if line == "require (" {
+                    in_block = true;
+                    continue;
+                } else if let Some(rest) = line.strip_prefix("require ") {
+                    rest
+                } else {
+                    continue;
+                };
+                let spec = spec.split("//").next().unwrap_or("").trim();
+                let mut parts = spec.split_whitespace();
+                if let (Some(path), Some(version)) = (parts.next(), parts.next()) {
+                    deps.push(("golang", path.to_string(), Some(version.to_string()), false));
+                }
+            }
+        }
+        "pyproject.toml" => {
+            // PEP 621 [project] dependencies = [...] 与 Poetry [tool.poetry.*dependencies]
+            let mut table = String::new();
+            let mut in_array = false;
+            for line in text.lines().map(str::trim) {
+                if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                    table = header.trim_matches(['[', ']']).to_string();
+                    continue;
+                }
+                if table == "project" && line.starts_with("dependencies") && line.contains('[') {
+                    in_array = !line.contains(']');
+                    let items = line.split_once('[').map(|(_, r)| r).unwrap_or("");
+                    deps.extend(items.split(',').filter_map(pep508_requirement));
+                    continue;
+                }
+                if in_array {
+                    in_array = !line.starts_with(']');
+                    deps.extend(line.split(',').filter_map(pep508_requirement));
+                    continue;
+                }
+                let poetry_dev = table.starts_with("tool.poetry.group.")
+                    || table == "tool.poetry.dev-dependencies";
+                if table == "tool.poetry.dependencies"
+                    || (poetry_dev && table.ends_with("dependencies"))
+                {
+                    let Some((key, value)) = line.split_once('=') else {
+                        continue;
+                    };
+                    let key = key.trim().trim_matches('"');
+                    if key == "python" || key.starts_with('#') {
+                        continue;
+                    }
+                    let value = value.trim();
+                    let version = if value.starts_with('{') {
+                        inline_table_value(value, "version")
+                    } else {
+                        toml_string(value)
+                    };
+                    deps.push(("pypi", key.to_string(), version, poetry_dev));
+                }
+            }
+        }
+        "Gemfile" => {
+            for line in text.lines().map(str::trim) {
+                let Some(rest) = line.strip_prefix("gem ") else {
+                    continue;
+                };
+                let mut args = rest
+                    .split(',')
+                    .map(|a| a.trim().trim_matches(['\'', '"']).to_string());
+                if let Some(name) = args.next() {
+                    let version = args.next().filter(|v| !v.contains(':'));
+                    deps.push(("gem", name, version, false));
+                }
+            }
+        }
+        "pom.xml" => {
+            for block in text.split("<dependency>").skip(1) {
+                let block = block.split("</dependency>").next().unwrap_or("");
+                let tag = |name: &str| {
+                    let open = format!("<{}>", name);
+                    let start = block.find(&open)? + open.len();
+                    let end = block[start..].find('<')? + start;
+                    Some(block[start..end].trim().to_string())
+                };
+                if let (Some(group), Some(artifact)) = (tag("groupId"), tag("artifactId")) {
+                    let dev = tag("scope").is_some_and(|s| s == "test");
+                    deps.push((
+                        "maven",
+                        format!("{}:{}", group, artifact),
+                        tag("version"),
+                        dev,
+                    ));
                 }
             }
         }
-    }
-
-    // 4. Dice Algorithm (Complexity Score via Random Walk)
-    // Run random walk starting from target node on the DIRECT graph (forward).
-    // "If I am complex, I call many things which call many things."
-    use rand::prelude::IndexedRandom; // rand 0.9 fix
-
-    // 🆕 使用 String (canonical_id) 而不是 i64 (symbol_id)
-    let mut walk_visits: HashMap<String, u32> = HashMap::new();
-    let num_walks = 1000;
-    let walk_length = 10;
-    let damping = 0.85;
-    let mut rng = rand::rng(); // rand 0.9 fix
-
-    for _ in 0..num_walks {
-        let mut curr = target_id.clone();
-        for _ in 0..walk_length {
-            *walk_visits.entry(curr.clone()).or_insert(0) += 1;
-
-            if rand::random::<f64>() > damping {
-                break;
+        name if name.ends_with(".csproj") => {
+            for chunk in text.split("<PackageReference").skip(1) {
+                let tag = chunk.split('>').next().unwrap_or("");
+                let attr = |key: &str| {
+                    let at = tag.find(&format!("{}=\"", key))? + key.len() + 2;
+                    Some(tag[at..].split('"').next()?.to_string())
+                };
+                if let Some(include) = attr("Include") {
+                    deps.push(("nuget", include, attr("Version"), false));
+                }
             }
-
-            match adjacency.get(&curr) {
-                Some(neighbors) if !neighbors.is_empty() => {
-                    curr = neighbors.choose(&mut rng).unwrap().clone();
+        }
+        _ => {
+            // requirements*.txt：-r / -e / URL / 注释跳过；dev 以文件名判断
+            let dev = file_name.contains("dev") || file_name.contains("test");
+            for line in text.lines() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.starts_with('-') || line.contains("://") {
+                    continue;
+                }
+                if let Some((_, name, version, _)) = pep508_requirement(line) {
+                    deps.push(("pypi", name, version, dev));
                 }
-                _ => break,
             }
         }
     }
+    deps
+}
 
-    // Calculate Score
-    // Scope (Affected Nodes in dependency chain) - actually Random Walk measures "Effort to understand dependencies".
-    let coverage = walk_visits.len();
+// "1.0" -> 1.0；非字符串值（true / 表）返回 None
+fn toml_string(value: &str) -> Option<String> {
+    let value = value.split(" #").next().unwrap_or("").trim();
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))?;
+    Some(inner.to_string())
+}
 
-    // Density (Fan-out)
-    let out_degree = adjacency.get(&target_id).map(|v| v.len()).unwrap_or(0);
-    let in_degree = reverse_adjacency
-        .get(&target_id)
-        .map(|v| v.len())
-        .unwrap_or(0);
+// { version = "1", features = [..] } 里取某个键
+fn inline_table_value(table: &str, key: &str) -> Option<String> {
+    let body = table.trim().trim_start_matches('{').trim_end_matches('}');
+    body.split(',').find_map(|kv| {
+        let (k, v) = kv.split_once('=')?;
+        (k.trim() == key).then(|| toml_string(v.trim())).flatten()
+    })
+}
 
-    // Formula from dice.py: (affected * 0.4) + (density * 0.3) + (variance * 0.3)
-    // Simplify for Rust MVP
-    let complexity_score =
-        (coverage as f64 * 0.5) + (out_degree as f64 * 2.0) + (in_degree as f64 * 1.0);
-    let normalized_score = if complexity_score > 100.0 {
-        100.0
-    } else {
-        complexity_score
+// PEP 508："requests[socks]>=2.0; python_version<'3.8'" -> (requests, >=2.0)
+fn pep508_requirement(spec: &str) -> Option<(&'static str, String, Option<String>, bool)> {
+    let spec = spec.trim().trim_matches(['"', '\'', ']', '[']).trim();
+    let spec = spec.split(';').next().unwrap_or("").trim();
+    let end = spec
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        .unwrap_or(spec.len());
+    let name = &spec[..end];
+    if name.is_empty() {
+        return None;
+    }
+    let rest = spec[end..].trim();
+    let rest = match rest.strip_prefix('[') {
+        Some(r) => r.split_once(']').map(|(_, v)| v).unwrap_or("").trim(),
+        None => rest,
     };
+    let version = (!rest.is_empty()).then(|| rest.replace(' ', ""));
+    Some(("pypi", name.to_string(), version, false))
+}
 
-    let complexity_level = if normalized_score < 20.0 {
-        "Simple"
-    } else if normalized_score < 50.0 {
-        "Medium"
-    } else if normalized_score < 80.0 {
-        "High"
-    } else {
-        "Extreme"
-    };
+fn collect_manifest_dependencies(roots: &[ProjectRoot]) -> Vec<ManifestDependency> {
+    let ignored = [
+        "node_modules",
+        "vendor",
+        "target",
+        ".git",
+        ".venv",
+        "venv",
+        "dist",
+        "build",
+    ];
+    let mut deps = vec![];
+    for root in roots {
+        let mut builder = WalkBuilder::new(&root.path);
+        builder.hidden(false);
+        builder.git_ignore(true);
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+        builder.filter_entry(move |entry| {
+            !(entry.file_type().is_some_and(|f| f.is_dir())
+                && ignored.contains(&entry.file_name().to_str().unwrap_or("")))
+        });
+        for entry in builder.build().flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type().is_some_and(|t| t.is_file()) || !is_manifest_file(&file_name) {
+                continue;
+            }
+            let Some(text) = fs::read(entry.path()).ok().and_then(|b| decode_source(&b)) else {
+                continue;
+            };
+            let rel = entry
+                .path()
+                .strip_prefix(&root.path)
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default();
+            let manifest = format!("{}{}", root.prefix(), rel);
+            for (ecosystem, name, version, dev) in parse_manifest(&file_name, &text) {
+                deps.push(ManifestDependency {
+                    ecosystem,
+                    name,
+                    version,
+                    dev,
+                    manifest: manifest.clone(),
+                });
+            }
+        }
+    }
+    // 同一依赖在多个清单里声明时保留第一处；任一处是运行期依赖就不算 dev
+    let mut seen: BTreeMap<(String, Option<String>), usize> = BTreeMap::new();
+    let mut unique: Vec<ManifestDependency> = vec![];
+    for dep in deps {
+        let key = (
+            format!("{}/{}", dep.ecosystem, dep.name),
+            dep.version.clone(),
+        );
+        match seen.get(&key) {
+            Some(&i) => unique[i].dev &= dep.dev,
+            None => {
+                seen.insert(key, unique.len());
+                unique.push(dep);
+            }
+        }
+    }
+    unique.sort_by(|a, b| {
+        (a.ecosystem, &a.name, &a.version).cmp(&(b.ecosystem, &b.name, &b.version))
+    });
+    unique
+}
 
-    // Risk Level (Only meaningful for backward)
-    let total_affected = direct_nodes.len() + indirect_nodes.len();
-    let risk_level = if total_affected == 0 {
-        "low"
-    } else if total_affected <= 3 {
-        "low"
-    } else if total_affected <= 10 {
-        "medium"
-    } else {
-        "high"
-    };
+// 秒级 UTC 时间戳 -> 2024-01-02T03:04:05Z（SPDX created 必填）
+fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Howard Hinnant civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
 
-    // Generate Checklist
-    let mut checklist = vec![format!(
-        "📌 Target Symbol: {} ({})",
-        target.qualified_name, target.file_path
-    )];
-    let label = if direction == "forward" {
-        "Dependency"
-    } else {
-        "Caller"
+fn run_sbom(args: &Args) -> anyhow::Result<()> {
+    let roots = project_roots(args)?;
+    let deps = collect_manifest_dependencies(&roots);
+    let project = match roots.as_slice() {
+        [root] => fs::canonicalize(&root.path)
+            .unwrap_or_else(|_| root.path.clone())
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "project".to_string()),
+        _ => "workspace".to_string(),
     };
-    for c in &direct_nodes {
-        checklist.push(format!(
-            "⚠️ Check {}: {}:{} ({})",
-            label, c.node.node_type, c.node.name, c.node.file_path
-        ));
+    let license = roots
+        .first()
+        .map(|r| project_license(&r.path))
+        .unwrap_or_default();
+    // 依赖集合决定文档标识：同样的清单得到同样的 serialNumber / namespace
+    let mut hasher = Sha256::new();
+    hasher.update(project.as_bytes());
+    for dep in &deps {
+        hasher.update([0u8]);
+        hasher.update(dep.purl().as_bytes());
+        hasher.update(dep.version.as_deref().unwrap_or("").as_bytes());
     }
-
-    let final_res = AnalysisResult {
-        status: "success".to_string(),
-        node_id: target_id,
-        complexity_score: normalized_score,
-        complexity_level: complexity_level.to_string(),
-        affected_nodes: total_affected,
-        direct_callers: direct_nodes,
-        indirect_callers: indirect_nodes,
-        risk_level: risk_level.to_string(),
-        modification_checklist: checklist,
+    let digest = hex::encode(hasher.finalize());
+    let tool = format!("ast_indexer_rust-{}", env!("CARGO_PKG_VERSION"));
+
+    let doc = match args.format.as_str() {
+        "cyclonedx" | "json" => {
+            let components: Vec<serde_json::Value> = deps
+                .iter()
+                .map(|d| {
+                    let mut c = serde_json::json!({
+                        "type": "library",
+                        "bom-ref": d.purl(),
+                        "name": d.name,
+                        "purl": d.purl(),
+                        "scope": if d.dev { "optional" } else { "required" },
+                        "properties": [{"name": "mpm:manifest", "value": d.manifest}],
+                    });
+                    if let Some(v) = &d.version {
+                        c["version"] = serde_json::json!(v);
+                    }
+                    c
+                })
+                .collect();
+            let mut root_component = serde_json::json!({
+                "type": "application",
+                "bom-ref": project,
+                "name": project,
+            });
+            if !license.is_empty() {
+                root_component["licenses"] = serde_json::json!([{"expression": license}]);
+            }
+            serde_json::json!({
+                "bomFormat": "CycloneDX",
+                "specVersion": "1.5",
+                "serialNumber": format!(
+                    "urn:uuid:{}-{}-{}-{}-{}",
+                    &digest[0..8], &digest[8..12], &digest[12..16], &digest[16..20], &digest[20..32]
+                ),
+                "version": 1,
+                "metadata": {
+                    "tools": {"components": [{"type": "application", "name": "ast_indexer_rust", "version": env!("CARGO_PKG_VERSION")}]},
+                    "component": root_component,
+                },
+                "components": components,
+                "dependencies": [{
+                    "ref": project,
+                    "dependsOn": deps.iter().map(|d| d.purl()).collect::<Vec<_>>(),
+                }],
+            })
+        }
+        "spdx" => {
+            let spdx_id = |i: usize| format!("SPDXRef-Package-{}", i + 1);
+            let mut packages = vec![serde_json::json!({
+                "SPDXID": "SPDXRef-Root",
+                "name": project,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": if license.is_empty() { "NOASSERTION".to_string() } else { license.clone() },
+            })];
+            let mut relationships = vec![serde_json::json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": "SPDXRef-Root",
+            })];
+            for (i, d) in deps.iter().enumerate() {
+                let mut p = serde_json::json!({
+                    "SPDXID": spdx_id(i),
+                    "name": d.name,
+                    "downloadLocation": "NOASSERTION",
+                    "filesAnalyzed": false,
+                    "licenseConcluded": "NOASSERTION",
+                    "licenseDeclared": "NOASSERTION",
+                    "comment": format!("declared in {}", d.manifest),
+                    "externalRefs": [{
+                        "referenceCategory": "PACKAGE-MANAGER",
+                        "referenceType": "purl",
+                        "referenceLocator": d.purl(),
+                    }],
+                });
+                if let Some(v) = &d.version {
+                    p["versionInfo"] = serde_json::json!(v);
+                }
+                packages.push(p);
+                relationships.push(match d.dev {
+                    true => serde_json::json!({
+                        "spdxElementId": spdx_id(i),
+                        "relationshipType": "DEV_DEPENDENCY_OF",
+                        "relatedSpdxElement": "SPDXRef-Root",
+                    }),
+                    false => serde_json::json!({
+                        "spdxElementId": "SPDXRef-Root",
+                        "relationshipType": "DEPENDS_ON",
+                        "relatedSpdxElement": spdx_id(i),
+                    }),
+                });
+            }
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            serde_json::json!({
+                "spdxVersion": "SPDX-2.3",
+                "dataLicense": "CC0-1.0",
+                "SPDXID": "SPDXRef-DOCUMENT",
+                "name": project,
+                "documentNamespace": format!("https://spdx.org/spdxdocs/{}-{}", project, &digest[..16]),
+                "creationInfo": {"created": utc_timestamp(now), "creators": [format!("Tool: {}", tool)]},
+                "packages": packages,
+                "relationships": relationships,
+            })
+        }
+        other => anyhow::bail!(
+            "unsupported format for sbom mode: {} (expected cyclonedx or spdx)",
+            other
+        ),
     };
 
     if let Some(out_path) = &args.output {
         let f = fs::File::create(out_path)?;
-        serde_json::to_writer(f, &final_res)?;
+        serde_json::to_writer(f, &doc)?;
     }
 
     Ok(())
 }
 
-// 🆕 修改：使用 canonical_id (String) 而不是 symbol_id (i64)
-fn get_node_by_id(conn: &Connection, id: &str) -> Result<Node> {
-    conn.query_row(
-        "SELECT canonical_id, name, qualified_name, file_path, line_start, line_end, symbol_type
-         FROM symbols JOIN files ON symbols.file_id = files.file_id
-         WHERE canonical_id = ?1",
-        [id],
-        |row| {
-            Ok(Node {
-                id: row.get::<_, String>(0)?, // 🆕 canonical_id
-                name: row.get(1)?,
-                qualified_name: row.get(2)?,
-                file_path: row.get(3)?,
-                line_start: row.get(4)?,
-                line_end: row.get(5)?,
-                node_type: row.get(6)?,
-                signature: None,
-                calls: vec![],
-            })
-        },
-    )
-}
-
 // ============================================================================
-// Snapshot & Diff
+// Validate Mode (抽样自检：重解析部分已索引文件，与库中符号逐一比对)
+// 怀疑索引损坏时使用：内容未变、抽取器未变的文件，重解析结果应与库中符号完全一致
 // ============================================================================
 
-#[derive(Serialize, Deserialize)]
-struct Snapshot {
-    timestamp: u64,
-    symbols: HashMap<String, SnapshotSymbol>, // key: qualified_name (or id if stable)
+#[derive(Serialize, Default)]
+struct LanguageValidation {
+    sampled: usize,
+    checked: usize,
+    drifted: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)] // Added PartialEq for easy diff
-struct SnapshotSymbol {
-    name: String,
-    qualified_name: String,
+#[derive(Serialize)]
+struct FileDrift {
     file_path: String,
-    symbol_type: String,
-    line_start: usize,
-    signature: Option<String>,
-    calls: Vec<String>, // List of callee qualified_names
+    language: String,
+    missing_symbols: Vec<String>, // 库中有、重解析没有
+    extra_symbols: Vec<String>,   // 重解析有、库中没有
+    changed: Vec<String>,         // 同一位置的符号类型 / 签名不一致
 }
 
-// 🆕 修改：使用 canonical_id
-fn run_snapshot(args: &Args) -> anyhow::Result<()> {
-    // Export current DB state to a JSON file
-    let conn = Connection::open(&args.db)?;
+#[derive(Serialize)]
+struct ValidateResult {
+    status: String, // ok | drift
+    generation: i64,
+    indexed_files: usize,
+    sampled: usize,
+    checked: usize,
+    seed: u64,
+    stale: Vec<String>,    // 索引后内容已修改（重新 index 即可，不算损坏）
+    missing: Vec<String>,  // 磁盘上已不存在
+    outdated: Vec<String>, // 由旧版抽取器写入，下次 index 会重解析
+    languages: BTreeMap<String, LanguageValidation>,
+    drift: Vec<FileDrift>,
+}
 
-    // 1. Load Symbols
-    let mut symbols_map: HashMap<String, SnapshotSymbol> = HashMap::new();
-    let mut id_to_qname: HashMap<String, String> = HashMap::new(); // 🆕 canonical_id -> qualified_name
+// files.extractor_version 末尾的特性开关 "-c1s1d0"：按索引时的开关重解析，签名 / 文档才可比
+fn features_from_tag(file_version: &str) -> Option<ExtractFeatures> {
+    let tag = file_version.rsplit('-').next()?.as_bytes();
+    match tag {
+        [b'c', c, b's', s, b'd', d] => Some(ExtractFeatures {
+            calls: *c == b'1',
+            signatures: *s == b'1',
+            docs: *d == b'1',
+        }),
+        _ => None,
+    }
+}
 
-    {
-        // 🆕 查询包含 canonical_id
-        let mut stmt = conn.prepare("SELECT canonical_id, name, qualified_name, file_path, line_start, symbol_type FROM symbols JOIN files ON symbols.file_id = files.file_id")?;
-        let rows = stmt.query_map([], |row| {
+fn run_validate(args: &Args) -> anyhow::Result<()> {
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+
+    let conn = Connection::open(&args.db)?;
+    init_db(&conn)?;
+    apply_read_filter(&conn, args.root.as_deref(), &[], None)?;
+    let roots = project_roots(args)?;
+    let parsers = get_parser_setup();
+    let type_map = load_type_map(args.type_map.as_deref())?;
+
+    let mut files: Vec<(i64, String, String, String, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT file_id, file_path, language, file_hash, extractor_version FROM files ORDER BY file_path",
+        )?;
+        let rows = stmt.query_map([], |r| {
             Ok((
-                row.get::<_, String>(0)?, // 🆕 canonical_id
-                SnapshotSymbol {
-                    name: row.get(1)?,
-                    qualified_name: row.get(2)?,
-                    file_path: row.get(3)?,
-                    symbol_type: row.get(5)?,
-                    line_start: row.get(4)?,
-                    signature: None,
-                    calls: vec![],
-                },
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, Option<String>>(4)?.unwrap_or_default(),
             ))
         })?;
+        rows.flatten()
+            .filter(|(_, _, language, _, _)| file_extractor_version(&parsers, language).is_some())
+            .collect()
+    };
+    let indexed_files = files.len();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+    files.shuffle(&mut rng);
+    files.truncate(args.sample);
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut res = ValidateResult {
+        status: "ok".to_string(),
+        generation: read_generation(&conn)?,
+        indexed_files,
+        sampled: files.len(),
+        checked: 0,
+        seed: args.seed,
+        stale: vec![],
+        missing: vec![],
+        outdated: vec![],
+        languages: BTreeMap::new(),
+        drift: vec![],
+    };
 
-        for r in rows {
-            if let Ok((id, sym)) = r {
-                id_to_qname.insert(id.clone(), sym.qualified_name.clone());
-                // Use canonical_id as stable key
-                symbols_map.insert(id, sym);
-            }
+    let mut stmt_symbols = conn.prepare(
+        "SELECT symbol_type, qualified_name, line_start, line_end, signature FROM symbols WHERE file_id = ?1",
+    )?;
+    for (file_id, file_path, language, file_hash, stored_version) in files {
+        let stats = res.languages.entry(language.clone()).or_default();
+        stats.sampled += 1;
+        let Some(content) = fs::read(resolve_source_path(&roots, &file_path))
+            .ok()
+            .and_then(|b| decode_source(&b))
+        else {
+            res.missing.push(file_path);
+            continue;
+        };
+        if hex::encode(Sha256::digest(content.as_bytes())) != file_hash {
+            res.stale.push(file_path);
+            continue;
         }
-    }
+        let features = features_from_tag(&stored_version);
+        let current = file_extractor_version(&parsers, &language);
+        let (Some(features), Some(current)) = (features, current) else {
+            res.outdated.push(file_path);
+            continue;
+        };
+        if stored_version != format!("{}-{}", current, features.cache_tag()) {
+            res.outdated.push(file_path);
+            continue;
+        }
+        stats.checked += 1;
+        res.checked += 1;
 
-    // 2. Load Calls (hydrate symbols)
-    {
-        // 🆕 JOIN symbols 表获取 canonical_id
-        let mut stmt = conn.prepare("SELECT s.canonical_id, c.callee_name FROM calls c JOIN symbols s ON c.caller_id = s.symbol_id")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        // (qualified_name, 起止行) -> (类型, 签名)；同一位置可能有多个同名符号（getter / setter）
+        type SymbolKey = (String, usize, usize);
+        let mut stored: BTreeMap<SymbolKey, Vec<(String, Option<String>)>> = BTreeMap::new();
+        let rows = stmt_symbols.query_map([file_id], |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, usize>(2)?,
+                r.get::<_, usize>(3)?,
+                r.get::<_, Option<String>>(4)?,
+            ))
         })?;
+        for (ty, qualified, start, end, signature) in rows.flatten() {
+            stored
+                .entry((qualified, start, end))
+                .or_default()
+                .push((ty, signature));
+        }
+        let parsed = extract_with_fallback(&parsers, &language, &content, features);
+        let mut fresh: BTreeMap<SymbolKey, Vec<(String, Option<String>)>> = BTreeMap::new();
+        for sym in parsed.symbols {
+            let ty = type_map.resolve(&language, &sym.symbol_type).to_string();
+            fresh
+                .entry((
+                    anon_in_file(&sym.qualified_name, &file_path),
+                    sym.line_start,
+                    sym.line_end,
+                ))
+                .or_default()
+                .push((ty, sym.signature));
+        }
 
-        for r in rows {
-            if let Ok((caller_canonical_id, callee_name)) = r {
-                if let Some(sym) = symbols_map.get_mut(&caller_canonical_id) {
-                    sym.calls.push(callee_name);
-                }
+        let describe = |(name, start, end): &SymbolKey, ty: &str| {
+            format!("{} {} (L{}-{})", ty, name, start, end)
+        };
+        let mut drift = FileDrift {
+            file_path: file_path.clone(),
+            language: language.clone(),
+            missing_symbols: vec![],
+            extra_symbols: vec![],
+            changed: vec![],
+        };
+        for (key, mut old) in stored {
+            let Some(mut new) = fresh.remove(&key) else {
+                drift
+                    .missing_symbols
+                    .extend(old.iter().map(|(ty, _)| describe(&key, ty)));
+                continue;
+            };
+            old.sort();
+            new.sort();
+            if old != new {
+                let show = |v: &[(String, Option<String>)]| {
+                    v.iter()
+                        .map(|(ty, sig)| format!("{} {}", ty, sig.as_deref().unwrap_or("-")))
+                        .collect::<Vec<_>>()
+                        .join(" | ")
+                };
+                drift.changed.push(format!(
+                    "{}: stored [{}], parsed [{}]",
+                    key.0,
+                    show(&old),
+                    show(&new)
+                ));
             }
         }
+        for (key, new) in fresh {
+            drift
+                .extra_symbols
+                .extend(new.iter().map(|(ty, _)| describe(&key, ty)));
+        }
+        if !drift.missing_symbols.is_empty()
+            || !drift.extra_symbols.is_empty()
+            || !drift.changed.is_empty()
+        {
+            stats.drifted += 1;
+            res.drift.push(drift);
+        }
+    }
+    if !res.drift.is_empty() {
+        res.status = "drift".to_string();
     }
-
-    let snapshot = Snapshot {
-        timestamp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-        symbols: symbols_map,
-    };
 
     if let Some(out_path) = &args.output {
         let f = fs::File::create(out_path)?;
-        serde_json::to_writer(f, &snapshot)?;
-    } else {
-        // Print to stdout? No, binary output usually silent unless error.
-    }
-
-    Ok(())
-}
-
-#[derive(Serialize)]
-struct DiffResult {
-    added: Vec<String>,
-    removed: Vec<String>,
-    modified: Vec<String>,
-    details: HashMap<String, DiffDetail>,
-}
-
-#[derive(Serialize)]
-struct DiffDetail {
-    change_type: String, // "signature_changed", "calls_changed", "moved"
-    diff_msg: String,
-}
-
-fn run_diff(args: &Args) -> anyhow::Result<()> {
-    let base_path = args.base.as_ref().expect("Base snapshot required for diff");
-    let target_path = args
-        .target
-        .as_ref()
-        .expect("Target snapshot required for diff");
+        serde_json::to_writer(f, &res)?;
+    }
 
-    let base: Snapshot = serde_json::from_reader(fs::File::open(base_path)?)?;
-    let target: Snapshot = serde_json::from_reader(fs::File::open(target_path)?)?;
+    Ok(())
+}
 
-    let mut added = vec![];
-    let mut removed = vec![];
-    let mut modified = vec![];
-    let mut details = HashMap::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Check Removed
-    for (k, _) in &base.symbols {
-        if !target.symbols.contains_key(k) {
-            removed.push(k.clone());
+    fn symbol_db(names: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO files (file_path, file_hash, updated_at) VALUES ('src/名前.py', '', 0)",
+            [],
+        )
+        .unwrap();
+        for (i, name) in names.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO symbols (file_id, name, qualified_name, canonical_id, symbol_type, line_start, line_end)
+                 VALUES (1, ?1, ?1, ?2, 'function', ?3, ?3)",
+                params![name, format!("func:src/名前.py::{}", name), i + 1],
+            )
+            .unwrap();
         }
+        conn
     }
 
-    // Check Added & Modified
-    for (k, target_sym) in &target.symbols {
-        if !base.symbols.contains_key(k) {
-            added.push(k.clone());
-        } else {
-            let base_sym = base.symbols.get(k).unwrap();
-
-            // Compare
-            let mut diffs = vec![];
+    #[test]
+    fn nfc_composes_decomposed_accents() {
+        assert_eq!(nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(nfc("caf\u{e9}"), "caf\u{e9}");
+        // 韩文字母 jamo 序列合成为音节
+        assert_eq!(nfc("\u{1112}\u{1161}\u{11ab}"), "\u{d55c}");
+    }
 
-            if base_sym.file_path != target_sym.file_path {
-                diffs.push(format!(
-                    "Moved from {} to {}",
-                    base_sym.file_path, target_sym.file_path
-                ));
-            }
+    #[test]
+    fn char_prefix_respects_multibyte_boundaries() {
+        assert_eq!(char_prefix("数据处理器", 4), Some("数据处理"));
+        assert_eq!(char_prefix("über_alles", 4), Some("über"));
+        assert_eq!(char_prefix("加载", 4), None);
+        assert_eq!(char_prefix("ñame", 4), Some("ñame"));
+    }
 
-            if base_sym.symbol_type != target_sym.symbol_type {
-                diffs.push(format!(
-                    "Type changed: {} -> {}",
-                    base_sym.symbol_type, target_sym.symbol_type
-                ));
-            }
+    #[test]
+    fn stem_match_handles_cjk_and_accented_queries() {
+        let conn = symbol_db(&["数据处理器", "数据处理工具", "验证", "überprüfen"]);
 
-            // Check Calls
-            let base_calls: HashSet<_> = base_sym.calls.iter().collect();
-            let target_calls: HashSet<_> = target_sym.calls.iter().collect();
+        let mut names: Vec<String> = stem_match_multi(&conn, "数据处理模块", 10)
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["数据处理器", "数据处理工具"]);
 
-            let new_calls: Vec<_> = target_calls.difference(&base_calls).collect();
-            let lost_calls: Vec<_> = base_calls.difference(&target_calls).collect();
+        // 不足 4 个字符不做词干匹配，也不能在多字节字符中间切片
+        assert!(stem_match_multi(&conn, "验证器", 10).is_empty());
 
-            if !new_calls.is_empty() {
-                diffs.push(format!("Added calls: {:?}", new_calls));
-            }
-            if !lost_calls.is_empty() {
-                diffs.push(format!("Removed calls: {:?}", lost_calls));
-            }
+        let names: Vec<String> = stem_match_multi(&conn, "überprüfung", 10)
+            .into_iter()
+            .map(|n| n.name)
+            .collect();
+        assert_eq!(names, ["überprüfen"]);
+    }
 
-            if !diffs.is_empty() {
-                modified.push(k.clone());
-                details.insert(
-                    k.clone(),
-                    DiffDetail {
-                        change_type: "modified".into(),
-                        diff_msg: diffs.join("; "),
-                    },
-                );
-            }
-        }
+    #[test]
+    fn normalized_names_match_regardless_of_source_encoding() {
+        let mut parsed = ParsedFile {
+            symbols: vec![PendingSymbol {
+                temp_id: 0,
+                parent_temp_id: None,
+                name: "re\u{301}sume\u{301}".to_string(),
+                qualified_name: "Cv.re\u{301}sume\u{301}".to_string(),
+                scope_path: "Cv::re\u{301}sume\u{301}".to_string(),
+                symbol_type: "method".to_string(),
+                line_start: 1,
+                line_end: 2,
+                signature: None,
+                doc: None,
+                visibility: None,
+                anchor: None,
+                signature_shape: None,
+            }],
+            calls: vec![PendingCall {
+                caller_temp_id: 0,
+                callee_name: "ve\u{301}rifier".to_string(),
+                line: 2,
+                kind: "call".to_string(),
+                receiver_type: None,
+                args: None,
+            }],
+            routes: vec![],
+            inherits: vec![],
+            imports: vec![],
+            decorators: vec![],
+            attributes: vec![],
+            grammar: String::new(),
+            error_nodes: 0,
+        };
+        normalize_identifiers(&mut parsed);
+        assert_eq!(parsed.symbols[0].name, "r\u{e9}sum\u{e9}");
+        assert_eq!(parsed.symbols[0].scope_path, "Cv::r\u{e9}sum\u{e9}");
+        assert_eq!(parsed.calls[0].callee_name, "v\u{e9}rifier");
+
+        let conn = symbol_db(&["r\u{e9}sum\u{e9}"]);
+        let exact = exact_match_multi(&conn, &nfc("re\u{301}sume\u{301}"), 5);
+        assert_eq!(exact.len(), 1);
+        let fuzzy = levenshtein_match_multi(&conn, "r\u{e9}sum\u{e9}s", 1, 5);
+        assert_eq!(fuzzy.len(), 1);
+        assert_eq!(fuzzy[0].1, 1);
     }
 
-    let res = DiffResult {
-        added,
-        removed,
-        modified,
-        details,
-    };
+    #[test]
+    fn signature_shape_changes_report_params_and_return_type() {
+        let param = |name: &str, ty: Option<&str>| SignatureParam {
+            name: name.to_string(),
+            param_type: ty.map(str::to_string),
+            kind: None,
+        };
+        let type_param = |name: &str, bounds: Option<&str>| TypeParam {
+            name: name.to_string(),
+            bounds: bounds.map(str::to_string),
+        };
+        let old = SignatureShape {
+            params: vec![param("a", Some("int")), param("b", None), param("c", None)],
+            returns: Some("str".to_string()),
+            type_params: vec![type_param("T", Some("Clone")), type_param("U", None)],
+        };
+        let new = SignatureShape {
+            params: vec![
+                param("b", None),
+                param("a", Some("float")),
+                param("d", None),
+            ],
+            returns: None,
+            type_params: vec![type_param("T", Some("Clone + Send"))],
+        };
+        assert_eq!(
+            signature_shape_changes(&old, &new),
+            [
+                "Parameters added: d",
+                "Parameters removed: c",
+                "Parameter type changed: a: int -> float",
+                "Parameters reordered: (a, b) -> (b, a)",
+                "Return type changed: str -> ?",
+                "Type parameters removed: U",
+                "Type parameter bounds changed: T: Clone -> Clone + Send",
+            ]
+        );
+        assert!(signature_shape_changes(&old, &old).is_empty());
+    }
 
-    if let Some(out_path) = &args.output {
-        let f = fs::File::create(out_path)?;
-        serde_json::to_writer(f, &res)?;
+    #[test]
+    fn arity_problems_follow_python_calling_rules() {
+        let param = |name: &str, kind: Option<&str>| SignatureParam {
+            name: name.to_string(),
+            param_type: None,
+            kind: kind.map(str::to_string),
+        };
+        // def put(self, key, value, ttl=None, *, sync=False)
+        let shape = SignatureShape {
+            params: vec![
+                param("self", None),
+                param("key", None),
+                param("value", None),
+                param("ttl", Some("optional")),
+                param("*", None),
+                param("sync", Some("optional")),
+            ],
+            returns: None,
+            type_params: vec![],
+        };
+        let call = |positional: usize, keywords: &[&str]| CallArgs {
+            positional,
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+        };
+        assert!(arity_problems(&shape, &call(2, &[])).is_empty());
+        assert!(arity_problems(&shape, &call(1, &["value", "sync"])).is_empty());
+        assert_eq!(
+            arity_problems(&shape, &call(1, &[])),
+            ["missing arguments: value"]
+        );
+        assert_eq!(
+            arity_problems(&shape, &call(4, &[])),
+            ["too many arguments: 4 given, at most 3"]
+        );
+        assert_eq!(
+            arity_problems(&shape, &call(2, &["key", "fast"])),
+            [
+                "multiple values for argument 'key'",
+                "unknown keyword argument 'fast'"
+            ]
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn manifest_parsers_keep_declared_versions_and_dev_scope() {
+        let cargo = "[dependencies]\nserde = { version = \"1.0\", features = [\"derive\"] }\nlog = \"=0.4.20\"\n\n[dev-dependencies]\ntempfile = \"3\"\n\n[dependencies.tokio]\nversion = \"1\"\n";
+        let deps: Vec<(String, Option<String>, bool)> = parse_manifest("Cargo.toml", cargo)
+            .into_iter()
+            .map(|(_, name, version, dev)| (name, version, dev))
+            .collect();
+        assert_eq!(
+            deps,
+            [
+                ("serde".to_string(), Some("1.0".to_string()), false),
+                ("log".to_string(), Some("=0.4.20".to_string()), false),
+                ("tempfile".to_string(), Some("3".to_string()), true),
+                ("tokio".to_string(), Some("1".to_string()), false),
+            ]
+        );
+
+        let dep = |ecosystem, name: &str, version: &str| ManifestDependency {
+            ecosystem,
+            name: name.to_string(),
+            version: Some(version.to_string()),
+            dev: false,
+            manifest: String::new(),
+        };
+        assert_eq!(dep("cargo", "serde", "1.0").purl(), "pkg:cargo/serde");
+        assert_eq!(
+            dep("cargo", "log", "=0.4.20").purl(),
+            "pkg:cargo/log@0.4.20"
+        );
+        assert_eq!(
+            dep("npm", "@scope/a", "1.2.3").purl(),
+            "pkg:npm/%40scope/a@1.2.3"
+        );
+        assert_eq!(dep("npm", "react", "^18").purl(), "pkg:npm/react");
+        assert_eq!(
+            pep508_requirement("requests[socks] >= 2.0; python_version < '3.8'"),
+            Some((
+                "pypi",
+                "requests".to_string(),
+                Some(">=2.0".to_string()),
+                false
+            ))
+        );
+    }
 
-// ============================================================================
-// Structure Mode - 快速目录结构扫描 (No AST)
-// ============================================================================
+    #[test]
+    fn deprecation_markers_keep_their_note() {
+        assert_eq!(
+            rust_deprecation_note(r#"deprecated(since = "1.2", note = "use new_api")"#),
+            "use new_api"
+        );
+        assert_eq!(rust_deprecation_note(r#"deprecated = "gone""#), "gone");
+        assert_eq!(
+            rust_deprecation_note(r#"deprecated(since = "1.2")"#),
+            "true"
+        );
+        assert_eq!(rust_deprecation_note("deprecated"), "true");
+
+        assert_eq!(
+            doc_deprecation("Old helper.\n@deprecated Use newHelper."),
+            Some("Use newHelper.".to_string())
+        );
+        assert_eq!(
+            doc_deprecation("Get fetches.\n\nDeprecated: use GetV2."),
+            Some("use GetV2.".to_string())
+        );
+        assert_eq!(
+            doc_deprecation(".. deprecated:: 2.0"),
+            Some("2.0".to_string())
+        );
+        assert_eq!(doc_deprecation("@deprecated"), Some("true".to_string()));
+        assert_eq!(doc_deprecation("@deprecatedSince 1.0"), None);
+        assert_eq!(doc_deprecation("Not deprecated: still fine"), None);
+
+        assert!(is_deprecation_decorator("Deprecated"));
+        assert!(is_deprecation_decorator("typing_extensions.deprecated"));
+        assert!(!is_deprecation_decorator("deprecation_warning"));
+    }
 
-#[derive(Serialize)]
-struct DirInfo {
-    file_count: usize,
-    files: Vec<String>,
-}
+    #[test]
+    fn generated_stubs_are_detected_and_reduced_to_proto_names() {
+        let go_header = ["// Code generated by protoc-gen-go-grpc. DO NOT EDIT."];
+        assert_eq!(detect_generated("api/user_grpc.pb.go", &go_header), "grpc");
+        assert_eq!(
+            detect_generated(
+                "api/user_pb2.py",
+                &["# Generated by the protocol buffer compiler.  DO NOT EDIT!"]
+            ),
+            "protobuf"
+        );
+        assert_eq!(
+            detect_generated(
+                "gen/mocks.go",
+                &["// Code generated by mockgen. DO NOT EDIT."]
+            ),
+            "generated"
+        );
+        assert_eq!(detect_generated("api/user.go", &["package api"]), "");
+
+        for (stub, proto) in [
+            ("NewUserServiceClient", "UserService"),
+            ("userServiceClient", "UserService"),
+            ("add_UserServiceServicer_to_server", "UserService"),
+            ("UnimplementedUserServiceServer", "UserService"),
+            ("_UserService_GetUser_Handler", "UserService_GetUser"),
+        ] {
+            assert!(
+                stub_base_names(stub).iter().any(|n| n == proto),
+                "{} -> {:?}",
+                stub,
+                stub_base_names(stub)
+            );
+        }
+        assert_eq!(
+            go_receiver_type("func (c *userServiceClient) GetUser(in *GetUserRequest) {"),
+            Some("userServiceClient")
+        );
+    }
 
-#[derive(Serialize)]
-struct StructureResult {
-    status: String,
-    total_files: usize,
-    structure: HashMap<String, DirInfo>,
-}
+    #[test]
+    fn query_scores_never_overlap_across_layers() {
+        let layers = [
+            (SCORE_EXACT, SCORE_EXACT),
+            (SCORE_DECORATOR, SCORE_DECORATOR),
+            (SCORE_PREFIX_SUFFIX, SCORE_PREFIX_SUFFIX),
+            (SCORE_SUBSTRING, SCORE_SUBSTRING),
+            (levenshtein_score(0), levenshtein_score(3)),
+            (SCORE_STEM, SCORE_STEM),
+        ];
+        for pair in layers.windows(2) {
+            assert!(pair[0].1 > pair[1].0, "{:?}", pair);
+        }
+        assert!(levenshtein_score(1) > levenshtein_score(2));
+
+        // 默认 --min-confidence 0.6：距离 1 以内算确定，2 以上交给调用方澄清
+        let conn = symbol_db(&["load_config"]);
+        let (_, candidates, _) = progressive_search_multi(&conn, "lod_confg");
+        assert_eq!(candidates[0].match_type, "levenshtein_d2");
+        assert!(query_confidence(true, &candidates) < 0.6);
+        assert_eq!(round_score(levenshtein_score(2), 2), 0.58);
+    }
 
-fn run_structure(args: &Args) -> anyhow::Result<()> {
-    // 快速目录扫描，不做任何 AST 解析
-    let project_path = Path::new(&args.project);
+    #[test]
+    fn field_receivers_resolve_from_class_declarations() {
+        let py = "class Service:\n    def __init__(self, store: Cache):\n        self.repo = Repo()\n        self.store = store\n";
+        let infer = |recv, fields, ext| infer_receiver_type(recv, "", None, Some(fields), ext);
+        assert_eq!(infer("self.repo", py, "py").as_deref(), Some("Repo"));
+        assert_eq!(infer("self.store", py, "py").as_deref(), Some("Cache"));
+
+        let java = "class Service { private Repo repo; void run() {} }";
+        assert_eq!(infer("repo", java, "java").as_deref(), Some("Repo"));
+        assert_eq!(infer("this.repo", java, "java").as_deref(), Some("Repo"));
+        // Python 的方法里不能省略 self.，裸名不查字段
+        assert_eq!(infer("repo", "repo: Repo", "py"), None);
+
+        let php = "function __construct(private Repo $repo) { $this->cache = new Cache(); }";
+        assert_eq!(infer("$this->repo", php, "php").as_deref(), Some("Repo"));
+        assert_eq!(infer("$this->cache", php, "php").as_deref(), Some("Cache"));
+        assert_eq!(
+            infer("@repo", "@repo = Repo.new", "rb").as_deref(),
+            Some("Repo")
+        );
+    }
 
-    let scan_root = if let Some(scope) = &args.scope {
-        let normalized = scope.trim().trim_start_matches("./").trim_matches('/');
-        if normalized.is_empty() {
-            PathBuf::from(&args.project)
-        } else {
-            project_path.join(normalized)
-        }
-    } else {
-        PathBuf::from(&args.project)
-    };
+    #[test]
+    fn analyze_graph_is_unchanged_by_an_identical_overlay() {
+        let dir = std::env::temp_dir().join(format!("ast_overlay_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = "def helper():\n    return 1\n\ndef main():\n    return helper()\n";
+        fs::write(dir.join("a.py"), source).unwrap();
+        // 末尾多一个空行：内容哈希不同才会写 overlay，而符号与调用边完全相同
+        fs::write(dir.join("buffer.txt"), format!("{}\n", source)).unwrap();
+        let args = |extra: &[&str]| {
+            let mut argv = vec![
+                "ast_indexer_rust".to_string(),
+                "--project".to_string(),
+                dir.display().to_string(),
+                "--db".to_string(),
+                dir.join("x.db").display().to_string(),
+                "--parse-cache".to_string(),
+                "off".to_string(),
+            ];
+            argv.extend(extra.iter().map(|s| s.to_string()));
+            Args::parse_from(argv)
+        };
+        let callers = || {
+            let output = dir.join("analyze.json");
+            run_analyze(&args(&[
+                "--mode",
+                "analyze",
+                "--query",
+                "helper",
+                "--output",
+                output.to_str().unwrap(),
+            ]))
+            .unwrap();
+            let result: serde_json::Value =
+                serde_json::from_slice(&fs::read(output).unwrap()).unwrap();
+            result["direct_callers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|c| c["node"]["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
 
-    // 构建目录遍历器
-    let mut builder = WalkBuilder::new(&scan_root);
-    builder.hidden(false);
-    builder.git_ignore(true);
+        let index = args(&["--mode", "index"]);
+        run_indexer(&index, &heartbeat_path(&index).unwrap()).unwrap();
+        let before = callers();
+        assert_eq!(before, ["func:a.py::main"]);
+
+        let buffer = dir.join("buffer.txt");
+        run_reindex_file(&args(&[
+            "--mode",
+            "reindex-file",
+            "--file",
+            "a.py",
+            "--content",
+            buffer.to_str().unwrap(),
+        ]))
+        .unwrap();
+        let conn = Connection::open(dir.join("x.db")).unwrap();
+        assert!(has_overlay(&conn, ""));
+        assert_eq!(callers(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-    // 应用忽略目录过滤（包含默认忽略）
-    let default_ignores: HashSet<String> = [
-        ".git",
-        "node_modules",
-        "vendor",
-        "dist",
-        "build",
-        "out",
-        "target",
-        "__pycache__",
-        ".venv",
-        "venv",
-        "site-packages",
-        ".m2",
-        ".gradle",
-        ".idea",
-        ".vscode",
-        "coverage",
-        "_build",
-        ".next",
-        ".nuxt",
-        ".svelte-kit",
-    ]
-    .into_iter()
-    .map(|s| s.to_string())
-    .collect();
+    #[test]
+    fn lsp_reader_recovers_from_bad_frames() {
+        let oversized = LSP_MAX_MESSAGE_BYTES + 1;
+        let stream = format!(
+            "Content-Length: 5\r\n\r\n{{bad}}Content-Length: {}\r\n\r\n{}Content-Length: 8\r\n\r\n{{\"id\":1}}",
+            oversized,
+            " ".repeat(oversized)
+        );
+        let mut reader = std::io::Cursor::new(stream.into_bytes());
+        let mut next = || read_lsp_message(&mut reader).unwrap();
+        assert_eq!(next().unwrap().unwrap_err().0, LSP_PARSE_ERROR);
+        assert_eq!(next().unwrap().unwrap_err().0, LSP_INVALID_REQUEST);
+        assert_eq!(next().unwrap().unwrap()["id"], 1);
+        assert!(next().is_none());
+    }
 
-    {
-        let mut ignore_set = default_ignores;
-        if let Some(ignores) = &args.ignore_dirs {
-            for s in ignores
-                .split(',')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-            {
-                ignore_set.insert(s.to_string());
+    #[test]
+    fn paths_escaping_the_project_are_rejected() {
+        let base = std::env::temp_dir().join(format!("ast_paths_{}", rand::random::<u32>()));
+        let project = base.join("project");
+        fs::create_dir_all(project.join(".mcp-data")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        let check = |extra: &[String]| {
+            let mut argv = vec![
+                "ast_indexer_rust".to_string(),
+                "--mode".to_string(),
+                "query".to_string(),
+                "--project".to_string(),
+                project.display().to_string(),
+            ];
+            if !extra.iter().any(|a| a == "--db") {
+                argv.push("--db".to_string());
+                argv.push(project.join(".mcp-data/symbols.db").display().to_string());
             }
+            argv.extend(extra.iter().cloned());
+            validate_paths(&Args::parse_from(argv))
+        };
+        let inside = |p: &str| project.join(p).display().to_string();
+        assert!(check(&["--output".to_string(), inside("out.json")]).is_ok());
+        assert!(check(&["--content".to_string(), "-".to_string()]).is_ok());
+        assert!(check(&["--parse-cache".to_string(), "off".to_string()]).is_ok());
+
+        // `..` 逃出项目根：词法消去后落在根外
+        for flag in [
+            "--db",
+            "--notify",
+            "--content",
+            "--risk-model",
+            "--type-map",
+        ] {
+            let err = check(&[flag.to_string(), inside("../outside/x.json")]).unwrap_err();
+            assert!(err.to_string().starts_with(flag), "{}", err);
         }
-        builder.filter_entry(move |entry| {
-            if !entry.file_type().map(|f| f.is_dir()).unwrap_or(false) {
-                return true;
+        assert!(check(&["--file".to_string(), "../outside/a.py".to_string()]).is_err());
+
+        // 项目内的符号链接指向根外：canonicalize 后落在根外，链接下尚不存在的文件同样拒绝
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("outside"), project.join("link")).unwrap();
+            for flag in ["--output", "--input", "--compare-with", "--parse-cache"] {
+                assert!(check(&[flag.to_string(), inside("link/new.json")]).is_err());
             }
-            !ignore_set.contains(entry.file_name().to_str().unwrap_or(""))
-        });
+            assert!(check(&["--file".to_string(), "link/a.py".to_string()]).is_err());
+            // link/.. 是链接目标的父目录（base），不是项目根
+            assert!(check(&["--output".to_string(), inside("link/../x.json")]).is_err());
+            assert!(check(&["--file".to_string(), "link/../x.py".to_string()]).is_err());
+        }
+        fs::remove_dir_all(&base).unwrap();
     }
 
-    // 应用扩展名过滤
-    let allowed_exts: HashSet<String> = args
-        .extensions
-        .as_ref()
-        .map(|s| {
-            s.split(',')
-                .map(|ext| ext.trim().trim_start_matches('.').to_string())
-                .collect()
-        })
-        .unwrap_or_default();
+    #[test]
+    fn grammars_share_the_runtime_abi() {
+        let setup = get_parser_setup();
+        for spec in GRAMMARS {
+            let abi = (spec.language)().abi_version();
+            assert!(
+                (tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION)
+                    .contains(&abi),
+                "{} grammar has ABI {}",
+                spec.what,
+                abi
+            );
+            // 查询能在该语法上编译
+            assert!(setup.get(spec.exts[0]).is_some(), "{}", spec.what);
+        }
+    }
 
-    // 收集文件，按目录分组
-    let include_files = args.detail == "full";
-    let file_list_limit: usize = 50;
-    let mut structure: HashMap<String, DirInfo> = HashMap::new();
-    let mut total_files = 0;
+    #[test]
+    fn same_file_overrides_share_one_node() {
+        let dir = std::env::temp_dir().join(format!("ast_overrides_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.py"),
+            "class Base:\n    def save(self):\n        pass\n\nclass Child(Base):\n    def save(self):\n        pass\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.py"),
+            "from a import Base\n\nclass Other(Base):\n    def save(self):\n        pass\n",
+        )
+        .unwrap();
+        let index = Args::parse_from([
+            "ast_indexer_rust",
+            "--mode",
+            "index",
+            "--project",
+            dir.to_str().unwrap(),
+            "--db",
+            dir.join("x.db").to_str().unwrap(),
+            "--parse-cache",
+            "off",
+        ]);
+        run_indexer(&index, &heartbeat_path(&index).unwrap()).unwrap();
+        let conn = Connection::open(dir.join("x.db")).unwrap();
+
+        // 跨文件的重写有 overrides 行
+        let rows: Vec<(String, String)> = conn
+            .prepare("SELECT method_id, base_id FROM overrides ORDER BY 1")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(
+            rows,
+            [("func:b.py::save".to_string(), "func:a.py::save".to_string())]
+        );
+        // 已知局限：同文件的 Base.save / Child.save 是同一个 canonical_id，没有自己到自己的行
+        let ids: Vec<String> = conn
+            .prepare("SELECT DISTINCT s.canonical_id FROM symbols s JOIN files f ON f.file_id = s.file_id WHERE f.file_path = 'a.py' AND s.name = 'save'")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(ids, ["func:a.py::save"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-    for entry in builder.build() {
-        if let Ok(entry) = entry {
-            if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
-                let path = entry.path();
+    #[test]
+    fn default_risk_weights_rank_untested_and_central_targets_higher() {
+        let dir = std::env::temp_dir().join(format!("ast_risk_{}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("tests")).unwrap();
+        let leaves: String = (0..10)
+            .map(|i| format!("def leaf{}():\n    pass\n\n", i))
+            .collect();
+        let hub_body: String = (0..10).map(|i| format!("    leaf{}()\n", i)).collect();
+        fs::write(
+            dir.join("lib.py"),
+            format!(
+                "def covered():\n    pass\n\ndef exposed():\n    pass\n\n{}def hub():\n{}",
+                leaves, hub_body
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("app.py"),
+            "def main():\n    exposed()\n    hub()\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("tests/test_lib.py"),
+            "def test_covered():\n    covered()\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("count_only.json"),
+            r#"{"weights": {"affected": 1, "untested": 0, "churn": 0, "centrality": 0}}"#,
+        )
+        .unwrap();
+        let args = |extra: &[&str]| {
+            let mut argv = vec![
+                "ast_indexer_rust".to_string(),
+                "--project".to_string(),
+                dir.display().to_string(),
+                "--db".to_string(),
+                dir.join("x.db").display().to_string(),
+                "--parse-cache".to_string(),
+                "off".to_string(),
+            ];
+            argv.extend(extra.iter().map(|s| s.to_string()));
+            Args::parse_from(argv)
+        };
+        let index = args(&["--mode", "index"]);
+        run_indexer(&index, &heartbeat_path(&index).unwrap()).unwrap();
+        let score = |query: &str, model: Option<&str>| {
+            let output = dir.join("analyze.json");
+            let mut extra = vec![
+                "--mode",
+                "analyze",
+                "--query",
+                query,
+                "--output",
+                output.to_str().unwrap(),
+            ];
+            let model_path = model.map(|m| dir.join(m).display().to_string());
+            if let Some(m) = &model_path {
+                extra.extend(["--risk-model", m.as_str()]);
+            }
+            run_analyze(&args(&extra)).unwrap();
+            let result: serde_json::Value =
+                serde_json::from_slice(&fs::read(output).unwrap()).unwrap();
+            result["risk_score"].as_f64().unwrap()
+        };
 
-                // 扩展名过滤
-                if !allowed_exts.is_empty() {
-                    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
-                    if !allowed_exts.contains(ext) {
-                        continue;
-                    }
-                }
+        // 三者都只有一个直接调用方：只按 affected 计分时分不出高低
+        let count_only: Vec<f64> = ["covered", "exposed", "hub"]
+            .iter()
+            .map(|q| score(q, Some("count_only.json")))
+            .collect();
+        assert_eq!(count_only, [1.0, 1.0, 1.0]);
+
+        // 默认权重：没测试覆盖的高于有测试的，扇出大的再高一档
+        let (covered, exposed, hub) = (
+            score("covered", None),
+            score("exposed", None),
+            score("hub", None),
+        );
+        assert!(covered < exposed, "{} < {}", covered, exposed);
+        assert!(exposed < hub, "{} < {}", exposed, hub);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-                // 计算相对路径
-                let rel_path = path.strip_prefix(project_path).unwrap_or(path);
-                let rel_str = rel_path.to_string_lossy().replace("\\", "/");
+    #[test]
+    fn docs_markdown_keeps_slash_and_underscore_modules_apart() {
+        let dir = std::env::temp_dir().join(format!("ast_docs_{}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir_all(dir.join("a_b")).unwrap();
+        fs::write(dir.join("a/b/m.py"), "def nested():\n    pass\n").unwrap();
+        fs::write(dir.join("a_b/m.py"), "def flat():\n    pass\n").unwrap();
+        let db = dir.join("x.db");
+        let index = Args::parse_from([
+            "ast_indexer_rust",
+            "--mode",
+            "index",
+            "--project",
+            dir.to_str().unwrap(),
+            "--db",
+            db.to_str().unwrap(),
+            "--parse-cache",
+            "off",
+        ]);
+        run_indexer(&index, &heartbeat_path(&index).unwrap()).unwrap();
+        let out = dir.join("docs");
+        run_docs(&Args::parse_from([
+            "ast_indexer_rust",
+            "--mode",
+            "docs",
+            "--project",
+            dir.to_str().unwrap(),
+            "--db",
+            db.to_str().unwrap(),
+            "--format",
+            "markdown",
+            "--output",
+            out.to_str().unwrap(),
+        ]))
+        .unwrap();
+
+        assert!(fs::read_to_string(out.join("a/b.md"))
+            .unwrap()
+            .contains("nested"));
+        assert!(fs::read_to_string(out.join("a_b.md"))
+            .unwrap()
+            .contains("flat"));
+        let index_md = fs::read_to_string(out.join("index.md")).unwrap();
+        assert!(index_md.contains("](a/b.md)"));
+        assert!(index_md.contains("](a_b.md)"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-                // 提取目录和文件名
-                let (dir, file_name) = if let Some(parent) = rel_path.parent() {
-                    let parent_str = parent.to_string_lossy().replace("\\", "/");
-                    let fname = rel_path
-                        .file_name()
-                        .map(|n| n.to_string_lossy().to_string())
-                        .unwrap_or_default();
-                    (parent_str, fname)
-                } else {
-                    ("".to_string(), rel_str.to_string())
-                };
+    #[test]
+    fn python_decorators_are_decorator_edges_not_calls() {
+        let dir = std::env::temp_dir().join(format!("ast_decorators_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("app.py"),
+            "def login_required(f):\n    return f\n\n@app.route(\"/\")\n@login_required\ndef index():\n    helper()\n\nclass A:\n    @property\n    def name(self):\n        pass\n\ndef helper():\n    pass\n",
+        )
+        .unwrap();
+        let index = Args::parse_from([
+            "ast_indexer_rust",
+            "--mode",
+            "index",
+            "--project",
+            dir.to_str().unwrap(),
+            "--db",
+            dir.join("x.db").to_str().unwrap(),
+            "--parse-cache",
+            "off",
+        ]);
+        run_indexer(&index, &heartbeat_path(&index).unwrap()).unwrap();
+        let conn = Connection::open(dir.join("x.db")).unwrap();
+
+        // 项目内的装饰器是 decorator 边；@property / @app.route 不留边
+        let rows: Vec<(String, String)> = conn
+            .prepare("SELECT callee_name, kind FROM calls ORDER BY callee_name")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("helper".to_string(), "call".to_string()),
+                ("login_required".to_string(), "decorator".to_string()),
+            ]
+        );
+        // decorated_by 关系不受影响
+        let decorators: Vec<String> = conn
+            .prepare("SELECT name FROM decorators ORDER BY name")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert_eq!(decorators, ["app.route", "login_required", "property"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-                // 添加到结构
-                let dir_info = structure.entry(dir).or_insert(DirInfo {
-                    file_count: 0,
-                    files: vec![],
-                });
-                dir_info.file_count += 1;
-                if include_files && dir_info.files.len() < file_list_limit {
-                    dir_info.files.push(file_name);
-                }
-                total_files += 1;
-            }
-        }
+    type Pairs = Vec<(String, String)>;
+
+    // 文本扫描器的产出：(symbol_type, qualified_name) 与 (调用者 qualified_name, 被调者)
+    fn scanned(p: ParsedFile) -> (Pairs, Pairs) {
+        let name_of = |tid: usize| {
+            p.symbols
+                .iter()
+                .find(|s| s.temp_id == tid)
+                .map_or(String::new(), |s| s.qualified_name.clone())
+        };
+        let symbols = p
+            .symbols
+            .iter()
+            .map(|s| (s.symbol_type.clone(), s.qualified_name.clone()))
+            .collect();
+        let calls = p
+            .calls
+            .iter()
+            .map(|c| (name_of(c.caller_temp_id), c.callee_name.clone()))
+            .collect();
+        (symbols, calls)
     }
 
-    // 输出结果
-    let result = StructureResult {
-        status: "success".to_string(),
-        total_files,
-        structure,
-    };
+    fn pairs(items: &[(&str, &str)]) -> Pairs {
+        items
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
 
-    if let Some(out_path) = &args.output {
-        let f = fs::File::create(out_path)?;
-        serde_json::to_writer(f, &result)?;
+    #[test]
+    fn lua_scanner_indexes_module_and_local_functions() {
+        let (symbols, calls) = scanned(extract_lua(
+            "local M = {}\n\nfunction M.greet(name)\n  return format(name)\nend\n\nlocal function format(s)\n  return s\nend\n\nreturn M\n",
+            ExtractFeatures::default(),
+        ));
+        assert_eq!(
+            symbols,
+            pairs(&[("function", "M::greet"), ("function", "format")])
+        );
+        assert_eq!(calls, pairs(&[("M::greet", "format")]));
     }
 
-    Ok(())
+    #[test]
+    fn haskell_scanner_merges_equations_into_one_function() {
+        let (symbols, calls) = scanned(extract_haskell(
+            "module Shapes where\n\ndata Shape = Circle Double | Square Double\n\narea :: Shape -> Double\narea (Circle r) = pi * square r\narea (Square s) = square s\n\nsquare :: Double -> Double\nsquare x = x * x\n",
+            ExtractFeatures::default(),
+        ));
+        assert_eq!(
+            symbols,
+            pairs(&[
+                ("struct", "Shape"),
+                ("function", "area"),
+                ("function", "square")
+            ])
+        );
+        assert_eq!(calls, pairs(&[("area", "square"), ("area", "square")]));
+    }
+
+    #[test]
+    fn sql_scanner_links_views_and_functions_to_tables() {
+        let (symbols, calls) = scanned(extract_sql(
+            "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);\nCREATE VIEW active_users AS SELECT * FROM users WHERE active = 1;\nCREATE FUNCTION user_count() RETURNS INT AS $$ SELECT count(*) FROM users $$ LANGUAGE sql;\n",
+            ExtractFeatures::default(),
+        ));
+        assert_eq!(
+            symbols,
+            pairs(&[
+                ("table", "users"),
+                ("view", "active_users"),
+                ("function", "user_count")
+            ])
+        );
+        assert_eq!(
+            calls,
+            pairs(&[
+                ("active_users", "users"),
+                ("user_count", "count"),
+                ("user_count", "users")
+            ])
+        );
+    }
+
+    #[test]
+    fn proto_scanner_nests_rpcs_under_services() {
+        let (symbols, calls) = scanned(extract_proto(
+            "syntax = \"proto3\";\npackage shop;\n\nmessage Order {\n  string id = 1;\n  Item item = 2;\n}\n\nmessage Item { string sku = 1; }\n\nservice Orders {\n  rpc Get(Order) returns (Item);\n}\n",
+            ExtractFeatures::default(),
+        ));
+        assert_eq!(
+            symbols,
+            pairs(&[
+                ("struct", "Order"),
+                ("struct", "Item"),
+                ("interface", "Orders"),
+                ("method", "Orders::Get")
+            ])
+        );
+        assert_eq!(
+            calls,
+            pairs(&[
+                ("Order", "Item"),
+                ("Orders::Get", "Order"),
+                ("Orders::Get", "Item")
+            ])
+        );
+    }
+
+    #[test]
+    fn terraform_scanner_names_blocks_by_address() {
+        let (symbols, calls) = scanned(extract_hcl(
+            "variable \"region\" {}\n\nresource \"aws_s3_bucket\" \"logs\" {\n  bucket = \"logs-${var.region}\"\n}\n\noutput \"bucket\" {\n  value = aws_s3_bucket.logs.id\n}\n",
+            ExtractFeatures::default(),
+        ));
+        assert_eq!(
+            symbols,
+            pairs(&[
+                ("variable", "var.region"),
+                ("resource", "aws_s3_bucket.logs"),
+                ("output", "output.bucket")
+            ])
+        );
+        assert_eq!(
+            calls,
+            pairs(&[
+                ("aws_s3_bucket.logs", "var.region"),
+                ("output.bucket", "aws_s3_bucket.logs")
+            ])
+        );
+    }
+
+    #[cfg(feature = "c")]
+    #[test]
+    fn objc_scanner_names_methods_by_selector() {
+        let parsed = extract_objc(
+            &get_parser_setup(),
+            "m",
+            "@interface Person : NSObject\n- (instancetype)initWithName:(NSString *)name age:(int)age;\n@end\n\n@implementation Person\n- (instancetype)initWithName:(NSString *)name age:(int)age {\n  [self greet];\n  return self;\n}\n- (void)greet {\n  NSLog(@\"hi\");\n}\n@end\n",
+            ExtractFeatures::default(),
+        );
+        let (symbols, calls) = scanned(parsed);
+        assert_eq!(
+            symbols,
+            pairs(&[
+                ("class", "Person"),
+                ("method", "Person::initWithName:age:"),
+                ("class", "Person"),
+                ("method", "Person::initWithName:age:"),
+                ("method", "Person::greet")
+            ])
+        );
+        assert_eq!(
+            calls,
+            pairs(&[
+                ("Person::initWithName:age:", "greet"),
+                ("Person::greet", "NSLog")
+            ])
+        );
+    }
 }
@@ -908,12 +908,13 @@ func wrapProjectMap(sm *SessionManager, ai *services.ASTIndexer) server.ToolHand
 			}
 
 			type dirCount struct {
-				Path  string
-				Count int
+				Path   string
+				Count  int
+				Readme string
 			}
 			dirs := make([]dirCount, 0, len(structureResult.Structure))
 			for p, info := range structureResult.Structure {
-				dirs = append(dirs, dirCount{Path: p, Count: info.FileCount})
+				dirs = append(dirs, dirCount{Path: p, Count: info.FileCount, Readme: info.Readme})
 			}
 			sort.Slice(dirs, func(i, j int) bool {
 				if dirs[i].Count == dirs[j].Count {
@@ -939,7 +940,11 @@ func wrapProjectMap(sm *SessionManager, ai *services.ASTIndexer) server.ToolHand
 				if path == "" {
 					path = "(root)"
 				}
-				sb.WriteString(fmt.Sprintf("- `%s/` (%d files)\n", path, dirs[i].Count))
+				sb.WriteString(fmt.Sprintf("- `%s/` (%d files)", path, dirs[i].Count))
+				if dirs[i].Readme != "" {
+					sb.WriteString(" — " + dirs[i].Readme)
+				}
+				sb.WriteString("\n")
 			}
 			if len(dirs) > limit {
 				sb.WriteString(fmt.Sprintf("\n... 其余 %d 个目录已省略，请使用 scope 下钻。\n", len(dirs)-limit))
@@ -400,6 +400,13 @@ func (mr *MapRenderer) renderWithMode(sb *strings.Builder, mode string, truncate
 		})
 
 		sb.WriteString(fmt.Sprintf("\n📂 **%s/**\n", dir))
+		readmeKey := dir
+		if dir == "(root)" {
+			readmeKey = ""
+		}
+		if readme := mr.Result.Readmes[readmeKey]; readme != "" {
+			sb.WriteString(fmt.Sprintf("  > %s\n", readme))
+		}
 
 		// 自适应折叠策略 (仅Standard模式)
 		topLimit := 10
@@ -231,6 +231,7 @@ func RegisterSystemTools(s *server.MCPServer, sm *SessionManager, ai *services.A
 返回：
   - status/mode/started_at/finished_at
   - heartbeat(processed/total)
+  - runs(正在运行的索引器进程：pid/mode/started_at，崩溃进程下次启动时自动清理)
   - symbols.db / symbols.db-wal / symbols.db-shm 文件大小
 
 触发词：
@@ -496,6 +497,15 @@ func wrapIndexStatus(sm *SessionManager) server.ToolHandlerFunc {
 			result["heartbeat_error"] = err.Error()
 		}
 
+		// 运行登记：索引器启动时登记、退出时注销，死进程与其遗留心跳在下次启动时清理
+		runsPath := filepath.Join(absRoot, ".mcp-data", "runs.json")
+		if raw, err := os.ReadFile(runsPath); err == nil {
+			var runs []map[string]interface{}
+			if err := json.Unmarshal(raw, &runs); err == nil {
+				result["runs"] = runs
+			}
+		}
+
 		sizeMap := map[string]int64{}
 		for _, name := range []string{"symbols.db", "symbols.db-wal", "symbols.db-shm"} {
 			p := filepath.Join(absRoot, ".mcp-data", name)